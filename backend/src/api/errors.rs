@@ -0,0 +1,31 @@
+use thiserror::Error;
+
+use crate::database::errors::DatabaseError;
+
+#[derive(Error, Debug)]
+pub enum ApiError {
+    #[error("Bad Request: {0}")]
+    BadRequest(String),
+    #[error("Not Found")]
+    NotFound,
+    #[error("Method Not Allowed")]
+    MethodNotAllowed,
+    #[error("Database Error: {0}")]
+    Database(#[from] DatabaseError),
+    #[error("JSON Error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Server Error: {0}")]
+    Server(String),
+}
+
+impl ApiError {
+    /// The HTTP status code returned for the error
+    pub fn status_code(&self) -> u16 {
+        match self {
+            ApiError::BadRequest(_) => 400,
+            ApiError::NotFound => 404,
+            ApiError::MethodNotAllowed => 405,
+            ApiError::Database(_) | ApiError::Json(_) | ApiError::Server(_) => 500,
+        }
+    }
+}
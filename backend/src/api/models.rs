@@ -0,0 +1,78 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::sensor_control::models::TemperatureData;
+
+/// A reading as returned by the API, with an RFC 3339 timestamp
+#[derive(Debug, Serialize)]
+pub struct Reading {
+    pub device_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bridge_id: Option<String>,
+    pub timestamp: DateTime<Utc>,
+    pub online: bool,
+    pub temperature: f32,
+    pub humidity: f32,
+}
+
+impl From<TemperatureData> for Reading {
+    fn from(data: TemperatureData) -> Self {
+        Reading {
+            device_name: data.device_name,
+            device_id: data.device_id,
+            bridge_id: data.bridge_id,
+            timestamp: data.timestamp,
+            online: data.online,
+            temperature: data.temperature,
+            humidity: data.humidity,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct History {
+    pub device_name: String,
+    /// Names the device has had before or since, whose readings are included
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub other_names: Vec<String>,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub readings: Vec<Reading>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Health {
+    pub status: &'static str,
+    pub version: &'static str,
+    pub uptime_secs: u64,
+    pub database: ComponentHealth,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nest_refresh_token: Option<RefreshTokenHealth>,
+}
+
+/// A component's health: `ok`, `unavailable` if the database cannot be reached, or `error`
+#[derive(Debug, Serialize)]
+pub struct ComponentHealth {
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The Nest refresh token's status: `ok`, `expiring`, `expired` or `error` if it was rejected
+#[derive(Debug, Serialize)]
+pub struct RefreshTokenHealth {
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_refreshed: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorBody {
+    pub error: String,
+}
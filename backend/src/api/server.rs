@@ -0,0 +1,826 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use chrono::{DateTime, Duration, Utc};
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use super::errors::ApiError;
+use super::models::{ComponentHealth, ErrorBody, Health, History, Reading, RefreshTokenHealth};
+
+use crate::database::errors::DatabaseError;
+use crate::datastore::storage::{Series, Storage};
+use crate::metrics;
+use crate::sensor_control::models::TemperatureData;
+use crate::sensor_control::nest::RefreshTokenStatus;
+use crate::sensor_control::sensors::RenameStore;
+use crate::shutdown::Shutdown;
+
+/// History returned when no `from` is given
+const DEFAULT_HISTORY_HOURS: i64 = 24;
+const DEFAULT_HISTORY_LIMIT: i64 = 10_000;
+const MAX_HISTORY_LIMIT: i64 = 100_000;
+/// The most renames read for a name or a device
+const MAX_RENAMES: i64 = 1_000;
+
+const JSON_CONTENT_TYPE: &str = "application/json";
+
+/// The endpoints, each of which only accepts `GET`
+enum Route<'a> {
+    Sensors,
+    /// The history of the device with the given percent-encoded name
+    History(&'a str),
+    Health,
+    Metrics,
+}
+
+/// A device as history requests find it, by its stable ID and the Hue bridge it is paired with
+#[derive(PartialEq, Eq, Hash)]
+struct Device {
+    device_id: String,
+    bridge_id: Option<String>,
+}
+
+/// A name a device had from `since` until it was renamed at `until`
+struct HeldName {
+    name: String,
+    since: DateTime<Utc>,
+    until: DateTime<Utc>,
+}
+
+/// A read-only JSON API over the stored readings.
+///
+/// - `GET /sensors` returns the latest reading for each device
+/// - `GET /sensors/{name}/history?from=&to=&limit=` returns readings between two RFC 3339
+///   timestamps, defaulting to the last 24 hours, for the device last called `name` by then,
+///   including its readings from before or after it was renamed
+/// - `GET /health` reports whether the backend, its database and the Nest refresh token are
+///   healthy
+/// - `GET /metrics` returns the Prometheus metrics
+pub struct ApiServer<T> {
+    data_store: Arc<T>,
+    nest_refresh_token: Option<Arc<Mutex<RefreshTokenStatus>>>,
+    renames: Option<Arc<RenameStore>>,
+    started: Instant,
+}
+
+impl<T> ApiServer<T>
+where
+    T: Storage<TemperatureData, Error = DatabaseError> + Send + Sync + 'static,
+{
+    pub fn new(data_store: Arc<T>) -> Self {
+        ApiServer {
+            data_store,
+            nest_refresh_token: None,
+            renames: None,
+            started: Instant::now(),
+        }
+    }
+
+    /// Reports the Nest refresh token in the health check, which fails once the token has
+    /// expired or been rejected
+    pub fn with_nest_refresh_token(mut self, status: Arc<Mutex<RefreshTokenStatus>>) -> Self {
+        self.nest_refresh_token = Some(status);
+        self
+    }
+
+    /// Follows the renames of Hue sensors in history requests, so that a name no longer in use
+    /// is found and a device's history lists its other names
+    pub fn with_renames(mut self, renames: Arc<RenameStore>) -> Self {
+        self.renames = Some(renames);
+        self
+    }
+
+    /// Serves requests on `bind_address` from a new thread until shutdown is triggered
+    pub fn run(
+        self,
+        bind_address: &str,
+        shutdown: &Shutdown,
+    ) -> Result<thread::JoinHandle<()>, ApiError> {
+        let server = Arc::new(
+            Server::http(bind_address).map_err(|error| ApiError::Server(error.to_string()))?,
+        );
+
+        log::info!("API listening on {}", server.server_addr());
+
+        // Unblock the request loop as soon as shutdown is triggered
+        let unblock_server = Arc::clone(&server);
+        let shutdown = shutdown.clone();
+        thread::Builder::new()
+            .name("API shutdown".to_string())
+            .spawn(move || {
+                shutdown.wait();
+                unblock_server.unblock();
+            })
+            .map_err(|error| ApiError::Server(error.to_string()))?;
+
+        thread::Builder::new()
+            .name("API".to_string())
+            .spawn(move || {
+                for request in server.incoming_requests() {
+                    self.handle(request);
+                }
+                log::debug!("API stopped");
+            })
+            .map_err(|error| ApiError::Server(error.to_string()))
+    }
+
+    fn handle(&self, request: Request) {
+        log::debug!("{} {}", request.method(), request.url());
+
+        let (status_code, content_type, body) = match self.route(request.method(), request.url()) {
+            Ok(response) => response,
+            Err(error) => {
+                if error.status_code() >= 500 {
+                    log::error!(
+                        "Error handling {} {}: {error}",
+                        request.method(),
+                        request.url()
+                    );
+                }
+                let body = serde_json::to_vec(&ErrorBody {
+                    error: error.to_string(),
+                })
+                .unwrap_or_default();
+                (error.status_code(), JSON_CONTENT_TYPE.to_string(), body)
+            }
+        };
+
+        let response = Response::from_data(body)
+            .with_status_code(status_code)
+            .with_header(content_type_header(&content_type));
+
+        if let Err(error) = request.respond(response) {
+            log::warn!("Error sending API response: {error}");
+        }
+    }
+
+    /// Routes a request, returning the status code, content type and body. Unknown paths are
+    /// 404s whatever the method.
+    fn route(&self, method: &Method, url: &str) -> Result<(u16, String, Vec<u8>), ApiError> {
+        let (path, query) = url.split_once('?').unwrap_or((url, ""));
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+        let route = match segments.as_slice() {
+            ["sensors"] => Route::Sensors,
+            ["sensors", name, "history"] => Route::History(name),
+            ["health"] => Route::Health,
+            ["metrics"] => Route::Metrics,
+            _ => return Err(ApiError::NotFound),
+        };
+
+        if *method != Method::Get {
+            return Err(ApiError::MethodNotAllowed);
+        }
+
+        match route {
+            Route::Sensors => json(200, &self.latest()?),
+            Route::History(name) => {
+                let name = percent_decode_str(name)
+                    .decode_utf8()
+                    .map_err(|_| ApiError::BadRequest("Sensor name is not UTF-8".to_string()))?;
+                json(200, &self.history(&name, query)?)
+            }
+            Route::Health => self.health(),
+            Route::Metrics => {
+                let (content_type, body) =
+                    metrics::render().map_err(|error| ApiError::Server(error.to_string()))?;
+                Ok((200, content_type, body))
+            }
+        }
+    }
+
+    /// The latest reading of each device by `device_id`, so that devices with the same name are
+    /// listed apart. Readings stored before readings had device IDs are listed by name, unless a
+    /// device with an ID has that name now.
+    fn latest(&self) -> Result<Vec<Reading>, ApiError> {
+        let latest =
+            self.data_store
+                .get_latest_timestamps("device_id", "device_name", "timestamp")?;
+        let mut readings: Vec<Reading> = Vec::new();
+
+        for (device_id, timestamp) in &latest.by_id {
+            readings.extend(
+                self.data_store
+                    .get_items_between(
+                        Series::new("device_id", device_id),
+                        "timestamp",
+                        *timestamp,
+                        *timestamp,
+                        1,
+                    )?
+                    .into_iter()
+                    .map(Reading::from),
+            );
+        }
+
+        for (name, timestamp) in &latest.by_name {
+            if readings.iter().any(|reading| reading.device_name == *name) {
+                continue;
+            }
+            readings.extend(
+                self.data_store
+                    .get_items_between(
+                        Series::new("device_name", name).without("device_id"),
+                        "timestamp",
+                        *timestamp,
+                        *timestamp,
+                        1,
+                    )?
+                    .into_iter()
+                    .map(Reading::from),
+            );
+        }
+
+        readings.sort_by(|a, b| {
+            a.device_name
+                .cmp(&b.device_name)
+                .then_with(|| a.device_id.cmp(&b.device_id))
+        });
+
+        Ok(readings)
+    }
+
+    fn history(&self, name: &str, query: &str) -> Result<History, ApiError> {
+        let params: HashMap<String, String> = form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect();
+
+        let to = match params.get("to") {
+            Some(to) => parse_timestamp("to", to)?,
+            None => Utc::now(),
+        };
+
+        let from = match params.get("from") {
+            Some(from) => parse_timestamp("from", from)?,
+            None => to - Duration::hours(DEFAULT_HISTORY_HOURS),
+        };
+
+        if from > to {
+            return Err(ApiError::BadRequest(
+                "from must not be after to".to_string(),
+            ));
+        }
+
+        let limit = match params.get("limit") {
+            Some(limit) => limit
+                .parse::<i64>()
+                .ok()
+                .filter(|limit| (1..=MAX_HISTORY_LIMIT).contains(limit))
+                .ok_or_else(|| {
+                    ApiError::BadRequest(format!(
+                        "limit must be a number from 1 to {MAX_HISTORY_LIMIT}"
+                    ))
+                })?,
+            None => DEFAULT_HISTORY_LIMIT,
+        };
+
+        let mut readings: Vec<Reading> = Vec::new();
+        let mut other_names: Vec<String> = Vec::new();
+
+        match self.device_named(name, from, to, limit)? {
+            Some(device) => {
+                readings.extend(
+                    self.data_store
+                        .get_items_between(
+                            Series::new("device_id", &device.device_id),
+                            "timestamp",
+                            from,
+                            to,
+                            limit,
+                        )?
+                        .into_iter()
+                        .filter(|reading| reading.bridge_id == device.bridge_id)
+                        .map(Reading::from),
+                );
+
+                // Readings stored before readings had device IDs can only be found by name, so
+                // each name is only searched while the device had it
+                for held in self.names_of(&device, name)? {
+                    if held.name != name && !other_names.contains(&held.name) {
+                        other_names.push(held.name.clone());
+                    }
+
+                    let (since, until) = (from.max(held.since), to.min(held.until));
+                    if since > until {
+                        continue;
+                    }
+                    readings.extend(
+                        self.data_store
+                            .get_items_between(
+                                Series::new("device_name", &held.name).without("device_id"),
+                                "timestamp",
+                                since,
+                                until,
+                                limit,
+                            )?
+                            .into_iter()
+                            .map(Reading::from),
+                    );
+                }
+            }
+            None => readings.extend(
+                self.data_store
+                    .get_items_between(
+                        Series::new("device_name", name),
+                        "timestamp",
+                        from,
+                        to,
+                        limit,
+                    )?
+                    .into_iter()
+                    .map(Reading::from),
+            ),
+        }
+
+        readings.sort_by_key(|reading| reading.timestamp);
+        readings.truncate(limit as usize);
+
+        Ok(History {
+            device_name: name.to_string(),
+            other_names,
+            from,
+            to,
+            readings,
+        })
+    }
+
+    /// The device that was last called `name` by `to`, going by its readings between `from` and
+    /// `to` and by the renames to and from the name, or `None` if its readings have no device ID
+    fn device_named(
+        &self,
+        name: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Option<Device>, ApiError> {
+        // When each device was last known to have the name
+        let mut last_named: HashMap<Device, DateTime<Utc>> = HashMap::new();
+        let mut named = |device: Device, timestamp: DateTime<Utc>| {
+            let last = last_named.entry(device).or_insert(timestamp);
+            *last = (*last).max(timestamp);
+        };
+
+        for reading in self.data_store.get_items_between(
+            Series::new("device_name", name),
+            "timestamp",
+            from,
+            to,
+            limit,
+        )? {
+            if let Some(device_id) = reading.device_id {
+                let device = Device {
+                    device_id,
+                    bridge_id: reading.bridge_id,
+                };
+                named(device, reading.timestamp);
+            }
+        }
+
+        if let Some(renames) = &self.renames {
+            for field in ["device_name", "previous_name"] {
+                for rename in renames.get_items_between(
+                    Series::new(field, name),
+                    "timestamp",
+                    DateTime::UNIX_EPOCH,
+                    to,
+                    MAX_RENAMES,
+                )? {
+                    let device = Device {
+                        device_id: rename.device_id,
+                        bridge_id: Some(rename.bridge_id),
+                    };
+                    named(device, rename.timestamp);
+                }
+            }
+        }
+
+        Ok(last_named
+            .into_iter()
+            .max_by_key(|(_, timestamp)| *timestamp)
+            .map(|(device, _)| device))
+    }
+
+    /// The names `device` has had, oldest first, going by its renames. A device that has never
+    /// been renamed has only ever had `name`.
+    fn names_of(&self, device: &Device, name: &str) -> Result<Vec<HeldName>, ApiError> {
+        // Renames recorded by earlier versions have the device ID as `resource_id`
+        let mut renames = Vec::new();
+        if let Some(rename_store) = &self.renames {
+            for field in ["device_id", "resource_id"] {
+                renames.extend(rename_store.get_items_between(
+                    Series::new(field, &device.device_id),
+                    "timestamp",
+                    DateTime::UNIX_EPOCH,
+                    DateTime::<Utc>::MAX_UTC,
+                    MAX_RENAMES,
+                )?);
+            }
+        }
+        renames.sort_by_key(|rename| rename.timestamp);
+
+        let mut names = Vec::new();
+        let mut since = DateTime::UNIX_EPOCH;
+        let mut current = name.to_string();
+        for rename in renames
+            .into_iter()
+            .filter(|rename| device.bridge_id.as_ref() == Some(&rename.bridge_id))
+        {
+            names.push(HeldName {
+                name: rename.previous_name,
+                since,
+                until: rename.timestamp,
+            });
+            since = rename.timestamp;
+            current = rename.device_name;
+        }
+        names.push(HeldName {
+            name: current,
+            since,
+            until: DateTime::<Utc>::MAX_UTC,
+        });
+
+        Ok(names)
+    }
+
+    /// The health report, returned with a 503 status if anything is unhealthy so that
+    /// `curl --fail` and container health checks notice
+    fn health(&self) -> Result<(u16, String, Vec<u8>), ApiError> {
+        let database = match self.data_store.ping() {
+            Ok(()) => ComponentHealth {
+                status: "ok",
+                error: None,
+            },
+            Err(error) => ComponentHealth {
+                status: if error.is_unavailable() {
+                    "unavailable"
+                } else {
+                    "error"
+                },
+                error: Some(error.to_string()),
+            },
+        };
+
+        let nest_refresh_token = self.nest_refresh_token.as_ref().map(|status| {
+            let status = status
+                .lock()
+                .expect("Nest refresh token status mutex poisoned");
+            RefreshTokenHealth {
+                status: status.state(Utc::now()),
+                expires_at: status.expires_at,
+                last_refreshed: status.last_refreshed,
+                error: status.error.clone(),
+            }
+        });
+
+        // An expiring token still works, so it is only a warning
+        let healthy = database.error.is_none()
+            && nest_refresh_token
+                .as_ref()
+                .is_none_or(|token| matches!(token.status, "ok" | "expiring"));
+
+        let health = Health {
+            status: if healthy { "ok" } else { "degraded" },
+            version: env!("CARGO_PKG_VERSION"),
+            uptime_secs: self.started.elapsed().as_secs(),
+            database,
+            nest_refresh_token,
+        };
+
+        json(if healthy { 200 } else { 503 }, &health)
+    }
+}
+
+fn json<S: Serialize>(status_code: u16, value: &S) -> Result<(u16, String, Vec<u8>), ApiError> {
+    Ok((
+        status_code,
+        JSON_CONTENT_TYPE.to_string(),
+        serde_json::to_vec(value)?,
+    ))
+}
+
+fn content_type_header(content_type: &str) -> Header {
+    Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes())
+        .expect("Content-Type header is valid")
+}
+
+fn parse_timestamp(name: &str, value: &str) -> Result<DateTime<Utc>, ApiError> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|timestamp| timestamp.with_timezone(&Utc))
+        .map_err(|error| {
+            ApiError::BadRequest(format!("{name} must be an RFC 3339 timestamp: {error}"))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::datastore::memory::InMemoryStorage;
+    use crate::sensor_control::models::DeviceRename;
+    use crate::tests::harness::{at_minute, reading};
+
+    type Api = ApiServer<InMemoryStorage<TemperatureData>>;
+
+    fn api(readings: &[TemperatureData]) -> Api {
+        let storage = InMemoryStorage::new(&["device_name", "timestamp"]);
+        storage.save_items(readings).unwrap();
+        ApiServer::new(Arc::new(storage))
+    }
+
+    /// The status code and JSON body of a request, as the server would respond
+    fn request(api: &Api, method: Method, url: &str) -> (u16, serde_json::Value) {
+        match api.route(&method, url) {
+            Ok((status_code, _, body)) => (status_code, serde_json::from_slice(&body).unwrap()),
+            Err(error) => (
+                error.status_code(),
+                serde_json::json!({ "error": error.to_string() }),
+            ),
+        }
+    }
+
+    fn get(api: &Api, url: &str) -> (u16, serde_json::Value) {
+        request(api, Method::Get, url)
+    }
+
+    /// The temperatures of the readings in a history response
+    fn temperatures(history: &serde_json::Value) -> Vec<f64> {
+        history["readings"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|reading| reading["temperature"].as_f64().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn sensors_are_listed_with_their_latest_reading_by_name() {
+        let api = api(&[
+            reading("Lounge", at_minute(1), 20.0),
+            reading("Lounge", at_minute(2), 21.0),
+            reading("Kitchen", at_minute(1), 19.0),
+        ]);
+
+        let (status_code, body) = get(&api, "/sensors");
+
+        assert_eq!(status_code, 200);
+        let latest: Vec<(&str, f64)> = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|reading| {
+                (
+                    reading["device_name"].as_str().unwrap(),
+                    reading["temperature"].as_f64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(latest, vec![("Kitchen", 19.0), ("Lounge", 21.0)]);
+    }
+
+    #[test]
+    fn sensors_with_the_same_name_are_listed_apart_by_device_id() {
+        let with_id = |device_id: &str, minute, temperature| TemperatureData {
+            device_id: Some(device_id.to_string()),
+            ..reading("Hallway", at_minute(minute), temperature)
+        };
+        let api = api(&[
+            reading("Hallway", at_minute(1), 18.0),
+            reading("Attic", at_minute(1), 15.0),
+            with_id("hue-1", 2, 20.0),
+            with_id("hue-1", 3, 21.0),
+            with_id("nest-1", 4, 22.0),
+        ]);
+
+        let (status_code, body) = get(&api, "/sensors");
+
+        // The Hallway's reading from before readings had IDs is from one of the two devices
+        // called Hallway now
+        assert_eq!(status_code, 200);
+        let latest: Vec<(&str, Option<&str>, f64)> = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|reading| {
+                (
+                    reading["device_name"].as_str().unwrap(),
+                    reading["device_id"].as_str(),
+                    reading["temperature"].as_f64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            latest,
+            vec![
+                ("Attic", None, 15.0),
+                ("Hallway", Some("hue-1"), 21.0),
+                ("Hallway", Some("nest-1"), 22.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn history_is_between_from_and_to_and_limited() {
+        let api = api(&[
+            reading("Living Room", at_minute(1), 21.0),
+            reading("Living Room", at_minute(2), 22.0),
+            reading("Living Room", at_minute(3), 23.0),
+            reading("Living Room", at_minute(4), 24.0),
+            reading("Kitchen", at_minute(2), 19.0),
+        ]);
+        let url =
+            "/sensors/Living%20Room/history?from=2026-01-01T00:02:00Z&to=2026-01-01T00:04:00Z";
+
+        let (status_code, history) = get(&api, url);
+        assert_eq!(status_code, 200);
+        assert_eq!(history["device_name"], "Living Room");
+        assert_eq!(history["from"], "2026-01-01T00:02:00Z");
+        assert_eq!(temperatures(&history), vec![22.0, 23.0, 24.0]);
+
+        let (_, history) = get(&api, &format!("{url}&limit=2"));
+        assert_eq!(temperatures(&history), vec![22.0, 23.0]);
+
+        // Offsets are converted to UTC
+        let (_, history) = get(
+            &api,
+            "/sensors/Living%20Room/history?from=2026-01-01T01:03:00%2B01:00&to=2026-01-01T00:03:00Z",
+        );
+        assert_eq!(temperatures(&history), vec![23.0]);
+    }
+
+    #[test]
+    fn history_finds_a_devices_readings_by_id_whatever_their_name() {
+        let with_id = |device_name, device_id: &str, minute, temperature| TemperatureData {
+            device_id: Some(device_id.to_string()),
+            ..reading(device_name, at_minute(minute), temperature)
+        };
+        let api = api(&[
+            reading("Lounge", at_minute(1), 20.0),
+            with_id("Lounge", "1", 2, 21.0),
+            with_id("Living Room", "1", 3, 22.0),
+            with_id("Kitchen", "2", 3, 19.0),
+        ]);
+
+        let (_, history) = get(
+            &api,
+            "/sensors/Lounge/history?from=2026-01-01T00:00:00Z&to=2026-01-01T01:00:00Z",
+        );
+
+        assert_eq!(temperatures(&history), vec![20.0, 21.0, 22.0]);
+    }
+
+    #[test]
+    fn history_follows_the_renames_of_one_device_only() {
+        let hue = |device_name, device_id: &str, minute, temperature| TemperatureData {
+            device_id: Some(device_id.to_string()),
+            bridge_id: Some("bridge".to_string()),
+            ..reading(device_name, at_minute(minute), temperature)
+        };
+        let rename =
+            |previous_name: &str, device_name: &str, device_id: &str, minute| DeviceRename {
+                device_name: device_name.to_string(),
+                previous_name: previous_name.to_string(),
+                bridge_id: "bridge".to_string(),
+                device_id: device_id.to_string(),
+                timestamp: at_minute(minute),
+            };
+
+        // Sensor a is renamed from Bedroom to Guest, and then sensor b from Spare to Bedroom.
+        // Their first readings were stored before readings had device IDs.
+        let renames = InMemoryStorage::new(&["device_name", "timestamp"]);
+        renames
+            .save_items(&[
+                rename("Bedroom", "Guest", "a", 3),
+                rename("Spare", "Bedroom", "b", 5),
+            ])
+            .unwrap();
+        let api = api(&[
+            reading("Bedroom", at_minute(0), 10.0),
+            hue("Bedroom", "a", 1, 11.0),
+            hue("Bedroom", "a", 2, 12.0),
+            hue("Guest", "a", 4, 14.0),
+            reading("Spare", at_minute(0), 20.0),
+            hue("Spare", "b", 1, 21.0),
+            hue("Spare", "b", 2, 22.0),
+            hue("Bedroom", "b", 6, 26.0),
+        ])
+        .with_renames(Arc::new(renames));
+        let history = |name: &str, to: &str| {
+            let (status_code, history) = get(
+                &api,
+                &format!("/sensors/{name}/history?from=2026-01-01T00:00:00Z&to={to}"),
+            );
+            assert_eq!(status_code, 200);
+            (temperatures(&history), history["other_names"].clone())
+        };
+
+        assert_eq!(
+            history("Guest", "2026-01-01T01:00:00Z"),
+            (vec![10.0, 11.0, 12.0, 14.0], serde_json::json!(["Bedroom"]))
+        );
+        assert_eq!(
+            history("Bedroom", "2026-01-01T01:00:00Z"),
+            (vec![20.0, 21.0, 22.0, 26.0], serde_json::json!(["Spare"]))
+        );
+        // Until sensor a was renamed, Bedroom was sensor a
+        assert_eq!(
+            history("Bedroom", "2026-01-01T00:02:30Z"),
+            (vec![10.0, 11.0, 12.0], serde_json::json!(["Guest"]))
+        );
+    }
+
+    #[test]
+    fn invalid_history_queries_are_bad_requests() {
+        let api = api(&[]);
+
+        for query in [
+            "from=yesterday",
+            "to=2026-01-01",
+            "from=2026-01-02T00:00:00Z&to=2026-01-01T00:00:00Z",
+            "limit=0",
+            "limit=100001",
+            "limit=ten",
+        ] {
+            let (status_code, body) = get(&api, &format!("/sensors/Lounge/history?{query}"));
+            assert_eq!(status_code, 400, "{query}");
+            assert!(
+                body["error"].as_str().unwrap().starts_with("Bad Request"),
+                "{query}: {body}"
+            );
+        }
+
+        let (status_code, _) = get(&api, "/sensors/%FF/history");
+        assert_eq!(status_code, 400);
+    }
+
+    #[test]
+    fn unknown_paths_are_not_found_whatever_the_method() {
+        let api = api(&[]);
+
+        assert_eq!(get(&api, "/sensor").0, 404);
+        assert_eq!(get(&api, "/sensors/Lounge").0, 404);
+        assert_eq!(request(&api, Method::Post, "/sensor").0, 404);
+        assert_eq!(request(&api, Method::Post, "/sensors").0, 405);
+        assert_eq!(request(&api, Method::Delete, "/health").0, 405);
+    }
+
+    #[test]
+    fn health_fails_once_the_nest_refresh_token_is_rejected() {
+        let status = Arc::new(Mutex::new(RefreshTokenStatus {
+            expires_at: None,
+            warning_period: Duration::days(1),
+            last_refreshed: None,
+            error: None,
+        }));
+        let api = api(&[]).with_nest_refresh_token(Arc::clone(&status));
+
+        let (status_code, health) = get(&api, "/health");
+        assert_eq!(status_code, 200);
+        assert_eq!(health["status"], "ok");
+        assert_eq!(health["database"]["status"], "ok");
+        assert_eq!(health["nest_refresh_token"]["status"], "ok");
+
+        status.lock().unwrap().error = Some("invalid_grant".to_string());
+
+        let (status_code, health) = get(&api, "/health");
+        assert_eq!(status_code, 503);
+        assert_eq!(health["status"], "degraded");
+        assert_eq!(health["nest_refresh_token"]["status"], "error");
+        assert_eq!(health["nest_refresh_token"]["error"], "invalid_grant");
+    }
+
+    #[test]
+    fn metrics_are_rendered_with_their_labels() {
+        let api = api(&[]);
+        metrics::record_readings(
+            "metrics-test",
+            &[TemperatureData {
+                humidity: 45.0,
+                ..reading("Metrics Lounge", at_minute(0), 21.5)
+            }],
+        );
+        metrics::record_insert("metrics_test", std::time::Duration::from_millis(20), 3);
+
+        let (status_code, content_type, body) = api.route(&Method::Get, "/metrics").unwrap();
+
+        assert_eq!(status_code, 200);
+        assert!(content_type.starts_with("text/plain"), "{content_type}");
+        let body = String::from_utf8(body).unwrap();
+        for line in [
+            r#"sensor_temperature_celsius{device_name="Metrics Lounge",source="metrics-test"} 21.5"#,
+            r#"sensor_humidity_percent{device_name="Metrics Lounge",source="metrics-test"} 45"#,
+            r#"sensor_online{device_name="Metrics Lounge",source="metrics-test"} 1"#,
+            r#"mongo_inserted_documents_total{collection="metrics_test"} 3"#,
+            r#"mongo_insert_duration_seconds_count{collection="metrics_test"} 1"#,
+            r#"mongo_insert_duration_seconds_sum{collection="metrics_test"} 0.02"#,
+        ] {
+            assert!(
+                body.lines().any(|rendered| rendered == line),
+                "{line} in {body}"
+            );
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::{Parser, Subcommand};
+
+use crate::sensor_control::commands::{EcoMode, ThermostatCommand, ThermostatMode};
+
+/// Polls Hue and Nest sensors and stores their readings in MongoDB.
+///
+/// Command-line flags take precedence over environment variables and the config file.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Cli {
+    /// Path to the TOML config file (defaults to config.toml, which may be absent)
+    #[arg(short, long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Log level: off, error, warn, info, debug or trace
+    #[arg(long, value_name = "LEVEL")]
+    pub log_level: Option<String>,
+
+    /// MongoDB connection URL
+    #[arg(long, value_name = "URL")]
+    pub mongo_url: Option<String>,
+
+    /// Override any config value, e.g. `--set nest.poll_interval_secs=60`
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    pub overrides: Vec<String>,
+
+    /// Poll the sensors but keep readings in memory and log them instead of writing to MongoDB
+    #[arg(long)]
+    pub dry_run: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// One-off commands, run instead of polling the sensors
+#[derive(Subcommand, Debug)]
+// The variant names are the subcommand names, e.g. `nest-command`
+#[allow(clippy::enum_variant_names)]
+pub enum Command {
+    /// Send a command to a Nest thermostat, recording it in the command audit trail
+    NestCommand {
+        /// The thermostat's name as stored with its readings, e.g. "Nest (Hallway)"
+        #[arg(long)]
+        device: String,
+
+        #[command(subcommand)]
+        command: NestCommand,
+    },
+
+    /// Pair with the Hue bridge and save the application key
+    HuePair {
+        /// The bridge's address, instead of discovering it
+        #[arg(long, value_name = "ADDRESS")]
+        bridge: Option<String>,
+
+        /// How long to wait for the link button to be pressed
+        #[arg(long, default_value_t = 60, value_name = "SECONDS")]
+        timeout_secs: u64,
+
+        /// Replace existing key files that every bridge shares, which may hold another bridge's
+        /// keys
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Authorize access to the Nest thermostat and save the new refresh token
+    NestAuth {
+        /// The authorization code, or the URL redirected to after consenting, instead of asking
+        #[arg(long, conflicts_with = "listen")]
+        code: Option<String>,
+
+        /// Listen for the consent redirect on this address, e.g. 127.0.0.1:8091, instead of
+        /// asking for the code; the redirect URI then defaults to http://localhost:<port>
+        #[arg(long, value_name = "ADDRESS")]
+        listen: Option<String>,
+
+        /// The redirect URI registered for the OAuth client
+        #[arg(long, value_name = "URI")]
+        redirect_uri: Option<String>,
+    },
+
+    /// Copy the readings from a plain collection into the time-series collection
+    /// `database.collection`, which is created if need be
+    MigrateTimeSeries {
+        /// The plain collection the readings were stored in, e.g. sensor_data
+        #[arg(long, value_name = "COLLECTION")]
+        from: String,
+
+        /// How many readings to insert at a time
+        #[arg(long, default_value_t = 1000, value_name = "COUNT")]
+        batch_size: usize,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum NestCommand {
+    /// Set the thermostat mode
+    SetMode { mode: ThermostatMode },
+    /// Set the heating setpoint, in HEAT mode
+    SetHeat { celsius: f32 },
+    /// Set the cooling setpoint, in COOL mode
+    SetCool { celsius: f32 },
+    /// Set the heating and cooling setpoints, in HEATCOOL mode
+    SetRange {
+        heat_celsius: f32,
+        cool_celsius: f32,
+    },
+    /// Turn eco mode on (manual-eco) or off
+    SetEco { mode: EcoMode },
+    /// Run the fan for a number of minutes
+    FanTimer { minutes: u64 },
+    /// Stop the fan timer
+    FanOff,
+}
+
+impl From<&NestCommand> for ThermostatCommand {
+    fn from(command: &NestCommand) -> Self {
+        match *command {
+            NestCommand::SetMode { mode } => ThermostatCommand::SetMode(mode),
+            NestCommand::SetHeat { celsius } => ThermostatCommand::SetHeat {
+                heat_celsius: celsius,
+            },
+            NestCommand::SetCool { celsius } => ThermostatCommand::SetCool {
+                cool_celsius: celsius,
+            },
+            NestCommand::SetRange {
+                heat_celsius,
+                cool_celsius,
+            } => ThermostatCommand::SetRange {
+                heat_celsius,
+                cool_celsius,
+            },
+            NestCommand::SetEco { mode } => ThermostatCommand::SetEcoMode(mode),
+            NestCommand::FanTimer { minutes } => {
+                ThermostatCommand::SetFanTimer(Duration::from_secs(minutes.saturating_mul(60)))
+            }
+            NestCommand::FanOff => ThermostatCommand::StopFanTimer,
+        }
+    }
+}
@@ -0,0 +1,21 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Error reading config file {path}: {source}")]
+    FileIO {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("Error parsing config file {path}: {source}")]
+    Parse {
+        path: String,
+        source: toml::de::Error,
+    },
+    #[error("Invalid override '{0}', expected KEY=VALUE")]
+    Override(String),
+    #[error("Invalid config: {0}")]
+    Deserialize(#[from] toml::de::Error),
+    #[error("Invalid config value for {key}: {message}")]
+    Invalid { key: String, message: String },
+}
@@ -0,0 +1,847 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+
+use super::cli::Cli;
+use super::errors::ConfigError;
+
+/// Config file read when no `--config` flag is given; it is optional
+pub const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+/// Environment variables with this prefix override config values, with `__` separating sections,
+/// e.g. `RUST_BACKEND__NEST__POLL_INTERVAL_SECS=60`
+const ENV_PREFIX: &str = "RUST_BACKEND__";
+
+/// Legacy environment variable for the MongoDB URL, still used by the docker compose files
+const MONGO_URL_ENV: &str = "MONGO_URL";
+
+/// Application configuration.
+///
+/// Values are layered, each layer overriding the last: built-in defaults, the TOML config file,
+/// environment variables and finally command-line flags.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub log_level: String,
+    pub database: DatabaseConfig,
+    pub hue: HueConfig,
+    pub nest: NestConfig,
+    pub scheduler: SchedulerConfig,
+    pub retention: RetentionConfig,
+    pub spool: SpoolConfig,
+    pub api: ApiConfig,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub url: String,
+    pub name: String,
+    pub collection: String,
+    /// Collection for the Nest thermostat mode, HVAC status and setpoints
+    pub thermostat_state_collection: String,
+    /// Collection for the audit trail of commands sent to the Nest thermostat
+    pub thermostat_command_collection: String,
+    /// Collection for the motion reports of the Hue motion sensors
+    pub motion_collection: String,
+    /// Collection for the light level reports of the Hue motion sensors
+    pub light_level_collection: String,
+    /// Collection for the battery level and state of the Hue sensors
+    pub battery_collection: String,
+    /// Collection for the renames of Hue sensors, so their history can be found under any name
+    pub rename_collection: String,
+    /// Collection for the device registry, which readings refer to by `device_id`
+    pub device_collection: String,
+    /// Store the readings in `collection` as a MongoDB time-series collection, creating it if it
+    /// does not exist
+    pub time_series: bool,
+    /// The expected interval between a sensor's readings, which MongoDB buckets them by
+    pub time_series_granularity: TimeSeriesGranularity,
+    /// Collections for the readings summarised by minute, hour and day
+    pub minute_rollup_collection: String,
+    pub hourly_rollup_collection: String,
+    pub daily_rollup_collection: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeSeriesGranularity {
+    Seconds,
+    Minutes,
+    Hours,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HueMode {
+    /// Poll the temperature resources every `poll_interval_secs`
+    Poll,
+    /// Subscribe to the bridge's server-sent event stream
+    Events,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HueTlsVerification {
+    /// Verify the certificate against the Hue root CA, with the bridge ID as its common name
+    Ca,
+    /// Trust each bridge's certificate the first time it is seen and reject any other afterwards
+    Pin,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HueConfig {
+    /// The bridges to use instead of discovering them
+    pub bridges: Vec<HueBridgeConfig>,
+    pub bridge_domain: String,
+    pub discovery_url: String,
+    /// Where each bridge's application key is read from, with `{bridge_id}` replaced by the
+    /// bridge's ID so that each bridge can have its own key
+    pub application_key_path: String,
+    /// Where `hue-pair` saves the Entertainment API client key, which may contain `{bridge_id}`
+    pub client_key_path: String,
+    pub mode: HueMode,
+    pub poll_interval_secs: u64,
+    /// How often polling also reads the sensors' connectivity and their motion, light level and
+    /// battery, or 0 to read them with every poll
+    pub services_poll_interval_secs: u64,
+    /// Sensors whose latest temperature report is older than this are reported offline
+    pub stale_after_secs: u64,
+    /// How often the device list is read again for new and renamed sensors
+    pub rediscovery_interval_secs: u64,
+    pub tls_verification: HueTlsVerification,
+    /// A PEM file of root CAs to use instead of the bundled Hue root CA
+    pub root_ca_path: String,
+    /// Where the `pin` verification records each bridge's certificate fingerprint, which may
+    /// contain `{bridge_id}`
+    pub certificate_pin_path: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HueBridgeConfig {
+    /// The bridge's IP address or host name
+    pub address: String,
+    /// Overrides `hue.application_key_path` for this bridge
+    #[serde(default)]
+    pub application_key_path: Option<String>,
+}
+
+/// The placeholder in key paths replaced with the bridge's ID
+const BRIDGE_ID_PLACEHOLDER: &str = "{bridge_id}";
+
+impl HueConfig {
+    /// The application key file for the bridge at `address`
+    pub fn application_key_path(&self, address: &str, bridge_id: &str) -> PathBuf {
+        let path = self
+            .bridges
+            .iter()
+            .find(|bridge| bridge.address == address)
+            .and_then(|bridge| bridge.application_key_path.as_deref())
+            .unwrap_or(&self.application_key_path);
+
+        PathBuf::from(path.replace(BRIDGE_ID_PLACEHOLDER, bridge_id))
+    }
+
+    /// The client key file for the bridge with ID `bridge_id`
+    pub fn client_key_path(&self, bridge_id: &str) -> PathBuf {
+        PathBuf::from(
+            self.client_key_path
+                .replace(BRIDGE_ID_PLACEHOLDER, bridge_id),
+        )
+    }
+
+    /// The key files `hue-pair` writes for the bridge at `address` that have no `{bridge_id}` in
+    /// their path and no per-bridge override, so that every bridge would share them
+    pub fn shared_key_paths(&self, address: &str, bridge_id: &str) -> Vec<PathBuf> {
+        let own_application_key = self
+            .bridges
+            .iter()
+            .any(|bridge| bridge.address == address && bridge.application_key_path.is_some());
+
+        let mut paths = Vec::new();
+        if !own_application_key && !self.application_key_path.contains(BRIDGE_ID_PLACEHOLDER) {
+            paths.push(self.application_key_path(address, bridge_id));
+        }
+        if !self.client_key_path.contains(BRIDGE_ID_PLACEHOLDER) {
+            paths.push(self.client_key_path(bridge_id));
+        }
+        paths
+    }
+
+    /// The certificate fingerprint file for the bridge with ID `bridge_id`
+    pub fn certificate_pin_path(&self, bridge_id: &str) -> PathBuf {
+        PathBuf::from(
+            self.certificate_pin_path
+                .replace(BRIDGE_ID_PLACEHOLDER, bridge_id),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NestMode {
+    /// Poll the SDM API every `poll_interval_secs`
+    Poll,
+    /// Pull events from the Device Access Pub/Sub subscription, polling every
+    /// `poll_interval_secs` as well
+    Events,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NestConfig {
+    pub credentials_path: String,
+    pub token_url: String,
+    /// Base URL of the Partner Connections Manager, where `nest-auth` sends the user to consent
+    pub partner_connections_url: String,
+    pub sdm_devices_url: String,
+    pub mode: NestMode,
+    pub poll_interval_secs: u64,
+    /// Base URL of the Pub/Sub REST API, which can point at the Pub/Sub emulator
+    pub pubsub_url: String,
+    /// The subscription to pull events from, `projects/{project}/subscriptions/{subscription}`
+    pub pubsub_subscription: String,
+    pub pubsub_max_messages: u32,
+    /// Warn this many days before the refresh token expires, and report it in the health check
+    pub refresh_token_warning_days: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SchedulerConfig {
+    /// Fraction of each source's interval by which polls are randomly moved earlier or later
+    pub jitter_fraction: f64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    /// Run the background job that rolls up and prunes the readings
+    pub enabled: bool,
+    /// How long to keep the raw readings once they have been rolled up, 0 keeping them forever
+    pub raw_days: u64,
+    /// How often the job runs
+    pub interval_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpoolConfig {
+    /// Directory for the spool files, one per collection, which hold items that could not be
+    /// saved until MongoDB is available again
+    pub directory: String,
+    /// The most each spool file may hold, after which new items are dropped
+    pub max_bytes: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiConfig {
+    pub enabled: bool,
+    pub bind_address: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            log_level: "info".to_string(),
+            database: DatabaseConfig::default(),
+            hue: HueConfig::default(),
+            nest: NestConfig::default(),
+            scheduler: SchedulerConfig::default(),
+            retention: RetentionConfig::default(),
+            spool: SpoolConfig::default(),
+            api: ApiConfig::default(),
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            url: "mongodb://localhost:27017".to_string(),
+            name: "web_database".to_string(),
+            collection: "sensor_data".to_string(),
+            thermostat_state_collection: "thermostat_state".to_string(),
+            thermostat_command_collection: "thermostat_commands".to_string(),
+            motion_collection: "motion".to_string(),
+            light_level_collection: "light_level".to_string(),
+            battery_collection: "battery".to_string(),
+            rename_collection: "device_renames".to_string(),
+            device_collection: "devices".to_string(),
+            time_series: false,
+            time_series_granularity: TimeSeriesGranularity::Seconds,
+            minute_rollup_collection: "sensor_data_minutely".to_string(),
+            hourly_rollup_collection: "sensor_data_hourly".to_string(),
+            daily_rollup_collection: "sensor_data_daily".to_string(),
+        }
+    }
+}
+
+impl Default for HueConfig {
+    fn default() -> Self {
+        HueConfig {
+            bridges: Vec::new(),
+            bridge_domain: "hue-bridge.home.arpa".to_string(),
+            discovery_url: "https://discovery.meethue.com/".to_string(),
+            application_key_path: "secrets/hue_application_key.txt".to_string(),
+            client_key_path: "secrets/hue_client_key.txt".to_string(),
+            mode: HueMode::Poll,
+            poll_interval_secs: 1,
+            services_poll_interval_secs: 60,
+            stale_after_secs: 7200,
+            rediscovery_interval_secs: 3600,
+            tls_verification: HueTlsVerification::Ca,
+            root_ca_path: String::new(),
+            certificate_pin_path: "secrets/hue_certificate_{bridge_id}.sha256".to_string(),
+        }
+    }
+}
+
+impl Default for NestConfig {
+    fn default() -> Self {
+        NestConfig {
+            credentials_path: "secrets/nest_credentials.json".to_string(),
+            token_url: "https://oauth2.googleapis.com/token".to_string(),
+            partner_connections_url: "https://nestservices.google.com/partnerconnections"
+                .to_string(),
+            sdm_devices_url: "https://smartdevicemanagement.googleapis.com/v1/enterprises"
+                .to_string(),
+            mode: NestMode::Poll,
+            poll_interval_secs: 300,
+            pubsub_url: "https://pubsub.googleapis.com/v1".to_string(),
+            pubsub_subscription: String::new(),
+            pubsub_max_messages: 100,
+            refresh_token_warning_days: 2,
+        }
+    }
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        SchedulerConfig {
+            jitter_fraction: 0.1,
+        }
+    }
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        RetentionConfig {
+            enabled: true,
+            raw_days: 0,
+            interval_secs: 300,
+        }
+    }
+}
+
+impl Default for SpoolConfig {
+    fn default() -> Self {
+        SpoolConfig {
+            directory: "spool".to_string(),
+            max_bytes: 100 * 1024 * 1024,
+        }
+    }
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        ApiConfig {
+            enabled: true,
+            bind_address: "0.0.0.0:8080".to_string(),
+        }
+    }
+}
+
+impl Config {
+    /// Loads the config from the file, environment and command line, then validates it.
+    pub fn load(cli: &Cli) -> Result<Self, ConfigError> {
+        Self::load_with_env(cli, std::env::vars())
+    }
+
+    /// Loads the config like `load`, with `env` as the environment variables
+    fn load_with_env(
+        cli: &Cli,
+        env: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, ConfigError> {
+        let env: Vec<(String, String)> = env.into_iter().collect();
+        let defaults =
+            toml::Table::try_from(Config::default()).expect("The default config serializes");
+
+        // Start with the config file, which only has to exist if it was given explicitly
+        let mut table = match &cli.config {
+            Some(path) => read_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                read_file(Path::new(DEFAULT_CONFIG_PATH))?
+            }
+            None => toml::Table::new(),
+        };
+
+        // Apply the environment variable overrides
+        if let Some((_, url)) = env.iter().find(|(key, _)| key == MONGO_URL_ENV) {
+            set_value(&mut table, &defaults, "database.url", url)?;
+        }
+
+        let mut env_overrides: Vec<(String, String)> = env
+            .into_iter()
+            .filter_map(|(key, value)| {
+                key.strip_prefix(ENV_PREFIX)
+                    .map(|key| (key.to_lowercase().replace("__", "."), value))
+            })
+            .collect();
+        env_overrides.sort();
+
+        for (key, value) in env_overrides {
+            set_value(&mut table, &defaults, &key, &value)?;
+        }
+
+        // Apply the command-line overrides
+        if let Some(log_level) = &cli.log_level {
+            set_value(&mut table, &defaults, "log_level", log_level)?;
+        }
+
+        if let Some(mongo_url) = &cli.mongo_url {
+            set_value(&mut table, &defaults, "database.url", mongo_url)?;
+        }
+
+        for item in &cli.overrides {
+            let (key, value) = item
+                .split_once('=')
+                .ok_or_else(|| ConfigError::Override(item.clone()))?;
+            set_value(&mut table, &defaults, key.trim(), value.trim())?;
+        }
+
+        let config: Config = toml::Value::Table(table).try_into()?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// The log level filter, which has already been checked by `validate`
+    pub fn log_level_filter(&self) -> LevelFilter {
+        LevelFilter::from_str(&self.log_level).unwrap_or(LevelFilter::Info)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if LevelFilter::from_str(&self.log_level).is_err() {
+            return Err(invalid(
+                "log_level",
+                "expected one of off, error, warn, info, debug or trace",
+            ));
+        }
+
+        if !(self.database.url.starts_with("mongodb://")
+            || self.database.url.starts_with("mongodb+srv://"))
+        {
+            return Err(invalid(
+                "database.url",
+                "expected a mongodb:// or mongodb+srv:// URL",
+            ));
+        }
+
+        for (key, value) in [
+            ("database.name", &self.database.name),
+            ("database.collection", &self.database.collection),
+            (
+                "database.thermostat_state_collection",
+                &self.database.thermostat_state_collection,
+            ),
+            (
+                "database.thermostat_command_collection",
+                &self.database.thermostat_command_collection,
+            ),
+            (
+                "database.motion_collection",
+                &self.database.motion_collection,
+            ),
+            (
+                "database.light_level_collection",
+                &self.database.light_level_collection,
+            ),
+            (
+                "database.battery_collection",
+                &self.database.battery_collection,
+            ),
+            (
+                "database.rename_collection",
+                &self.database.rename_collection,
+            ),
+            (
+                "database.device_collection",
+                &self.database.device_collection,
+            ),
+            (
+                "database.minute_rollup_collection",
+                &self.database.minute_rollup_collection,
+            ),
+            (
+                "database.hourly_rollup_collection",
+                &self.database.hourly_rollup_collection,
+            ),
+            (
+                "database.daily_rollup_collection",
+                &self.database.daily_rollup_collection,
+            ),
+            ("hue.bridge_domain", &self.hue.bridge_domain),
+            ("hue.application_key_path", &self.hue.application_key_path),
+            ("hue.client_key_path", &self.hue.client_key_path),
+            ("hue.certificate_pin_path", &self.hue.certificate_pin_path),
+            ("nest.credentials_path", &self.nest.credentials_path),
+            ("spool.directory", &self.spool.directory),
+        ] {
+            if value.trim().is_empty() {
+                return Err(invalid(key, "must not be empty"));
+            }
+        }
+
+        for (key, value) in [
+            ("hue.discovery_url", &self.hue.discovery_url),
+            ("nest.token_url", &self.nest.token_url),
+            (
+                "nest.partner_connections_url",
+                &self.nest.partner_connections_url,
+            ),
+            ("nest.sdm_devices_url", &self.nest.sdm_devices_url),
+            ("nest.pubsub_url", &self.nest.pubsub_url),
+        ] {
+            if !(value.starts_with("http://") || value.starts_with("https://")) {
+                return Err(invalid(key, "expected an http:// or https:// URL"));
+            }
+        }
+
+        for (key, value) in [
+            ("hue.poll_interval_secs", self.hue.poll_interval_secs),
+            ("hue.stale_after_secs", self.hue.stale_after_secs),
+            (
+                "hue.rediscovery_interval_secs",
+                self.hue.rediscovery_interval_secs,
+            ),
+            ("nest.poll_interval_secs", self.nest.poll_interval_secs),
+            ("retention.interval_secs", self.retention.interval_secs),
+            ("spool.max_bytes", self.spool.max_bytes),
+        ] {
+            if value == 0 {
+                return Err(invalid(key, "must be greater than zero"));
+            }
+        }
+
+        for bridge in &self.hue.bridges {
+            if bridge.address.trim().is_empty() {
+                return Err(invalid("hue.bridges.address", "must not be empty"));
+            }
+        }
+
+        if self.nest.mode == NestMode::Events {
+            if !self.nest.pubsub_subscription.starts_with("projects/") {
+                return Err(invalid(
+                    "nest.pubsub_subscription",
+                    "expected projects/<project>/subscriptions/<subscription> in events mode",
+                ));
+            }
+
+            if self.nest.pubsub_max_messages == 0 {
+                return Err(invalid(
+                    "nest.pubsub_max_messages",
+                    "must be greater than zero",
+                ));
+            }
+        }
+
+        if self.api.enabled
+            && self
+                .api
+                .bind_address
+                .parse::<std::net::SocketAddr>()
+                .is_err()
+        {
+            return Err(invalid(
+                "api.bind_address",
+                "expected an address and port, e.g. 0.0.0.0:8080",
+            ));
+        }
+
+        if !(0.0..1.0).contains(&self.scheduler.jitter_fraction) {
+            return Err(invalid(
+                "scheduler.jitter_fraction",
+                "must be at least 0 and less than 1",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+fn invalid(key: &str, message: &str) -> ConfigError {
+    ConfigError::Invalid {
+        key: key.to_string(),
+        message: message.to_string(),
+    }
+}
+
+fn read_file(path: &Path) -> Result<toml::Table, ConfigError> {
+    let path_name = path.display().to_string();
+
+    let contents = std::fs::read_to_string(path).map_err(|source| ConfigError::FileIO {
+        path: path_name.clone(),
+        source,
+    })?;
+
+    toml::from_str(&contents).map_err(|source| ConfigError::Parse {
+        path: path_name,
+        source,
+    })
+}
+
+/// The value at a dotted key in the table, if it has one
+fn get_value<'a>(table: &'a toml::Table, key: &str) -> Option<&'a toml::Value> {
+    let mut parts = key.split('.');
+    let mut value = table.get(parts.next()?)?;
+    for part in parts {
+        value = value.as_table()?.get(part)?;
+    }
+    Some(value)
+}
+
+/// Sets a dotted key (e.g. `nest.poll_interval_secs`) in the table.
+///
+/// The value is parsed as a TOML value so numbers and booleans keep their type, and is otherwise
+/// treated as a plain string. A key that is a string in the table, or in `defaults` if the table
+/// does not have it, is always set to a string, so that e.g. a database named `1234` is not read
+/// as a number.
+fn set_value(
+    table: &mut toml::Table,
+    defaults: &toml::Table,
+    key: &str,
+    raw: &str,
+) -> Result<(), ConfigError> {
+    let is_string = get_value(table, key)
+        .or_else(|| get_value(defaults, key))
+        .is_some_and(toml::Value::is_str);
+    let value = match toml::from_str::<toml::Table>(&format!("value = {raw}"))
+        .ok()
+        .and_then(|mut parsed| parsed.remove("value"))
+    {
+        Some(value) if !is_string || value.is_str() => value,
+        _ => toml::Value::String(raw.to_string()),
+    };
+
+    let mut parts: Vec<&str> = key.split('.').collect();
+    let last = parts.pop().unwrap_or_default();
+
+    let mut current = table;
+    for part in parts {
+        current = current
+            .entry(part)
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+            .as_table_mut()
+            .ok_or_else(|| invalid(key, &format!("{part} is not a section")))?;
+    }
+
+    current.insert(last.to_string(), value);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    use crate::tests::harness::temp_file;
+
+    const CONFIG: &str = r#"
+log_level = "warn"
+
+[database]
+url = "mongodb://file:27017"
+
+[hue]
+poll_interval_secs = 5
+
+[nest]
+poll_interval_secs = 10
+"#;
+
+    /// Loads the config file `contents` with the environment `env` and the command-line `args`
+    fn load(contents: &str, env: &[(&str, &str)], args: &[&str]) -> Result<Config, ConfigError> {
+        let path = temp_file("config.toml", contents);
+        let path = path.to_string_lossy();
+        let cli = Cli::parse_from(
+            ["rust-backend", "--config", &path]
+                .into_iter()
+                .chain(args.iter().copied()),
+        );
+        let env = env
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()));
+
+        Config::load_with_env(&cli, env)
+    }
+
+    /// The key an invalid config was rejected for
+    fn invalid_key(args: &[&str]) -> String {
+        match load(CONFIG, &[], args) {
+            Err(ConfigError::Invalid { key, .. }) => key,
+            other => panic!("Expected {args:?} to be invalid, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn the_environment_overrides_the_file_and_the_command_line_overrides_both() {
+        let env = [
+            ("RUST_BACKEND__HUE__POLL_INTERVAL_SECS", "6"),
+            ("RUST_BACKEND__NEST__POLL_INTERVAL_SECS", "20"),
+            ("MONGO_URL", "mongodb://env:27017"),
+            ("HOME", "/root"),
+        ];
+
+        let config = load(CONFIG, &env, &[]).unwrap();
+        assert_eq!(config.log_level, "warn");
+        assert_eq!(config.hue.poll_interval_secs, 6);
+        assert_eq!(config.nest.poll_interval_secs, 20);
+        assert_eq!(config.database.url, "mongodb://env:27017");
+
+        let args = [
+            "--set",
+            "nest.poll_interval_secs=30",
+            "--mongo-url",
+            "mongodb://cli:27017",
+            "--log-level",
+            "debug",
+        ];
+        let config = load(CONFIG, &env, &args).unwrap();
+        assert_eq!(config.log_level, "debug");
+        assert_eq!(config.hue.poll_interval_secs, 6);
+        assert_eq!(config.nest.poll_interval_secs, 30);
+        assert_eq!(config.database.url, "mongodb://cli:27017");
+
+        // Anything not set anywhere keeps its default
+        assert_eq!(config.spool.max_bytes, SpoolConfig::default().max_bytes);
+    }
+
+    #[test]
+    fn overrides_keep_their_types() {
+        let config = load(
+            CONFIG,
+            &[("RUST_BACKEND__DATABASE__TIME_SERIES", "true")],
+            &[
+                "--set",
+                "hue.bridge_domain=10.0.0.2",
+                "--set",
+                "scheduler.jitter_fraction=0.25",
+            ],
+        )
+        .unwrap();
+
+        assert!(config.database.time_series);
+        assert_eq!(config.hue.bridge_domain, "10.0.0.2");
+        assert_eq!(config.scheduler.jitter_fraction, 0.25);
+
+        // String settings stay strings, even when they look like numbers or booleans
+        let config = load(
+            CONFIG,
+            &[("RUST_BACKEND__DATABASE__NAME", "1234")],
+            &[
+                "--set",
+                "hue.bridge_domain=true",
+                "--set",
+                "log_level=\"debug\"",
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(config.database.name, "1234");
+        assert_eq!(config.hue.bridge_domain, "true");
+        assert_eq!(config.log_level, "debug");
+    }
+
+    #[test]
+    fn invalid_values_are_rejected_naming_the_key() {
+        assert_eq!(invalid_key(&["--log-level", "loud"]), "log_level");
+        assert_eq!(
+            invalid_key(&["--mongo-url", "http://localhost"]),
+            "database.url"
+        );
+        assert_eq!(
+            invalid_key(&["--set", "database.collection="]),
+            "database.collection"
+        );
+        assert_eq!(
+            invalid_key(&["--set", "nest.token_url=localhost"]),
+            "nest.token_url"
+        );
+        assert_eq!(
+            invalid_key(&["--set", "retention.interval_secs=0"]),
+            "retention.interval_secs"
+        );
+        assert_eq!(
+            invalid_key(&["--set", "nest.mode=events"]),
+            "nest.pubsub_subscription"
+        );
+        assert_eq!(
+            invalid_key(&["--set", "api.bind_address=localhost"]),
+            "api.bind_address"
+        );
+        assert_eq!(
+            invalid_key(&["--set", "scheduler.jitter_fraction=1.0"]),
+            "scheduler.jitter_fraction"
+        );
+    }
+
+    #[test]
+    fn malformed_config_is_an_error() {
+        assert!(matches!(
+            load("log_level = ", &[], &[]),
+            Err(ConfigError::Parse { .. })
+        ));
+        assert!(matches!(
+            load(CONFIG, &[], &["--set", "nest.poll_interval_secs"]),
+            Err(ConfigError::Override(_))
+        ));
+        assert!(matches!(
+            load(CONFIG, &[], &["--set", "nest.poll_interval=60"]),
+            Err(ConfigError::Deserialize(_))
+        ));
+        assert!(matches!(
+            load(
+                CONFIG,
+                &[("RUST_BACKEND__NEST__POLL_INTERVAL_SECS", "soon")],
+                &[]
+            ),
+            Err(ConfigError::Deserialize(_))
+        ));
+    }
+
+    #[test]
+    fn key_paths_without_a_bridge_id_are_shared_by_every_bridge() {
+        let application_key = PathBuf::from("secrets/hue_application_key.txt");
+        let client_key = PathBuf::from("secrets/hue_client_key.txt");
+        let mut hue = HueConfig::default();
+        assert_eq!(
+            hue.shared_key_paths("192.0.2.2", "bridge-2"),
+            vec![application_key.clone(), client_key.clone()]
+        );
+
+        hue.bridges.push(HueBridgeConfig {
+            address: "192.0.2.2".to_string(),
+            application_key_path: Some("secrets/upstairs.txt".to_string()),
+        });
+        assert_eq!(
+            hue.shared_key_paths("192.0.2.2", "bridge-2"),
+            vec![client_key.clone()]
+        );
+        assert_eq!(
+            hue.shared_key_paths("192.0.2.3", "bridge-3"),
+            vec![application_key, client_key]
+        );
+
+        hue.application_key_path = "secrets/hue_application_key_{bridge_id}.txt".to_string();
+        hue.client_key_path = "secrets/hue_client_key_{bridge_id}.txt".to_string();
+        assert!(hue.shared_key_paths("192.0.2.3", "bridge-3").is_empty());
+    }
+}
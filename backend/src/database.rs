@@ -1,2 +1,3 @@
+pub mod availability;
 pub mod client;
 pub mod errors;
@@ -0,0 +1,138 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mongodb::sync::Client;
+
+use super::errors::DatabaseError;
+
+use crate::metrics;
+use crate::shutdown::Shutdown;
+
+/// How often MongoDB is pinged while it is available
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long to wait before the first retry once MongoDB is unavailable, doubling on each
+/// failed retry up to `MAX_RETRY_INTERVAL`
+const INITIAL_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Whether MongoDB was reachable when last tried, shared by every collection. It starts out
+/// available so that the first operation finds out.
+static AVAILABLE: AtomicBool = AtomicBool::new(true);
+
+/// Whether MongoDB is available. While it is not, operations fail straight away with
+/// `DatabaseError::Unavailable` rather than waiting to time out.
+pub fn is_available() -> bool {
+    AVAILABLE.load(Ordering::Relaxed)
+}
+
+/// Records that MongoDB became unavailable with `error`, logging it if it was available
+pub fn set_unavailable(error: &DatabaseError) {
+    if AVAILABLE.swap(false, Ordering::Relaxed) {
+        log::warn!("MongoDB is unavailable, retrying in the background: {error}");
+    }
+    metrics::record_mongo_available(false);
+}
+
+/// Records that MongoDB is available, logging it if it was not
+pub(super) fn set_available() {
+    if !AVAILABLE.swap(true, Ordering::Relaxed) {
+        log::info!("MongoDB is available again");
+    }
+    metrics::record_mongo_available(true);
+}
+
+/// Pings MongoDB
+pub fn ping(client: &Client) -> Result<(), DatabaseError> {
+    client
+        .database("admin")
+        .run_command(mongodb::bson::doc! { "ping": 1 })
+        .run()?;
+    Ok(())
+}
+
+/// Pings MongoDB in the background to notice when it goes away and when it comes back,
+/// backing off while it is unavailable. An operation finding it unavailable in between pings
+/// starts the retries straight away.
+pub struct AvailabilityMonitor {
+    client: Client,
+}
+
+impl AvailabilityMonitor {
+    pub fn new(client: Client) -> Self {
+        AvailabilityMonitor { client }
+    }
+
+    /// Runs the monitor on its own thread until shutdown
+    pub fn run(self, shutdown: &Shutdown) -> Result<thread::JoinHandle<()>, std::io::Error> {
+        let shutdown = shutdown.clone();
+
+        thread::Builder::new()
+            .name("mongo-monitor".to_string())
+            .spawn(move || {
+                let mut retry_interval = INITIAL_RETRY_INTERVAL;
+                while !shutdown.is_triggered() {
+                    let stopped = match ping(&self.client) {
+                        Ok(()) => {
+                            set_available();
+                            retry_interval = INITIAL_RETRY_INTERVAL;
+                            wait_while_available(&shutdown, CHECK_INTERVAL)
+                        }
+                        Err(error) => {
+                            set_unavailable(&error);
+                            log::debug!(
+                                "Retrying MongoDB in {}s: {error}",
+                                retry_interval.as_secs()
+                            );
+                            let interval = retry_interval;
+                            retry_interval = next_retry_interval(retry_interval);
+                            shutdown.wait_timeout(interval)
+                        }
+                    };
+
+                    if stopped {
+                        break;
+                    }
+                }
+                log::debug!("MongoDB monitor stopped");
+            })
+    }
+}
+
+/// Waits for up to `interval`, checking every `INITIAL_RETRY_INTERVAL` whether an operation has
+/// found MongoDB unavailable and returning early if so. Returns true if shutdown was requested.
+fn wait_while_available(shutdown: &Shutdown, interval: Duration) -> bool {
+    let deadline = Instant::now() + interval;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() || !is_available() {
+            return false;
+        }
+        if shutdown.wait_timeout(remaining.min(INITIAL_RETRY_INTERVAL)) {
+            return true;
+        }
+    }
+}
+
+/// How long to wait before the next retry, having waited `interval` before the last one
+fn next_retry_interval(interval: Duration) -> Duration {
+    (interval * 2).min(MAX_RETRY_INTERVAL)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retries_back_off_up_to_the_maximum() {
+        let intervals: Vec<u64> = std::iter::successors(Some(INITIAL_RETRY_INTERVAL), |interval| {
+            Some(next_retry_interval(*interval))
+        })
+        .take(8)
+        .map(|interval| interval.as_secs())
+        .collect();
+
+        assert_eq!(intervals, [1, 2, 4, 8, 16, 32, 60, 60]);
+    }
+}
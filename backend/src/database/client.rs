@@ -1,63 +1,336 @@
-use std::process::exit;
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Instant;
 
+use chrono::{DateTime, Utc};
 use serde::{Serialize, de::DeserializeOwned};
 
+use mongodb::IndexModel;
+use mongodb::bson::{Bson, Document, doc};
+use mongodb::options::{FindOptions, TimeseriesGranularity, TimeseriesOptions};
+use mongodb::results::CollectionType;
 use mongodb::sync::Client;
 
-use once_cell::sync::Lazy;
+use once_cell::sync::OnceCell;
 
-use super::errors::DatabaseError;
+use super::availability;
+use super::errors::{DatabaseError, is_retryable_write};
 
-use crate::datastore::storage::Storage;
+use crate::config::settings::TimeSeriesGranularity;
+use crate::datastore::storage::{LatestTimestamps, Series, Storage};
+use crate::metrics;
 
-// Default MongoDB URL
-const MONGO_URL: &str = "mongodb://localhost:27017";
+// A singleton MongoDB client that is initialized once and reused across the application, with
+// the URL it connects to.
+static MONGO_CLIENT: OnceCell<(String, Client)> = OnceCell::new();
 
-// A singleton MongoDB client that is initialized once and reused across the application.
-static MONGO_CLIENT: Lazy<Client> = Lazy::new(|| {
-    // Get the MongoDB URL from the environment variable or use the default
+/// Creates a client for MongoDB at the given URL.
+///
+/// The client connects in the background, so MongoDB does not have to be reachable yet; only an
+/// invalid URL is an error.
+fn connect(database_url: &str) -> Result<Client, DatabaseError> {
     log::info!("Initializing MongoDB client");
-    let database_url = match std::env::var("MONGO_URL") {
-        Ok(url) => url,
-        Err(_) => {
-            log::debug!("MONGO_URL environment variable not set, using default");
-            MONGO_URL.to_string()
+
+    // Set up MongoDB client options with a timeout of 5 seconds, unless the URL sets one
+    let mut client_options = mongodb::options::ClientOptions::parse(database_url).run()?;
+    client_options
+        .server_selection_timeout
+        .get_or_insert(std::time::Duration::new(5, 0));
+    client_options
+        .connect_timeout
+        .get_or_insert(std::time::Duration::new(5, 0));
+
+    Ok(mongodb::sync::Client::with_options(client_options)?)
+}
+
+/// The server's error code for an insert that breaks a unique index
+const DUPLICATE_KEY_CODE: i32 = 11000;
+
+/// The server's error code for a collection that does not exist
+const NAMESPACE_NOT_FOUND_CODE: i32 = 26;
+
+/// The number of items an unordered insert failed to write, if they all broke a unique index
+fn duplicate_keys(error: &mongodb::error::Error) -> Option<usize> {
+    match error.kind.as_ref() {
+        mongodb::error::ErrorKind::InsertMany(insert_error)
+            if insert_error.write_concern_error.is_none() =>
+        {
+            let write_errors = insert_error.write_errors.as_ref()?;
+            write_errors
+                .iter()
+                .all(|write_error| write_error.code == DUPLICATE_KEY_CODE)
+                .then_some(write_errors.len())
         }
-    };
+        _ => None,
+    }
+}
 
-    // Set up MongoDB client options with a timeout of 5 seconds
-    let mut client_options = mongodb::options::ClientOptions::parse(database_url)
-        .run()
-        .expect("Failed to parse MongoDB URI");
-    client_options.server_selection_timeout = Some(std::time::Duration::new(5, 0));
-    client_options.connect_timeout = Some(std::time::Duration::new(5, 0));
-
-    // Create a new MongoDB client with the options
-    let client = mongodb::sync::Client::with_options(client_options)
-        .expect("Failed to create MongoDB client");
-
-    // Ping the client to ensure it's connected
-    match client
-        .database("admin")
-        .run_command(mongodb::bson::doc! { "ping": 1 })
-        .run()
-    {
-        Ok(_) => log::info!("MongoDB connection established"),
-        Err(e) => {
-            log::error!("Failed to ping MongoDB: {e}");
-            exit(1)
+/// The positions of the items an unordered insert failed to write, less those that broke a
+/// unique index and so are already stored: those worth trying again, and those rejected for good
+fn unwritten_items(error: &mongodb::error::Error) -> Option<(Vec<usize>, Vec<usize>)> {
+    match error.kind.as_ref() {
+        mongodb::error::ErrorKind::InsertMany(insert_error)
+            if insert_error.write_concern_error.is_none() =>
+        {
+            let mut retry = Vec::new();
+            let mut rejected = Vec::new();
+            for write_error in insert_error.write_errors.as_ref()? {
+                match write_error.code {
+                    DUPLICATE_KEY_CODE => {}
+                    code if is_retryable_write(code) => retry.push(write_error.index),
+                    _ => rejected.push(write_error.index),
+                }
+            }
+            Some((retry, rejected))
+        }
+        _ => None,
+    }
+}
+
+/// The layout of a time-series collection.
+///
+/// MongoDB buckets the documents of a time-series collection by their meta field, so the fields
+/// that identify a series are moved under it on the way in and back to the top level on the way
+/// out, and queries on them are rewritten to match. The rest of the backend sees the same
+/// documents as from a plain collection.
+#[derive(Debug, Clone)]
+pub struct TimeSeries {
+    pub time_field: &'static str,
+    pub meta_field: &'static str,
+    /// The top-level fields stored under `meta_field`
+    pub meta_fields: &'static [&'static str],
+    pub granularity: TimeSeriesGranularity,
+}
+
+impl TimeSeries {
+    fn options(&self) -> TimeseriesOptions {
+        TimeseriesOptions::builder()
+            .time_field(self.time_field)
+            .meta_field(Some(self.meta_field.to_string()))
+            .granularity(Some(match self.granularity {
+                TimeSeriesGranularity::Seconds => TimeseriesGranularity::Seconds,
+                TimeSeriesGranularity::Minutes => TimeseriesGranularity::Minutes,
+                TimeSeriesGranularity::Hours => TimeseriesGranularity::Hours,
+            }))
+            .build()
+    }
+
+    /// The path of a top-level field in the stored documents
+    pub fn path(&self, field: &str) -> String {
+        if self.meta_fields.contains(&field) {
+            format!("{}.{field}", self.meta_field)
+        } else {
+            field.to_string()
+        }
+    }
+
+    /// Moves the meta fields of a document under the meta field
+    fn nest_meta(&self, mut document: Document) -> Document {
+        let mut meta = Document::new();
+        for field in self.meta_fields {
+            if let Some(value) = document.remove(*field) {
+                meta.insert(*field, value);
+            }
+        }
+        document.insert(self.meta_field, meta);
+        document
+    }
+
+    /// Moves the meta fields of a stored document back to the top level
+    fn flatten_meta(&self, mut document: Document) -> Document {
+        if let Some(Bson::Document(meta)) = document.remove(self.meta_field) {
+            document.extend(meta);
+        }
+        document
+    }
+}
+
+/// How far a copy into a time-series collection has got, recorded after each batch so that an
+/// interrupted copy carries on where it left off
+#[derive(Debug, Clone, Default, PartialEq)]
+struct CopyProgress {
+    /// Every document newer than this has been copied
+    copied_after: Option<mongodb::bson::DateTime>,
+    finished: bool,
+}
+
+impl CopyProgress {
+    fn from_document(document: &Document) -> Self {
+        CopyProgress {
+            copied_after: document.get_datetime("copied_after").ok().copied(),
+            finished: document.get_bool("finished").unwrap_or(false),
+        }
+    }
+
+    fn to_document(&self) -> Document {
+        doc! { "copied_after": self.copied_after, "finished": self.finished }
+    }
+}
+
+/// Where a copy into a time-series collection writes its documents and its progress
+trait CopyTarget {
+    /// The documents stored from `from` to `to`, laid out as they are stored
+    fn stored_between(
+        &self,
+        from: mongodb::bson::DateTime,
+        to: mongodb::bson::DateTime,
+    ) -> Result<Vec<Document>, DatabaseError>;
+
+    /// Inserts a batch of documents, returning how many there were
+    fn insert(&mut self, documents: Vec<Document>) -> Result<usize, DatabaseError>;
+
+    fn record(&mut self, progress: &CopyProgress) -> Result<(), DatabaseError>;
+}
+
+/// Copies `documents`, newest first, into `target` `batch_size` at a time from where `progress`
+/// left off, returning how many were copied.
+///
+/// Documents are compared with those already stored over each batch's time span, so a batch
+/// that was written before the copy was interrupted, or a reading that the backend has stored
+/// itself, is not written twice.
+fn copy_documents(
+    time_series: &TimeSeries,
+    documents: impl IntoIterator<Item = Result<Document, DatabaseError>>,
+    mut progress: CopyProgress,
+    batch_size: usize,
+    target: &mut impl CopyTarget,
+) -> Result<usize, DatabaseError> {
+    if progress.finished {
+        return Ok(0);
+    }
+
+    let mut count = 0;
+    let mut batch = Vec::with_capacity(batch_size);
+    for document in documents {
+        let document = time_series.nest_meta(document?);
+        let newer = progress.copied_after.is_some_and(|copied_after| {
+            document
+                .get_datetime(time_series.time_field)
+                .is_ok_and(|timestamp| *timestamp > copied_after)
+        });
+        if newer {
+            continue;
         }
+
+        batch.push(document);
+        if batch.len() == batch_size {
+            let full = std::mem::take(&mut batch);
+            count += copy_batch(time_series, full, &mut progress, target)?;
+            log::info!("Copied {count} document(s)");
+        }
+    }
+    if !batch.is_empty() {
+        count += copy_batch(time_series, batch, &mut progress, target)?;
+    }
+
+    progress.finished = true;
+    target.record(&progress)?;
+    Ok(count)
+}
+
+/// Copies a batch of documents, newest first, that are not already stored, and records that
+/// everything newer than the oldest of them has been copied
+fn copy_batch(
+    time_series: &TimeSeries,
+    batch: Vec<Document>,
+    progress: &mut CopyProgress,
+    target: &mut impl CopyTarget,
+) -> Result<usize, DatabaseError> {
+    let time = |document: &Document| document.get_datetime(time_series.time_field).ok().copied();
+    let (Some(newest), Some(oldest)) = (
+        batch.iter().find_map(time),
+        batch.iter().rev().find_map(time),
+    ) else {
+        return target.insert(batch);
     };
 
-    // Return the client
-    client
-});
+    let stored = target.stored_between(oldest, newest)?;
+    let is_stored = |document: &Document| {
+        stored.iter().any(|other| {
+            time(other) == time(document)
+                && other.get(time_series.meta_field) == document.get(time_series.meta_field)
+        })
+    };
+    let batch: Vec<Document> = batch
+        .into_iter()
+        .filter(|document| !is_stored(document))
+        .collect();
+
+    let count = if batch.is_empty() {
+        0
+    } else {
+        target.insert(batch)?
+    };
+    progress.copied_after = Some(oldest);
+    target.record(progress)?;
+    Ok(count)
+}
+
+/// The latest timestamps of a collection, read once and then kept up to date as items are
+/// inserted
+struct LatestCache {
+    id_field: String,
+    name_field: String,
+    timestamp_field: String,
+    timestamps: LatestTimestamps,
+    /// Set once the timestamps have been read, until which they are only those of inserted items
+    seeded: bool,
+}
+
+impl LatestCache {
+    fn is_for(&self, id_field: &str, name_field: &str, timestamp_field: &str) -> bool {
+        self.id_field == id_field
+            && self.name_field == name_field
+            && self.timestamp_field == timestamp_field
+    }
+
+    /// Merges in the timestamps that have been read
+    fn seed(&mut self, timestamps: LatestTimestamps) {
+        self.timestamps.merge(timestamps);
+        self.seeded = true;
+    }
+
+    /// Records the timestamps of inserted items that are newer than the latest
+    fn update<'a, T: Serialize + 'a>(
+        &mut self,
+        items: impl IntoIterator<Item = &'a T>,
+    ) -> Result<(), DatabaseError> {
+        for item in items {
+            let document = mongodb::bson::to_document(item).map_err(mongodb::error::Error::from)?;
+            let (Ok(name), Ok(timestamp)) = (
+                document.get_str(&self.name_field),
+                document.get_datetime(&self.timestamp_field),
+            ) else {
+                continue;
+            };
+            let Some(timestamp) = DateTime::from_timestamp_millis(timestamp.timestamp_millis())
+            else {
+                continue;
+            };
+
+            self.timestamps
+                .record(document.get_str(&self.id_field).ok(), name, timestamp);
+        }
+        Ok(())
+    }
+}
 
 /// A MongoDB client that implements the Storage trait.
 pub struct MongoClient<T> {
     client: Client,
     database_name: String,
     collection_name: String,
+    /// Set if the collection is a time-series collection
+    time_series: Option<TimeSeries>,
+    indexes: Vec<IndexModel>,
+    /// The names of indexes to drop if the collection has them
+    dropped_indexes: Vec<String>,
+    /// Set once the collection has been created if need be and indexed
+    ready: OnceCell<()>,
+    /// The latest timestamps of each set of fields they have been read for
+    latest: Mutex<Vec<LatestCache>>,
+    /// Held while the latest timestamps are read, so that they are only read once
+    seeding: Mutex<()>,
     _marker: std::marker::PhantomData<T>,
 }
 
@@ -66,22 +339,414 @@ where
     T: Serialize + Send + Sync + 'static,
 {
     /// Creates a new MongoClient instance with the specified database and collection names.
-    pub fn new(database_name: &str, collection_name: &str) -> Result<Self, DatabaseError> {
-        log::info!("Creating MongoClient for database: {database_name}, collection: {collection_name}");
+    ///
+    /// The underlying connection to `database_url` is made on first use and shared by all instances,
+    /// so every instance has to use the same URL, and the collection is set up the first time
+    /// MongoDB is available.
+    pub fn new(
+        database_url: &str,
+        database_name: &str,
+        collection_name: &str,
+    ) -> Result<Self, DatabaseError> {
+        log::info!(
+            "Creating MongoClient for database: {database_name}, collection: {collection_name}"
+        );
+        let (url, client) = MONGO_CLIENT.get_or_try_init(|| {
+            connect(database_url).map(|client| (database_url.to_string(), client))
+        })?;
+        if url != database_url {
+            return Err(DatabaseError::DifferentUrl);
+        }
+
         Ok(MongoClient {
-            client: MONGO_CLIENT.clone(),
+            client: client.clone(),
             database_name: database_name.to_string(),
             collection_name: collection_name.to_string(),
+            time_series: None,
+            indexes: Vec::new(),
+            dropped_indexes: Vec::new(),
+            ready: OnceCell::new(),
+            latest: Mutex::new(Vec::new()),
+            seeding: Mutex::new(()),
             _marker: std::marker::PhantomData,
         })
     }
 
+    /// Uses the collection as a time-series collection laid out as `time_series`, which is
+    /// created when the collection is set up if it does not exist yet.
+    ///
+    /// An existing plain collection cannot be converted, so it is an error; its documents can be
+    /// copied into a new time-series collection with `copy_from`.
+    pub fn with_time_series(mut self, time_series: TimeSeries) -> Self {
+        self.time_series = Some(time_series);
+        self
+    }
+
+    /// Creates `index` on the collection when it is set up
+    pub fn with_index(mut self, index: IndexModel) -> Self {
+        self.indexes.push(index);
+        self
+    }
+
+    /// Drops the index named `name` when the collection is set up, if it has one, before creating
+    /// its indexes
+    pub fn without_index(mut self, name: &str) -> Self {
+        self.dropped_indexes.push(name.to_string());
+        self
+    }
+
+    /// The client shared by every collection
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Sets up the collection now, rather than on first use
+    pub fn prepare(&self) -> Result<(), DatabaseError> {
+        self.connected(|| Ok(()))
+    }
+
+    /// Runs `operation` once the collection is set up, failing straight away if MongoDB is
+    /// unavailable and recording it if the operation finds that it is
+    fn connected<R>(
+        &self,
+        operation: impl FnOnce() -> Result<R, DatabaseError>,
+    ) -> Result<R, DatabaseError> {
+        if !availability::is_available() {
+            return Err(DatabaseError::Unavailable);
+        }
+
+        let result = self
+            .ready
+            .get_or_try_init(|| self.set_up())
+            .and_then(|()| operation());
+        if let Err(error) = &result
+            && error.is_unavailable()
+        {
+            availability::set_unavailable(error);
+        }
+        result
+    }
+
+    /// Creates the collection if it is a time-series collection that does not exist yet, drops the
+    /// indexes it should no longer have and creates its indexes
+    fn set_up(&self) -> Result<(), DatabaseError> {
+        if let Some(time_series) = &self.time_series {
+            let database = self.client.database(&self.database_name);
+            let existing = database
+                .list_collections()
+                .filter(doc! { "name": &self.collection_name })
+                .run()?
+                .next()
+                .transpose()?;
+
+            match existing {
+                Some(collection) if collection.collection_type == CollectionType::Timeseries => {
+                    log::info!("Using time-series collection {}", self.collection_name);
+                }
+                Some(_) => return Err(DatabaseError::NotTimeSeries(self.collection_name.clone())),
+                None => {
+                    database
+                        .create_collection(&self.collection_name)
+                        .timeseries(time_series.options())
+                        .run()?;
+                    log::info!("Created time-series collection {}", self.collection_name);
+                }
+            }
+        }
+
+        if !self.dropped_indexes.is_empty() {
+            let existing = match self.get_documents().list_index_names().run() {
+                Ok(existing) => existing,
+                // A collection that does not exist yet has no indexes
+                Err(error)
+                    if matches!(
+                        error.kind.as_ref(),
+                        mongodb::error::ErrorKind::Command(command_error)
+                            if command_error.code == NAMESPACE_NOT_FOUND_CODE
+                    ) =>
+                {
+                    Vec::new()
+                }
+                Err(error) => return Err(error.into()),
+            };
+            for name in self
+                .dropped_indexes
+                .iter()
+                .filter(|name| existing.contains(name))
+            {
+                self.get_documents().drop_index(name).run()?;
+                log::info!("Dropped the index {name} of {}", self.collection_name);
+            }
+        }
+
+        if !self.indexes.is_empty() {
+            self.get_documents()
+                .create_indexes(self.indexes.clone())
+                .run()?;
+            log::info!("Indexed {}", self.collection_name);
+        }
+
+        Ok(())
+    }
+
     /// Get the collection from the MongoDB client
     pub fn get_collection(&self) -> mongodb::sync::Collection<T> {
         self.client
             .database(&self.database_name)
             .collection::<T>(&self.collection_name)
     }
+
+    /// The collection as raw documents, as they are stored in a time-series collection
+    fn get_documents(&self) -> mongodb::sync::Collection<Document> {
+        self.client
+            .database(&self.database_name)
+            .collection::<Document>(&self.collection_name)
+    }
+
+    /// The path of a top-level field of `T` in the stored documents
+    fn path(&self, field: &str) -> String {
+        match &self.time_series {
+            Some(time_series) => time_series.path(field),
+            None => field.to_string(),
+        }
+    }
+
+    /// A filter on the items of `series`
+    fn series_filter(&self, series: Series) -> Document {
+        let mut filter = doc! { self.path(series.field): series.value };
+        if let Some(without) = series.without {
+            filter.insert(self.path(without), doc! { "$exists": false });
+        }
+        filter
+    }
+
+    /// The items as they are stored in a time-series collection
+    fn stored_documents(
+        &self,
+        time_series: &TimeSeries,
+        data: &[T],
+    ) -> Result<Vec<Document>, DatabaseError> {
+        data.iter()
+            .map(|item| {
+                let document =
+                    mongodb::bson::to_document(item).map_err(mongodb::error::Error::from)?;
+                Ok(time_series.nest_meta(document))
+            })
+            .collect()
+    }
+
+    /// Copies the documents of the plain collection `collection_name` into this time-series
+    /// collection, `batch_size` at a time and newest first, returning how many were copied.
+    ///
+    /// The progress of the copy is recorded in the `migrations` collection after each batch, so
+    /// an interrupted copy carries on where it left off and a finished one is not run again.
+    /// Readings already in this collection, such as those stored since switching to it, are not
+    /// copied twice.
+    pub fn copy_from(
+        &self,
+        collection_name: &str,
+        batch_size: usize,
+    ) -> Result<usize, DatabaseError> {
+        self.connected(|| self.copy(collection_name, batch_size))
+    }
+
+    fn copy(&self, collection_name: &str, batch_size: usize) -> Result<usize, DatabaseError> {
+        let Some(time_series) = &self.time_series else {
+            return Err(DatabaseError::NotTimeSeries(self.collection_name.clone()));
+        };
+        let time_field = time_series.time_field;
+        let database = self.client.database(&self.database_name);
+
+        let mut target = MongoCopyTarget {
+            collection: self.get_documents(),
+            time_field,
+            id: format!("{collection_name} to {}", self.collection_name),
+            migrations: database.collection::<Document>(MIGRATIONS_COLLECTION),
+        };
+        let progress = target
+            .migrations
+            .find_one(doc! { "_id": &target.id })
+            .run()?
+            .map(|document| CopyProgress::from_document(&document))
+            .unwrap_or_default();
+        if progress.finished {
+            log::info!("{collection_name} has already been copied");
+            return Ok(0);
+        }
+
+        let filter = match progress.copied_after {
+            Some(copied_after) => doc! { time_field: { "$lte": copied_after } },
+            None => doc! {},
+        };
+        let documents = database
+            .collection::<Document>(collection_name)
+            .find(filter)
+            .sort(doc! { time_field: -1 })
+            .run()?
+            .map(|document| document.map_err(DatabaseError::from));
+
+        copy_documents(time_series, documents, progress, batch_size, &mut target)
+    }
+
+    /// Records the timestamps of inserted items in the latest timestamps that have been read
+    fn update_latest<'a>(
+        &self,
+        items: impl IntoIterator<Item = &'a T> + Clone,
+    ) -> Result<(), DatabaseError>
+    where
+        T: 'a,
+    {
+        for latest in self.lock_latest().iter_mut() {
+            latest.update(items.clone())?;
+        }
+        Ok(())
+    }
+
+    fn lock_latest(&self) -> MutexGuard<'_, Vec<LatestCache>> {
+        self.latest
+            .lock()
+            .expect("Latest timestamps mutex poisoned")
+    }
+
+    /// Reads the latest timestamps by ID, and by name for the items without an ID
+    fn read_latest_timestamps(
+        &self,
+        id_field: &str,
+        name_field: &str,
+        timestamp_field: &str,
+    ) -> Result<LatestTimestamps, DatabaseError> {
+        let id_path = self.path(id_field);
+        Ok(LatestTimestamps {
+            by_id: self.read_latest(
+                doc! { &id_path: { "$exists": true } },
+                id_field,
+                timestamp_field,
+            )?,
+            by_name: self.read_latest(
+                doc! { &id_path: { "$exists": false } },
+                name_field,
+                timestamp_field,
+            )?,
+        })
+    }
+
+    /// Reads the latest timestamp of each `key_field` among the items matching `filter` with
+    /// one aggregation.
+    ///
+    /// In a plain collection, the filters on the ID match the device index and the ID-less
+    /// readings are sorted by the name index, so the first of each key is read in index order
+    /// rather than sorting the collection. A time-series collection has no such index, so its
+    /// buckets are grouped by the greatest timestamp instead.
+    fn read_latest(
+        &self,
+        filter: Document,
+        key_field: &str,
+        timestamp_field: &str,
+    ) -> Result<HashMap<String, DateTime<Utc>>, DatabaseError> {
+        let key_path = self.path(key_field);
+        let timestamp_path = self.path(timestamp_field);
+        let pipeline = match &self.time_series {
+            Some(_) => vec![
+                doc! { "$match": filter },
+                doc! { "$group": {
+                    "_id": format!("${key_path}"),
+                    "timestamp": { "$max": format!("${timestamp_path}") },
+                } },
+            ],
+            None => vec![
+                doc! { "$match": filter },
+                doc! { "$sort": { &key_path: 1, &timestamp_path: -1 } },
+                doc! { "$group": {
+                    "_id": format!("${key_path}"),
+                    "timestamp": { "$first": format!("${timestamp_path}") },
+                } },
+            ],
+        };
+
+        self.get_documents()
+            .aggregate(pipeline)
+            .run()?
+            .filter_map(|document| match document {
+                Ok(document) => {
+                    let name = document.get_str("_id").ok()?;
+                    let timestamp = document.get_datetime("timestamp").ok()?;
+                    let timestamp = DateTime::from_timestamp_millis(timestamp.timestamp_millis())?;
+                    Some(Ok((name.to_string(), timestamp)))
+                }
+                Err(error) => Some(Err(error.into())),
+            })
+            .collect()
+    }
+}
+
+/// The collection that records how far each copy into a time-series collection has got
+const MIGRATIONS_COLLECTION: &str = "migrations";
+
+/// A time-series collection being copied into, with its copy's progress document
+struct MongoCopyTarget {
+    collection: mongodb::sync::Collection<Document>,
+    time_field: &'static str,
+    /// The ID of the progress document
+    id: String,
+    migrations: mongodb::sync::Collection<Document>,
+}
+
+impl CopyTarget for MongoCopyTarget {
+    fn stored_between(
+        &self,
+        from: mongodb::bson::DateTime,
+        to: mongodb::bson::DateTime,
+    ) -> Result<Vec<Document>, DatabaseError> {
+        Ok(self
+            .collection
+            .find(doc! { self.time_field: { "$gte": from, "$lte": to } })
+            .run()?
+            .collect::<Result<_, _>>()?)
+    }
+
+    fn insert(&mut self, documents: Vec<Document>) -> Result<usize, DatabaseError> {
+        let started = Instant::now();
+        self.collection.insert_many(&documents).run()?;
+        metrics::record_insert(self.collection.name(), started.elapsed(), documents.len());
+        Ok(documents.len())
+    }
+
+    fn record(&mut self, progress: &CopyProgress) -> Result<(), DatabaseError> {
+        self.migrations
+            .replace_one(doc! { "_id": &self.id }, progress.to_document())
+            .upsert(true)
+            .run()?;
+        Ok(())
+    }
+}
+
+impl<T> MongoClient<T>
+where
+    T: Serialize + DeserializeOwned + Unpin + Send + Sync + 'static,
+{
+    /// Finds the items matching `filter`, reading them back from a time-series collection's
+    /// layout if need be
+    fn find(&self, filter: Document, options: FindOptions) -> Result<Vec<T>, DatabaseError> {
+        match &self.time_series {
+            Some(time_series) => self
+                .get_documents()
+                .find(filter)
+                .with_options(options)
+                .run()?
+                .map(|document| {
+                    let document = time_series.flatten_meta(document?);
+                    Ok(mongodb::bson::from_document(document)
+                        .map_err(mongodb::error::Error::from)?)
+                })
+                .collect(),
+            None => Ok(self
+                .get_collection()
+                .find(filter)
+                .with_options(options)
+                .run()?
+                .collect::<Result<Vec<T>, _>>()?),
+        }
+    }
 }
 
 impl<T> Storage<T> for MongoClient<T>
@@ -90,49 +755,124 @@ where
 {
     type Error = DatabaseError;
     fn save_item(&self, data: &T) -> Result<(), Self::Error> {
-        log::debug!("Saving item to MongoDB");
+        self.connected(|| {
+            log::debug!("Saving item to MongoDB");
 
-        // Get the collection from the MongoDB client
-        let collection = self
-            .client
-            .database(&self.database_name)
-            .collection::<T>(&self.collection_name);
+            // Get the collection from the MongoDB client
+            let collection = self
+                .client
+                .database(&self.database_name)
+                .collection::<T>(&self.collection_name);
 
-        // Insert the data into the collection
-        collection.insert_one(data).run()?;
-        log::debug!("Item saved to MongoDB");
-        Ok(())
+            // Insert the data into the collection
+            let started = Instant::now();
+            match &self.time_series {
+                Some(time_series) => {
+                    let documents =
+                        self.stored_documents(time_series, std::slice::from_ref(data))?;
+                    self.get_documents().insert_many(documents).run()?;
+                }
+                None => {
+                    collection.insert_one(data).run()?;
+                }
+            }
+            metrics::record_insert(&self.collection_name, started.elapsed(), 1);
+            self.update_latest(std::slice::from_ref(data))?;
+            log::debug!("Item saved to MongoDB");
+            Ok(())
+        })
     }
 
     fn save_items(&self, data: &[T]) -> Result<(), Self::Error> {
-        log::debug!("Saving items to MongoDB");
+        self.connected(|| {
+            log::debug!("Saving items to MongoDB");
 
-        // Check if the data is empty, if so, return early to avoid an empty insert operation error
-        if data.is_empty() {
-            log::debug!("No items to save to MongoDB");
-            return Ok(());
-        }
+            // Check if the data is empty, if so, return early to avoid an empty insert operation error
+            if data.is_empty() {
+                log::debug!("No items to save to MongoDB");
+                return Ok(());
+            }
 
-        // Get the collection from the MongoDB client
-        let collection = self
-            .client
-            .database(&self.database_name)
-            .collection::<T>(&self.collection_name);
+            // Get the collection from the MongoDB client
+            let collection = self
+                .client
+                .database(&self.database_name)
+                .collection::<T>(&self.collection_name);
 
-        // Set the insert options to allow unordered inserts, this ensures that all items are inserted even if some fail
-        let insert_options = mongodb::options::InsertManyOptions::builder()
-            .ordered(false)
-            .build();
+            // Set the insert options to allow unordered inserts, this ensures that all items are inserted even if some fail
+            let insert_options = mongodb::options::InsertManyOptions::builder()
+                .ordered(false)
+                .build();
 
-        // Insert the data into the collection
-        collection
-            .insert_many(data)
-            .with_options(insert_options)
-            .run()?;
+            // Insert the data into the collection
+            let started = Instant::now();
+            let result = match &self.time_series {
+                Some(time_series) => self
+                    .get_documents()
+                    .insert_many(self.stored_documents(time_series, data)?)
+                    .with_options(insert_options)
+                    .run(),
+                None => collection
+                    .insert_many(data)
+                    .with_options(insert_options)
+                    .run(),
+            };
 
-        log::debug!("Items saved to MongoDB");
+            // Unordered inserts still insert the documents that did not fail
+            let inserted = match &result {
+                Ok(_) => data.len(),
+                Err(error) => match error.kind.as_ref() {
+                    mongodb::error::ErrorKind::InsertMany(insert_error) => data
+                        .len()
+                        .saturating_sub(insert_error.write_errors.as_ref().map_or(0, Vec::len)),
+                    _ => 0,
+                },
+            };
+            metrics::record_insert(&self.collection_name, started.elapsed(), inserted);
 
-        Ok(())
+            // Items rejected by the unique index are already stored, so they are still the latest
+            if let Err(error) = result {
+                if let Some(duplicates) = duplicate_keys(&error) {
+                    self.update_latest(data)?;
+                    return Err(DatabaseError::DuplicateKey(duplicates));
+                }
+                let Some((retry, rejected)) = unwritten_items(&error) else {
+                    return Err(error.into());
+                };
+                self.update_latest(
+                    data.iter()
+                        .enumerate()
+                        .filter(|(index, _)| !retry.contains(index) && !rejected.contains(index))
+                        .map(|(_, item)| item),
+                )?;
+                return Err(DatabaseError::NotWritten {
+                    retry,
+                    rejected,
+                    message: error.to_string(),
+                });
+            }
+            self.update_latest(data)?;
+
+            log::debug!("Items saved to MongoDB");
+
+            Ok(())
+        })
+    }
+
+    fn upsert_item(&self, key_field: &str, key: &str, data: &T) -> Result<(), Self::Error> {
+        self.connected(|| {
+            log::debug!("Upserting {key} in MongoDB");
+
+            let started = Instant::now();
+            self.get_collection()
+                .replace_one(mongodb::bson::doc! { key_field: key }, data)
+                .upsert(true)
+                .run()?;
+            metrics::record_insert(&self.collection_name, started.elapsed(), 1);
+
+            log::debug!("Item upserted in MongoDB");
+            Ok(())
+        })
     }
 
     fn get_latest_items(
@@ -140,55 +880,478 @@ where
         name_field: &str,
         timestamp_field: &str,
     ) -> Result<Vec<T>, Self::Error> {
-        log::debug!("Getting latest items from MongoDB");
+        self.connected(|| {
+            log::debug!("Getting latest items from MongoDB");
 
-        // Get the collection from the MongoDB client
-        let collection = self
-            .client
-            .database(&self.database_name)
-            .collection::<T>(&self.collection_name);
+            let name_path = self.path(name_field);
+            let timestamp_path = self.path(timestamp_field);
 
-        // Get all of the unique device names
-        let device_names: Vec<String> = collection
-            .distinct(name_field, mongodb::bson::doc! {})
-            .run()?
-            .into_iter()
-            .filter_map(|item| item.as_str().map(String::from))
-            .collect();
+            // Get all of the unique device names
+            let device_names: Vec<String> = self
+                .get_documents()
+                .distinct(&name_path, mongodb::bson::doc! {})
+                .run()?
+                .into_iter()
+                .filter_map(|item| item.as_str().map(String::from))
+                .collect();
 
-        log::debug!("Device names: {device_names:?}");
+            log::debug!("Device names: {device_names:?}");
 
-        // Prepare a vector to hold the latest items
-        let mut items = Vec::new();
+            // Prepare a vector to hold the latest items
+            let mut items = Vec::new();
+
+            // Iterate over each device name and find the latest item for each
+            for device_name in device_names {
+                let filter = mongodb::bson::doc! { &name_path: &device_name };
+                let options = mongodb::options::FindOptions::builder()
+                    .sort(mongodb::bson::doc! { &timestamp_path: -1 })
+                    .limit(1)
+                    .build();
+
+                // Find the latest item for the current device name
+                let result = self.find(filter, options).inspect_err(|e| {
+                    log::error!("Error retrieving item for device {device_name}: {e}");
+                })?;
+
+                // If a result is found, push it to the items vector
+                if let Some(item) = result.into_iter().next() {
+                    items.push(item);
+                } else {
+                    log::warn!("No items found for device: {device_name}");
+                }
+            }
+
+            log::debug!("Latest items retrieved from MongoDB");
+            Ok(items)
+        })
+    }
+
+    /// The latest timestamps are read once and then kept up to date by `save_items`, so they are
+    /// still known while MongoDB is unavailable. Until they have been read, those of the items
+    /// inserted since the first attempt are returned if there are any.
+    fn get_latest_timestamps(
+        &self,
+        id_field: &str,
+        name_field: &str,
+        timestamp_field: &str,
+    ) -> Result<LatestTimestamps, Self::Error> {
+        // The timestamps that have been read, if they have
+        let seeded = |cache: &[LatestCache]| {
+            cache
+                .iter()
+                .find(|latest| latest.is_for(id_field, name_field, timestamp_field))
+                .filter(|latest| latest.seeded)
+                .map(|latest| latest.timestamps.clone())
+        };
+        if let Some(timestamps) = seeded(&self.lock_latest()) {
+            return Ok(timestamps);
+        }
+
+        // Only the first caller reads them, and the others wait for it rather than read them
+        // again, while saving items goes on recording the timestamps of the items it inserts
+        let _seeding = self.seeding.lock().expect("Seeding mutex poisoned");
+        {
+            let mut cache = self.lock_latest();
+            if let Some(timestamps) = seeded(&cache) {
+                return Ok(timestamps);
+            }
+            if !cache
+                .iter()
+                .any(|latest| latest.is_for(id_field, name_field, timestamp_field))
+            {
+                cache.push(LatestCache {
+                    id_field: id_field.to_string(),
+                    name_field: name_field.to_string(),
+                    timestamp_field: timestamp_field.to_string(),
+                    timestamps: LatestTimestamps::default(),
+                    seeded: false,
+                });
+            }
+        }
+
+        let result =
+            self.connected(|| self.read_latest_timestamps(id_field, name_field, timestamp_field));
+        let mut cache = self.lock_latest();
+        let latest = cache
+            .iter_mut()
+            .find(|latest| latest.is_for(id_field, name_field, timestamp_field))
+            .expect("The latest timestamps are added before they are read");
+        match result {
+            Ok(timestamps) => {
+                log::debug!(
+                    "Read the latest timestamps of {} device(s) and {} name(s) in {}",
+                    timestamps.by_id.len(),
+                    timestamps.by_name.len(),
+                    self.collection_name
+                );
+                latest.seed(timestamps);
+                Ok(latest.timestamps.clone())
+            }
+            Err(error) if latest.timestamps != LatestTimestamps::default() => {
+                log::warn!(
+                    "Error reading the latest timestamps in {}, using those inserted since: {error}",
+                    self.collection_name
+                );
+                Ok(latest.timestamps.clone())
+            }
+            Err(error) => Err(error),
+        }
+    }
 
-        // Iterate over each device name and find the latest item for each
-        for device_name in device_names {
-            let filter = mongodb::bson::doc! { name_field: &device_name };
+    fn get_items_between(
+        &self,
+        series: Series,
+        timestamp_field: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Vec<T>, Self::Error> {
+        self.connected(|| {
+            log::debug!(
+                "Getting items for {} between {from} and {to} from MongoDB",
+                series.value
+            );
+
+            let timestamp_path = self.path(timestamp_field);
+            let mut filter = self.series_filter(series);
+            filter.insert(
+                &timestamp_path,
+                doc! {
+                    "$gte": mongodb::bson::DateTime::from_millis(from.timestamp_millis()),
+                    "$lte": mongodb::bson::DateTime::from_millis(to.timestamp_millis()),
+                },
+            );
             let options = mongodb::options::FindOptions::builder()
-                .sort(mongodb::bson::doc! { timestamp_field: -1 })
-                .limit(1)
+                .sort(mongodb::bson::doc! { &timestamp_path: 1 })
+                .limit(limit)
                 .build();
 
-            // Find the latest item for the current device name
-            let result = collection.find(filter)
-                .with_options(options)
-                .run()?;
+            let items = self.find(filter, options)?;
+
+            log::debug!("Retrieved {} item(s) from MongoDB", items.len());
+            Ok(items)
+        })
+    }
+
+    fn delete_items_before(
+        &self,
+        series: Series,
+        timestamp_field: &str,
+        before: DateTime<Utc>,
+    ) -> Result<u64, Self::Error> {
+        self.connected(|| {
+            log::debug!(
+                "Deleting items for {} before {before} from MongoDB",
+                series.value
+            );
+
+            let mut filter = self.series_filter(series);
+            filter.insert(
+                self.path(timestamp_field),
+                doc! { "$lt": mongodb::bson::DateTime::from_millis(before.timestamp_millis()) },
+            );
+            let result = self.get_documents().delete_many(filter).run()?;
+
+            log::debug!("Deleted {} item(s) from MongoDB", result.deleted_count);
+            Ok(result.deleted_count)
+        })
+    }
+
+    fn delete_items_between(
+        &self,
+        series: Series,
+        timestamp_field: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<u64, Self::Error> {
+        self.connected(|| {
+            log::debug!(
+                "Deleting items for {} between {from} and {to} from MongoDB",
+                series.value
+            );
+
+            let mut filter = self.series_filter(series);
+            filter.insert(
+                self.path(timestamp_field),
+                doc! {
+                    "$gte": mongodb::bson::DateTime::from_millis(from.timestamp_millis()),
+                    "$lte": mongodb::bson::DateTime::from_millis(to.timestamp_millis()),
+                },
+            );
+            let result = self.get_documents().delete_many(filter).run()?;
+
+            log::debug!("Deleted {} item(s) from MongoDB", result.deleted_count);
+            Ok(result.deleted_count)
+        })
+    }
+
+    fn ping(&self) -> Result<(), Self::Error> {
+        self.connected(|| availability::ping(&self.client))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::sensor_control::models::TemperatureData;
+    use crate::tests::harness::{at_minute, reading};
+
+    const TIME_SERIES: TimeSeries = TimeSeries {
+        time_field: "timestamp",
+        meta_field: "meta",
+        meta_fields: &["device_name", "device_id", "bridge_id", "source"],
+        granularity: TimeSeriesGranularity::Seconds,
+    };
+
+    #[test]
+    fn readings_are_stored_with_their_series_under_the_meta_field() {
+        let reading = TemperatureData {
+            device_id: Some("device-1".to_string()),
+            source: Some("hue".to_string()),
+            ..reading("Lounge", at_minute(0), 20.5)
+        };
+
+        let stored = TIME_SERIES.nest_meta(mongodb::bson::to_document(&reading).unwrap());
+        assert_eq!(
+            stored.get_document("meta").unwrap(),
+            &doc! { "device_name": "Lounge", "device_id": "device-1", "source": "hue" }
+        );
+        assert!(stored.get_datetime("timestamp").is_ok());
+        assert!(!stored.contains_key("device_name"));
+
+        let read: TemperatureData =
+            mongodb::bson::from_document(TIME_SERIES.flatten_meta(stored)).unwrap();
+        assert_eq!(read.device_name, "Lounge");
+        assert_eq!(read.device_id.as_deref(), Some("device-1"));
+        assert_eq!(read.timestamp, reading.timestamp);
+        assert_eq!(read.temperature, 20.5);
+
+        assert_eq!(TIME_SERIES.path("device_name"), "meta.device_name");
+        assert_eq!(TIME_SERIES.path("timestamp"), "timestamp");
+    }
+
+    /// A time-series collection held in memory, which can be interrupted before recording a
+    /// batch's progress
+    #[derive(Default)]
+    struct TestTarget {
+        documents: Vec<Document>,
+        progress: CopyProgress,
+        /// How many more batches' progress is recorded before the copy is interrupted
+        records_left: Option<usize>,
+    }
 
-            // If a result is found, push it to the items vector
-            if let Some(item) = result.into_iter().next() {
-                match item {
-                    Ok(item) => items.push(item),
-                    Err(e) => {
-                        log::error!("Error retrieving item for device {device_name}: {e}");
-                        return Err(DatabaseError::from(e));
+    impl CopyTarget for TestTarget {
+        fn stored_between(
+            &self,
+            from: mongodb::bson::DateTime,
+            to: mongodb::bson::DateTime,
+        ) -> Result<Vec<Document>, DatabaseError> {
+            Ok(self
+                .documents
+                .iter()
+                .filter(|document| {
+                    document
+                        .get_datetime("timestamp")
+                        .is_ok_and(|timestamp| (from..=to).contains(timestamp))
+                })
+                .cloned()
+                .collect())
+        }
+
+        fn insert(&mut self, documents: Vec<Document>) -> Result<usize, DatabaseError> {
+            let count = documents.len();
+            self.documents.extend(documents);
+            Ok(count)
+        }
+
+        fn record(&mut self, progress: &CopyProgress) -> Result<(), DatabaseError> {
+            match &mut self.records_left {
+                Some(0) => Err(DatabaseError::Unavailable),
+                records_left => {
+                    if let Some(left) = records_left {
+                        *left -= 1;
                     }
+                    self.progress = progress.clone();
+                    Ok(())
                 }
-            } else {
-                log::warn!("No items found for device: {device_name}");
             }
         }
+    }
+
+    /// Copies `source` into `target`, newest first, three at a time
+    fn copy(source: &[TemperatureData], target: &mut TestTarget) -> Result<usize, DatabaseError> {
+        let mut source: Vec<&TemperatureData> = source.iter().collect();
+        source.sort_by_key(|reading| std::cmp::Reverse(reading.timestamp));
+        let documents = source
+            .into_iter()
+            .map(|reading| Ok(mongodb::bson::to_document(reading).unwrap()));
+        copy_documents(&TIME_SERIES, documents, target.progress.clone(), 3, target)
+    }
+
+    fn stored(reading: &TemperatureData) -> Document {
+        TIME_SERIES.nest_meta(mongodb::bson::to_document(reading).unwrap())
+    }
+
+    #[test]
+    fn an_interrupted_copy_carries_on_where_it_left_off() {
+        let source: Vec<TemperatureData> = (1..=4)
+            .flat_map(|minute| {
+                [
+                    reading("Lounge", at_minute(minute), 20.0),
+                    reading("Kitchen", at_minute(minute), 19.0),
+                ]
+            })
+            .collect();
+        // Readings stored since switching to the time-series collection, one of them late and
+        // older than most of those left to copy, and one also in the old collection
+        let stored_since = [
+            reading("Lounge", at_minute(5), 21.0),
+            reading("Hall", at_minute(1), 18.0),
+            reading("Kitchen", at_minute(2), 19.0),
+        ];
+        let mut target = TestTarget {
+            documents: stored_since.iter().map(stored).collect(),
+            // Interrupted after writing the second batch but before recording it
+            records_left: Some(1),
+            ..TestTarget::default()
+        };
+
+        assert!(copy(&source, &mut target).is_err());
+        assert_eq!(target.documents.len(), 3 + 5);
+        assert_eq!(
+            target.progress.copied_after,
+            Some(mongodb::bson::DateTime::from_millis(
+                at_minute(3).timestamp_millis()
+            ))
+        );
+        assert!(!target.progress.finished);
+
+        target.records_left = None;
+        assert_eq!(copy(&source, &mut target).unwrap(), 2);
+        assert!(target.progress.finished);
+
+        let mut copied: Vec<(String, DateTime<Utc>)> = target
+            .documents
+            .iter()
+            .map(|document| {
+                let reading: TemperatureData =
+                    mongodb::bson::from_document(TIME_SERIES.flatten_meta(document.clone()))
+                        .unwrap();
+                (reading.device_name, reading.timestamp)
+            })
+            .collect();
+        copied.sort();
+
+        let mut expected: Vec<(String, DateTime<Utc>)> = source
+            .iter()
+            .chain(&stored_since[..2])
+            .map(|reading| (reading.device_name.clone(), reading.timestamp))
+            .collect();
+        expected.sort();
+        assert_eq!(copied, expected);
+
+        // Nothing is copied once it has finished
+        assert_eq!(copy(&source, &mut target).unwrap(), 0);
+        assert_eq!(target.documents.len(), expected.len());
+    }
+
+    fn cache(timestamps: LatestTimestamps, seeded: bool) -> LatestCache {
+        LatestCache {
+            id_field: "device_id".to_string(),
+            name_field: "device_name".to_string(),
+            timestamp_field: "timestamp".to_string(),
+            timestamps,
+            seeded,
+        }
+    }
+
+    #[test]
+    fn latest_timestamps_only_move_forward() {
+        let mut latest = cache(
+            LatestTimestamps {
+                by_name: HashMap::from([("Lounge".to_string(), at_minute(5))]),
+                ..LatestTimestamps::default()
+            },
+            true,
+        );
+
+        latest
+            .update(&[
+                reading("Lounge", at_minute(4), 20.0),
+                reading("Kitchen", at_minute(2), 20.0),
+                reading("Kitchen", at_minute(1), 20.0),
+                TemperatureData {
+                    device_id: Some("device-1".to_string()),
+                    ..reading("Lounge", at_minute(6), 20.0)
+                },
+            ])
+            .unwrap();
+
+        // Items with an ID are only recorded under it
+        assert_eq!(
+            latest.timestamps,
+            LatestTimestamps {
+                by_id: HashMap::from([("device-1".to_string(), at_minute(6))]),
+                by_name: HashMap::from([
+                    ("Lounge".to_string(), at_minute(5)),
+                    ("Kitchen".to_string(), at_minute(2))
+                ]),
+            }
+        );
+    }
+
+    #[test]
+    fn items_inserted_before_the_latest_timestamps_are_read_are_kept() {
+        let mut latest = cache(LatestTimestamps::default(), false);
+
+        latest
+            .update(&[reading("Lounge", at_minute(6), 20.0)])
+            .unwrap();
+        latest.seed(LatestTimestamps {
+            by_name: HashMap::from([
+                ("Lounge".to_string(), at_minute(5)),
+                ("Kitchen".to_string(), at_minute(2)),
+            ]),
+            ..LatestTimestamps::default()
+        });
+
+        assert!(latest.seeded);
+        assert_eq!(
+            latest.timestamps.by_name,
+            HashMap::from([
+                ("Lounge".to_string(), at_minute(6)),
+                ("Kitchen".to_string(), at_minute(2))
+            ])
+        );
+    }
+
+    #[test]
+    fn operations_fail_straight_away_once_mongodb_is_unavailable() {
+        // Nothing listens on port 1, and the client is created without connecting
+        let client = MongoClient::<TemperatureData>::new(
+            "mongodb://127.0.0.1:1/?serverSelectionTimeoutMS=200&connectTimeoutMS=200",
+            "test",
+            "readings",
+        )
+        .unwrap();
+
+        // The connection is shared, so it cannot be to another URL
+        assert!(matches!(
+            MongoClient::<TemperatureData>::new("mongodb://127.0.0.1:2", "test", "readings"),
+            Err(DatabaseError::DifferentUrl)
+        ));
+
+        let result = client.get_latest_items("device_name", "timestamp");
+        assert!(result.unwrap_err().is_unavailable());
+        assert!(!availability::is_available());
+
+        let started = Instant::now();
+        let result = client.get_latest_items("device_name", "timestamp");
+        assert!(matches!(result, Err(DatabaseError::Unavailable)));
+        assert!(started.elapsed() < std::time::Duration::from_secs(1));
 
-        log::debug!("Latest items retrieved from MongoDB");
-        Ok(items)
+        // Availability is shared by every collection, so it is not left unavailable
+        availability::set_available();
     }
 }
@@ -4,4 +4,84 @@ use thiserror::Error;
 pub enum DatabaseError {
     #[error("MongoDB Error: {0}")]
     MongoDB(#[from] mongodb::error::Error),
+    #[error("BSON Error: {0}")]
+    Bson(#[from] bson::error::Error),
+    #[error("Duplicate Key Error: {0} item(s) already stored")]
+    DuplicateKey(usize),
+    /// Some items of an unordered insert were not written, by their positions in the batch: those
+    /// that may be written if they are tried again, and those that were rejected for good
+    #[error(
+        "Write Error: {} item(s) not written, {} of them rejected: {message}",
+        retry.len() + rejected.len(),
+        rejected.len()
+    )]
+    NotWritten {
+        retry: Vec<usize>,
+        rejected: Vec<usize>,
+        message: String,
+    },
+    #[error(
+        "{0} is not a time-series collection; copy it into a new one with `rust-backend migrate-time-series`"
+    )]
+    NotTimeSeries(String),
+    #[error("Spool Error: {0}")]
+    Spool(#[from] std::io::Error),
+    #[error("MongoDB is unavailable")]
+    Unavailable,
+    #[error(
+        "MongoDB is already connected to another URL; every collection has to use the same one"
+    )]
+    DifferentUrl,
+}
+
+impl DatabaseError {
+    /// Whether the error is from MongoDB being unreachable, rather than from the operation
+    pub fn is_unavailable(&self) -> bool {
+        match self {
+            DatabaseError::Unavailable => true,
+            DatabaseError::MongoDB(error) => matches!(
+                error.kind.as_ref(),
+                mongodb::error::ErrorKind::ServerSelection { .. }
+                    | mongodb::error::ErrorKind::Io(_)
+                    | mongodb::error::ErrorKind::ConnectionPoolCleared { .. }
+            ),
+            _ => false,
+        }
+    }
+
+    /// Whether the items could never be written as they are, such as a document that is too large
+    /// or does not convert to BSON, rather than the write failing this time
+    pub fn is_rejected(&self) -> bool {
+        match self {
+            DatabaseError::Bson(_) => true,
+            DatabaseError::NotWritten {
+                retry, rejected, ..
+            } => retry.is_empty() && !rejected.is_empty(),
+            DatabaseError::MongoDB(error) => match error.kind.as_ref() {
+                mongodb::error::ErrorKind::BsonSerialization(_)
+                | mongodb::error::ErrorKind::BsonDeserialization(_)
+                | mongodb::error::ErrorKind::InvalidArgument { .. } => true,
+                mongodb::error::ErrorKind::Write(mongodb::error::WriteFailure::WriteError(
+                    write_error,
+                )) => !is_retryable_write(write_error.code),
+                mongodb::error::ErrorKind::InsertMany(insert_error) => {
+                    insert_error.write_concern_error.is_none()
+                        && insert_error.write_errors.as_ref().is_some_and(|errors| {
+                            errors.iter().all(|error| !is_retryable_write(error.code))
+                        })
+                }
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+/// Whether a write that failed with the server's error `code` may succeed if it is tried again,
+/// going by the codes MongoDB retries writes on, and lock and time limit errors
+pub fn is_retryable_write(code: i32) -> bool {
+    matches!(
+        code,
+        6 | 7 | 50 | 89 | 91 | 112 | 189 | 262 | 9001 | 10107 | 11600 | 11602 | 13435 | 13436
+    )
 }
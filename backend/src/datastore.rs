@@ -1 +1,3 @@
+pub mod memory;
+pub mod spool;
 pub mod storage;
@@ -0,0 +1,390 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use bson::{Bson, Document};
+use chrono::{DateTime, Utc};
+use serde::{Serialize, de::DeserializeOwned};
+
+use super::storage::{LatestTimestamps, Series, Storage};
+
+use crate::database::errors::DatabaseError;
+
+/// An in-memory implementation of the Storage trait, for tests and dry runs.
+///
+/// Items are kept as BSON documents, as MongoDB would store them, and a unique index over
+/// `unique_fields` is enforced the same way: unordered inserts store every item that does not
+/// duplicate an existing one, and then report the duplicates as an error. Like the partial index
+/// on the device ID, it leaves out the items that do not have all of the fields.
+pub struct InMemoryStorage<T> {
+    unique_fields: Vec<String>,
+    log_writes: bool,
+    documents: Mutex<Vec<Document>>,
+    _marker: std::marker::PhantomData<T>,
+}
+
+impl<T> InMemoryStorage<T> {
+    /// Creates an empty store with a unique index over `unique_fields`
+    pub fn new(unique_fields: &[&str]) -> Self {
+        InMemoryStorage {
+            unique_fields: unique_fields
+                .iter()
+                .map(|field| field.to_string())
+                .collect(),
+            log_writes: false,
+            documents: Mutex::new(Vec::new()),
+            _marker: std::marker::PhantomData,
+        }
+    }
+
+    /// Logs every item as it is written, so a dry run shows what would have been stored
+    pub fn with_write_logging(mut self) -> Self {
+        self.log_writes = true;
+        self
+    }
+
+    /// Every stored item in insertion order
+    #[cfg(test)]
+    pub fn items(&self) -> Result<Vec<T>, DatabaseError>
+    where
+        T: DeserializeOwned,
+    {
+        self.lock()
+            .iter()
+            .map(|document| Ok(bson::deserialize_from_document(document.clone())?))
+            .collect()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Document>> {
+        self.documents
+            .lock()
+            .expect("In-memory storage mutex poisoned")
+    }
+
+    /// The values of the unique fields, which identify a document for the unique index
+    fn unique_key(&self, document: &Document) -> Vec<Option<Bson>> {
+        self.unique_fields
+            .iter()
+            .map(|field| document.get(field).cloned())
+            .collect()
+    }
+}
+
+impl<T> Storage<T> for InMemoryStorage<T>
+where
+    T: Serialize + DeserializeOwned + Unpin + Send + Sync + 'static,
+{
+    type Error = DatabaseError;
+
+    fn save_item(&self, data: &T) -> Result<(), Self::Error> {
+        self.save_items(std::slice::from_ref(data))
+    }
+
+    fn save_items(&self, data: &[T]) -> Result<(), Self::Error> {
+        let new_documents = data
+            .iter()
+            .map(bson::serialize_to_document)
+            .collect::<Result<Vec<Document>, _>>()?;
+
+        let mut documents = self.lock();
+        let mut duplicates = 0;
+
+        for document in new_documents {
+            let key = self.unique_key(&document);
+
+            if !self.unique_fields.is_empty()
+                && key.iter().all(Option::is_some)
+                && documents
+                    .iter()
+                    .any(|existing| self.unique_key(existing) == key)
+            {
+                log::debug!("Duplicate key, not storing: {document}");
+                duplicates += 1;
+                continue;
+            }
+
+            if self.log_writes {
+                log::info!("Write: {document}");
+            }
+
+            documents.push(document);
+        }
+
+        if duplicates > 0 {
+            return Err(DatabaseError::DuplicateKey(duplicates));
+        }
+
+        Ok(())
+    }
+
+    fn upsert_item(&self, key_field: &str, key: &str, data: &T) -> Result<(), Self::Error> {
+        let document = bson::serialize_to_document(data)?;
+
+        if self.log_writes {
+            log::info!("Upsert: {document}");
+        }
+
+        let mut documents = self.lock();
+        match documents
+            .iter_mut()
+            .find(|existing| existing.get_str(key_field).is_ok_and(|value| value == key))
+        {
+            Some(existing) => *existing = document,
+            None => documents.push(document),
+        }
+
+        Ok(())
+    }
+
+    fn get_latest_items(
+        &self,
+        name_field: &str,
+        timestamp_field: &str,
+    ) -> Result<Vec<T>, Self::Error> {
+        let documents = self.lock();
+        let mut latest: HashMap<String, &Document> = HashMap::new();
+
+        for document in documents.iter() {
+            let Ok(name) = document.get_str(name_field) else {
+                continue;
+            };
+
+            let newer = latest.get(name).is_none_or(|current| {
+                compare(current.get(timestamp_field), document.get(timestamp_field))
+                    == Ordering::Less
+            });
+
+            if newer {
+                latest.insert(name.to_string(), document);
+            }
+        }
+
+        latest
+            .into_values()
+            .map(|document| Ok(bson::deserialize_from_document(document.clone())?))
+            .collect()
+    }
+
+    fn get_latest_timestamps(
+        &self,
+        id_field: &str,
+        name_field: &str,
+        timestamp_field: &str,
+    ) -> Result<LatestTimestamps, Self::Error> {
+        let mut latest = LatestTimestamps::default();
+
+        for document in self.lock().iter() {
+            let (Ok(name), Ok(timestamp)) = (
+                document.get_str(name_field),
+                document.get_datetime(timestamp_field),
+            ) else {
+                continue;
+            };
+            latest.record(document.get_str(id_field).ok(), name, timestamp.to_chrono());
+        }
+
+        Ok(latest)
+    }
+
+    fn get_items_between(
+        &self,
+        series: Series,
+        timestamp_field: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Vec<T>, Self::Error> {
+        let from = Bson::DateTime(bson::DateTime::from_millis(from.timestamp_millis()));
+        let to = Bson::DateTime(bson::DateTime::from_millis(to.timestamp_millis()));
+
+        let documents = self.lock();
+        let mut items: Vec<&Document> = documents
+            .iter()
+            .filter(|document| in_series(document, series))
+            .filter(|document| {
+                let timestamp = document.get(timestamp_field);
+                compare(Some(&from), timestamp) != Ordering::Greater
+                    && compare(timestamp, Some(&to)) != Ordering::Greater
+            })
+            .collect();
+
+        items.sort_by(|a, b| compare(a.get(timestamp_field), b.get(timestamp_field)));
+
+        items
+            .into_iter()
+            .take(usize::try_from(limit).unwrap_or(0))
+            .map(|document| Ok(bson::deserialize_from_document(document.clone())?))
+            .collect()
+    }
+
+    fn delete_items_before(
+        &self,
+        series: Series,
+        timestamp_field: &str,
+        before: DateTime<Utc>,
+    ) -> Result<u64, Self::Error> {
+        let before = Bson::DateTime(bson::DateTime::from_millis(before.timestamp_millis()));
+
+        let mut documents = self.lock();
+        let count = documents.len();
+        documents.retain(|document| {
+            !(in_series(document, series)
+                && compare(document.get(timestamp_field), Some(&before)) == Ordering::Less)
+        });
+        let deleted = count - documents.len();
+
+        if self.log_writes && deleted > 0 {
+            log::info!(
+                "Delete: {deleted} item(s) of {} before {before}",
+                series.value
+            );
+        }
+
+        Ok(deleted as u64)
+    }
+
+    fn delete_items_between(
+        &self,
+        series: Series,
+        timestamp_field: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<u64, Self::Error> {
+        let from = Bson::DateTime(bson::DateTime::from_millis(from.timestamp_millis()));
+        let to = Bson::DateTime(bson::DateTime::from_millis(to.timestamp_millis()));
+
+        let mut documents = self.lock();
+        let count = documents.len();
+        documents.retain(|document| {
+            let timestamp = document.get(timestamp_field);
+            !(in_series(document, series)
+                && compare(Some(&from), timestamp) != Ordering::Greater
+                && compare(timestamp, Some(&to)) != Ordering::Greater)
+        });
+        let deleted = count - documents.len();
+
+        if self.log_writes && deleted > 0 {
+            log::info!(
+                "Delete: {deleted} item(s) of {} from {from} to {to}",
+                series.value
+            );
+        }
+
+        Ok(deleted as u64)
+    }
+}
+
+/// Whether a document is one of the items of `series`
+fn in_series(document: &Document, series: Series) -> bool {
+    document
+        .get_str(series.field)
+        .is_ok_and(|value| value == series.value)
+        && series
+            .without
+            .is_none_or(|field| !document.contains_key(field))
+}
+
+/// Orders BSON date-times, with missing or non-date values first
+fn compare(a: Option<&Bson>, b: Option<&Bson>) -> Ordering {
+    let millis = |value: Option<&Bson>| match value {
+        Some(Bson::DateTime(date_time)) => Some(date_time.timestamp_millis()),
+        _ => None,
+    };
+
+    millis(a).cmp(&millis(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::sensor_control::models::TemperatureData;
+    use crate::tests::harness::{at_minute, reading};
+
+    fn storage() -> InMemoryStorage<TemperatureData> {
+        InMemoryStorage::new(&["device_name", "timestamp"])
+    }
+
+    #[test]
+    fn duplicates_are_rejected_after_storing_the_rest() {
+        let storage = storage();
+        storage
+            .save_item(&reading("Lounge", at_minute(0), 20.0))
+            .unwrap();
+
+        let result = storage.save_items(&[
+            reading("Lounge", at_minute(0), 21.0),
+            reading("Lounge", at_minute(1), 22.0),
+            reading("Kitchen", at_minute(0), 19.0),
+        ]);
+
+        assert!(matches!(result, Err(DatabaseError::DuplicateKey(1))));
+
+        let temperatures: Vec<f32> = storage
+            .items()
+            .unwrap()
+            .iter()
+            .map(|item| item.temperature)
+            .collect();
+        assert_eq!(temperatures, vec![20.0, 22.0, 19.0]);
+    }
+
+    #[test]
+    fn latest_items_are_per_name() {
+        let storage = storage();
+        storage
+            .save_items(&[
+                reading("Lounge", at_minute(2), 22.0),
+                reading("Lounge", at_minute(5), 25.0),
+                reading("Lounge", at_minute(3), 23.0),
+                reading("Kitchen", at_minute(1), 19.0),
+            ])
+            .unwrap();
+
+        let mut latest = storage
+            .get_latest_items("device_name", "timestamp")
+            .unwrap();
+        latest.sort_by(|a, b| a.device_name.cmp(&b.device_name));
+
+        let latest: Vec<(&str, f32)> = latest
+            .iter()
+            .map(|item| (item.device_name.as_str(), item.temperature))
+            .collect();
+        assert_eq!(latest, vec![("Kitchen", 19.0), ("Lounge", 25.0)]);
+    }
+
+    #[test]
+    fn items_between_are_inclusive_sorted_and_limited() {
+        let storage = storage();
+        storage
+            .save_items(&[
+                reading("Lounge", at_minute(4), 24.0),
+                reading("Lounge", at_minute(1), 21.0),
+                reading("Lounge", at_minute(2), 22.0),
+                reading("Lounge", at_minute(3), 23.0),
+                reading("Kitchen", at_minute(2), 19.0),
+            ])
+            .unwrap();
+
+        let from = at_minute(2);
+        let to = at_minute(4);
+
+        let between = |limit| {
+            storage
+                .get_items_between(
+                    Series::new("device_name", "Lounge"),
+                    "timestamp",
+                    from,
+                    to,
+                    limit,
+                )
+                .unwrap()
+                .iter()
+                .map(|item| item.temperature)
+                .collect::<Vec<f32>>()
+        };
+
+        assert_eq!(between(10), vec![22.0, 23.0, 24.0]);
+        assert_eq!(between(2), vec![22.0, 23.0]);
+    }
+}
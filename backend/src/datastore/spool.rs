@@ -0,0 +1,910 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufReader, BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bson::Document;
+use chrono::{DateTime, Utc};
+use serde::{Serialize, de::DeserializeOwned};
+
+use super::storage::{LatestTimestamps, Series, Storage};
+
+use crate::database::errors::DatabaseError;
+use crate::metrics;
+use crate::shutdown::Shutdown;
+
+/// How many spooled items are written back at a time
+const REPLAY_BATCH_SIZE: usize = 1_000;
+
+/// How often the spools are written back in the background, which does nothing while they are
+/// empty or the database is unavailable
+const REPLAY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long to wait before writing a spool back again after it failed for any reason other than
+/// the database being unavailable
+const REPLAY_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Writes back the items a storage is holding back, such as `Storage::replay` of a store
+pub type Replay = Box<dyn Fn() -> Result<(), DatabaseError> + Send>;
+
+/// Wraps a storage with a write-ahead spool on disk, so that items are not lost while the
+/// storage is unavailable.
+///
+/// Items that fail to save are appended to the spool file as BSON documents, and so are the
+/// items saved after them until the spool has been written back, so that nothing is written out
+/// of order. Items the storage rejects for good, such as a document that is too large, are set
+/// aside in a file of their own next to the spool instead, so they cannot hold up the rest. The spool is written back by `replay`, which `run_replay` calls in the background so
+/// that it happens as soon as the storage recovers. The spool is kept across restarts and stops
+/// taking items once it reaches `max_bytes`.
+///
+/// While the storage is unavailable, the latest items are the latest it returned merged with
+/// those in the spool, so readings are still deduplicated.
+pub struct SpooledStorage<S, T> {
+    inner: S,
+    path: PathBuf,
+    /// The name of the spool in the metrics, from its file name
+    name: String,
+    max_bytes: u64,
+    /// Held while writing to the storage or the spool file, so that items are written in order
+    /// without holding `state` while waiting on the storage
+    writing: Mutex<()>,
+    state: Mutex<SpoolState>,
+    _marker: std::marker::PhantomData<T>,
+}
+
+struct SpoolState {
+    /// The spooled documents, oldest first, as they are in the file
+    documents: Vec<Document>,
+    bytes: u64,
+    /// The latest item of each name, as last read from the storage
+    latest: HashMap<String, Document>,
+}
+
+impl<S, T> SpooledStorage<S, T>
+where
+    S: Storage<T, Error = DatabaseError>,
+    T: Serialize + DeserializeOwned + Unpin + Send + Sync + 'static,
+{
+    /// Wraps `inner` with the spool at `path`, reading back anything left in it
+    pub fn open(inner: S, path: &Path, max_bytes: u64) -> Result<Self, DatabaseError> {
+        let documents = read_spool(path)?;
+        let bytes = fs::metadata(path).map_or(0, |metadata| metadata.len());
+        let name = path
+            .file_stem()
+            .map_or_else(String::new, |stem| stem.to_string_lossy().into_owned());
+        metrics::record_spooled(&name, documents.len());
+
+        if !documents.is_empty() {
+            log::warn!(
+                "{} item(s) spooled in {} will be written once the database is available",
+                documents.len(),
+                path.display()
+            );
+        }
+
+        Ok(SpooledStorage {
+            inner,
+            path: path.to_path_buf(),
+            name,
+            max_bytes,
+            writing: Mutex::new(()),
+            state: Mutex::new(SpoolState {
+                documents,
+                bytes,
+                latest: HashMap::new(),
+            }),
+            _marker: std::marker::PhantomData,
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SpoolState> {
+        self.state.lock().expect("Spool mutex poisoned")
+    }
+
+    fn lock_writing(&self) -> MutexGuard<'_, ()> {
+        self.writing.lock().expect("Spool writing mutex poisoned")
+    }
+
+    /// Writes the spooled items back to the storage in order, stopping at the first batch that
+    /// it fails to write all of and keeping the items that may still be written. Items the
+    /// storage already has are dropped, and those it rejects are set aside. Must be called with
+    /// `writing` held.
+    fn write_back(&self) -> Result<(), DatabaseError> {
+        // How many documents from the start of the spool have been tried, and of those, the ones
+        // to keep and the ones to set aside
+        let mut replayed = 0;
+        let mut kept = Vec::new();
+        let mut rejected = Vec::new();
+        let mut result = Ok(());
+        loop {
+            let documents: Vec<Document> = {
+                let state = self.lock();
+                let end = state.documents.len().min(replayed + REPLAY_BATCH_SIZE);
+                state.documents[replayed..end].to_vec()
+            };
+            if documents.is_empty() {
+                break;
+            }
+            replayed += documents.len();
+
+            let mut items = Vec::with_capacity(documents.len());
+            for document in documents {
+                match bson::deserialize_from_document::<T>(document.clone()) {
+                    Ok(item) => items.push((item, document)),
+                    Err(error) => {
+                        log::error!("Spooled item {document} cannot be read back: {error}");
+                        rejected.push(document);
+                    }
+                }
+            }
+            let (items, documents): (Vec<T>, Vec<Document>) = items.into_iter().unzip();
+
+            let saved = self.try_save(&items);
+            if let Some(error) = &saved.error
+                && !saved.rejected.is_empty()
+            {
+                log::error!(
+                    "{} spooled item(s) rejected by the database: {error}",
+                    saved.rejected.len()
+                );
+            }
+            rejected.extend(saved.rejected.iter().map(|index| documents[*index].clone()));
+            kept.extend(saved.retry.iter().map(|index| documents[*index].clone()));
+            if let Some(error) = saved.error
+                && !saved.retry.is_empty()
+            {
+                result = Err(error);
+                break;
+            }
+        }
+
+        if !rejected.is_empty() {
+            self.set_aside(&rejected)?;
+        }
+        if replayed > 0 {
+            log::info!(
+                "Wrote {} spooled item(s) from {}",
+                replayed - kept.len() - rejected.len(),
+                self.path.display()
+            );
+            let mut state = self.lock();
+            state.documents.splice(..replayed, kept);
+            state.bytes = rewrite_spool(&self.path, &state.documents)?;
+            metrics::record_spooled(&self.name, state.documents.len());
+        }
+
+        result
+    }
+
+    /// Saves `items`, sorting out any that were not written
+    fn try_save(&self, items: &[T]) -> Saved {
+        match self.inner.save_items(items) {
+            Ok(()) | Err(DatabaseError::DuplicateKey(_)) => Saved::default(),
+            Err(error) => self.sort_out(items, error),
+        }
+    }
+
+    /// Sorts out which of `items` may be written on another try and which the storage rejected
+    /// for good, after saving them failed with `error`. A batch that is rejected as a whole is
+    /// saved an item at a time to find the items at fault.
+    fn sort_out(&self, items: &[T], error: DatabaseError) -> Saved {
+        match &error {
+            DatabaseError::NotWritten {
+                retry, rejected, ..
+            } => Saved {
+                retry: retry.clone(),
+                rejected: rejected.clone(),
+                error: Some(error),
+            },
+            _ if error.is_rejected() && items.len() > 1 => {
+                let mut saved = Saved::default();
+                for (index, item) in items.iter().enumerate() {
+                    match self.inner.save_item(item) {
+                        Ok(()) | Err(DatabaseError::DuplicateKey(_)) => {}
+                        Err(error) if error.is_rejected() => {
+                            saved.rejected.push(index);
+                            saved.error = Some(error);
+                        }
+                        Err(error) => {
+                            saved.retry.extend(index..items.len());
+                            saved.error = Some(error);
+                            break;
+                        }
+                    }
+                }
+                saved
+            }
+            _ if error.is_rejected() => Saved {
+                rejected: vec![0],
+                error: Some(error),
+                ..Saved::default()
+            },
+            _ => Saved {
+                retry: (0..items.len()).collect(),
+                error: Some(error),
+                ..Saved::default()
+            },
+        }
+    }
+
+    /// Appends documents the storage rejected to the file next to the spool, where they are kept
+    /// to be looked into rather than tried again
+    fn set_aside(&self, documents: &[Document]) -> Result<(), DatabaseError> {
+        let path = self.path.with_extension("rejected.bson");
+        log::error!(
+            "Setting aside {} rejected item(s) in {}",
+            documents.len(),
+            path.display()
+        );
+        let contents = documents
+            .iter()
+            .map(Document::to_vec)
+            .collect::<Result<Vec<Vec<u8>>, _>>()?
+            .concat();
+        append_to_file(&path, &contents)?;
+        metrics::record_spool_rejected(&self.name, documents.len());
+        Ok(())
+    }
+
+    /// Appends items to the spool, unless it is full. Must be called with `writing` held.
+    fn append<'a>(&self, data: impl IntoIterator<Item = &'a T>) -> Result<(), DatabaseError>
+    where
+        T: 'a,
+    {
+        let documents = data
+            .into_iter()
+            .map(bson::serialize_to_document)
+            .collect::<Result<Vec<Document>, _>>()?;
+        let contents = documents
+            .iter()
+            .map(Document::to_vec)
+            .collect::<Result<Vec<Vec<u8>>, _>>()?
+            .concat();
+
+        if self.lock().bytes + contents.len() as u64 > self.max_bytes {
+            log::error!(
+                "The spool {} is full, dropping {} item(s)",
+                self.path.display(),
+                documents.len()
+            );
+            metrics::record_spool_dropped(&self.name, documents.len());
+            return Ok(());
+        }
+
+        append_to_file(&self.path, &contents)?;
+
+        let mut state = self.lock();
+        state.bytes += contents.len() as u64;
+        state.documents.extend(documents);
+        metrics::record_spooled(&self.name, state.documents.len());
+        Ok(())
+    }
+}
+
+impl<S, T> Storage<T> for SpooledStorage<S, T>
+where
+    S: Storage<T, Error = DatabaseError>,
+    T: Serialize + DeserializeOwned + Unpin + Send + Sync + 'static,
+{
+    type Error = DatabaseError;
+
+    fn save_item(&self, data: &T) -> Result<(), Self::Error> {
+        self.save_items(std::slice::from_ref(data))
+    }
+
+    fn save_items(&self, data: &[T]) -> Result<(), Self::Error> {
+        let _writing = self.lock_writing();
+
+        // Spooled items go first, so nothing is written out of order
+        let spooled = self.lock().documents.len();
+        if spooled > 0 {
+            log::debug!(
+                "Spooling {} item(s) to {} behind the {spooled} waiting to be written",
+                data.len(),
+                self.path.display()
+            );
+            return self.append(data);
+        }
+
+        let saved = match self.inner.save_items(data) {
+            Ok(()) => return Ok(()),
+            Err(DatabaseError::DuplicateKey(count)) => {
+                return Err(DatabaseError::DuplicateKey(count));
+            }
+            Err(error) => self.sort_out(data, error),
+        };
+        let Some(error) = saved.error else {
+            return Ok(());
+        };
+
+        if !saved.rejected.is_empty() {
+            log::error!(
+                "{} item(s) rejected by the database: {error}",
+                saved.rejected.len()
+            );
+            let documents = saved
+                .rejected
+                .iter()
+                .filter_map(|index| match bson::serialize_to_document(&data[*index]) {
+                    Ok(document) => Some(document),
+                    Err(error) => {
+                        log::error!("Dropping an item that cannot be stored as BSON: {error}");
+                        None
+                    }
+                })
+                .collect::<Vec<Document>>();
+            self.set_aside(&documents)?;
+        }
+        if saved.retry.is_empty() {
+            return Ok(());
+        }
+
+        // MongoDB being unavailable has already been logged
+        let level = match error {
+            DatabaseError::Unavailable => log::Level::Debug,
+            _ => log::Level::Warn,
+        };
+        log::log!(
+            level,
+            "Error saving {} item(s), spooling them to {}: {error}",
+            saved.retry.len(),
+            self.path.display()
+        );
+        self.append(saved.retry.iter().map(|index| &data[*index]))
+    }
+
+    fn upsert_item(&self, key_field: &str, key: &str, data: &T) -> Result<(), Self::Error> {
+        self.inner.upsert_item(key_field, key, data)
+    }
+
+    fn get_latest_items(
+        &self,
+        name_field: &str,
+        timestamp_field: &str,
+    ) -> Result<Vec<T>, Self::Error> {
+        let result = self.inner.get_latest_items(name_field, timestamp_field);
+        let mut state = self.lock();
+
+        match result {
+            Ok(items) => {
+                state.latest = items
+                    .iter()
+                    .map(bson::serialize_to_document)
+                    .collect::<Result<Vec<Document>, _>>()?
+                    .into_iter()
+                    .filter_map(|document| {
+                        let name = document.get_str(name_field).ok()?.to_string();
+                        Some((name, document))
+                    })
+                    .collect();
+            }
+            Err(DatabaseError::Unavailable) => {}
+            Err(error) => {
+                log::warn!("Error getting the latest items, using the last known: {error}")
+            }
+        }
+
+        let mut latest = state.latest.clone();
+        for document in &state.documents {
+            let Ok(name) = document.get_str(name_field) else {
+                continue;
+            };
+            let newer = latest.get(name).is_none_or(|current| {
+                current.get_datetime(timestamp_field).ok()
+                    < document.get_datetime(timestamp_field).ok()
+            });
+            if newer {
+                latest.insert(name.to_string(), document.clone());
+            }
+        }
+
+        latest
+            .into_values()
+            .map(|document| Ok(bson::deserialize_from_document(document)?))
+            .collect()
+    }
+
+    fn get_latest_timestamps(
+        &self,
+        id_field: &str,
+        name_field: &str,
+        timestamp_field: &str,
+    ) -> Result<LatestTimestamps, Self::Error> {
+        let result = self
+            .inner
+            .get_latest_timestamps(id_field, name_field, timestamp_field);
+
+        // Until the storage has returned them, items are only deduplicated against the spool. Any
+        // that the storage already has are dropped as duplicates when they are written back to a
+        // collection with a unique index, but a time-series collection has none, so they are
+        // stored twice
+        let mut latest = match result {
+            Ok(latest) => latest,
+            Err(DatabaseError::Unavailable) => LatestTimestamps::default(),
+            Err(error) => {
+                log::warn!("Error getting the latest timestamps, using the spooled: {error}");
+                LatestTimestamps::default()
+            }
+        };
+
+        for document in &self.lock().documents {
+            let (Ok(name), Ok(timestamp)) = (
+                document.get_str(name_field),
+                document.get_datetime(timestamp_field),
+            ) else {
+                continue;
+            };
+            latest.record(document.get_str(id_field).ok(), name, timestamp.to_chrono());
+        }
+
+        Ok(latest)
+    }
+
+    fn get_items_between(
+        &self,
+        series: Series,
+        timestamp_field: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Vec<T>, Self::Error> {
+        self.inner
+            .get_items_between(series, timestamp_field, from, to, limit)
+    }
+
+    fn delete_items_before(
+        &self,
+        series: Series,
+        timestamp_field: &str,
+        before: DateTime<Utc>,
+    ) -> Result<u64, Self::Error> {
+        self.inner
+            .delete_items_before(series, timestamp_field, before)
+    }
+
+    fn delete_items_between(
+        &self,
+        series: Series,
+        timestamp_field: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<u64, Self::Error> {
+        self.inner
+            .delete_items_between(series, timestamp_field, from, to)
+    }
+
+    fn ping(&self) -> Result<(), Self::Error> {
+        self.inner.ping()
+    }
+
+    /// Writes back the spool, if there is anything in it
+    fn replay(&self) -> Result<(), Self::Error> {
+        let _writing = self.lock_writing();
+        if self.lock().documents.is_empty() {
+            return Ok(());
+        }
+        self.write_back()
+    }
+
+    /// Writes back whatever it can of the spool, leaving the rest for the next start
+    fn flush(&self) -> Result<(), Self::Error> {
+        if let Err(error) = self.replay() {
+            log::warn!(
+                "{} item(s) left in {} to be written on the next start: {error}",
+                self.lock().documents.len(),
+                self.path.display()
+            );
+        }
+
+        self.inner.flush()
+    }
+}
+
+/// What became of a batch of items that the storage was asked to save: the positions of those
+/// that were not written, and the error
+#[derive(Default)]
+struct Saved {
+    /// The items that may be written if they are tried again
+    retry: Vec<usize>,
+    /// The items the storage rejected for good
+    rejected: Vec<usize>,
+    error: Option<DatabaseError>,
+}
+
+/// Calls each of `replays` every `REPLAY_INTERVAL` on a new thread until shutdown is triggered,
+/// so that spooled items are written back as soon as the database is available again rather
+/// than when the next items are saved
+pub fn run_replay(
+    replays: Vec<Replay>,
+    shutdown: &Shutdown,
+) -> Result<thread::JoinHandle<()>, std::io::Error> {
+    let shutdown = shutdown.clone();
+
+    thread::Builder::new()
+        .name("spool replay".to_string())
+        .spawn(move || {
+            let mut retry_at: Vec<Option<Instant>> = vec![None; replays.len()];
+            while !shutdown.wait_timeout(REPLAY_INTERVAL) {
+                for (replay, retry_at) in replays.iter().zip(&mut retry_at) {
+                    if retry_at.is_some_and(|retry_at| Instant::now() < retry_at) {
+                        continue;
+                    }
+                    *retry_at = match replay() {
+                        Ok(()) => None,
+                        Err(error) if error.is_unavailable() => None,
+                        Err(error) => {
+                            log::warn!(
+                                "Error writing back spooled items, retrying in {}s: {error}",
+                                REPLAY_RETRY_INTERVAL.as_secs()
+                            );
+                            Some(Instant::now() + REPLAY_RETRY_INTERVAL)
+                        }
+                    };
+                }
+            }
+            log::debug!("Spool replay stopped");
+        })
+}
+
+/// Appends `contents` to the file at `path`, creating it and its directory if need be, and waits
+/// for it to be written to disk
+fn append_to_file(path: &Path, contents: &[u8]) -> Result<(), DatabaseError> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    file.write_all(contents)?;
+    file.sync_data()?;
+    Ok(())
+}
+
+/// Reads the documents in a spool file, stopping at a document that was only partly written
+fn read_spool(path: &Path) -> Result<Vec<Document>, DatabaseError> {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => return Err(error.into()),
+    };
+    let length = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+
+    let mut documents = Vec::new();
+    let mut read = 0;
+    while read < length {
+        match Document::from_reader(&mut reader) {
+            Ok(document) => {
+                read += document.to_vec()?.len() as u64;
+                documents.push(document);
+            }
+            Err(error) => {
+                log::warn!(
+                    "Ignoring the end of {}, which was not fully written: {error}",
+                    path.display()
+                );
+                rewrite_spool(path, &documents)?;
+                break;
+            }
+        }
+    }
+
+    Ok(documents)
+}
+
+/// Replaces the spool file with `documents`, removing it if there are none, and returns its new
+/// size
+fn rewrite_spool(path: &Path, documents: &[Document]) -> Result<u64, DatabaseError> {
+    if documents.is_empty() {
+        match fs::remove_file(path) {
+            Ok(()) => {}
+            Err(error) if error.kind() == ErrorKind::NotFound => {}
+            Err(error) => return Err(error.into()),
+        }
+        return Ok(0);
+    }
+
+    let temp_path = path.with_extension("tmp");
+    let mut writer = BufWriter::new(fs::File::create(&temp_path)?);
+    for document in documents {
+        document.to_writer(&mut writer)?;
+    }
+    writer
+        .into_inner()
+        .map_err(|error| error.into_error())?
+        .sync_data()?;
+    fs::rename(&temp_path, path)?;
+
+    Ok(fs::metadata(path)?.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use chrono::Timelike;
+
+    use super::*;
+
+    use crate::datastore::memory::InMemoryStorage;
+    use crate::sensor_control::models::TemperatureData;
+    use crate::tests::harness::{at_minute, reading, temp_file, wait_until};
+
+    /// In-memory storage that fails every call while it is down
+    struct FlakyStorage {
+        storage: InMemoryStorage<TemperatureData>,
+        down: AtomicBool,
+    }
+
+    impl FlakyStorage {
+        fn check(&self) -> Result<(), DatabaseError> {
+            if self.down.load(Ordering::Relaxed) {
+                return Err(std::io::Error::other("database down").into());
+            }
+            Ok(())
+        }
+    }
+
+    impl Storage<TemperatureData> for Arc<FlakyStorage> {
+        type Error = DatabaseError;
+
+        fn save_item(&self, data: &TemperatureData) -> Result<(), Self::Error> {
+            self.save_items(std::slice::from_ref(data))
+        }
+
+        /// Readings hotter than any sensor reports stand in for documents the database rejects,
+        /// and the rest are written as by an unordered insert
+        fn save_items(&self, data: &[TemperatureData]) -> Result<(), Self::Error> {
+            self.check()?;
+            let mut rejected = Vec::new();
+            for (index, reading) in data.iter().enumerate() {
+                if reading.temperature > 100.0 {
+                    rejected.push(index);
+                } else {
+                    self.storage.save_item(reading)?;
+                }
+            }
+            if rejected.is_empty() {
+                return Ok(());
+            }
+            Err(DatabaseError::NotWritten {
+                retry: Vec::new(),
+                rejected,
+                message: "too hot".to_string(),
+            })
+        }
+
+        fn upsert_item(
+            &self,
+            key_field: &str,
+            key: &str,
+            data: &TemperatureData,
+        ) -> Result<(), Self::Error> {
+            self.check()?;
+            self.storage.upsert_item(key_field, key, data)
+        }
+
+        fn get_latest_items(
+            &self,
+            name_field: &str,
+            timestamp_field: &str,
+        ) -> Result<Vec<TemperatureData>, Self::Error> {
+            self.check()?;
+            self.storage.get_latest_items(name_field, timestamp_field)
+        }
+
+        fn get_latest_timestamps(
+            &self,
+            id_field: &str,
+            name_field: &str,
+            timestamp_field: &str,
+        ) -> Result<LatestTimestamps, Self::Error> {
+            self.check()?;
+            self.storage
+                .get_latest_timestamps(id_field, name_field, timestamp_field)
+        }
+
+        fn get_items_between(
+            &self,
+            series: Series,
+            timestamp_field: &str,
+            from: DateTime<Utc>,
+            to: DateTime<Utc>,
+            limit: i64,
+        ) -> Result<Vec<TemperatureData>, Self::Error> {
+            self.check()?;
+            self.storage
+                .get_items_between(series, timestamp_field, from, to, limit)
+        }
+
+        fn delete_items_before(
+            &self,
+            series: Series,
+            timestamp_field: &str,
+            before: DateTime<Utc>,
+        ) -> Result<u64, Self::Error> {
+            self.check()?;
+            self.storage
+                .delete_items_before(series, timestamp_field, before)
+        }
+
+        fn delete_items_between(
+            &self,
+            series: Series,
+            timestamp_field: &str,
+            from: DateTime<Utc>,
+            to: DateTime<Utc>,
+        ) -> Result<u64, Self::Error> {
+            self.check()?;
+            self.storage
+                .delete_items_between(series, timestamp_field, from, to)
+        }
+    }
+
+    fn minutes(storage: &FlakyStorage) -> Vec<u32> {
+        storage
+            .storage
+            .items()
+            .unwrap()
+            .into_iter()
+            .map(|reading| reading.timestamp.minute())
+            .collect()
+    }
+
+    fn flaky_storage(down: bool) -> Arc<FlakyStorage> {
+        Arc::new(FlakyStorage {
+            storage: InMemoryStorage::new(&["device_name", "timestamp"]),
+            down: AtomicBool::new(down),
+        })
+    }
+
+    #[test]
+    fn readings_are_spooled_while_the_database_is_down_and_written_back_in_order() {
+        let path = temp_file("sensor_data.bson", "");
+        let storage = flaky_storage(false);
+        let spooled = SpooledStorage::open(Arc::clone(&storage), &path, 1024 * 1024).unwrap();
+        spooled
+            .save_items(&[reading("Lounge", at_minute(0), 20.0)])
+            .unwrap();
+        assert_eq!(
+            spooled
+                .get_latest_items("device_name", "timestamp")
+                .unwrap()[0]
+                .timestamp
+                .minute(),
+            0
+        );
+
+        storage.down.store(true, Ordering::Relaxed);
+        spooled
+            .save_items(&[reading("Lounge", at_minute(1), 20.0)])
+            .unwrap();
+        spooled
+            .save_items(&[
+                reading("Lounge", at_minute(2), 20.0),
+                reading("Lounge", at_minute(3), 20.0),
+            ])
+            .unwrap();
+
+        // The latest reading is still known, so readings are still deduplicated
+        let latest = spooled
+            .get_latest_items("device_name", "timestamp")
+            .unwrap();
+        assert_eq!(latest[0].timestamp.minute(), 3);
+        let latest = spooled
+            .get_latest_timestamps("device_id", "device_name", "timestamp")
+            .unwrap();
+        assert_eq!(latest.by_name["Lounge"].minute(), 3);
+
+        // The spool survives a restart
+        drop(spooled);
+        let spooled = SpooledStorage::open(Arc::clone(&storage), &path, 1024 * 1024).unwrap();
+        spooled.flush().unwrap();
+        assert_eq!(minutes(&storage), vec![0]);
+
+        // Readings saved once the database is back wait behind the spool until it is replayed
+        storage.down.store(false, Ordering::Relaxed);
+        spooled
+            .save_items(&[reading("Lounge", at_minute(4), 20.0)])
+            .unwrap();
+        assert_eq!(minutes(&storage), vec![0]);
+        spooled.replay().unwrap();
+        assert_eq!(minutes(&storage), vec![0, 1, 2, 3, 4]);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn spools_are_written_back_in_the_background_once_the_database_is_back() {
+        let path = temp_file("sensor_data.bson", "");
+        let storage = flaky_storage(true);
+        let spooled =
+            Arc::new(SpooledStorage::open(Arc::clone(&storage), &path, 1024 * 1024).unwrap());
+        spooled
+            .save_items(&[reading("Lounge", at_minute(0), 20.0)])
+            .unwrap();
+
+        let shutdown = Shutdown::new();
+        let replay: Replay = Box::new({
+            let spooled = Arc::clone(&spooled);
+            move || spooled.replay()
+        });
+        let handle = run_replay(vec![replay], &shutdown).unwrap();
+
+        storage.down.store(false, Ordering::Relaxed);
+        wait_until(|| minutes(&storage) == [0]);
+
+        shutdown.trigger();
+        handle.join().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn readings_are_dropped_once_the_spool_is_full() {
+        let path = temp_file("sensor_data.bson", "");
+        let storage = flaky_storage(true);
+        let size = bson::serialize_to_document(&reading("Lounge", at_minute(0), 20.0))
+            .unwrap()
+            .to_vec()
+            .unwrap()
+            .len();
+        let spooled = SpooledStorage::open(Arc::clone(&storage), &path, 2 * size as u64).unwrap();
+
+        for minute in 0..3 {
+            spooled
+                .save_items(&[reading("Lounge", at_minute(minute), 20.0)])
+                .unwrap();
+        }
+
+        storage.down.store(false, Ordering::Relaxed);
+        spooled.flush().unwrap();
+        assert_eq!(minutes(&storage), vec![0, 1]);
+    }
+
+    #[test]
+    fn rejected_readings_are_set_aside_rather_than_spooled() {
+        let path = temp_file("sensor_data.bson", "");
+        let rejected_path = path.with_extension("rejected.bson");
+        let storage = flaky_storage(false);
+        let spooled = SpooledStorage::open(Arc::clone(&storage), &path, 1024 * 1024).unwrap();
+
+        spooled
+            .save_items(&[
+                reading("Lounge", at_minute(0), 20.0),
+                reading("Lounge", at_minute(1), 1000.0),
+                reading("Lounge", at_minute(2), 20.0),
+            ])
+            .unwrap();
+        assert_eq!(minutes(&storage), vec![0, 2]);
+
+        // A rejected reading that was spooled while the database was down does not hold up the
+        // readings behind it
+        storage.down.store(true, Ordering::Relaxed);
+        spooled
+            .save_items(&[
+                reading("Lounge", at_minute(3), 1000.0),
+                reading("Lounge", at_minute(4), 20.0),
+            ])
+            .unwrap();
+        storage.down.store(false, Ordering::Relaxed);
+        spooled.replay().unwrap();
+        assert_eq!(minutes(&storage), vec![0, 2, 4]);
+        assert!(!path.exists());
+
+        let set_aside: Vec<u32> = read_spool(&rejected_path)
+            .unwrap()
+            .into_iter()
+            .map(|document| {
+                bson::deserialize_from_document::<TemperatureData>(document)
+                    .unwrap()
+                    .timestamp
+                    .minute()
+            })
+            .collect();
+        assert_eq!(set_aside, vec![1, 3]);
+    }
+}
@@ -1,5 +1,67 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
 use serde::{de::DeserializeOwned, Serialize};
 
+/// The latest timestamp of each device in a collection: by its ID, and by its name for the items
+/// that have no ID, which were stored before devices had IDs
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LatestTimestamps {
+    pub by_id: HashMap<String, DateTime<Utc>>,
+    pub by_name: HashMap<String, DateTime<Utc>>,
+}
+
+impl LatestTimestamps {
+    /// Records an item's timestamp under its ID, or under its name if it has no ID, if it is the
+    /// latest
+    pub fn record(&mut self, id: Option<&str>, name: &str, timestamp: DateTime<Utc>) {
+        let (timestamps, key) = match id {
+            Some(id) => (&mut self.by_id, id),
+            None => (&mut self.by_name, name),
+        };
+        let latest = timestamps.entry(key.to_string()).or_insert(timestamp);
+        *latest = (*latest).max(timestamp);
+    }
+
+    /// Records the timestamps of `other` that are later
+    pub fn merge(&mut self, other: LatestTimestamps) {
+        for (id, timestamp) in other.by_id {
+            self.record(Some(&id), "", timestamp);
+        }
+        for (name, timestamp) in other.by_name {
+            self.record(None, &name, timestamp);
+        }
+    }
+}
+
+/// The items of one series, such as one device's readings: those whose `field` is `value`,
+/// leaving out any that have the field `without`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Series<'a> {
+    pub field: &'a str,
+    pub value: &'a str,
+    pub without: Option<&'a str>,
+}
+
+impl<'a> Series<'a> {
+    pub fn new(field: &'a str, value: &'a str) -> Self {
+        Series {
+            field,
+            value,
+            without: None,
+        }
+    }
+
+    /// Leaves out the items that have `field`, such as those of a name's readings that were
+    /// stored with a device ID
+    pub fn without(self, field: &'a str) -> Self {
+        Series {
+            without: Some(field),
+            ..self
+        }
+    }
+}
+
 #[allow(dead_code)]
 pub trait Storage<T>
 where
@@ -9,4 +71,60 @@ where
     fn save_item(&self, data: &T) -> Result<(), Self::Error>;
     fn save_items(&self, data: &[T]) -> Result<(), Self::Error>;
     fn get_latest_items(&self, name_field: &str, timestamp_field: &str) -> Result<Vec<T>, Self::Error>;
+
+    /// Gets the latest timestamp of each `id_field`, and of each `name_field` among the items
+    /// without an `id_field`, for deduplicating items before they are saved
+    fn get_latest_timestamps(
+        &self,
+        id_field: &str,
+        name_field: &str,
+        timestamp_field: &str,
+    ) -> Result<LatestTimestamps, Self::Error>;
+
+    /// Inserts the item, or replaces the stored item whose `key_field` is `key`
+    fn upsert_item(&self, key_field: &str, key: &str, data: &T) -> Result<(), Self::Error>;
+
+    /// Gets up to `limit` items of `series` with timestamps from `from` to `to` inclusive, oldest first
+    fn get_items_between(
+        &self,
+        series: Series,
+        timestamp_field: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Vec<T>, Self::Error>;
+
+    /// Deletes the items of `series` with timestamps before `before`, returning how many were
+    /// deleted
+    fn delete_items_before(
+        &self,
+        series: Series,
+        timestamp_field: &str,
+        before: DateTime<Utc>,
+    ) -> Result<u64, Self::Error>;
+
+    /// Deletes the items of `series` with timestamps from `from` to `to` inclusive, returning how
+    /// many were deleted
+    fn delete_items_between(
+        &self,
+        series: Series,
+        timestamp_field: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<u64, Self::Error>;
+
+    /// Checks that the storage is reachable
+    fn ping(&self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Writes back anything the storage held back while it could not write it, if it can now
+    fn replay(&self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Writes out anything the storage is holding back, called once at shutdown
+    fn flush(&self) -> Result<(), Self::Error> {
+        Ok(())
+    }
 }
@@ -1,22 +1,80 @@
 use std::io::Write;
+use std::path::Path;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+
+mod api;
+use api::server::ApiServer;
+
+mod config;
+use config::cli::{Cli, Command};
+use config::settings::{Config, HueMode, NestMode};
+
+mod metrics;
 
 mod datastore;
+use datastore::memory::InMemoryStorage;
+use datastore::spool::{Replay, SpooledStorage, run_replay};
+use datastore::storage::Storage;
 
 mod database;
-use database::client::MongoClient;
+use database::availability::AvailabilityMonitor;
+use database::client::{MongoClient, TimeSeries};
+use database::errors::DatabaseError;
 
 mod sensor_control;
 use mongodb::{IndexModel, options::IndexOptions};
+use sensor_control::commands::ThermostatCommand;
+use sensor_control::errors::SensorError;
+use sensor_control::hue_pair;
+use sensor_control::hue_tls;
+use sensor_control::models::{
+    DeviceRecord, NestCredentials, TemperatureData, ThermostatCommandRecord, ThermostatState,
+};
 use sensor_control::nest::NestThermostat;
-use sensor_control::sensors::Sensors;
+use sensor_control::nest_auth;
+use sensor_control::registry::{DeviceRegistry, DeviceStore};
+use sensor_control::retention::{Retention, RollupStores};
+use sensor_control::scheduler::Scheduler;
+use sensor_control::secrets;
+use sensor_control::sensors::{HueStores, Sensors};
+
+mod shutdown;
+use shutdown::Shutdown;
+
+#[cfg(test)]
+mod tests;
+
+/// Exit code when the backend fails to start
+const EXIT_STARTUP_FAILED: u8 = 1;
+
+/// Exit code when a thread panicked or pending writes could not be flushed during shutdown
+const EXIT_SHUTDOWN_FAILED: u8 = 2;
 
-const DATABASE_NAME: &str = "web_database";
-const COLLECTION_NAME: &str = "sensor_data";
+/// Exit code when a one-off command fails
+const EXIT_COMMAND_FAILED: u8 = 3;
 
-fn main() {
-    // Initialize the logger
+/// How often `hue-pair` checks whether the link button has been pressed
+const HUE_PAIRING_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+fn main() -> ExitCode {
+    let started = Instant::now();
+
+    // Parse the command line and load the layered config
+    let cli = Cli::parse();
+    let config = Config::load(&cli);
+
+    // Initialize the logger, falling back to info level if the config could not be loaded
     env_logger::Builder::new()
-        .filter(Some("rust_backend"), log::LevelFilter::Info)
+        .filter(
+            Some("rust_backend"),
+            config
+                .as_ref()
+                .map_or(log::LevelFilter::Info, Config::log_level_filter),
+        )
         .format(|buf, record| {
             writeln!(
                 buf,
@@ -36,106 +94,917 @@ fn main() {
         env!("CARGO_PKG_VERSION")
     );
 
-    log::info!("Reading Hue Application Key from file");
-
-    // Read the Hue Application Key from the file
-    let hue_application_key = match std::fs::read_to_string("secrets/hue_application_key.txt") {
-        Ok(key) => key,
+    let config = match config {
+        Ok(config) => config,
         Err(error) => {
-            log::error!("Error reading Hue Application Key: {error}");
-            return;
+            log::error!("{error}");
+            return ExitCode::from(EXIT_STARTUP_FAILED);
         }
     };
 
+    match &cli.command {
+        Some(Command::NestCommand { device, command }) => {
+            return nest_command(&config, cli.dry_run, device, &command.into());
+        }
+        Some(Command::HuePair {
+            bridge,
+            timeout_secs,
+            force,
+        }) => {
+            return hue_pair(&config, bridge.as_deref(), *timeout_secs, *force);
+        }
+        Some(Command::NestAuth {
+            code,
+            listen,
+            redirect_uri,
+        }) => {
+            return nest_auth(
+                &config,
+                code.as_deref(),
+                listen.as_deref(),
+                redirect_uri.as_deref(),
+            );
+        }
+        Some(Command::MigrateTimeSeries { from, batch_size }) => {
+            return migrate_time_series(&config, from, *batch_size);
+        }
+        None => {}
+    }
+
+    if cli.dry_run {
+        log::info!("Dry run: readings are kept in memory and logged instead of written to MongoDB");
+        let data_store = InMemoryStorage::new(&["device_id", "timestamp"]).with_write_logging();
+        let thermostat_store =
+            InMemoryStorage::new(&["device_name", "timestamp"]).with_write_logging();
+        let hue_stores = HueStores {
+            motion: Arc::new(
+                InMemoryStorage::new(&["device_id", "timestamp"]).with_write_logging(),
+            ),
+            light_level: Arc::new(
+                InMemoryStorage::new(&["device_id", "timestamp"]).with_write_logging(),
+            ),
+            battery: Arc::new(
+                InMemoryStorage::new(&["device_id", "timestamp"]).with_write_logging(),
+            ),
+            renames: Arc::new(
+                InMemoryStorage::new(&["device_name", "timestamp"]).with_write_logging(),
+            ),
+        };
+        let device_store = InMemoryStorage::new(&["device_id"]).with_write_logging();
+        let rollup_stores = RollupStores {
+            minute: Arc::new(
+                InMemoryStorage::new(&["device_id", "timestamp"]).with_write_logging(),
+            ),
+            hour: Arc::new(InMemoryStorage::new(&["device_id", "timestamp"]).with_write_logging()),
+            day: Arc::new(InMemoryStorage::new(&["device_id", "timestamp"]).with_write_logging()),
+        };
+        return run(
+            &config,
+            Arc::new(data_store),
+            Arc::new(thermostat_store),
+            hue_stores,
+            Arc::new(device_store),
+            rollup_stores,
+            None,
+            started,
+        );
+    }
+
     log::info!("Creating Writer");
 
-    // Create a new MongoClient struct for Hue
-    let hue_mongo_client = match MongoClient::new(DATABASE_NAME, COLLECTION_NAME) {
+    // Create a new MongoClient struct
+    let mongo_client = match MongoClient::new(
+        &config.database.url,
+        &config.database.name,
+        &config.database.collection,
+    ) {
         Ok(client) => client,
         Err(error) => {
             log::error!("Error creating MongoClient: {error}");
-            return;
+            return ExitCode::from(EXIT_STARTUP_FAILED);
         }
     };
 
-    // Create a compound unique index on the device_name and timestamp fields
-    let index_model = IndexModel::builder()
+    let thermostat_client = match MongoClient::new(
+        &config.database.url,
+        &config.database.name,
+        &config.database.thermostat_state_collection,
+    ) {
+        Ok(client) => client,
+        Err(error) => {
+            log::error!("Error creating MongoClient: {error}");
+            return ExitCode::from(EXIT_STARTUP_FAILED);
+        }
+    };
+
+    // Time-series collections cannot have unique indexes, so their readings are only
+    // deduplicated against the latest stored timestamps
+    let mongo_client = if config.database.time_series {
+        with_time_series_indexes(mongo_client, readings_time_series(&config))
+    } else {
+        with_device_indexes(mongo_client)
+    };
+
+    let mongo_client = match prepare(mongo_client) {
+        Ok(client) => client,
+        Err(error) => {
+            log::error!("Error setting up the readings collection: {error}");
+            return ExitCode::from(EXIT_STARTUP_FAILED);
+        }
+    };
+
+    let thermostat_client = match prepare(thermostat_client.with_index(unique_index())) {
+        Ok(client) => client,
+        Err(error) => {
+            log::error!("Error setting up the thermostat state collection: {error}");
+            return ExitCode::from(EXIT_STARTUP_FAILED);
+        }
+    };
+
+    // MongoDB is watched from the start, so that it is retried if it is not up yet
+    let monitor = AvailabilityMonitor::new(mongo_client.client().clone());
+
+    let hue_stores = match hue_collections(&config) {
+        Ok(hue_stores) => hue_stores,
+        Err(error) => {
+            log::error!("Error creating the Hue collections: {error}");
+            return ExitCode::from(EXIT_STARTUP_FAILED);
+        }
+    };
+
+    let device_store = match device_collection(&config) {
+        Ok(device_store) => device_store,
+        Err(error) => {
+            log::error!("Error creating the device collection: {error}");
+            return ExitCode::from(EXIT_STARTUP_FAILED);
+        }
+    };
+
+    // Readings and thermostat state that fail to save are spooled to disk and written later
+    let mongo_client = match spooled(&config, mongo_client, &config.database.collection) {
+        Ok(client) => client,
+        Err(error) => {
+            log::error!("Error opening the spool: {error}");
+            return ExitCode::from(EXIT_STARTUP_FAILED);
+        }
+    };
+
+    let thermostat_client = match spooled(
+        &config,
+        thermostat_client,
+        &config.database.thermostat_state_collection,
+    ) {
+        Ok(client) => client,
+        Err(error) => {
+            log::error!("Error opening the spool: {error}");
+            return ExitCode::from(EXIT_STARTUP_FAILED);
+        }
+    };
+
+    let rollup_stores = match rollup_collections(&config) {
+        Ok(rollup_stores) => rollup_stores,
+        Err(error) => {
+            log::error!("Error creating the rollup collections: {error}");
+            return ExitCode::from(EXIT_STARTUP_FAILED);
+        }
+    };
+
+    log::info!("Created MongoClient");
+
+    run(
+        &config,
+        Arc::new(mongo_client),
+        Arc::new(thermostat_client),
+        hue_stores,
+        Arc::new(device_store),
+        rollup_stores,
+        Some(monitor),
+        started,
+    )
+}
+
+/// Sends a single command to a Nest thermostat, recording it in the command collection
+fn nest_command(
+    config: &Config,
+    dry_run: bool,
+    device_name: &str,
+    command: &ThermostatCommand,
+) -> ExitCode {
+    type CommandStore = dyn Storage<ThermostatCommandRecord, Error = DatabaseError> + Send + Sync;
+
+    let command_store: Arc<CommandStore> = if dry_run {
+        Arc::new(InMemoryStorage::new(&[]).with_write_logging())
+    } else {
+        match MongoClient::new(
+            &config.database.url,
+            &config.database.name,
+            &config.database.thermostat_command_collection,
+        ) {
+            Ok(client) => Arc::new(client),
+            Err(error) => {
+                log::error!("Error creating MongoClient: {error}");
+                return ExitCode::from(EXIT_STARTUP_FAILED);
+            }
+        }
+    };
+
+    let nest = match NestThermostat::new(&config.nest) {
+        Ok(nest) => nest.with_command_store(command_store),
+        Err(error) => {
+            log::error!("Error creating Nest Thermostat: {error}");
+            return ExitCode::from(EXIT_STARTUP_FAILED);
+        }
+    };
+
+    match nest.execute_command(device_name, command) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(_) => ExitCode::from(EXIT_COMMAND_FAILED),
+    }
+}
+
+/// Pairs with the Hue bridge once its link button is pressed, saving the application key and client
+/// key to the secrets directory. Key files shared by every bridge are only replaced with `force`,
+/// as they may hold another bridge's keys.
+fn hue_pair(config: &Config, bridge: Option<&str>, timeout_secs: u64, force: bool) -> ExitCode {
+    let bridge = match bridge {
+        Some(bridge) => bridge.to_string(),
+        None => match Sensors::discover_bridges(&config.hue).as_deref() {
+            Ok([bridge]) => bridge.clone(),
+            Ok(bridges) => {
+                log::error!(
+                    "Found {} Hue bridges ({}); choose one with --bridge",
+                    bridges.len(),
+                    bridges.join(", ")
+                );
+                return ExitCode::from(EXIT_COMMAND_FAILED);
+            }
+            Err(error) => {
+                log::error!("Error finding the Hue bridge: {error}");
+                return ExitCode::from(EXIT_COMMAND_FAILED);
+            }
+        },
+    };
+
+    let bridge_url = format!("https://{bridge}");
+    let bridge_id = match Sensors::get_bridge_id(&config.hue, &bridge_url) {
+        Ok(bridge_id) => bridge_id,
+        Err(error) => {
+            log::error!("Error reading the Hue bridge ID: {error}");
+            return ExitCode::from(EXIT_COMMAND_FAILED);
+        }
+    };
+
+    if !force {
+        let existing: Vec<String> = config
+            .hue
+            .shared_key_paths(&bridge, &bridge_id)
+            .into_iter()
+            .filter(|path| path.exists())
+            .map(|path| path.display().to_string())
+            .collect();
+
+        if !existing.is_empty() {
+            log::error!(
+                "{} already exist(s) and may hold another bridge's keys. Put {{bridge_id}} in hue.application_key_path and hue.client_key_path to give each bridge its own keys, or pass --force to replace them",
+                existing.join(", ")
+            );
+            return ExitCode::from(EXIT_COMMAND_FAILED);
+        }
+    }
+
+    let agent = match hue_tls::agent(&config.hue, Some(&bridge_id)) {
+        Ok(agent) => agent,
+        Err(error) => {
+            log::error!("Error setting up TLS for the Hue bridge: {error}");
+            return ExitCode::from(EXIT_COMMAND_FAILED);
+        }
+    };
+
+    println!("Press the link button on the Hue bridge {bridge_id} at {bridge}\n");
+
+    let keys = match hue_pair::pair(
+        &agent,
+        &bridge_url,
+        &hue_pair::device_type(),
+        HUE_PAIRING_POLL_INTERVAL,
+        Duration::from_secs(timeout_secs),
+    ) {
+        Ok(keys) => keys,
+        Err(error) => {
+            log::error!("Error pairing with the Hue bridge: {error}");
+            return ExitCode::from(EXIT_COMMAND_FAILED);
+        }
+    };
+
+    let mut saved = vec![(
+        config.hue.application_key_path(&bridge, &bridge_id),
+        keys.username,
+    )];
+    saved.extend(
+        keys.clientkey
+            .map(|key| (config.hue.client_key_path(&bridge_id), key)),
+    );
+
+    for (path, key) in saved {
+        if let Err(error) = secrets::write_atomically(&path, key.as_bytes()) {
+            log::error!("Error saving {}: {error}", path.display());
+            return ExitCode::from(EXIT_COMMAND_FAILED);
+        }
+        log::info!("Saved {}", path.display());
+    }
+
+    ExitCode::SUCCESS
+}
+
+/// Walks the user through granting access to their Nest devices and saves the new refresh token
+/// to the credentials file
+fn nest_auth(
+    config: &Config,
+    code: Option<&str>,
+    listen: Option<&str>,
+    redirect_uri: Option<&str>,
+) -> ExitCode {
+    let credentials_path = std::path::Path::new(&config.nest.credentials_path);
+    let credentials: NestCredentials = match std::fs::read_to_string(credentials_path)
+        .map_err(SensorError::from)
+        .and_then(|json| serde_json::from_str(&json).map_err(SensorError::from))
+    {
+        Ok(credentials) => credentials,
+        Err(error) => {
+            log::error!("Error reading Nest credentials: {error}");
+            return ExitCode::from(EXIT_STARTUP_FAILED);
+        }
+    };
+
+    // The redirect has to come back to the listener when there is one
+    let redirect_uri = match (redirect_uri, listen) {
+        (Some(redirect_uri), _) => redirect_uri.to_string(),
+        (None, Some(listen)) => {
+            let port = listen.rsplit_once(':').map_or(listen, |(_, port)| port);
+            format!("http://localhost:{port}")
+        }
+        (None, None) => nest_auth::DEFAULT_REDIRECT_URI.to_string(),
+    };
+
+    println!("Open this URL, sign in and allow access to your devices:\n");
+    println!(
+        "{}\n",
+        nest_auth::consent_url(&config.nest, &credentials, &redirect_uri)
+    );
+
+    let code = match (code, listen) {
+        (Some(code), _) => nest_auth::parse_code(code),
+        (None, Some(listen)) => match nest_auth::wait_for_code(listen) {
+            Ok(code) => Some(code),
+            Err(error) => {
+                log::error!("{error}");
+                return ExitCode::from(EXIT_COMMAND_FAILED);
+            }
+        },
+        (None, None) => {
+            print!("Paste the code, or the URL you were redirected to: ");
+            let _ = std::io::stdout().flush();
+            let mut input = String::new();
+            match std::io::stdin().read_line(&mut input) {
+                Ok(_) => nest_auth::parse_code(&input),
+                Err(error) => {
+                    log::error!("Error reading the authorization code: {error}");
+                    return ExitCode::from(EXIT_COMMAND_FAILED);
+                }
+            }
+        }
+    };
+
+    let Some(code) = code else {
+        log::error!("No authorization code was given");
+        return ExitCode::from(EXIT_COMMAND_FAILED);
+    };
+
+    let result = nest_auth::exchange_code(&config.nest, &credentials, &code, &redirect_uri)
+        .and_then(|tokens| {
+            let refresh_token = tokens.refresh_token.unwrap_or_default();
+            let expires_at = tokens
+                .refresh_token_expires_in
+                .map(|secs| chrono::Utc::now() + chrono::Duration::seconds(secs as i64));
+            nest_auth::save_refresh_token(credentials_path, &refresh_token, expires_at)?;
+            Ok(expires_at)
+        });
+
+    match result {
+        Ok(expires_at) => {
+            if let Some(expires_at) = expires_at {
+                log::warn!(
+                    "The refresh token expires at {expires_at} because the OAuth app is in Testing; publish it for tokens that do not expire"
+                );
+            }
+            ExitCode::SUCCESS
+        }
+        Err(error) => {
+            log::error!("Error authorizing Nest: {error}");
+            ExitCode::from(EXIT_COMMAND_FAILED)
+        }
+    }
+}
+
+/// Copies the readings from the plain collection `from` into the time-series collection
+fn migrate_time_series(config: &Config, from: &str, batch_size: usize) -> ExitCode {
+    if !config.database.time_series {
+        log::error!("Set database.time_series = true to migrate to a time-series collection");
+        return ExitCode::from(EXIT_COMMAND_FAILED);
+    }
+    if from == config.database.collection {
+        log::error!(
+            "Readings cannot be copied into the collection they are in; set database.collection to a new collection"
+        );
+        return ExitCode::from(EXIT_COMMAND_FAILED);
+    }
+
+    let client = MongoClient::<TemperatureData>::new(
+        &config.database.url,
+        &config.database.name,
+        &config.database.collection,
+    )
+    .map(|client| with_time_series_indexes(client, readings_time_series(config)));
+    let client = match client {
+        Ok(client) => client,
+        Err(error) => {
+            log::error!("Error opening the time-series collection: {error}");
+            return ExitCode::from(EXIT_STARTUP_FAILED);
+        }
+    };
+
+    match client.copy_from(from, batch_size.max(1)) {
+        Ok(count) => {
+            log::info!(
+                "Copied {count} reading(s) from {from} into {}",
+                config.database.collection
+            );
+            ExitCode::SUCCESS
+        }
+        Err(error) => {
+            log::error!("Error copying the readings from {from}: {error}");
+            ExitCode::from(EXIT_COMMAND_FAILED)
+        }
+    }
+}
+
+/// The layout of the readings' time-series collection, whose readings are bucketed by the
+/// device and source they came from
+fn readings_time_series(config: &Config) -> TimeSeries {
+    TimeSeries {
+        time_field: "timestamp",
+        meta_field: "meta",
+        meta_fields: &["device_name", "device_id", "bridge_id", "source"],
+        granularity: config.database.time_series_granularity,
+    }
+}
+
+/// Makes a collection of readings a time-series collection, indexed by device ID and by name under
+/// its meta field, as it cannot have the unique and partial indexes of a plain collection
+fn with_time_series_indexes<T>(client: MongoClient<T>, time_series: TimeSeries) -> MongoClient<T>
+where
+    T: serde::Serialize + Send + Sync + 'static,
+{
+    let index = |field| {
+        IndexModel::builder()
+            .keys(mongodb::bson::doc! {
+                time_series.path(field): 1,
+                time_series.time_field: -1,
+            })
+            .build()
+    };
+
+    client
+        .with_index(index("device_id"))
+        .with_index(index("device_name"))
+        .with_time_series(time_series)
+}
+
+/// A compound unique index on the device_name and timestamp fields, for the collections that are
+/// kept by name
+fn unique_index() -> IndexModel {
+    IndexModel::builder()
         .keys(mongodb::bson::doc! {
             "device_name": 1,
             "timestamp": -1,
         })
         .options(IndexOptions::builder().unique(true).build())
+        .build()
+}
+
+/// A compound unique index on the device_id and timestamp fields, for readings, which keep their
+/// device's ID when it is renamed. Readings stored before devices had IDs are left out of it.
+fn device_index() -> IndexModel {
+    IndexModel::builder()
+        .keys(mongodb::bson::doc! {
+            "device_id": 1,
+            "timestamp": -1,
+        })
+        .options(
+            IndexOptions::builder()
+                .unique(true)
+                .partial_filter_expression(mongodb::bson::doc! {
+                    "device_id": { "$exists": true },
+                })
+                .build(),
+        )
+        .build()
+}
+
+/// An index on the device_name and timestamp fields, for the readings stored before readings had
+/// device IDs and for finding readings by name. It is named so as not to clash with the unique
+/// index on the same fields that `LEGACY_INDEX` names.
+fn name_index() -> IndexModel {
+    IndexModel::builder()
+        .keys(mongodb::bson::doc! {
+            "device_name": 1,
+            "timestamp": -1,
+        })
+        .options(
+            IndexOptions::builder()
+                .name("device_name_timestamp".to_string())
+                .build(),
+        )
+        .build()
+}
+
+/// The unique index on the device_name and timestamp fields that collections of readings had
+/// before readings were kept by device ID, which rejects a reading from a device renamed to
+/// another device's old name as a duplicate
+const LEGACY_INDEX: &str = "device_name_1_timestamp_-1";
+
+/// Indexes a collection of readings by device ID and by name, replacing its legacy index
+fn with_device_indexes<T>(client: MongoClient<T>) -> MongoClient<T>
+where
+    T: serde::Serialize + Send + Sync + 'static,
+{
+    client
+        .without_index(LEGACY_INDEX)
+        .with_index(device_index())
+        .with_index(name_index())
+}
+
+/// Sets up a collection now if MongoDB is available, leaving it to be set up on first use if not
+fn prepare<T>(client: MongoClient<T>) -> Result<MongoClient<T>, DatabaseError>
+where
+    T: serde::Serialize + Send + Sync + 'static,
+{
+    match client.prepare() {
+        Err(error) if !error.is_unavailable() => Err(error),
+        _ => Ok(client),
+    }
+}
+
+/// Opens the collections for the Hue motion sensors' motion, light level and battery, and for
+/// the sensors' renames
+fn hue_collections(config: &Config) -> Result<HueStores, DatabaseError> {
+    Ok(HueStores {
+        motion: Arc::new(open_spooled_collection(
+            config,
+            &config.database.motion_collection,
+        )?),
+        light_level: Arc::new(open_spooled_collection(
+            config,
+            &config.database.light_level_collection,
+        )?),
+        battery: Arc::new(open_spooled_collection(
+            config,
+            &config.database.battery_collection,
+        )?),
+        renames: Arc::new(open_collection(
+            config,
+            &config.database.rename_collection,
+            unique_index(),
+        )?),
+    })
+}
+
+/// Opens the device registry's collection, with a unique index on the device ID
+fn device_collection(config: &Config) -> Result<MongoClient<DeviceRecord>, DatabaseError> {
+    let client = MongoClient::new(
+        &config.database.url,
+        &config.database.name,
+        &config.database.device_collection,
+    )?;
+
+    let index_model = IndexModel::builder()
+        .keys(mongodb::bson::doc! { "device_id": 1 })
+        .options(IndexOptions::builder().unique(true).build())
         .build();
 
-    match hue_mongo_client
-        .get_collection()
-        .create_index(index_model)
-        .run()
-    {
-        Ok(_) => log::info!("Index created successfully"),
-        Err(e) => {
-            log::error!("Error creating index: {e}");
-            return;
-        }
+    prepare(client.with_index(index_model))
+}
+
+/// Opens the collections for the readings summarised by minute, hour and day, which are kept by
+/// device ID like the readings
+fn rollup_collections(config: &Config) -> Result<RollupStores, DatabaseError> {
+    Ok(RollupStores {
+        minute: Arc::new(open_readings_collection(
+            config,
+            &config.database.minute_rollup_collection,
+        )?),
+        hour: Arc::new(open_readings_collection(
+            config,
+            &config.database.hourly_rollup_collection,
+        )?),
+        day: Arc::new(open_readings_collection(
+            config,
+            &config.database.daily_rollup_collection,
+        )?),
+    })
+}
+
+/// Opens a collection with the unique index `index`
+fn open_collection<T>(
+    config: &Config,
+    collection: &str,
+    index: IndexModel,
+) -> Result<MongoClient<T>, DatabaseError>
+where
+    T: serde::Serialize + Send + Sync + 'static,
+{
+    let client = MongoClient::new(&config.database.url, &config.database.name, collection)?;
+
+    prepare(client.with_index(index))
+}
+
+/// Opens a collection of readings, or of their rollups, with the readings' indexes
+fn open_readings_collection<T>(
+    config: &Config,
+    collection: &str,
+) -> Result<MongoClient<T>, DatabaseError>
+where
+    T: serde::Serialize + Send + Sync + 'static,
+{
+    let client = MongoClient::new(&config.database.url, &config.database.name, collection)?;
+
+    prepare(with_device_indexes(client))
+}
+
+/// Opens a collection of Hue readings with the same indexes as the readings, and a spool that
+/// keeps its items while MongoDB is unavailable
+fn open_spooled_collection<T>(
+    config: &Config,
+    collection: &str,
+) -> Result<SpooledStorage<MongoClient<T>, T>, DatabaseError>
+where
+    T: serde::Serialize + serde::de::DeserializeOwned + Unpin + Send + Sync + 'static,
+{
+    spooled(
+        config,
+        open_readings_collection(config, collection)?,
+        collection,
+    )
+}
+
+/// Wraps a collection with its spool in `spool.directory`
+fn spooled<T>(
+    config: &Config,
+    client: MongoClient<T>,
+    collection: &str,
+) -> Result<SpooledStorage<MongoClient<T>, T>, DatabaseError>
+where
+    T: serde::Serialize + serde::de::DeserializeOwned + Unpin + Send + Sync + 'static,
+{
+    let path = Path::new(&config.spool.directory).join(format!("{collection}.bson"));
+    SpooledStorage::open(client, &path, config.spool.max_bytes)
+}
+
+/// Starts the API and sensors, which share `data_store`, and runs until shutdown.
+///
+/// Nest thermostat state is stored separately in `thermostat_store`, and the Hue sensors' motion,
+/// light level, battery and renames in `hue_stores`. Every device readings are taken from is
+/// registered in `device_store`, and the readings are rolled up into `rollup_stores`. MongoDB is
+/// watched by `monitor`, if the stores are in MongoDB.
+#[allow(clippy::too_many_arguments)]
+fn run<T, S>(
+    config: &Config,
+    data_store: Arc<T>,
+    thermostat_store: Arc<S>,
+    hue_stores: HueStores,
+    device_store: Arc<DeviceStore>,
+    rollup_stores: RollupStores,
+    monitor: Option<AvailabilityMonitor>,
+    started: Instant,
+) -> ExitCode
+where
+    T: Storage<TemperatureData, Error = DatabaseError> + Send + Sync + 'static,
+    S: Storage<ThermostatState, Error = DatabaseError> + Send + Sync + 'static,
+{
+    // Wake every thread as soon as SIGTERM or SIGINT is received
+    let shutdown = Shutdown::new();
+    if let Err(error) = shutdown.register_signals() {
+        log::error!("Error registering signal handlers: {error}");
+        return ExitCode::from(EXIT_STARTUP_FAILED);
     }
 
-    log::info!("Created MongoClient");
+    let monitor_handle = match monitor.map(|monitor| monitor.run(&shutdown)).transpose() {
+        Ok(handle) => handle,
+        Err(error) => {
+            log::error!("Error starting the MongoDB monitor: {error}");
+            return ExitCode::from(EXIT_STARTUP_FAILED);
+        }
+    };
 
-    // Separate client for Nest (shares the underlying Mongo connection pool)
-    let nest_mongo_client = match MongoClient::new(DATABASE_NAME, COLLECTION_NAME) {
-        Ok(client) => client,
+    let replays: Vec<Replay> = vec![
+        Box::new({
+            let data_store = Arc::clone(&data_store);
+            move || data_store.replay()
+        }),
+        Box::new({
+            let thermostat_store = Arc::clone(&thermostat_store);
+            move || thermostat_store.replay()
+        }),
+        Box::new({
+            let hue_stores = hue_stores.clone();
+            move || hue_stores.replay()
+        }),
+    ];
+    let replay_handle = match run_replay(replays, &shutdown) {
+        Ok(handle) => handle,
         Err(error) => {
-            log::error!("Error creating Nest MongoClient: {error}");
-            return;
+            log::error!("Error starting the spool replay: {error}");
+            return ExitCode::from(EXIT_STARTUP_FAILED);
         }
     };
 
+    let registry = Arc::new(DeviceRegistry::new(device_store));
+
     log::info!("Creating Hue Sensors");
 
-    let sensors = match Sensors::new(&hue_application_key, hue_mongo_client) {
-        Ok(sensors) => sensors,
+    let bridges = match Sensors::new(&config.hue) {
+        Ok(bridges) => bridges,
         Err(error) => {
             log::error!("{error}");
-            return;
+            return ExitCode::from(EXIT_STARTUP_FAILED);
         }
     };
 
     log::info!("Creating Nest Thermostat");
 
-    let nest = match NestThermostat::new(nest_mongo_client) {
-        Ok(nest) => nest,
+    let nest = match NestThermostat::new(&config.nest) {
+        Ok(nest) => nest
+            .with_state_store(Arc::clone(&thermostat_store) as _)
+            .with_registry(Arc::clone(&registry)),
         Err(error) => {
             log::error!("Error creating Nest Thermostat: {error}");
-            return;
+            return ExitCode::from(EXIT_STARTUP_FAILED);
         }
     };
 
-    log::info!("Starting Hue Sensors");
+    log::info!("Starting API");
 
-    let hue_handle = match sensors.run() {
-        Ok(handle) => handle,
-        Err(error) => {
-            log::error!("Error starting Hue sensors: {error}");
-            return;
+    let api_handle = if config.api.enabled {
+        let api = ApiServer::new(Arc::clone(&data_store))
+            .with_nest_refresh_token(nest.refresh_token_status())
+            .with_renames(Arc::clone(&hue_stores.renames));
+        match api.run(&config.api.bind_address, &shutdown) {
+            Ok(handle) => Some(handle),
+            Err(error) => {
+                log::error!("Error starting API server: {error}");
+                return ExitCode::from(EXIT_STARTUP_FAILED);
+            }
         }
+    } else {
+        log::info!("API disabled");
+        None
     };
 
-    log::info!("Starting Nest Thermostat");
+    let mut scheduler = Scheduler::new(Arc::clone(&data_store), config.scheduler.jitter_fraction);
+
+    log::info!("Starting Hue Sensors ({:?} mode)", config.hue.mode);
 
-    let nest_handle = match nest.run() {
+    // Sources in events mode run their own threads, otherwise they are polled by the scheduler
+    let mut event_handles = Vec::new();
+
+    // Each bridge is a source of its own, so the bridges are polled concurrently
+    for sensors in bridges {
+        let sensors = sensors
+            .with_stores(hue_stores.clone())
+            .with_registry(Arc::clone(&registry));
+        match config.hue.mode {
+            HueMode::Poll => scheduler.add_source(Box::new(sensors)),
+            HueMode::Events => match sensors.run_events(Arc::clone(&data_store), &shutdown) {
+                Ok(handle) => event_handles.push(("Hue", handle)),
+                Err(error) => {
+                    log::error!("Error starting Hue sensors: {error}");
+                    return ExitCode::from(EXIT_STARTUP_FAILED);
+                }
+            },
+        }
+    }
+
+    log::info!("Starting Nest Thermostat ({:?} mode)", config.nest.mode);
+
+    match config.nest.mode {
+        NestMode::Poll => scheduler.add_source(Box::new(nest)),
+        NestMode::Events => match nest.run_events(Arc::clone(&data_store), &shutdown) {
+            Ok(handle) => event_handles.push(("Nest", handle)),
+            Err(error) => {
+                log::error!("Error starting Nest events: {error}");
+                return ExitCode::from(EXIT_STARTUP_FAILED);
+            }
+        },
+    }
+
+    log::info!("Starting scheduler");
+
+    let scheduler_handle = match scheduler.run(&shutdown) {
         Ok(handle) => handle,
         Err(error) => {
-            log::error!("Error starting Nest Thermostat: {error}");
-            return;
+            log::error!("Error starting scheduler: {error}");
+            return ExitCode::from(EXIT_STARTUP_FAILED);
         }
     };
 
+    let retention_handle = if config.retention.enabled {
+        log::info!("Starting retention job");
+        let retention = Retention::new(&config.retention, Arc::clone(&data_store), rollup_stores);
+        match retention.run(&shutdown) {
+            Ok(handle) => Some(handle),
+            Err(error) => {
+                log::error!("Error starting the retention job: {error}");
+                return ExitCode::from(EXIT_STARTUP_FAILED);
+            }
+        }
+    } else {
+        log::info!("Retention job disabled");
+        None
+    };
+
     log::info!("Sensors started");
+
+    shutdown.wait();
+    let shutdown_started = Instant::now();
+
     log::info!("Waiting for sensor threads to finish");
 
-    hue_handle.join().unwrap();
-    nest_handle.join().unwrap();
+    let mut clean = scheduler_handle.join();
+
+    if let Some(api_handle) = api_handle
+        && api_handle.join().is_err()
+    {
+        log::error!("API thread panicked");
+        clean = false;
+    }
+
+    if let Some(retention_handle) = retention_handle
+        && retention_handle.join().is_err()
+    {
+        log::error!("Retention thread panicked");
+        clean = false;
+    }
+
+    if let Some(monitor_handle) = monitor_handle
+        && monitor_handle.join().is_err()
+    {
+        log::error!("MongoDB monitor thread panicked");
+        clean = false;
+    }
+
+    // The event threads finish any write in progress before stopping, so they are joined before
+    // the pending writes are flushed
+    for (name, handle) in event_handles {
+        if handle.join().is_err() {
+            log::error!("{name} events thread panicked");
+            clean = false;
+        }
+    }
+
+    if replay_handle.join().is_err() {
+        log::error!("Spool replay thread panicked");
+        clean = false;
+    }
+
+    log::info!("Flushing pending writes");
+
+    if let Err(error) = data_store.flush() {
+        log::error!("Error flushing pending writes: {error}");
+        clean = false;
+    }
+
+    if let Err(error) = thermostat_store.flush() {
+        log::error!("Error flushing pending thermostat state writes: {error}");
+        clean = false;
+    }
+
+    if let Err(error) = hue_stores.flush() {
+        log::error!("Error flushing pending Hue motion, light level and battery writes: {error}");
+        clean = false;
+    }
 
     log::info!("Sensors finished");
-    log::info!("Exiting");
+    log::info!(
+        "Shut down in {:.2?} after running for {}s",
+        shutdown_started.elapsed(),
+        started.elapsed().as_secs()
+    );
+
+    if clean {
+        log::info!("Exiting");
+        ExitCode::SUCCESS
+    } else {
+        log::error!("Exiting after an unclean shutdown");
+        ExitCode::from(EXIT_SHUTDOWN_FAILED)
+    }
 }
@@ -0,0 +1,228 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, Gauge, GaugeVec, HistogramVec, IntCounterVec, TextEncoder, register_gauge,
+    register_gauge_vec, register_histogram_vec, register_int_counter_vec,
+};
+
+use crate::sensor_control::models::TemperatureData;
+
+// Prometheus metrics, registered with the default registry the first time they are used.
+
+static TEMPERATURE: Lazy<GaugeVec> = Lazy::new(|| {
+    register_gauge_vec!(
+        "sensor_temperature_celsius",
+        "Latest temperature reading",
+        &["device_name", "source"]
+    )
+    .expect("Failed to register sensor_temperature_celsius")
+});
+
+static HUMIDITY: Lazy<GaugeVec> = Lazy::new(|| {
+    register_gauge_vec!(
+        "sensor_humidity_percent",
+        "Latest relative humidity reading",
+        &["device_name", "source"]
+    )
+    .expect("Failed to register sensor_humidity_percent")
+});
+
+static ONLINE: Lazy<GaugeVec> = Lazy::new(|| {
+    register_gauge_vec!(
+        "sensor_online",
+        "Whether the sensor was online at its latest reading (1) or not (0)",
+        &["device_name", "source"]
+    )
+    .expect("Failed to register sensor_online")
+});
+
+static POLLS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "source_polls_total",
+        "Polls of each sensor source by result",
+        &["source", "result"]
+    )
+    .expect("Failed to register source_polls_total")
+});
+
+static HTTP_RESPONSES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "http_responses_total",
+        "HTTP responses from upstream services by status code, or \"error\" if there was no response",
+        &["service", "status"]
+    )
+    .expect("Failed to register http_responses_total")
+});
+
+static TOKEN_REFRESHES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "nest_token_refreshes_total",
+        "Nest access token refreshes by result",
+        &["result"]
+    )
+    .expect("Failed to register nest_token_refreshes_total")
+});
+
+static REFRESH_TOKEN_EXPIRY: Lazy<Gauge> = Lazy::new(|| {
+    register_gauge!(
+        "nest_refresh_token_expiry_timestamp_seconds",
+        "When the Nest refresh token expires, as a Unix timestamp, if it expires"
+    )
+    .expect("Failed to register nest_refresh_token_expiry_timestamp_seconds")
+});
+
+static INSERT_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "mongo_insert_duration_seconds",
+        "Time taken by MongoDB inserts",
+        &["collection"]
+    )
+    .expect("Failed to register mongo_insert_duration_seconds")
+});
+
+static INSERTED_DOCUMENTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "mongo_inserted_documents_total",
+        "Documents inserted into MongoDB",
+        &["collection"]
+    )
+    .expect("Failed to register mongo_inserted_documents_total")
+});
+
+static MONGO_AVAILABLE: Lazy<Gauge> = Lazy::new(|| {
+    register_gauge!(
+        "mongo_available",
+        "Whether MongoDB was reachable when last tried (1) or not (0)"
+    )
+    .expect("Failed to register mongo_available")
+});
+
+static SPOOLED_ITEMS: Lazy<GaugeVec> = Lazy::new(|| {
+    register_gauge_vec!(
+        "spool_items",
+        "Items spooled to disk waiting to be written to MongoDB",
+        &["collection"]
+    )
+    .expect("Failed to register spool_items")
+});
+
+static SPOOL_DROPPED_ITEMS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "spool_dropped_items_total",
+        "Items dropped because the spool was full",
+        &["collection"]
+    )
+    .expect("Failed to register spool_dropped_items_total")
+});
+
+static SPOOL_REJECTED_ITEMS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "spool_rejected_items_total",
+        "Items set aside because MongoDB rejected them",
+        &["collection"]
+    )
+    .expect("Failed to register spool_rejected_items_total")
+});
+
+/// Upstream services whose HTTP responses are counted
+pub const HUE_SERVICE: &str = "hue";
+pub const SDM_SERVICE: &str = "sdm";
+pub const GOOGLE_OAUTH_SERVICE: &str = "google_oauth";
+pub const PUBSUB_SERVICE: &str = "pubsub";
+
+/// Sets the reading gauges from the latest readings of a source
+pub fn record_readings(source: &str, readings: &[TemperatureData]) {
+    for reading in readings {
+        let labels = [reading.device_name.as_str(), source];
+        TEMPERATURE
+            .with_label_values(&labels)
+            .set(f64::from(reading.temperature));
+        HUMIDITY
+            .with_label_values(&labels)
+            .set(f64::from(reading.humidity));
+        ONLINE
+            .with_label_values(&labels)
+            .set(if reading.online { 1.0 } else { 0.0 });
+    }
+}
+
+/// Counts a poll of a source
+pub fn record_poll(source: &str, success: bool) {
+    POLLS
+        .with_label_values(&[source, if success { "success" } else { "failure" }])
+        .inc();
+}
+
+/// Counts the status code of an upstream HTTP call, passing the result through unchanged
+pub fn record_http<T>(
+    service: &str,
+    result: Result<ureq::http::Response<T>, ureq::Error>,
+) -> Result<ureq::http::Response<T>, ureq::Error> {
+    let status = match &result {
+        Ok(response) => response.status().as_u16().to_string(),
+        Err(ureq::Error::StatusCode(code)) => code.to_string(),
+        Err(_) => "error".to_string(),
+    };
+
+    HTTP_RESPONSES.with_label_values(&[service, &status]).inc();
+
+    result
+}
+
+/// Sets when the Nest refresh token expires, for alerting before it does
+pub fn record_refresh_token_expiry(expires_at: DateTime<Utc>) {
+    REFRESH_TOKEN_EXPIRY.set(expires_at.timestamp() as f64);
+}
+
+/// Counts a Nest access token refresh
+pub fn record_token_refresh(success: bool) {
+    TOKEN_REFRESHES
+        .with_label_values(&[if success { "success" } else { "failure" }])
+        .inc();
+}
+
+/// Records the duration of a MongoDB insert and the number of documents it inserted
+pub fn record_insert(collection: &str, duration: Duration, documents: usize) {
+    INSERT_DURATION
+        .with_label_values(&[collection])
+        .observe(duration.as_secs_f64());
+    INSERTED_DOCUMENTS
+        .with_label_values(&[collection])
+        .inc_by(documents as u64);
+}
+
+/// Sets whether MongoDB is available
+pub fn record_mongo_available(available: bool) {
+    MONGO_AVAILABLE.set(if available { 1.0 } else { 0.0 });
+}
+
+/// Sets the number of items in a collection's spool
+pub fn record_spooled(collection: &str, items: usize) {
+    SPOOLED_ITEMS
+        .with_label_values(&[collection])
+        .set(items as f64);
+}
+
+/// Counts the items dropped because a collection's spool was full
+pub fn record_spool_dropped(collection: &str, items: usize) {
+    SPOOL_DROPPED_ITEMS
+        .with_label_values(&[collection])
+        .inc_by(items as u64);
+}
+
+/// Counts the items of a collection set aside because MongoDB rejected them
+pub fn record_spool_rejected(collection: &str, items: usize) {
+    SPOOL_REJECTED_ITEMS
+        .with_label_values(&[collection])
+        .inc_by(items as u64);
+}
+
+/// Renders every registered metric in the Prometheus text format
+pub fn render() -> Result<(String, Vec<u8>), prometheus::Error> {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    encoder.encode(&prometheus::gather(), &mut buffer)?;
+    Ok((encoder.format_type().to_string(), buffer))
+}
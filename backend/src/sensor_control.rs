@@ -1,6 +1,14 @@
+pub mod commands;
+pub mod errors;
+pub mod hue_pair;
+pub mod hue_tls;
+pub mod models;
 pub mod nest;
+pub mod nest_auth;
+pub mod registry;
+pub mod retention;
+pub mod scheduler;
+pub mod secrets;
 pub mod sensors;
-
-mod errors;
-mod models;
-mod store;
+pub mod source;
+pub mod store;
@@ -0,0 +1,226 @@
+use std::time::Duration;
+
+use serde_json::json;
+
+use super::errors::SensorError;
+use super::models::{ECO_MODE_OFF, NestDevice};
+
+/// The setpoint range accepted by Nest thermostats
+pub const MIN_SETPOINT_CELSIUS: f32 = 9.0;
+pub const MAX_SETPOINT_CELSIUS: f32 = 32.0;
+
+/// The longest fan timer accepted by the SDM API
+pub const MAX_FAN_TIMER: Duration = Duration::from_secs(12 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ThermostatMode {
+    Heat,
+    Cool,
+    HeatCool,
+    Off,
+}
+
+impl ThermostatMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ThermostatMode::Heat => "HEAT",
+            ThermostatMode::Cool => "COOL",
+            ThermostatMode::HeatCool => "HEATCOOL",
+            ThermostatMode::Off => "OFF",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum EcoMode {
+    ManualEco,
+    Off,
+}
+
+impl EcoMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EcoMode::ManualEco => "MANUAL_ECO",
+            EcoMode::Off => ECO_MODE_OFF,
+        }
+    }
+}
+
+/// A command for a Nest thermostat, sent to the SDM API's `:executeCommand` endpoint
+#[derive(Debug, Clone, PartialEq)]
+pub enum ThermostatCommand {
+    SetMode(ThermostatMode),
+    /// Sets the heating setpoint, which requires the thermostat to be in HEAT mode
+    SetHeat {
+        heat_celsius: f32,
+    },
+    /// Sets the cooling setpoint, which requires the thermostat to be in COOL mode
+    SetCool {
+        cool_celsius: f32,
+    },
+    /// Sets both setpoints, which requires the thermostat to be in HEATCOOL mode
+    SetRange {
+        heat_celsius: f32,
+        cool_celsius: f32,
+    },
+    SetEcoMode(EcoMode),
+    /// Runs the fan for a duration, rounded down to whole seconds
+    SetFanTimer(Duration),
+    StopFanTimer,
+}
+
+impl ThermostatCommand {
+    /// The SDM command name
+    pub fn name(&self) -> &'static str {
+        match self {
+            ThermostatCommand::SetMode(_) => "sdm.devices.commands.ThermostatMode.SetMode",
+            ThermostatCommand::SetHeat { .. } => {
+                "sdm.devices.commands.ThermostatTemperatureSetpoint.SetHeat"
+            }
+            ThermostatCommand::SetCool { .. } => {
+                "sdm.devices.commands.ThermostatTemperatureSetpoint.SetCool"
+            }
+            ThermostatCommand::SetRange { .. } => {
+                "sdm.devices.commands.ThermostatTemperatureSetpoint.SetRange"
+            }
+            ThermostatCommand::SetEcoMode(_) => "sdm.devices.commands.ThermostatEco.SetMode",
+            ThermostatCommand::SetFanTimer(_) | ThermostatCommand::StopFanTimer => {
+                "sdm.devices.commands.Fan.SetTimer"
+            }
+        }
+    }
+
+    /// The SDM command parameters
+    pub fn params(&self) -> serde_json::Value {
+        match self {
+            ThermostatCommand::SetMode(mode) => json!({ "mode": mode.as_str() }),
+            ThermostatCommand::SetHeat { heat_celsius } => json!({ "heatCelsius": heat_celsius }),
+            ThermostatCommand::SetCool { cool_celsius } => json!({ "coolCelsius": cool_celsius }),
+            ThermostatCommand::SetRange {
+                heat_celsius,
+                cool_celsius,
+            } => json!({ "heatCelsius": heat_celsius, "coolCelsius": cool_celsius }),
+            ThermostatCommand::SetEcoMode(mode) => json!({ "mode": mode.as_str() }),
+            ThermostatCommand::SetFanTimer(duration) => json!({
+                "timerMode": "ON",
+                "duration": format!("{}s", duration.as_secs()),
+            }),
+            ThermostatCommand::StopFanTimer => json!({ "timerMode": "OFF" }),
+        }
+    }
+
+    /// Checks the command against the device's traits and current modes, so that commands the
+    /// thermostat would reject are not sent
+    pub fn validate(&self, device: &NestDevice) -> Result<(), SensorError> {
+        let traits = &device.traits;
+        let current_mode = traits
+            .thermostat_mode
+            .as_ref()
+            .map(|mode| mode.mode.as_str());
+
+        match self {
+            ThermostatCommand::SetMode(mode) => {
+                let thermostat_mode = traits
+                    .thermostat_mode
+                    .as_ref()
+                    .ok_or_else(|| invalid("the device does not support thermostat modes"))?;
+                check_available(mode.as_str(), &thermostat_mode.available_modes)
+            }
+            ThermostatCommand::SetHeat { heat_celsius } => {
+                check_setpoint_mode(device, current_mode, ThermostatMode::Heat)?;
+                check_setpoint(*heat_celsius)
+            }
+            ThermostatCommand::SetCool { cool_celsius } => {
+                check_setpoint_mode(device, current_mode, ThermostatMode::Cool)?;
+                check_setpoint(*cool_celsius)
+            }
+            ThermostatCommand::SetRange {
+                heat_celsius,
+                cool_celsius,
+            } => {
+                check_setpoint_mode(device, current_mode, ThermostatMode::HeatCool)?;
+                check_setpoint(*heat_celsius)?;
+                check_setpoint(*cool_celsius)?;
+                if heat_celsius >= cool_celsius {
+                    return Err(invalid("the heat setpoint must be below the cool setpoint"));
+                }
+                Ok(())
+            }
+            ThermostatCommand::SetEcoMode(mode) => {
+                let eco = traits
+                    .thermostat_eco
+                    .as_ref()
+                    .ok_or_else(|| invalid("the device does not support eco mode"))?;
+                check_available(mode.as_str(), &eco.available_modes)
+            }
+            ThermostatCommand::SetFanTimer(duration) => {
+                check_fan(device)?;
+                if duration.as_secs() == 0 || *duration > MAX_FAN_TIMER {
+                    return Err(invalid(&format!(
+                        "the fan timer must be from 1s to {}s",
+                        MAX_FAN_TIMER.as_secs()
+                    )));
+                }
+                Ok(())
+            }
+            ThermostatCommand::StopFanTimer => check_fan(device),
+        }
+    }
+}
+
+fn invalid(message: &str) -> SensorError {
+    SensorError::InvalidCommand(message.to_string())
+}
+
+fn check_available(mode: &str, available_modes: &[String]) -> Result<(), SensorError> {
+    if available_modes.iter().any(|available| available == mode) {
+        Ok(())
+    } else {
+        Err(invalid(&format!(
+            "{mode} is not one of the available modes {available_modes:?}"
+        )))
+    }
+}
+
+/// Setpoints can only be set for the current mode, and not at all while eco mode is on
+fn check_setpoint_mode(
+    device: &NestDevice,
+    current_mode: Option<&str>,
+    required_mode: ThermostatMode,
+) -> Result<(), SensorError> {
+    if device
+        .traits
+        .thermostat_eco
+        .as_ref()
+        .is_some_and(|eco| eco.mode != ECO_MODE_OFF)
+    {
+        return Err(invalid("setpoints cannot be changed while eco mode is on"));
+    }
+
+    if current_mode != Some(required_mode.as_str()) {
+        return Err(invalid(&format!(
+            "the thermostat must be in {} mode, but is in {} mode",
+            required_mode.as_str(),
+            current_mode.unwrap_or("an unknown")
+        )));
+    }
+
+    Ok(())
+}
+
+fn check_setpoint(celsius: f32) -> Result<(), SensorError> {
+    if (MIN_SETPOINT_CELSIUS..=MAX_SETPOINT_CELSIUS).contains(&celsius) {
+        Ok(())
+    } else {
+        Err(invalid(&format!(
+            "setpoints must be from {MIN_SETPOINT_CELSIUS}°C to {MAX_SETPOINT_CELSIUS}°C, not {celsius}°C"
+        )))
+    }
+}
+
+fn check_fan(device: &NestDevice) -> Result<(), SensorError> {
+    match device.traits.fan {
+        Some(_) => Ok(()),
+        None => Err(invalid("the device has no fan")),
+    }
+}
@@ -8,4 +8,16 @@ pub enum SensorError {
     FileIO(#[from] std::io::Error),
     #[error("JSON Error: {0}")]
     Json(#[from] serde_json::Error),
+    #[error("Device not found: {0}")]
+    DeviceNotFound(String),
+    #[error("Invalid command: {0}")]
+    InvalidCommand(String),
+    #[error("Invalid event: {0}")]
+    InvalidEvent(String),
+    #[error("Authorization Error: {0}")]
+    Authorization(String),
+    #[error("Pairing Error: {0}")]
+    Pairing(String),
+    #[error("Certificate Error: {0}")]
+    Certificate(String),
 }
@@ -0,0 +1,74 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ureq::Agent;
+
+use super::errors::SensorError;
+use super::models::{HUE_LINK_BUTTON_NOT_PRESSED, HuePairingResult, HuePairingSuccess};
+
+use crate::metrics;
+
+/// The bridge limits the device part of `devicetype` to 19 characters
+const MAX_DEVICE_NAME_LENGTH: usize = 19;
+
+/// The `devicetype` the bridge lists the application key under, `rust-backend#<hostname>`
+pub fn device_type() -> String {
+    let hostname = std::env::var("HOSTNAME").unwrap_or_else(|_| "server".to_string());
+    let device_name: String = hostname.chars().take(MAX_DEVICE_NAME_LENGTH).collect();
+
+    format!("rust-backend#{device_name}")
+}
+
+/// Asks the bridge at `bridge_url` for an application key every `poll_interval` until its link
+/// button is pressed, giving up after `timeout`
+pub fn pair(
+    agent: &Agent,
+    bridge_url: &str,
+    device_type: &str,
+    poll_interval: Duration,
+    timeout: Duration,
+) -> Result<HuePairingSuccess, SensorError> {
+    let started = Instant::now();
+    let body = serde_json::json!({
+        "devicetype": device_type,
+        "generateclientkey": true,
+    });
+
+    loop {
+        let mut response = metrics::record_http(
+            metrics::HUE_SERVICE,
+            agent.post(format!("{bridge_url}/api")).send_json(&body),
+        )?;
+
+        let results = response.body_mut().read_json::<Vec<HuePairingResult>>()?;
+
+        match results.into_iter().next() {
+            Some(HuePairingResult {
+                success: Some(success),
+                ..
+            }) => return Ok(success),
+            Some(HuePairingResult {
+                error: Some(error), ..
+            }) if error.error_type == HUE_LINK_BUTTON_NOT_PRESSED => {
+                log::debug!("Waiting for the link button to be pressed");
+            }
+            Some(HuePairingResult {
+                error: Some(error), ..
+            }) => return Err(SensorError::Pairing(error.description)),
+            _ => {
+                return Err(SensorError::Pairing(
+                    "The bridge returned an empty response".to_string(),
+                ));
+            }
+        }
+
+        if started.elapsed() + poll_interval > timeout {
+            return Err(SensorError::Pairing(format!(
+                "The link button was not pressed within {}s",
+                timeout.as_secs()
+            )));
+        }
+
+        thread::sleep(poll_interval);
+    }
+}
@@ -0,0 +1,341 @@
+use std::fmt;
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::verify_server_cert_signed_by_trust_anchor;
+use rustls::crypto::{CryptoProvider, verify_tls12_signature, verify_tls13_signature};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::server::ParsedCertificate;
+use rustls::{
+    ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, SignatureScheme,
+    StreamOwned,
+};
+use sha2::{Digest, Sha256};
+use ureq::Agent;
+use ureq::unversioned::resolver::DefaultResolver;
+use ureq::unversioned::transport::{
+    Buffers, ConnectionDetails, Connector, Either, LazyBuffers, NextTimeout, TcpConnector,
+    Transport, TransportAdapter,
+};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use super::errors::SensorError;
+use super::secrets;
+
+use crate::config::settings::{HueConfig, HueTlsVerification};
+
+/// The Signify root CA that issues the certificates of bridges on current firmware, each with the
+/// bridge ID as its common name
+const HUE_ROOT_CA: &[u8] = include_bytes!("../../certs/hue_root_ca.pem");
+
+/// An agent for requests to the bridge with ID `bridge_id`, verifying its certificate as
+/// `config.tls_verification` says.
+///
+/// Without a bridge ID, e.g. while asking the bridge for it, the certificate must still be issued
+/// by the root CA, but it may be for any bridge and is not checked against a pin.
+pub fn agent(config: &HueConfig, bridge_id: Option<&str>) -> Result<Agent, SensorError> {
+    let trust = match config.tls_verification {
+        HueTlsVerification::Ca => Trust::RootCa(root_certificates(config)?),
+        HueTlsVerification::Pin => Trust::Pin(config.clone()),
+    };
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let verifier = BridgeVerifier {
+        trust,
+        bridge_id: bridge_id.map(str::to_lowercase),
+        provider: Arc::clone(&provider),
+    };
+
+    let tls_config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|error| SensorError::Certificate(error.to_string()))?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+
+    let connector = ().chain(TcpConnector::default()).chain(BridgeTlsConnector {
+        tls_config: Arc::new(tls_config),
+    });
+
+    Ok(Agent::with_parts(
+        Agent::config_builder().build(),
+        connector,
+        DefaultResolver::default(),
+    ))
+}
+
+/// The bundled Hue root CA, or those in `config.root_ca_path` if it is set
+fn root_certificates(config: &HueConfig) -> Result<RootCertStore, SensorError> {
+    let pem = if config.root_ca_path.is_empty() {
+        HUE_ROOT_CA.to_vec()
+    } else {
+        fs::read(&config.root_ca_path)?
+    };
+
+    let mut roots = RootCertStore::empty();
+    for certificate in CertificateDer::pem_slice_iter(&pem) {
+        let certificate = certificate.map_err(|error| {
+            SensorError::Certificate(format!("Error reading the Hue root CA: {error}"))
+        })?;
+        roots
+            .add(certificate)
+            .map_err(|error| SensorError::Certificate(format!("Invalid Hue root CA: {error}")))?;
+    }
+
+    if roots.is_empty() {
+        return Err(SensorError::Certificate(format!(
+            "No certificates in {}",
+            config.root_ca_path
+        )));
+    }
+
+    Ok(roots)
+}
+
+/// The SHA-256 fingerprint of a certificate, formatted like `openssl x509 -fingerprint`
+fn fingerprint(certificate: &[u8]) -> String {
+    Sha256::digest(certificate)
+        .iter()
+        .map(|byte| format!("{byte:02X}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// The certificate's subject common name, in lower case like the bridge IDs
+fn common_name(certificate: &CertificateDer<'_>) -> Option<String> {
+    let (_, certificate) = X509Certificate::from_der(certificate).ok()?;
+    let common_name = certificate.subject().iter_common_name().next()?;
+
+    common_name.as_str().ok().map(str::to_lowercase)
+}
+
+/// Checks the certificate against the fingerprint pinned for the bridge, pinning it if the bridge
+/// has not been seen before
+fn check_pin(
+    path: &Path,
+    bridge_id: &str,
+    certificate: &CertificateDer<'_>,
+) -> Result<(), rustls::Error> {
+    let fingerprint = fingerprint(certificate);
+
+    match fs::read_to_string(path) {
+        Ok(pinned) if pinned.trim() == fingerprint => Ok(()),
+        Ok(pinned) => {
+            let message = format!(
+                "The certificate of Hue bridge {bridge_id} has changed from SHA-256 {} to {fingerprint}; \
+                 delete {} to trust the new certificate if the change is expected",
+                pinned.trim(),
+                path.display()
+            );
+            log::error!("{message}");
+            Err(rustls::Error::General(message))
+        }
+        Err(error) if error.kind() == ErrorKind::NotFound => {
+            log::warn!(
+                "Trusting the certificate of Hue bridge {bridge_id} with SHA-256 {fingerprint} \
+                 on first use, pinned in {}",
+                path.display()
+            );
+            secrets::write_atomically(path, format!("{fingerprint}\n").as_bytes()).map_err(
+                |error| rustls::Error::General(format!("Error saving {}: {error}", path.display())),
+            )
+        }
+        Err(error) => Err(rustls::Error::General(format!(
+            "Error reading {}: {error}",
+            path.display()
+        ))),
+    }
+}
+
+#[derive(Debug)]
+enum Trust {
+    /// Certificates must be issued by one of these roots for the bridge ID
+    RootCa(RootCertStore),
+    /// Certificates must match the fingerprint pinned at `certificate_pin_path`
+    Pin(HueConfig),
+}
+
+/// Verifies bridge certificates by bridge ID, as bridges are reached by IP address and their
+/// certificates name no host
+#[derive(Debug)]
+struct BridgeVerifier {
+    trust: Trust,
+    bridge_id: Option<String>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for BridgeVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        match &self.trust {
+            Trust::RootCa(roots) => {
+                let certificate = ParsedCertificate::try_from(end_entity)?;
+                verify_server_cert_signed_by_trust_anchor(
+                    &certificate,
+                    roots,
+                    intermediates,
+                    now,
+                    self.provider.signature_verification_algorithms.all,
+                )?;
+
+                let common_name = common_name(end_entity).unwrap_or_default();
+                if let Some(bridge_id) = &self.bridge_id
+                    && common_name != *bridge_id
+                {
+                    let message = format!(
+                        "The certificate is for Hue bridge {common_name:?}, not {bridge_id}"
+                    );
+                    log::error!("{message}");
+                    return Err(rustls::Error::General(message));
+                }
+            }
+            Trust::Pin(config) => match &self.bridge_id {
+                Some(bridge_id) => check_pin(
+                    &config.certificate_pin_path(bridge_id),
+                    bridge_id,
+                    end_entity,
+                )?,
+                // Nothing secret is sent before the bridge ID is known, and older bridges'
+                // self-signed certificates need not name the bridge
+                None => {
+                    log::debug!("Not checking the certificate pin before the bridge ID is known")
+                }
+            },
+        }
+
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            certificate,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            certificate,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// Wraps connections to `https` URLs in TLS with the bridge verifier, as ureq's own TLS
+/// configuration cannot take a custom verifier
+#[derive(Debug)]
+struct BridgeTlsConnector {
+    tls_config: Arc<ClientConfig>,
+}
+
+impl<In: Transport> Connector<In> for BridgeTlsConnector {
+    type Out = Either<In, BridgeTlsTransport>;
+
+    fn connect(
+        &self,
+        details: &ConnectionDetails,
+        chained: Option<In>,
+    ) -> Result<Option<Self::Out>, ureq::Error> {
+        let Some(transport) = chained else {
+            return Ok(None);
+        };
+
+        if !details.needs_tls() || transport.is_tls() {
+            return Ok(Some(Either::A(transport)));
+        }
+
+        let host = details
+            .uri
+            .host()
+            .unwrap_or_default()
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let server_name = ServerName::try_from(host.to_string())
+            .map_err(|_| ureq::Error::BadUri(format!("Invalid Hue bridge address: {host}")))?;
+
+        let mut connection = ClientConnection::new(Arc::clone(&self.tls_config), server_name)?;
+        let mut socket = TransportAdapter::new(transport.boxed());
+        socket.set_timeout(details.timeout);
+        connection.complete_io(&mut socket)?;
+
+        Ok(Some(Either::B(BridgeTlsTransport {
+            buffers: LazyBuffers::new(
+                details.config.input_buffer_size(),
+                details.config.output_buffer_size(),
+            ),
+            stream: StreamOwned::new(connection, socket),
+        })))
+    }
+}
+
+struct BridgeTlsTransport {
+    buffers: LazyBuffers,
+    stream: StreamOwned<ClientConnection, TransportAdapter>,
+}
+
+impl fmt::Debug for BridgeTlsTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BridgeTlsTransport").finish()
+    }
+}
+
+impl Transport for BridgeTlsTransport {
+    fn buffers(&mut self) -> &mut dyn Buffers {
+        &mut self.buffers
+    }
+
+    fn transmit_output(&mut self, amount: usize, timeout: NextTimeout) -> Result<(), ureq::Error> {
+        self.stream.get_mut().set_timeout(timeout);
+        self.stream.write_all(&self.buffers.output()[..amount])?;
+
+        Ok(())
+    }
+
+    fn await_input(&mut self, timeout: NextTimeout) -> Result<bool, ureq::Error> {
+        self.stream.get_mut().set_timeout(timeout);
+
+        let input = self.buffers.input_append_buf();
+        let amount = self.stream.read(input)?;
+        self.buffers.input_appended(amount);
+
+        Ok(amount > 0)
+    }
+
+    fn is_open(&mut self) -> bool {
+        self.stream.get_mut().get_mut().is_open()
+    }
+
+    fn is_tls(&self) -> bool {
+        true
+    }
+}
@@ -10,6 +10,37 @@ pub struct HueBridge {
     pub internalipaddress: String,
 }
 
+/// The unauthenticated `/api/0/config` response
+#[derive(Deserialize, Debug)]
+pub struct HueBridgeConfig {
+    pub bridgeid: String,
+}
+
+/// One result of a `POST /api` pairing request, which either succeeds or fails with an error
+#[derive(Deserialize, Debug)]
+pub struct HuePairingResult {
+    pub success: Option<HuePairingSuccess>,
+    pub error: Option<HueApiError>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct HuePairingSuccess {
+    /// The application key, sent in the `hue-application-key` header
+    pub username: String,
+    /// The key for the Entertainment API, only returned when `generateclientkey` is set
+    pub clientkey: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct HueApiError {
+    #[serde(rename = "type")]
+    pub error_type: u32,
+    pub description: String,
+}
+
+/// The error returned until the bridge's link button has been pressed
+pub const HUE_LINK_BUTTON_NOT_PRESSED: u32 = 101;
+
 #[derive(Deserialize, Debug)]
 pub struct Metadata {
     pub name: String,
@@ -23,10 +54,31 @@ pub struct Service {
 
 #[derive(Deserialize, Debug)]
 pub struct Device {
+    pub id: String,
     pub metadata: Metadata,
+    #[serde(default)]
+    pub product_data: Option<HueProductData>,
     pub services: Vec<Service>,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct HueProductData {
+    /// e.g. SML001 for the indoor motion sensor
+    pub model_id: String,
+}
+
+/// A room, whose children are the devices in it
+#[derive(Deserialize, Debug)]
+pub struct HueRoom {
+    pub metadata: Metadata,
+    pub children: Vec<Service>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct HueRoomList {
+    pub data: Vec<HueRoom>,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct DeviceList {
     pub data: Vec<Device>,
@@ -54,10 +106,97 @@ pub struct HueTemperatureList {
     pub data: Vec<HueTemperatureData>,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct HueEvent {
+    #[serde(rename = "type")]
+    pub event_type: String,
+    #[serde(default)]
+    pub data: Vec<HueEventData>,
+}
+
+/// A resource in an event or a resource list, with the fields of its type
+#[derive(Deserialize, Debug)]
+pub struct HueEventData {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub resource_type: String,
+    #[serde(default)]
+    pub temperature: Option<HueEventTemperature>,
+    #[serde(default)]
+    pub motion: Option<HueMotion>,
+    /// The light level of a `light_level` resource
+    #[serde(default)]
+    pub light: Option<HueLightLevel>,
+    /// The battery of a `device_power` resource
+    #[serde(default)]
+    pub power_state: Option<HuePowerState>,
+    /// The status of a `zigbee_connectivity` resource: connected, disconnected,
+    /// connectivity_issue or unidirectional_incoming
+    #[serde(default)]
+    pub status: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct HueEventTemperature {
+    #[serde(default)]
+    pub temperature_report: Option<TemperatureReport>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct HueMotion {
+    #[serde(default)]
+    pub motion_report: Option<MotionReport>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MotionReport {
+    pub changed: DateTime<Utc>,
+    pub motion: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct HueLightLevel {
+    #[serde(default)]
+    pub light_level_report: Option<LightLevelReport>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LightLevelReport {
+    pub changed: DateTime<Utc>,
+    /// 10000 * log10(lux) + 1
+    pub light_level: u32,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct HuePowerState {
+    /// normal, low or critical
+    #[serde(default)]
+    pub battery_state: Option<String>,
+    #[serde(default)]
+    pub battery_level: Option<u8>,
+}
+
+/// The `motion`, `light_level`, `device_power` and `zigbee_connectivity` resource lists
+#[derive(Deserialize, Debug)]
+pub struct HueResourceList {
+    pub data: Vec<HueEventData>,
+}
+
 #[serde_as]
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TemperatureData {
     pub device_name: String,
+    /// The stable ID of the device in the `devices` collection, missing from readings stored
+    /// before devices were registered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+    /// The ID of the Hue bridge the sensor is paired with, for Hue readings
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bridge_id: Option<String>,
+    /// Where the reading came from, `hue` or `nest`, missing from readings stored before it was
+    /// recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
     #[serde_as(as = "datetime::FromChrono04DateTime")]
     pub timestamp: DateTime<Utc>,
     pub online: bool,
@@ -65,18 +204,142 @@ pub struct TemperatureData {
     pub humidity: f32,
 }
 
+/// A motion report from a Hue motion sensor
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MotionData {
+    pub device_name: String,
+    pub device_id: String,
+    pub bridge_id: String,
+    #[serde_as(as = "datetime::FromChrono04DateTime")]
+    pub timestamp: DateTime<Utc>,
+    pub motion: bool,
+}
+
+/// A light level report from a Hue motion sensor
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LightLevelData {
+    pub device_name: String,
+    pub device_id: String,
+    pub bridge_id: String,
+    #[serde_as(as = "datetime::FromChrono04DateTime")]
+    pub timestamp: DateTime<Utc>,
+    /// The level as reported, 10000 * log10(lux) + 1
+    pub light_level: u32,
+    pub lux: f32,
+}
+
+/// The battery of a Hue sensor, recorded when it changes as the bridge does not timestamp it
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatteryData {
+    pub device_name: String,
+    pub device_id: String,
+    pub bridge_id: String,
+    #[serde_as(as = "datetime::FromChrono04DateTime")]
+    pub timestamp: DateTime<Utc>,
+    pub battery_level: u8,
+    /// normal, low or critical
+    pub battery_state: String,
+}
+
+/// A Hue sensor being renamed in the Hue app, recorded under its new name
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeviceRename {
+    pub device_name: String,
+    pub previous_name: String,
+    pub bridge_id: String,
+    /// The stable ID of the device in the `devices` collection, stored as `resource_id` by
+    /// earlier versions
+    #[serde(alias = "resource_id")]
+    pub device_id: String,
+    #[serde_as(as = "datetime::FromChrono04DateTime")]
+    pub timestamp: DateTime<Utc>,
+}
+
+/// A device in the `devices` collection, which readings refer to by `device_id`
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceRecord {
+    /// The Hue device ID or the SDM device name, which do not change when the device is renamed
+    pub device_id: String,
+    /// hue or nest
+    pub vendor: String,
+    pub model: Option<String>,
+    pub room: Option<String>,
+    /// The name its readings are stored under
+    pub display_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bridge_id: Option<String>,
+    #[serde_as(as = "datetime::FromChrono04DateTime")]
+    pub first_seen: DateTime<Utc>,
+    #[serde_as(as = "datetime::FromChrono04DateTime")]
+    pub last_seen: DateTime<Utc>,
+}
+
+/// The minimum, maximum and mean of a measurement over a rollup period
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RollupStats {
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+}
+
+/// A device's readings summarised over a minute, hour or day
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rollup {
+    pub device_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bridge_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// The start of the period, in UTC
+    #[serde_as(as = "datetime::FromChrono04DateTime")]
+    pub timestamp: DateTime<Utc>,
+    /// How many readings were taken in the period
+    pub count: u32,
+    pub temperature: RollupStats,
+    pub humidity: RollupStats,
+    /// The fraction of the readings taken while the device was online
+    pub online_fraction: f32,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct NestCredentials {
     pub client_id: String,
     pub client_secret: String,
     pub refresh_token: String,
     pub project_id: String,
+    /// When the refresh token expires, if it was issued to an OAuth app in Testing
+    #[serde(default)]
+    pub refresh_token_expires_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct NestTokenResponse {
     pub access_token: String,
     pub expires_in: u32,
+    /// A replacement refresh token, if Google rotated it
+    #[serde(default)]
+    pub refresh_token: Option<String>,
+    /// How long the refresh token has left, if it was issued to an OAuth app in Testing
+    #[serde(default)]
+    pub refresh_token_expires_in: Option<u64>,
+}
+
+/// The response to exchanging an authorization code
+#[derive(Debug, Deserialize)]
+pub struct NestAuthorizationResponse {
+    #[serde(default)]
+    pub refresh_token: Option<String>,
+    /// Only present while the OAuth app is in Testing, when refresh tokens last 7 days
+    #[serde(default)]
+    pub refresh_token_expires_in: Option<u64>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -87,7 +350,7 @@ pub struct NestDeviceList {
 
 #[derive(Debug, Deserialize)]
 pub struct NestDevice {
-    #[allow(dead_code)]
+    /// The resource name, `enterprises/{project_id}/devices/{device_id}`
     pub name: String,
     #[serde(rename = "type")]
     pub device_type: String,
@@ -127,7 +390,7 @@ pub struct NestParentRelation {
     pub display_name: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 pub struct NestTraits {
     #[serde(default, rename = "sdm.devices.traits.Info")]
     pub info: Option<NestInfoTrait>,
@@ -137,6 +400,16 @@ pub struct NestTraits {
     pub connectivity: Option<NestConnectivityTrait>,
     #[serde(default, rename = "sdm.devices.traits.Temperature")]
     pub temperature: Option<NestTemperatureTrait>,
+    #[serde(default, rename = "sdm.devices.traits.ThermostatMode")]
+    pub thermostat_mode: Option<NestThermostatModeTrait>,
+    #[serde(default, rename = "sdm.devices.traits.ThermostatEco")]
+    pub thermostat_eco: Option<NestThermostatEcoTrait>,
+    #[serde(default, rename = "sdm.devices.traits.ThermostatHvac")]
+    pub thermostat_hvac: Option<NestThermostatHvacTrait>,
+    #[serde(default, rename = "sdm.devices.traits.ThermostatTemperatureSetpoint")]
+    pub thermostat_setpoint: Option<NestThermostatSetpointTrait>,
+    #[serde(default, rename = "sdm.devices.traits.Fan")]
+    pub fan: Option<NestFanTrait>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -162,4 +435,111 @@ pub struct NestTemperatureTrait {
     pub ambient_temperature_celsius: f32,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct NestThermostatModeTrait {
+    /// HEAT, COOL, HEATCOOL or OFF
+    pub mode: String,
+    #[serde(default, rename = "availableModes")]
+    pub available_modes: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NestThermostatEcoTrait {
+    /// MANUAL_ECO or OFF
+    pub mode: String,
+    #[serde(default, rename = "availableModes")]
+    pub available_modes: Vec<String>,
+    #[serde(default, rename = "heatCelsius")]
+    pub heat_celsius: Option<f32>,
+    #[serde(default, rename = "coolCelsius")]
+    pub cool_celsius: Option<f32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NestThermostatHvacTrait {
+    /// HEATING, COOLING or OFF
+    pub status: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NestThermostatSetpointTrait {
+    #[serde(default, rename = "heatCelsius")]
+    pub heat_celsius: Option<f32>,
+    #[serde(default, rename = "coolCelsius")]
+    pub cool_celsius: Option<f32>,
+}
+
+/// Present on thermostats with a fan that can be controlled
+#[derive(Debug, Deserialize)]
+pub struct NestFanTrait {}
+
+/// What a thermostat was doing and targeting when it was polled
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ThermostatState {
+    pub device_name: String,
+    #[serde_as(as = "datetime::FromChrono04DateTime")]
+    pub timestamp: DateTime<Utc>,
+    pub mode: Option<String>,
+    pub eco_mode: Option<String>,
+    pub hvac_status: Option<String>,
+    /// The active heating setpoint, which is the eco setpoint while eco mode is on
+    pub heat_setpoint_celsius: Option<f32>,
+    /// The active cooling setpoint, which is the eco setpoint while eco mode is on
+    pub cool_setpoint_celsius: Option<f32>,
+}
+
+/// A command sent to a thermostat and its result, kept as an audit trail
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ThermostatCommandRecord {
+    pub device_name: String,
+    #[serde_as(as = "datetime::FromChrono04DateTime")]
+    pub timestamp: DateTime<Utc>,
+    /// The SDM command name, e.g. `sdm.devices.commands.ThermostatMode.SetMode`
+    pub command: String,
+    pub params: serde_json::Value,
+    pub success: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PubSubPullResponse {
+    #[serde(default, rename = "receivedMessages")]
+    pub received_messages: Vec<PubSubReceivedMessage>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PubSubReceivedMessage {
+    #[serde(rename = "ackId")]
+    pub ack_id: String,
+    pub message: PubSubMessage,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PubSubMessage {
+    /// The base64 encoded event
+    #[serde(default)]
+    pub data: String,
+}
+
+/// A Device Access event, as published to Pub/Sub
+#[derive(Debug, Deserialize)]
+pub struct NestEvent {
+    pub timestamp: DateTime<Utc>,
+    #[serde(default, rename = "resourceUpdate")]
+    pub resource_update: Option<NestResourceUpdate>,
+}
+
+/// The traits of a device that changed
+#[derive(Debug, Deserialize)]
+pub struct NestResourceUpdate {
+    /// The device's resource name, `enterprises/{project_id}/devices/{device_id}`
+    pub name: String,
+    #[serde(default)]
+    pub traits: NestTraits,
+}
+
+pub const ECO_MODE_OFF: &str = "OFF";
+
 pub const THERMOSTAT_TYPE: &str = "sdm.devices.types.THERMOSTAT";
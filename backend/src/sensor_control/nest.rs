@@ -1,78 +1,156 @@
-use std::sync::Arc;
-use std::sync::Mutex;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
 use chrono::{DateTime, Utc};
-use signal_hook::flag::register;
 
+use super::commands::ThermostatCommand;
 use super::errors::SensorError;
 use super::models::{
-    NestCredentials, NestDeviceList, NestTokenResponse, TemperatureData, THERMOSTAT_TYPE,
+    DeviceRecord, ECO_MODE_OFF, NestCredentials, NestDevice, NestDeviceList, NestEvent,
+    NestTokenResponse, NestTraits, PubSubPullResponse, PubSubReceivedMessage, THERMOSTAT_TYPE,
+    TemperatureData, ThermostatCommandRecord, ThermostatState,
 };
+use super::nest_auth;
+use super::registry::DeviceRegistry;
+use super::source::SensorSource;
 use super::store;
 
+use crate::config::settings::NestConfig;
 use crate::database::errors::DatabaseError;
 use crate::datastore::storage::Storage;
+use crate::metrics;
+use crate::shutdown::Shutdown;
 
-const NEST_CREDENTIALS_PATH: &str = "secrets/nest_credentials.json";
-const TOKEN_URL: &str = "https://oauth2.googleapis.com/token";
-const SDM_DEVICES_URL: &str = "https://smartdevicemanagement.googleapis.com/v1/enterprises";
-const POLL_INTERVAL_SECS: u64 = 300;
 const TOKEN_EXPIRY_SKEW_SECS: i64 = 60;
 
+/// How long any call to Google may take, so that a stalled call cannot hold up the thread that
+/// made it, or shutdown. Pub/Sub pulls return straight away rather than waiting for events, so
+/// that the thread notices shutdown between them.
+pub(super) const GOOGLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait for shutdown after a pull returns no events
+const EMPTY_PULL_WAIT: Duration = Duration::from_secs(1);
+
 struct CachedAccessToken {
     token: String,
     expires_at: DateTime<Utc>,
 }
 
-pub struct NestThermostat<T> {
+/// The state of the Nest refresh token, shared with the health check
+#[derive(Debug, Clone)]
+pub struct RefreshTokenStatus {
+    /// When the refresh token expires, if it was issued to an OAuth app in Testing
+    pub expires_at: Option<DateTime<Utc>>,
+    /// How long before `expires_at` the token counts as expiring
+    pub warning_period: chrono::Duration,
+    /// When an access token was last issued
+    pub last_refreshed: Option<DateTime<Utc>>,
+    /// Why the token endpoint last rejected the refresh token, cleared by the next success
+    pub error: Option<String>,
+}
+
+impl RefreshTokenStatus {
+    /// `error` if the token was rejected, `expired`, `expiring` within the warning period, or `ok`
+    pub fn state(&self, now: DateTime<Utc>) -> &'static str {
+        match self.expires_at {
+            _ if self.error.is_some() => "error",
+            Some(expires_at) if expires_at <= now => "expired",
+            Some(expires_at) if expires_at - self.warning_period <= now => "expiring",
+            _ => "ok",
+        }
+    }
+}
+
+type ThermostatStateStore = dyn Storage<ThermostatState, Error = DatabaseError> + Send + Sync;
+type ThermostatCommandStore =
+    dyn Storage<ThermostatCommandRecord, Error = DatabaseError> + Send + Sync;
+
+pub struct NestThermostat {
+    config: NestConfig,
     credentials: NestCredentials,
+    /// Where new refresh tokens are saved, if the credentials were read from a file
+    credentials_path: Option<PathBuf>,
+    /// The current refresh token, which replaces `credentials.refresh_token` if Google rotates it
+    refresh_token: Mutex<String>,
+    refresh_token_status: Arc<Mutex<RefreshTokenStatus>>,
     access_token: Mutex<Option<CachedAccessToken>>,
-    data_store: T,
+    state_store: Option<Arc<ThermostatStateStore>>,
+    command_store: Option<Arc<ThermostatCommandStore>>,
+    registry: Option<Arc<DeviceRegistry>>,
 }
 
-impl<T> NestThermostat<T>
-where
-    T: Storage<TemperatureData, Error = DatabaseError> + Send + 'static,
-{
-    pub fn new(data_store: T) -> Result<Self, SensorError> {
+impl NestThermostat {
+    pub fn new(config: &NestConfig) -> Result<Self, SensorError> {
         log::trace!("Creating NestThermostat");
 
-        let credentials_json = std::fs::read_to_string(NEST_CREDENTIALS_PATH)?;
+        let credentials_json = std::fs::read_to_string(&config.credentials_path)?;
         let credentials: NestCredentials = serde_json::from_str(&credentials_json)?;
 
-        Ok(NestThermostat {
+        let nest = NestThermostat {
+            credentials_path: Some(PathBuf::from(&config.credentials_path)),
+            ..NestThermostat::with_credentials(config, credentials)
+        };
+        nest.check_refresh_token_expiry();
+
+        Ok(nest)
+    }
+
+    /// Creates a NestThermostat with credentials that have already been loaded, which are not
+    /// saved if Google rotates the refresh token
+    pub fn with_credentials(config: &NestConfig, credentials: NestCredentials) -> Self {
+        let refresh_token_status = RefreshTokenStatus {
+            expires_at: credentials.refresh_token_expires_at,
+            warning_period: chrono::Duration::days(config.refresh_token_warning_days as i64),
+            last_refreshed: None,
+            error: None,
+        };
+
+        NestThermostat {
+            config: config.clone(),
+            credentials_path: None,
+            refresh_token: Mutex::new(credentials.refresh_token.clone()),
+            refresh_token_status: Arc::new(Mutex::new(refresh_token_status)),
             credentials,
             access_token: Mutex::new(None),
-            data_store,
-        })
+            state_store: None,
+            command_store: None,
+            registry: None,
+        }
     }
 
-    pub fn run(self) -> Result<thread::JoinHandle<()>, SensorError> {
-        let term = Arc::new(AtomicBool::new(false));
-        register(signal_hook::consts::SIGTERM, Arc::clone(&term))?;
-        register(signal_hook::consts::SIGINT, Arc::clone(&term))?;
+    /// Records the mode, eco mode, HVAC status and setpoints of each thermostat in `state_store`
+    /// every time the readings are fetched
+    pub fn with_state_store(mut self, state_store: Arc<ThermostatStateStore>) -> Self {
+        self.state_store = Some(state_store);
+        self
+    }
 
-        Ok(thread::spawn(move || {
-            while !term.load(Ordering::Relaxed) {
-                match self.poll_once() {
-                    Ok(()) => log::debug!("Nest poll complete"),
-                    Err(error) => log::error!("Error polling Nest thermostat: {error}"),
-                }
+    /// Records every command sent by `execute_command`, and whether it succeeded, in `command_store`
+    pub fn with_command_store(mut self, command_store: Arc<ThermostatCommandStore>) -> Self {
+        self.command_store = Some(command_store);
+        self
+    }
 
-                thread::sleep(Duration::from_secs(POLL_INTERVAL_SECS));
-            }
-        }))
+    /// Registers the thermostats in `registry` every time they are polled
+    pub fn with_registry(mut self, registry: Arc<DeviceRegistry>) -> Self {
+        self.registry = Some(registry);
+        self
     }
 
-    fn poll_once(&self) -> Result<(), SensorError> {
-        let readings = self.get_temperatures()?;
-        if let Err(error) = store::store_temperatures(&self.data_store, readings) {
-            log::error!("Error saving Nest temperatures: {error}");
-        }
-        Ok(())
+    /// The refresh token's expiry and whether it was last accepted, for the health check
+    pub fn refresh_token_status(&self) -> Arc<Mutex<RefreshTokenStatus>> {
+        Arc::clone(&self.refresh_token_status)
+    }
+
+    fn lock_refresh_token_status(&self) -> MutexGuard<'_, RefreshTokenStatus> {
+        self.refresh_token_status
+            .lock()
+            .expect("Nest refresh token status mutex poisoned")
     }
 
     fn invalidate_access_token(&self) {
@@ -99,41 +177,55 @@ where
         }
 
         log::info!("Refreshing Nest access token");
-        let mut response = match ureq::post(TOKEN_URL).send_form([
-            ("client_id", self.credentials.client_id.as_str()),
-            ("client_secret", self.credentials.client_secret.as_str()),
-            ("refresh_token", self.credentials.refresh_token.as_str()),
-            ("grant_type", "refresh_token"),
-        ]) {
-            Ok(response) => response,
-            Err(ureq::Error::StatusCode(status)) => {
-                // Best-effort body read isn't available on StatusCode errors from ureq;
-                // retry with status-as-error disabled so we can log Google's message.
-                let mut error_response = ureq::post(TOKEN_URL)
-                    .config()
-                    .http_status_as_error(false)
-                    .build()
-                    .send_form([
-                        ("client_id", self.credentials.client_id.as_str()),
-                        ("client_secret", self.credentials.client_secret.as_str()),
-                        ("refresh_token", self.credentials.refresh_token.as_str()),
-                        ("grant_type", "refresh_token"),
-                    ])?;
-                let body = error_response
+        let refresh_token = self
+            .refresh_token
+            .lock()
+            .expect("Nest refresh token mutex poisoned")
+            .clone();
+
+        // Keep error responses so that Google's message can be logged
+        let result = metrics::record_http(
+            metrics::GOOGLE_OAUTH_SERVICE,
+            ureq::post(&self.config.token_url)
+                .config()
+                .http_status_as_error(false)
+                .timeout_global(Some(GOOGLE_TIMEOUT))
+                .build()
+                .send_form([
+                    ("client_id", self.credentials.client_id.as_str()),
+                    ("client_secret", self.credentials.client_secret.as_str()),
+                    ("refresh_token", refresh_token.as_str()),
+                    ("grant_type", "refresh_token"),
+                ]),
+        );
+
+        let mut response = match result {
+            Ok(response) if response.status() == 200 => response,
+            Ok(mut response) => {
+                metrics::record_token_refresh(false);
+                let status = response.status().as_u16();
+                let body = response
                     .body_mut()
                     .read_to_string()
                     .unwrap_or_else(|_| "<unreadable>".to_string());
                 log::error!(
                     "Nest token refresh failed with HTTP {status}: {body}. \
-                     If this is invalid_grant, re-authorize and update secrets/nest_credentials.json \
-                     (Testing apps expire refresh tokens after ~7 days)."
+                     If this is invalid_grant, run `rust-backend nest-auth` to re-authorize \
+                     (Testing apps expire refresh tokens after 7 days)."
                 );
+                self.lock_refresh_token_status().error = Some(format!("HTTP {status}: {body}"));
                 return Err(SensorError::Ureq(ureq::Error::StatusCode(status)));
             }
-            Err(error) => return Err(SensorError::Ureq(error)),
+            Err(error) => {
+                metrics::record_token_refresh(false);
+                return Err(SensorError::Ureq(error));
+            }
         };
+        metrics::record_token_refresh(true);
 
         let token_response: NestTokenResponse = response.body_mut().read_json()?;
+        self.update_refresh_token(&token_response);
+
         let expires_at =
             Utc::now() + chrono::Duration::seconds(i64::from(token_response.expires_in));
 
@@ -149,68 +241,225 @@ where
         Ok(token_response.access_token)
     }
 
+    /// Records a successful refresh, saving the refresh token if Google rotated it or reported
+    /// an expiry that was not known before
+    fn update_refresh_token(&self, token_response: &NestTokenResponse) {
+        let now = Utc::now();
+        let expires_at = token_response
+            .refresh_token_expires_in
+            .map(|secs| now + chrono::Duration::seconds(secs as i64));
+
+        let mut refresh_token = self
+            .refresh_token
+            .lock()
+            .expect("Nest refresh token mutex poisoned");
+        let rotated = match &token_response.refresh_token {
+            Some(new_token) if *new_token != *refresh_token => {
+                log::info!("Google issued a new Nest refresh token");
+                *refresh_token = new_token.clone();
+                true
+            }
+            _ => false,
+        };
+
+        let (expires_at, expiry_learned) = {
+            let mut status = self.lock_refresh_token_status();
+            let expiry_learned = status.expires_at.is_none() && expires_at.is_some();
+            status.last_refreshed = Some(now);
+            status.error = None;
+            // A rotated token without a reported expiry does not expire
+            if rotated || expires_at.is_some() {
+                status.expires_at = expires_at;
+            }
+            (status.expires_at, expiry_learned)
+        };
+
+        if let Some(path) = &self.credentials_path
+            && (rotated || expiry_learned)
+            && let Err(error) = nest_auth::save_refresh_token(path, &refresh_token, expires_at)
+        {
+            log::error!(
+                "Error saving the Nest refresh token to {}: {error}",
+                path.display()
+            );
+        }
+        drop(refresh_token);
+
+        self.check_refresh_token_expiry();
+    }
+
+    /// Warns if the refresh token expires within the warning period, and exports its expiry
+    fn check_refresh_token_expiry(&self) {
+        let status = self.lock_refresh_token_status().clone();
+        let Some(expires_at) = status.expires_at else {
+            return;
+        };
+
+        metrics::record_refresh_token_expiry(expires_at);
+
+        match status.state(Utc::now()) {
+            "expired" => log::error!(
+                "The Nest refresh token expired at {expires_at}; run `rust-backend nest-auth` to re-authorize"
+            ),
+            "expiring" => log::warn!(
+                "The Nest refresh token expires in {} hours, at {expires_at}; run `rust-backend nest-auth` to re-authorize before then",
+                (expires_at - Utc::now()).num_hours()
+            ),
+            _ => log::debug!("The Nest refresh token expires at {expires_at}"),
+        }
+    }
+
     fn fetch_devices(&self, access_token: &str) -> Result<NestDeviceList, SensorError> {
         let url = format!(
             "{}/{}/devices",
-            SDM_DEVICES_URL, self.credentials.project_id
+            self.config.sdm_devices_url, self.credentials.project_id
         );
 
-        let mut response = ureq::get(&url)
-            .header("Authorization", &format!("Bearer {access_token}"))
-            .header("Content-Type", "application/json")
-            .call()?;
+        let mut response = metrics::record_http(
+            metrics::SDM_SERVICE,
+            ureq::get(&url)
+                .header("Authorization", &format!("Bearer {access_token}"))
+                .header("Content-Type", "application/json")
+                .config()
+                .timeout_global(Some(GOOGLE_TIMEOUT))
+                .build()
+                .call(),
+        )?;
 
         Ok(response.body_mut().read_json()?)
     }
 
-    fn get_temperatures(&self) -> Result<Vec<TemperatureData>, SensorError> {
-        log::debug!("Getting Nest thermostat readings");
-
+    /// Calls `request` with the cached access token, refreshing the token and retrying once if the
+    /// SDM API rejects it
+    fn with_access_token<R>(
+        &self,
+        request: impl Fn(&str) -> Result<R, SensorError>,
+    ) -> Result<R, SensorError> {
         let access_token = self.get_access_token()?;
-        let body = match self.fetch_devices(&access_token) {
-            Ok(body) => body,
+        match request(&access_token) {
             Err(SensorError::Ureq(ureq::Error::StatusCode(401))) => {
                 log::warn!("Nest API returned 401; forcing token refresh");
                 self.invalidate_access_token();
                 let access_token = self.get_access_token()?;
-                self.fetch_devices(&access_token)?
+                request(&access_token)
             }
-            Err(error) => return Err(error),
-        };
+            result => result,
+        }
+    }
+
+    /// Sends a command to the thermostat named `device_name`, after checking it against the
+    /// thermostat's current state.
+    ///
+    /// Every command is recorded in the command store along with its result, including commands
+    /// that fail validation and are never sent.
+    pub fn execute_command(
+        &self,
+        device_name: &str,
+        command: &ThermostatCommand,
+    ) -> Result<(), SensorError> {
+        log::info!(
+            "Sending {} {} to Nest {device_name}",
+            command.name(),
+            command.params()
+        );
+
+        let result = self.send_command(device_name, command);
+
+        match &result {
+            Ok(()) => log::info!("Nest {device_name} accepted {}", command.name()),
+            Err(error) => log::error!(
+                "Nest {device_name} command {} failed: {error}",
+                command.name()
+            ),
+        }
+
+        if let Some(command_store) = &self.command_store {
+            let record = ThermostatCommandRecord {
+                device_name: device_name.to_string(),
+                timestamp: Utc::now(),
+                command: command.name().to_string(),
+                params: command.params(),
+                success: result.is_ok(),
+                error: result.as_ref().err().map(ToString::to_string),
+            };
+
+            if let Err(error) = command_store.save_item(&record) {
+                log::error!("Error saving Nest command record: {error}");
+            }
+        }
+
+        result
+    }
+
+    fn send_command(
+        &self,
+        device_name: &str,
+        command: &ThermostatCommand,
+    ) -> Result<(), SensorError> {
+        let body = self.with_access_token(|access_token| self.fetch_devices(access_token))?;
+
+        let device = body
+            .devices
+            .iter()
+            .filter(|device| device.device_type == THERMOSTAT_TYPE)
+            .find(|device| device.display_name() == device_name)
+            .ok_or_else(|| SensorError::DeviceNotFound(device_name.to_string()))?;
+
+        command.validate(device)?;
+
+        // The device ID is the last segment of its resource name
+        let device_id = device.name.rsplit('/').next().unwrap_or_default();
+        let url = format!(
+            "{}/{}/devices/{device_id}:executeCommand",
+            self.config.sdm_devices_url, self.credentials.project_id
+        );
+        let request = serde_json::json!({
+            "command": command.name(),
+            "params": command.params(),
+        });
+
+        self.with_access_token(|access_token| {
+            metrics::record_http(
+                metrics::SDM_SERVICE,
+                ureq::post(&url)
+                    .header("Authorization", &format!("Bearer {access_token}"))
+                    .config()
+                    .timeout_global(Some(GOOGLE_TIMEOUT))
+                    .build()
+                    .send_json(&request),
+            )?;
+            Ok(())
+        })
+    }
+
+    /// Fetches the thermostats, recording their state, and returns the time of the poll and the
+    /// latest values of each thermostat
+    fn poll(&self) -> Result<(DateTime<Utc>, Vec<ThermostatReading>), SensorError> {
+        let body = self.with_access_token(|access_token| self.fetch_devices(access_token))?;
 
         let now = Utc::now();
 
-        let temperatures: Vec<TemperatureData> = body
+        self.record_states(&body, now);
+        self.register(&body, now);
+
+        let thermostats = body
             .devices
             .iter()
             .filter(|device| device.device_type == THERMOSTAT_TYPE)
-            .filter_map(|device| {
-                let temperature = device
-                    .traits
-                    .temperature
-                    .as_ref()
-                    .map(|t| t.ambient_temperature_celsius)?;
-                let humidity = device
-                    .traits
-                    .humidity
-                    .as_ref()
-                    .map(|h| h.ambient_humidity_percent)
-                    .unwrap_or(0.0);
-                let online = device
-                    .traits
-                    .connectivity
-                    .as_ref()
-                    .map(|c| c.status.eq_ignore_ascii_case("ONLINE"))
-                    .unwrap_or(false);
-
-                Some(TemperatureData {
-                    device_name: device.display_name(),
-                    timestamp: now,
-                    online,
-                    temperature,
-                    humidity,
-                })
-            })
+            .map(|device| ThermostatReading::new(device, now))
+            .collect();
+
+        Ok((now, thermostats))
+    }
+
+    fn get_temperatures(&self) -> Result<Vec<TemperatureData>, SensorError> {
+        log::debug!("Getting Nest thermostat readings");
+
+        let (now, thermostats) = self.poll()?;
+
+        let temperatures: Vec<TemperatureData> = thermostats
+            .iter()
+            .filter_map(|thermostat| thermostat.reading(now))
             .collect();
 
         for reading in &temperatures {
@@ -225,4 +474,481 @@ where
 
         Ok(temperatures)
     }
+
+    /// Pulls thermostat events from the Device Access Pub/Sub subscription and stores a reading
+    /// for every temperature, humidity or connectivity update, timestamped with the event.
+    ///
+    /// Events only carry the traits that changed, so the thermostats are also polled every
+    /// `poll_interval_secs` to fill in the other values and record the thermostat state. While
+    /// the subscription cannot be pulled from, the thermostats are only polled, and the
+    /// subscription is retried with each poll.
+    ///
+    /// Pulls return straight away, and the thread waits for shutdown between empty pulls.
+    /// Messages are only acknowledged once their readings are stored, so an event that could not
+    /// be applied is redelivered.
+    pub fn run_events<T>(
+        self,
+        data_store: Arc<T>,
+        shutdown: &Shutdown,
+    ) -> Result<thread::JoinHandle<()>, SensorError>
+    where
+        T: Storage<TemperatureData, Error = DatabaseError> + Send + Sync + 'static,
+    {
+        let shutdown = shutdown.clone();
+
+        Ok(thread::Builder::new()
+            .name("Nest events".to_string())
+            .spawn(move || {
+                // The latest values of each thermostat by resource name
+                let mut thermostats: HashMap<String, ThermostatReading> = HashMap::new();
+                let mut next_poll = Instant::now();
+                let mut subscription_available = true;
+
+                while !shutdown.is_triggered() {
+                    if Instant::now() >= next_poll {
+                        self.poll_events_fallback(data_store.as_ref(), &mut thermostats);
+                        next_poll = Instant::now() + self.interval();
+                    }
+
+                    match self.pull_events() {
+                        Ok(messages) => {
+                            if !subscription_available {
+                                log::info!("Nest Pub/Sub subscription is available again");
+                                subscription_available = true;
+                            }
+
+                            if messages.is_empty() {
+                                if shutdown.wait_timeout(EMPTY_PULL_WAIT) {
+                                    break;
+                                }
+                                continue;
+                            }
+
+                            self.store_events(data_store.as_ref(), &mut thermostats, &messages);
+                        }
+                        Err(error) => {
+                            if subscription_available {
+                                log::warn!(
+                                    "Nest Pub/Sub subscription unavailable ({error}), polling every {:?} instead",
+                                    self.interval()
+                                );
+                                subscription_available = false;
+                            } else {
+                                log::debug!("Nest Pub/Sub subscription still unavailable: {error}");
+                            }
+
+                            let until_poll = next_poll.saturating_duration_since(Instant::now());
+                            if shutdown.wait_timeout(until_poll) {
+                                break;
+                            }
+                        }
+                    }
+                }
+
+                log::debug!("Nest events stopped");
+            })?)
+    }
+
+    /// Polls the thermostats, stores the readings and refreshes the latest values used to fill in
+    /// events, returning whether the thermostats could be polled
+    fn poll_events_fallback<T>(
+        &self,
+        data_store: &T,
+        thermostats: &mut HashMap<String, ThermostatReading>,
+    ) -> bool
+    where
+        T: Storage<TemperatureData, Error = DatabaseError>,
+    {
+        let mut polled_ok = false;
+        let result = self
+            .poll()
+            .map_err(|error| format!("Error getting readings: {error}"))
+            .and_then(|(now, polled)| {
+                polled_ok = true;
+                let readings: Vec<TemperatureData> = polled
+                    .iter()
+                    .filter_map(|thermostat| thermostat.reading(now))
+                    .collect();
+                for mut thermostat in polled {
+                    if let Some(previous) = thermostats.get(&thermostat.name) {
+                        thermostat.events = previous.events;
+                    }
+                    thermostats.insert(thermostat.name.clone(), thermostat);
+                }
+
+                metrics::record_readings(self.name(), &readings);
+                store::store_temperatures(data_store, readings)
+                    .map_err(|error| format!("Error saving readings: {error}"))
+            });
+
+        metrics::record_poll(self.name(), result.is_ok());
+
+        if let Err(error) = result {
+            log::error!("Nest: {error}");
+        }
+
+        polled_ok
+    }
+
+    fn pull_events(&self) -> Result<Vec<PubSubReceivedMessage>, SensorError> {
+        let url = format!(
+            "{}/{}:pull",
+            self.config.pubsub_url, self.config.pubsub_subscription
+        );
+        let request = serde_json::json!({
+            "maxMessages": self.config.pubsub_max_messages,
+            "returnImmediately": true,
+        });
+
+        let response: PubSubPullResponse = self.with_access_token(|access_token| {
+            let mut response = metrics::record_http(
+                metrics::PUBSUB_SERVICE,
+                ureq::post(&url)
+                    .header("Authorization", &format!("Bearer {access_token}"))
+                    .config()
+                    .timeout_global(Some(GOOGLE_TIMEOUT))
+                    .build()
+                    .send_json(&request),
+            )?;
+            Ok(response.body_mut().read_json()?)
+        })?;
+
+        log::debug!("Pulled {} Nest event(s)", response.received_messages.len());
+
+        Ok(response.received_messages)
+    }
+
+    fn acknowledge_events(&self, ack_ids: &[&str]) -> Result<(), SensorError> {
+        let url = format!(
+            "{}/{}:acknowledge",
+            self.config.pubsub_url, self.config.pubsub_subscription
+        );
+        let request = serde_json::json!({ "ackIds": ack_ids });
+
+        self.with_access_token(|access_token| {
+            metrics::record_http(
+                metrics::PUBSUB_SERVICE,
+                ureq::post(&url)
+                    .header("Authorization", &format!("Bearer {access_token}"))
+                    .config()
+                    .timeout_global(Some(GOOGLE_TIMEOUT))
+                    .build()
+                    .send_json(&request),
+            )?;
+            Ok(())
+        })
+    }
+
+    /// Stores the readings from a batch of events, acknowledging them once they are stored so
+    /// that events are redelivered if storing fails.
+    ///
+    /// An event for a device that is not a known thermostat polls the thermostats again, as the
+    /// thermostat may have been added since the last poll. Its message is only acknowledged once
+    /// a poll has shown that the device is not a thermostat.
+    fn store_events<T>(
+        &self,
+        data_store: &T,
+        thermostats: &mut HashMap<String, ThermostatReading>,
+        messages: &[PubSubReceivedMessage],
+    ) where
+        T: Storage<TemperatureData, Error = DatabaseError>,
+    {
+        let events: Vec<(&str, Option<NestEvent>)> = messages
+            .iter()
+            .map(|message| match decode_event(&message.message.data) {
+                Ok(event) => (message.ack_id.as_str(), Some(event)),
+                Err(error) => {
+                    log::warn!("Error decoding Nest event: {error}");
+                    (message.ack_id.as_str(), None)
+                }
+            })
+            .collect();
+
+        let unknown_device = events.iter().any(|(_, event)| {
+            event
+                .as_ref()
+                .and_then(|event| event.resource_update.as_ref())
+                .is_some_and(|update| !thermostats.contains_key(&update.name))
+        });
+        let polled = unknown_device && self.poll_events_fallback(data_store, thermostats);
+
+        // Restored if the readings cannot be stored, so that the redelivered events apply again
+        let previous = thermostats.clone();
+        let mut readings = Vec::new();
+        let mut ack_ids = Vec::new();
+
+        for (ack_id, event) in &events {
+            let Some((event, update)) = event
+                .as_ref()
+                .and_then(|event| Some((event, event.resource_update.as_ref()?)))
+            else {
+                ack_ids.push(*ack_id);
+                continue;
+            };
+
+            let Some(thermostat) = thermostats.get_mut(&update.name) else {
+                if unknown_device && !polled {
+                    log::debug!("Nest event for an unknown device, leaving it to be redelivered");
+                    continue;
+                }
+                log::debug!(
+                    "Nest event for a device that is not a thermostat: {}",
+                    update.name
+                );
+                ack_ids.push(*ack_id);
+                continue;
+            };
+
+            readings.extend(thermostat.apply_event(&update.traits, event.timestamp));
+            ack_ids.push(*ack_id);
+        }
+
+        log::trace!("Nest event readings: {readings:?}");
+
+        if !readings.is_empty() {
+            metrics::record_readings(self.name(), &readings);
+
+            // Events are stored as they arrive, even when a poll since has stored a later reading.
+            // Readings the unique index rejects are events that were redelivered.
+            match data_store.save_items(&readings) {
+                Ok(()) | Err(DatabaseError::DuplicateKey(_)) => {}
+                Err(error) => {
+                    log::error!("Error saving Nest event readings: {error}");
+                    *thermostats = previous;
+                    return;
+                }
+            }
+        }
+
+        if ack_ids.is_empty() {
+            return;
+        }
+
+        if let Err(error) = self.acknowledge_events(&ack_ids) {
+            log::warn!("Error acknowledging Nest events: {error}");
+        }
+    }
+
+    /// Registers the thermostats under their resource names
+    fn register(&self, body: &NestDeviceList, now: DateTime<Utc>) {
+        let Some(registry) = &self.registry else {
+            return;
+        };
+
+        let devices: Vec<DeviceRecord> = body
+            .devices
+            .iter()
+            .filter(|device| device.device_type == THERMOSTAT_TYPE)
+            .map(|device| DeviceRecord {
+                device_id: device.name.clone(),
+                vendor: "nest".to_string(),
+                model: Some(device.device_type.clone()),
+                room: device
+                    .parent_relations
+                    .iter()
+                    .map(|relation| relation.display_name.trim())
+                    .find(|name| !name.is_empty())
+                    .map(str::to_string),
+                display_name: device.display_name(),
+                bridge_id: None,
+                first_seen: now,
+                last_seen: now,
+            })
+            .collect();
+
+        registry.seen(devices, now);
+    }
+
+    /// Stores the state of each thermostat, logging rather than returning errors so that a
+    /// failure does not lose the temperature readings
+    fn record_states(&self, body: &NestDeviceList, timestamp: DateTime<Utc>) {
+        let Some(state_store) = &self.state_store else {
+            return;
+        };
+
+        let states: Vec<ThermostatState> = body
+            .devices
+            .iter()
+            .filter(|device| device.device_type == THERMOSTAT_TYPE)
+            .map(|device| thermostat_state(device, timestamp))
+            .collect();
+
+        for state in &states {
+            log::debug!(
+                "Nest {}: mode={:?}, eco={:?}, hvac={:?}, heat={:?}°C, cool={:?}°C",
+                state.device_name,
+                state.mode,
+                state.eco_mode,
+                state.hvac_status,
+                state.heat_setpoint_celsius,
+                state.cool_setpoint_celsius
+            );
+        }
+
+        if let Err(error) = state_store.save_items(&states) {
+            log::error!("Error saving Nest thermostat state: {error}");
+        }
+    }
+}
+
+/// The latest values of a thermostat, which events update a trait at a time
+#[derive(Clone, Debug)]
+struct ThermostatReading {
+    /// The device's resource name, which events refer to it by
+    name: String,
+    device_name: String,
+    temperature: Option<f32>,
+    humidity: f32,
+    online: bool,
+    /// When the values were polled
+    polled_at: DateTime<Utc>,
+    /// The latest event stored for each trait, kept across polls
+    events: TraitEvents,
+}
+
+/// The timestamp of the latest event stored for each trait of a thermostat
+#[derive(Clone, Copy, Debug, Default)]
+struct TraitEvents {
+    temperature: Option<DateTime<Utc>>,
+    humidity: Option<DateTime<Utc>>,
+    connectivity: Option<DateTime<Utc>>,
+}
+
+impl ThermostatReading {
+    fn new(device: &NestDevice, polled_at: DateTime<Utc>) -> Self {
+        let mut reading = ThermostatReading {
+            name: device.name.clone(),
+            device_name: device.display_name(),
+            temperature: None,
+            humidity: 0.0,
+            online: false,
+            polled_at,
+            events: TraitEvents::default(),
+        };
+        reading.update(&device.traits);
+        reading
+    }
+
+    /// Applies the traits present in a device or event
+    fn update(&mut self, traits: &NestTraits) {
+        if let Some(temperature) = &traits.temperature {
+            self.temperature = Some(temperature.ambient_temperature_celsius);
+        }
+        if let Some(humidity) = &traits.humidity {
+            self.humidity = humidity.ambient_humidity_percent;
+        }
+        if let Some(connectivity) = &traits.connectivity {
+            self.online = connectivity.status.eq_ignore_ascii_case("ONLINE");
+        }
+    }
+
+    /// Applies an event at `timestamp`, returning its reading unless every trait in it has
+    /// already been stored from an event at least as recent, as when an event is redelivered.
+    ///
+    /// Events can arrive after a poll that is more recent than they are. Their reading is still
+    /// stored, but the values are only updated by traits newer than both the poll and the latest
+    /// event for that trait.
+    fn apply_event(
+        &mut self,
+        traits: &NestTraits,
+        timestamp: DateTime<Utc>,
+    ) -> Option<TemperatureData> {
+        let mut event = self.clone();
+        event.update(traits);
+        let reading = event.reading(timestamp)?;
+
+        // Records the event against a trait it carries, returning whether it is more recent than
+        // the trait's current value
+        let polled_at = self.polled_at;
+        let mut is_new = false;
+        let mut apply = |present: bool, latest: &mut Option<DateTime<Utc>>| {
+            if !present || latest.is_some_and(|latest| latest >= timestamp) {
+                return false;
+            }
+            *latest = Some(timestamp);
+            is_new = true;
+            timestamp > polled_at
+        };
+
+        if apply(traits.temperature.is_some(), &mut self.events.temperature) {
+            self.temperature = event.temperature;
+        }
+        if apply(traits.humidity.is_some(), &mut self.events.humidity) {
+            self.humidity = event.humidity;
+        }
+        if apply(traits.connectivity.is_some(), &mut self.events.connectivity) {
+            self.online = event.online;
+        }
+
+        is_new.then_some(reading)
+    }
+
+    /// The reading at `timestamp`, if the temperature is known
+    fn reading(&self, timestamp: DateTime<Utc>) -> Option<TemperatureData> {
+        Some(TemperatureData {
+            device_name: self.device_name.clone(),
+            device_id: Some(self.name.clone()),
+            bridge_id: None,
+            source: Some("nest".to_string()),
+            timestamp,
+            online: self.online,
+            temperature: self.temperature?,
+            humidity: self.humidity,
+        })
+    }
+}
+
+/// Decodes the base64 data of a Pub/Sub message into a Device Access event
+fn decode_event(data: &str) -> Result<NestEvent, SensorError> {
+    let json = BASE64_STANDARD
+        .decode(data)
+        .map_err(|error| SensorError::InvalidEvent(error.to_string()))?;
+    Ok(serde_json::from_slice(&json)?)
+}
+
+/// Maps the thermostat traits of a device onto its state, taking the setpoints from the eco
+/// trait while eco mode is on as the regular setpoints are not reported then
+fn thermostat_state(device: &NestDevice, timestamp: DateTime<Utc>) -> ThermostatState {
+    let traits = &device.traits;
+
+    let eco = traits
+        .thermostat_eco
+        .as_ref()
+        .filter(|eco| eco.mode != ECO_MODE_OFF);
+
+    let (heat_setpoint_celsius, cool_setpoint_celsius) = match (eco, &traits.thermostat_setpoint) {
+        (Some(eco), _) => (eco.heat_celsius, eco.cool_celsius),
+        (None, Some(setpoint)) => (setpoint.heat_celsius, setpoint.cool_celsius),
+        (None, None) => (None, None),
+    };
+
+    ThermostatState {
+        device_name: device.display_name(),
+        timestamp,
+        mode: traits
+            .thermostat_mode
+            .as_ref()
+            .map(|mode| mode.mode.clone()),
+        eco_mode: traits.thermostat_eco.as_ref().map(|eco| eco.mode.clone()),
+        hvac_status: traits
+            .thermostat_hvac
+            .as_ref()
+            .map(|hvac| hvac.status.clone()),
+        heat_setpoint_celsius,
+        cool_setpoint_celsius,
+    }
+}
+
+impl SensorSource for NestThermostat {
+    fn name(&self) -> &str {
+        "Nest"
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(self.config.poll_interval_secs)
+    }
+
+    fn fetch_readings(&self) -> Result<Vec<TemperatureData>, SensorError> {
+        self.get_temperatures()
+    }
 }
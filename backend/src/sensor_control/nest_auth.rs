@@ -0,0 +1,177 @@
+use std::fs;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use tiny_http::{Response, Server};
+
+use super::errors::SensorError;
+use super::models::{NestAuthorizationResponse, NestCredentials};
+use super::nest::GOOGLE_TIMEOUT;
+use super::secrets;
+
+use crate::config::settings::NestConfig;
+use crate::metrics;
+
+/// The redirect URI from the setup guide, which shows the code in the browser's address bar
+pub const DEFAULT_REDIRECT_URI: &str = "https://www.google.com";
+
+/// Reading the thermostats needs the SDM scope, and the events mode needs the Pub/Sub scope
+const SCOPES: &str =
+    "https://www.googleapis.com/auth/sdm.service https://www.googleapis.com/auth/pubsub";
+
+/// The Partner Connections Manager URL at which the account owner grants access to their devices
+pub fn consent_url(
+    config: &NestConfig,
+    credentials: &NestCredentials,
+    redirect_uri: &str,
+) -> String {
+    let query = form_urlencoded::Serializer::new(String::new())
+        .append_pair("redirect_uri", redirect_uri)
+        .append_pair("access_type", "offline")
+        .append_pair("prompt", "consent")
+        .append_pair("client_id", &credentials.client_id)
+        .append_pair("response_type", "code")
+        .append_pair("scope", SCOPES)
+        .finish();
+
+    format!(
+        "{}/{}/auth?{query}",
+        config.partner_connections_url.trim_end_matches('/'),
+        credentials.project_id
+    )
+}
+
+/// Finds the authorization code in what the user pasted, which may be the code itself or the
+/// whole URL they were redirected to
+pub fn parse_code(input: &str) -> Option<String> {
+    let input = input.trim();
+
+    if !input.contains("code=") {
+        return (!input.is_empty()).then(|| input.to_string());
+    }
+
+    let query = input.split_once('?').map_or(input, |(_, query)| query);
+    form_urlencoded::parse(query.as_bytes())
+        .find(|(key, _)| key == "code")
+        .map(|(_, code)| code.into_owned())
+}
+
+/// Listens on `bind_address` for the consent redirect and returns its authorization code
+pub fn wait_for_code(bind_address: &str) -> Result<String, SensorError> {
+    let server = Server::http(bind_address)
+        .map_err(|error| SensorError::Authorization(error.to_string()))?;
+
+    log::info!(
+        "Waiting for the consent redirect on {}",
+        server.server_addr()
+    );
+
+    for request in server.incoming_requests() {
+        let code = request.url().split_once('?').and_then(|(_, query)| {
+            form_urlencoded::parse(query.as_bytes())
+                .find(|(key, _)| key == "code")
+                .map(|(_, code)| code.into_owned())
+        });
+
+        let (status, message) = match &code {
+            Some(_) => (
+                200,
+                "Nest authorization complete, you can close this window.",
+            ),
+            None => (400, "No authorization code in the request."),
+        };
+
+        if let Err(error) = request.respond(Response::from_string(message).with_status_code(status))
+        {
+            log::warn!("Error responding to the consent redirect: {error}");
+        }
+
+        if let Some(code) = code {
+            return Ok(code);
+        }
+    }
+
+    Err(SensorError::Authorization(
+        "Redirect listener stopped before receiving a code".to_string(),
+    ))
+}
+
+/// Exchanges an authorization code for tokens at the token endpoint
+pub fn exchange_code(
+    config: &NestConfig,
+    credentials: &NestCredentials,
+    code: &str,
+    redirect_uri: &str,
+) -> Result<NestAuthorizationResponse, SensorError> {
+    log::info!("Exchanging the authorization code for tokens");
+
+    let mut response = metrics::record_http(
+        metrics::GOOGLE_OAUTH_SERVICE,
+        ureq::post(&config.token_url)
+            .config()
+            .http_status_as_error(false)
+            .timeout_global(Some(GOOGLE_TIMEOUT))
+            .build()
+            .send_form([
+                ("client_id", credentials.client_id.as_str()),
+                ("client_secret", credentials.client_secret.as_str()),
+                ("code", code),
+                ("grant_type", "authorization_code"),
+                ("redirect_uri", redirect_uri),
+            ]),
+    )?;
+
+    if response.status() != 200 {
+        let body = response
+            .body_mut()
+            .read_to_string()
+            .unwrap_or_else(|_| "<unreadable>".to_string());
+        return Err(SensorError::Authorization(format!(
+            "Token endpoint returned HTTP {}: {body}",
+            response.status()
+        )));
+    }
+
+    let tokens: NestAuthorizationResponse = response.body_mut().read_json()?;
+
+    if tokens.refresh_token.is_none() {
+        return Err(SensorError::Authorization(
+            "The token response has no refresh token; remove the app's access and consent again"
+                .to_string(),
+        ));
+    }
+
+    Ok(tokens)
+}
+
+/// Replaces the refresh token and its expiry in the credentials file, keeping its other fields
+pub fn save_refresh_token(
+    path: &Path,
+    refresh_token: &str,
+    expires_at: Option<DateTime<Utc>>,
+) -> Result<(), SensorError> {
+    let mut credentials: serde_json::Value = serde_json::from_str(&fs::read_to_string(path)?)?;
+
+    let object = credentials.as_object_mut().ok_or_else(|| {
+        SensorError::Authorization(format!("{} is not a JSON object", path.display()))
+    })?;
+    object.insert(
+        "refresh_token".to_string(),
+        serde_json::Value::String(refresh_token.to_string()),
+    );
+    match expires_at {
+        Some(expires_at) => object.insert(
+            "refresh_token_expires_at".to_string(),
+            serde_json::Value::String(expires_at.to_rfc3339()),
+        ),
+        None => object.remove("refresh_token_expires_at"),
+    };
+
+    let mut contents = serde_json::to_string_pretty(&credentials)?;
+    contents.push('\n');
+    secrets::write_atomically(path, contents.as_bytes())?;
+
+    log::info!("Saved the new refresh token to {}", path.display());
+
+    Ok(())
+}
@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, TimeDelta, Utc};
+
+use super::models::DeviceRecord;
+
+use crate::database::errors::DatabaseError;
+use crate::datastore::storage::Storage;
+
+pub type DeviceStore = dyn Storage<DeviceRecord, Error = DatabaseError> + Send + Sync;
+
+/// How stale a device's `last_seen` may get before it is written again, so that devices that
+/// report every second are not rewritten every second
+const LAST_SEEN_INTERVAL: TimeDelta = TimeDelta::minutes(10);
+
+/// The `devices` collection, which records every device readings have been taken from under
+/// its stable ID, along with its current name, model and room
+pub struct DeviceRegistry {
+    store: Arc<DeviceStore>,
+    /// The registered devices by ID, as last written, once they have been loaded
+    devices: Mutex<Option<HashMap<String, DeviceRecord>>>,
+}
+
+impl DeviceRegistry {
+    /// Creates a registry of the devices in `store`, which are loaded when devices are first seen
+    /// so that the database does not have to be available yet
+    pub fn new(store: Arc<DeviceStore>) -> Self {
+        DeviceRegistry {
+            store,
+            devices: Mutex::new(None),
+        }
+    }
+
+    /// Loads the devices already registered in the store
+    fn load(&self) -> Result<HashMap<String, DeviceRecord>, DatabaseError> {
+        let devices: HashMap<String, DeviceRecord> = self
+            .store
+            .get_latest_items("device_id", "last_seen")?
+            .into_iter()
+            .map(|device| (device.device_id.clone(), device))
+            .collect();
+
+        log::info!("{} device(s) registered", devices.len());
+
+        Ok(devices)
+    }
+
+    /// Records that the devices were seen at `now`, given as they are now with `now` as both
+    /// their first and last seen time.
+    ///
+    /// A device is only written if it is new, its name, model or room has changed, or its
+    /// `last_seen` is out of date. Nothing is written until the registered devices have been
+    /// loaded, so that their first seen times are kept. Errors are logged rather than returned so
+    /// that they never hold up the readings.
+    pub fn seen(&self, devices: Vec<DeviceRecord>, now: DateTime<Utc>) {
+        let mut registered = self.devices.lock().expect("Device registry mutex poisoned");
+
+        let registered = match &mut *registered {
+            Some(registered) => registered,
+            None => match self.load() {
+                Ok(loaded) => registered.insert(loaded),
+                // MongoDB being unavailable has already been logged
+                Err(DatabaseError::Unavailable) => return,
+                Err(error) => {
+                    log::warn!(
+                        "Error loading the device registry, not registering devices: {error}"
+                    );
+                    return;
+                }
+            },
+        };
+
+        for device in devices {
+            let device = match registered.get(&device.device_id) {
+                Some(known) => {
+                    let device = DeviceRecord {
+                        first_seen: known.first_seen,
+                        last_seen: now,
+                        ..device
+                    };
+                    let unchanged = DeviceRecord {
+                        last_seen: known.last_seen,
+                        ..device.clone()
+                    } == *known;
+
+                    if unchanged && now - known.last_seen < LAST_SEEN_INTERVAL {
+                        continue;
+                    }
+                    if !unchanged {
+                        log::info!("Updating {} device {}", device.vendor, device.device_id);
+                    }
+                    device
+                }
+                None => {
+                    log::info!(
+                        "Registering {} device {} as {}",
+                        device.vendor,
+                        device.device_id,
+                        device.display_name
+                    );
+                    device
+                }
+            };
+
+            match self
+                .store
+                .upsert_item("device_id", &device.device_id, &device)
+            {
+                Ok(()) => {
+                    registered.insert(device.device_id.clone(), device);
+                }
+                Err(error) => log::error!("Error saving device {}: {error}", device.device_id),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::datastore::memory::InMemoryStorage;
+
+    fn device(display_name: &str, now: DateTime<Utc>) -> DeviceRecord {
+        DeviceRecord {
+            device_id: "device-1".to_string(),
+            vendor: "hue".to_string(),
+            model: Some("SML001".to_string()),
+            room: Some("Lounge".to_string()),
+            display_name: display_name.to_string(),
+            bridge_id: Some("001788fffe000000".to_string()),
+            first_seen: now,
+            last_seen: now,
+        }
+    }
+
+    #[test]
+    fn devices_are_written_when_new_changed_or_last_seen_is_out_of_date() {
+        let store = Arc::new(InMemoryStorage::new(&["device_id"]));
+        let registry = DeviceRegistry::new(store.clone());
+        let first_seen: DateTime<Utc> = "2026-01-01T00:00:00Z".parse().unwrap();
+
+        registry.seen(vec![device("Hallway", first_seen)], first_seen);
+
+        // Seen again soon after, with nothing changed, so not written
+        let soon = first_seen + TimeDelta::minutes(1);
+        registry.seen(vec![device("Hallway", soon)], soon);
+        assert_eq!(store.items().unwrap()[0].last_seen, first_seen);
+
+        let renamed = first_seen + TimeDelta::minutes(2);
+        registry.seen(vec![device("Landing", renamed)], renamed);
+
+        let later = renamed + LAST_SEEN_INTERVAL;
+        registry.seen(vec![device("Landing", later)], later);
+
+        let devices = store.items().unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].display_name, "Landing");
+        assert_eq!(devices[0].first_seen, first_seen);
+        assert_eq!(devices[0].last_seen, later);
+
+        // The registered devices are read back on startup
+        let registry = DeviceRegistry::new(store.clone());
+        registry.seen(vec![device("Landing", later)], later);
+        assert_eq!(store.items().unwrap()[0].last_seen, later);
+    }
+}
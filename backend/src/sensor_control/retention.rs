@@ -0,0 +1,912 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+
+use super::models::{Rollup, RollupStats, TemperatureData};
+use super::store::Reading;
+
+use crate::config::settings::RetentionConfig;
+use crate::database::errors::DatabaseError;
+use crate::datastore::storage::{Series, Storage};
+use crate::shutdown::Shutdown;
+
+pub type RollupStore = dyn Storage<Rollup, Error = DatabaseError> + Send + Sync;
+
+/// How long after a period ends before it is rolled up, so that readings stored a little late,
+/// e.g. from events, are included
+const SETTLE_TIME: TimeDelta = TimeDelta::minutes(2);
+
+/// The most items read from a collection at once, well above the number in a batch of any level
+/// at one reading per second
+const READ_LIMIT: i64 = 100_000;
+
+/// How often progress is logged while a long backlog is rolled up
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The collections for the readings summarised by minute, hour and day
+#[derive(Clone)]
+pub struct RollupStores {
+    pub minute: Arc<RollupStore>,
+    pub hour: Arc<RollupStore>,
+    pub day: Arc<RollupStore>,
+}
+
+/// A level of rollups, each summarising the level below, or the raw readings for minutes
+struct Level<'a> {
+    name: &'static str,
+    period: TimeDelta,
+    /// How much of the level below is read at a time
+    batch: TimeDelta,
+    store: &'a RollupStore,
+}
+
+/// The items of a device, by its ID, or by its name for the readings stored before devices had
+/// IDs, so that a renamed device keeps one series of rollups and devices that share a name do not
+/// get one between them
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum DeviceKey {
+    Id(String),
+    Name(String),
+}
+
+impl DeviceKey {
+    fn of(item: &impl Reading) -> Self {
+        match item.device_id() {
+            Some(id) => DeviceKey::Id(id.to_string()),
+            None => DeviceKey::Name(item.device_name().to_string()),
+        }
+    }
+
+    fn series(&self) -> Series<'_> {
+        match self {
+            DeviceKey::Id(id) => Series::new("device_id", id),
+            DeviceKey::Name(name) => Series::new("device_name", name).without("device_id"),
+        }
+    }
+}
+
+impl fmt::Display for DeviceKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceKey::Id(id) => write!(f, "device {id}"),
+            DeviceKey::Name(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+/// Anything that can be summarised into a rollup
+trait Rollable: Reading {
+    fn to_rollup(&self) -> Rollup;
+}
+
+impl Rollable for TemperatureData {
+    fn to_rollup(&self) -> Rollup {
+        let stats = |value| RollupStats {
+            min: value,
+            max: value,
+            mean: value,
+        };
+
+        Rollup {
+            device_name: self.device_name.clone(),
+            device_id: self.device_id.clone(),
+            bridge_id: self.bridge_id.clone(),
+            source: self.source.clone(),
+            timestamp: self.timestamp,
+            count: 1,
+            temperature: stats(self.temperature),
+            humidity: stats(self.humidity),
+            online_fraction: if self.online { 1.0 } else { 0.0 },
+        }
+    }
+}
+
+impl Rollable for Rollup {
+    fn to_rollup(&self) -> Rollup {
+        self.clone()
+    }
+}
+
+/// The background job that rolls the raw readings up into minute, hourly and daily summaries
+/// and then deletes the raw readings that are older than the retention window.
+///
+/// Each level is rolled up from the level below, a period at a time once it is complete, and
+/// picks up after its latest stored rollup, so a backlog of readings is worked through on the
+/// first run and a run that is interrupted carries on where it stopped.
+pub struct Retention<T> {
+    data_store: Arc<T>,
+    rollups: RollupStores,
+    /// How long raw readings are kept, if they are ever deleted
+    raw_retention: Option<TimeDelta>,
+    interval: Duration,
+    /// Where each device's raw readings have been deleted up to since the job started, before
+    /// which any raw readings were stored late
+    deleted_before: Mutex<HashMap<DeviceKey, DateTime<Utc>>>,
+}
+
+/// What a run of the retention job did
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RetentionSummary {
+    pub minutes: usize,
+    pub hours: usize,
+    pub days: usize,
+    /// Minutes rolled up again because readings were stored after they were rolled up
+    pub rerolled: usize,
+    pub deleted: u64,
+}
+
+impl<T> Retention<T>
+where
+    T: Storage<TemperatureData, Error = DatabaseError> + Send + Sync + 'static,
+{
+    pub fn new(config: &RetentionConfig, data_store: Arc<T>, rollups: RollupStores) -> Self {
+        Retention {
+            data_store,
+            rollups,
+            raw_retention: i64::try_from(config.raw_days)
+                .ok()
+                .filter(|days| *days > 0)
+                .and_then(TimeDelta::try_days),
+            interval: Duration::from_secs(config.interval_secs),
+            deleted_before: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Starts the job on its own thread, running straight away and then every interval until
+    /// shutdown is triggered
+    pub fn run(self, shutdown: &Shutdown) -> Result<thread::JoinHandle<()>, std::io::Error> {
+        let shutdown = shutdown.clone();
+
+        thread::Builder::new()
+            .name("retention".to_string())
+            .spawn(move || {
+                while !shutdown.is_triggered() {
+                    match self.run_once(Utc::now(), &shutdown) {
+                        Ok(summary) if summary != RetentionSummary::default() => log::info!(
+                            "Rolled up {} minute(s), {} hour(s) and {} day(s), rolled up {} minute(s) again, deleted {} raw reading(s)",
+                            summary.minutes,
+                            summary.hours,
+                            summary.days,
+                            summary.rerolled,
+                            summary.deleted
+                        ),
+                        Ok(_) => log::debug!("Nothing to roll up or delete"),
+                        Err(error) => log::error!("Error rolling up the readings: {error}"),
+                    }
+
+                    if shutdown.wait_timeout(self.interval) {
+                        break;
+                    }
+                }
+                log::debug!("Retention job stopped");
+            })
+    }
+
+    /// Rolls up every complete period that has not been rolled up yet and then deletes the raw
+    /// readings past the retention window, stopping early if shutdown is triggered
+    pub fn run_once(
+        &self,
+        now: DateTime<Utc>,
+        shutdown: &Shutdown,
+    ) -> Result<RetentionSummary, DatabaseError> {
+        let minute = Level {
+            name: "minute",
+            period: TimeDelta::minutes(1),
+            batch: TimeDelta::hours(1),
+            store: self.rollups.minute.as_ref(),
+        };
+        let hour = Level {
+            name: "hour",
+            period: TimeDelta::hours(1),
+            batch: TimeDelta::days(7),
+            store: self.rollups.hour.as_ref(),
+        };
+        let day = Level {
+            name: "day",
+            period: TimeDelta::days(1),
+            batch: TimeDelta::days(90),
+            store: self.rollups.day.as_ref(),
+        };
+
+        let mut summary = RetentionSummary {
+            minutes: roll_up(self.data_store.as_ref(), &minute, now, shutdown)?,
+            hours: roll_up(minute.store, &hour, now, shutdown)?,
+            days: roll_up(hour.store, &day, now, shutdown)?,
+            ..RetentionSummary::default()
+        };
+
+        if let Some(raw_retention) = self.raw_retention
+            && !shutdown.is_triggered()
+        {
+            self.delete_raw([&minute, &hour, &day], now - raw_retention, &mut summary)?;
+        }
+
+        Ok(summary)
+    }
+
+    /// Deletes each device's raw readings from before `cutoff`, keeping any that have not been
+    /// rolled up yet. Any that were stored after their minute was rolled up are rolled up again
+    /// first, so that they are not lost.
+    fn delete_raw(
+        &self,
+        levels: [&Level; 3],
+        cutoff: DateTime<Utc>,
+        summary: &mut RetentionSummary,
+    ) -> Result<(), DatabaseError> {
+        let minute = levels[0];
+        let rolled_up = latest_timestamps(minute.store)?;
+        // Only whole minutes are deleted, so that the readings left of a minute are never taken
+        // for ones that were stored late
+        let cutoff = period_start(cutoff, minute.period);
+
+        for device in latest_timestamps(self.data_store.as_ref())?.into_keys() {
+            let Some(rolled_up) = rolled_up.get(&device) else {
+                continue;
+            };
+            let before = cutoff.min(*rolled_up + minute.period);
+
+            let deleted_before = self.deleted_before(&device);
+            summary.rerolled += roll_up_late(
+                self.data_store.as_ref(),
+                levels,
+                &device,
+                before,
+                deleted_before,
+            )?;
+
+            let count =
+                self.data_store
+                    .delete_items_before(device.series(), "timestamp", before)?;
+            if count > 0 {
+                log::debug!("Deleted {count} raw reading(s) of {device} from before {before}");
+            }
+            summary.deleted += count;
+
+            self.deleted_before
+                .lock()
+                .expect("Retention mutex poisoned")
+                .entry(device)
+                .and_modify(|deleted_before| *deleted_before = (*deleted_before).max(before))
+                .or_insert(before);
+        }
+
+        Ok(())
+    }
+
+    /// Where the device's raw readings have been deleted up to since the job started
+    fn deleted_before(&self, device: &DeviceKey) -> Option<DateTime<Utc>> {
+        self.deleted_before
+            .lock()
+            .expect("Retention mutex poisoned")
+            .get(device)
+            .copied()
+    }
+}
+
+/// Rolls up the complete periods of each device in `source` that are not in the level's store
+/// yet, returning how many rollups were stored
+fn roll_up<S, R>(
+    source: &S,
+    level: &Level,
+    now: DateTime<Utc>,
+    shutdown: &Shutdown,
+) -> Result<usize, DatabaseError>
+where
+    S: Storage<R, Error = DatabaseError> + ?Sized,
+    R: Rollable,
+{
+    // Periods that start before `end` are complete
+    let end = period_start(now - SETTLE_TIME, level.period);
+    let rolled_up = latest_timestamps(level.store)?;
+    let mut stored = 0;
+
+    for device in latest_timestamps(source)?.into_keys() {
+        let mut start = match rolled_up.get(&device) {
+            Some(timestamp) => *timestamp + level.period,
+            None => match first_item(source, &device, DateTime::UNIX_EPOCH, end)? {
+                Some(timestamp) => period_start(timestamp, level.period),
+                None => continue,
+            },
+        };
+        let mut logged = Instant::now();
+
+        while start < end && !shutdown.is_triggered() {
+            let batch_end = (start + level.batch).min(end);
+            let items = source.get_items_between(
+                device.series(),
+                "timestamp",
+                start,
+                batch_end - TimeDelta::milliseconds(1),
+                READ_LIMIT,
+            )?;
+
+            if items.is_empty() {
+                // Skip over any gap in the readings rather than reading it a batch at a time
+                match first_item(source, &device, batch_end, end)? {
+                    Some(timestamp) => start = period_start(timestamp, level.period),
+                    None => break,
+                }
+                continue;
+            }
+
+            let rollups = summarise(&items, level.period);
+            level.store.save_items(&rollups)?;
+            stored += rollups.len();
+            start = batch_end;
+
+            if logged.elapsed() >= PROGRESS_INTERVAL {
+                log::info!("Rolling up {device} by {}: up to {start}", level.name);
+                logged = Instant::now();
+            }
+        }
+    }
+
+    Ok(stored)
+}
+
+/// Rolls up the minutes of a device's raw readings from before `before` again where their
+/// rollups do not count every reading, along with the hours and days that have been rolled up
+/// from them, returning how many minutes were rolled up again.
+///
+/// Readings stored after their minute was rolled up, such as delayed Nest events or readings
+/// written back from the spool, are otherwise left out of the rollups. Those from before
+/// `deleted_before`, where the raw readings have already been deleted, are added to their
+/// minute's rollup. Until the raw readings have been deleted once since the job started, it is
+/// not known where they were deleted up to, so a minute with fewer raw readings than its rollup
+/// counts is taken to have had the rest deleted, and late readings are missed in a minute that
+/// happens to have as many.
+fn roll_up_late<S>(
+    source: &S,
+    [minute, hour, day]: [&Level; 3],
+    device: &DeviceKey,
+    before: DateTime<Utc>,
+    deleted_before: Option<DateTime<Utc>>,
+) -> Result<usize, DatabaseError>
+where
+    S: Storage<TemperatureData, Error = DatabaseError> + ?Sized,
+{
+    let Some(first) = first_item(source, device, DateTime::UNIX_EPOCH, before)? else {
+        return Ok(0);
+    };
+    let mut start = period_start(first, minute.period);
+    let mut hours = BTreeSet::new();
+    let mut rolled_up = 0;
+
+    while start < before {
+        let batch_end = (start + minute.batch).min(before);
+        let items = source.get_items_between(
+            device.series(),
+            "timestamp",
+            start,
+            batch_end - TimeDelta::milliseconds(1),
+            READ_LIMIT,
+        )?;
+
+        if items.is_empty() {
+            match first_item(source, device, batch_end, before)? {
+                Some(timestamp) => start = period_start(timestamp, minute.period),
+                None => break,
+            }
+            continue;
+        }
+
+        let stored: HashMap<DateTime<Utc>, Rollup> = minute
+            .store
+            .get_items_between(
+                device.series(),
+                "timestamp",
+                start,
+                batch_end - TimeDelta::milliseconds(1),
+                READ_LIMIT,
+            )?
+            .into_iter()
+            .map(|rollup| (rollup.timestamp, rollup))
+            .collect();
+
+        for rollup in summarise(&items, minute.period) {
+            let rollup = match stored.get(&rollup.timestamp) {
+                None => rollup,
+                // The other readings of the minute have been deleted, so only the late ones are left
+                Some(stored)
+                    if deleted_before.map_or(rollup.count < stored.count, |deleted_before| {
+                        rollup.timestamp < deleted_before
+                    }) =>
+                {
+                    combine(&[stored.clone(), rollup.clone()], rollup.timestamp)
+                }
+                Some(stored) if rollup.count > stored.count => rollup,
+                Some(_) => continue,
+            };
+            replace(minute.store, &rollup)?;
+            hours.insert(period_start(rollup.timestamp, hour.period));
+            rolled_up += 1;
+        }
+        start = batch_end;
+    }
+
+    let mut days = BTreeSet::new();
+    for start in hours {
+        if roll_up_again(minute.store, hour, device, start)? {
+            days.insert(period_start(start, day.period));
+        }
+    }
+    for start in days {
+        roll_up_again(hour.store, day, device, start)?;
+    }
+
+    if rolled_up > 0 {
+        log::info!("Rolled up {rolled_up} minute(s) of {device} again for readings stored late");
+    }
+
+    Ok(rolled_up)
+}
+
+/// Rolls up the period of `level` starting at `start` again from `source`, if it has been rolled
+/// up already, returning whether it had
+fn roll_up_again(
+    source: &RollupStore,
+    level: &Level,
+    device: &DeviceKey,
+    start: DateTime<Utc>,
+) -> Result<bool, DatabaseError> {
+    let existing = level
+        .store
+        .get_items_between(device.series(), "timestamp", start, start, 1)?;
+    if existing.is_empty() {
+        return Ok(false);
+    }
+
+    let items = source.get_items_between(
+        device.series(),
+        "timestamp",
+        start,
+        start + level.period - TimeDelta::milliseconds(1),
+        READ_LIMIT,
+    )?;
+    for rollup in summarise(&items, level.period) {
+        replace(level.store, &rollup)?;
+    }
+
+    Ok(true)
+}
+
+/// Stores `rollup` in place of the device's rollup of the same period
+fn replace(store: &RollupStore, rollup: &Rollup) -> Result<(), DatabaseError> {
+    store.delete_items_between(
+        DeviceKey::of(rollup).series(),
+        "timestamp",
+        rollup.timestamp,
+        rollup.timestamp,
+    )?;
+    store.save_item(rollup)
+}
+
+/// The timestamp of the first item of a device from `from` up to but not including `to`
+fn first_item<S, R>(
+    source: &S,
+    device: &DeviceKey,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Option<DateTime<Utc>>, DatabaseError>
+where
+    S: Storage<R, Error = DatabaseError> + ?Sized,
+    R: Reading,
+{
+    if from >= to {
+        return Ok(None);
+    }
+
+    Ok(source
+        .get_items_between(
+            device.series(),
+            "timestamp",
+            from,
+            to - TimeDelta::milliseconds(1),
+            1,
+        )?
+        .first()
+        .map(Reading::timestamp))
+}
+
+/// The timestamp of each device's latest item, which MongoDB stores read once and then keep up
+/// to date as items are saved
+fn latest_timestamps<S, R>(store: &S) -> Result<HashMap<DeviceKey, DateTime<Utc>>, DatabaseError>
+where
+    S: Storage<R, Error = DatabaseError> + ?Sized,
+    R: Reading,
+{
+    let latest = store.get_latest_timestamps("device_id", "device_name", "timestamp")?;
+    let by_id = latest
+        .by_id
+        .into_iter()
+        .map(|(id, timestamp)| (DeviceKey::Id(id), timestamp));
+    let by_name = latest
+        .by_name
+        .into_iter()
+        .map(|(name, timestamp)| (DeviceKey::Name(name), timestamp));
+    Ok(by_id.chain(by_name).collect())
+}
+
+/// Summarises a device's items, oldest first, into a rollup per period
+fn summarise<R: Rollable>(items: &[R], period: TimeDelta) -> Vec<Rollup> {
+    items
+        .chunk_by(|a, b| period_start(a.timestamp(), period) == period_start(b.timestamp(), period))
+        .map(|items| {
+            let rollups: Vec<Rollup> = items.iter().map(Rollable::to_rollup).collect();
+            combine(&rollups, period_start(items[0].timestamp(), period))
+        })
+        .collect()
+}
+
+/// Combines the rollups of shorter periods into a rollup of the period starting at
+/// `timestamp`, taking the device's details from the latest
+fn combine(rollups: &[Rollup], timestamp: DateTime<Utc>) -> Rollup {
+    let count: u32 = rollups.iter().map(|rollup| rollup.count).sum();
+    let mean = |value: fn(&Rollup) -> f32| {
+        let total: f64 = rollups
+            .iter()
+            .map(|rollup| f64::from(value(rollup)) * f64::from(rollup.count))
+            .sum();
+        (total / f64::from(count.max(1))) as f32
+    };
+    let stats = |stats: fn(&Rollup) -> RollupStats| RollupStats {
+        min: rollups
+            .iter()
+            .map(|rollup| stats(rollup).min)
+            .fold(f32::INFINITY, f32::min),
+        max: rollups
+            .iter()
+            .map(|rollup| stats(rollup).max)
+            .fold(f32::NEG_INFINITY, f32::max),
+        mean: 0.0,
+    };
+
+    let latest = &rollups[rollups.len() - 1];
+    Rollup {
+        device_name: latest.device_name.clone(),
+        device_id: latest.device_id.clone(),
+        bridge_id: latest.bridge_id.clone(),
+        source: latest.source.clone(),
+        timestamp,
+        count,
+        temperature: RollupStats {
+            mean: mean(|rollup| rollup.temperature.mean),
+            ..stats(|rollup| rollup.temperature)
+        },
+        humidity: RollupStats {
+            mean: mean(|rollup| rollup.humidity.mean),
+            ..stats(|rollup| rollup.humidity)
+        },
+        online_fraction: mean(|rollup| rollup.online_fraction),
+    }
+}
+
+/// The start of the period `timestamp` falls in, periods being counted from midnight UTC
+fn period_start(timestamp: DateTime<Utc>, period: TimeDelta) -> DateTime<Utc> {
+    timestamp
+        .duration_trunc(period)
+        .expect("Rollup periods are at most a day")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::datastore::memory::InMemoryStorage;
+    use crate::tests::harness::reading;
+
+    fn store() -> Arc<InMemoryStorage<Rollup>> {
+        Arc::new(InMemoryStorage::new(&["device_id", "timestamp"]))
+    }
+
+    #[test]
+    fn readings_are_rolled_up_by_minute_hour_and_day_then_deleted() {
+        let start: DateTime<Utc> = "2026-01-01T00:00:00Z".parse().unwrap();
+        let data_store = Arc::new(InMemoryStorage::new(&["device_id", "timestamp"]));
+        let readings: Vec<TemperatureData> = (0..12)
+            .map(|index| {
+                let temperature = 18.0 + index as f32;
+                TemperatureData {
+                    device_id: Some("device-Lounge".to_string()),
+                    online: temperature < 22.0,
+                    humidity: 40.0,
+                    ..reading(
+                        "Lounge",
+                        start + TimeDelta::seconds(10 * index),
+                        temperature,
+                    )
+                }
+            })
+            .chain([reading("Kitchen", start + TimeDelta::days(2), 19.0)])
+            .collect();
+        data_store.save_items(&readings).unwrap();
+
+        let (minute, hour, day) = (store(), store(), store());
+        let retention = Retention::new(
+            &RetentionConfig {
+                enabled: true,
+                raw_days: 1,
+                interval_secs: 300,
+            },
+            data_store.clone(),
+            RollupStores {
+                minute: minute.clone(),
+                hour: hour.clone(),
+                day: day.clone(),
+            },
+        );
+        let shutdown = Shutdown::new();
+
+        // The Kitchen's reading is too recent to roll up, so it is kept
+        let now = start + TimeDelta::days(2) + TimeDelta::seconds(30);
+        let summary = retention.run_once(now, &shutdown).unwrap();
+        assert_eq!(
+            summary,
+            RetentionSummary {
+                minutes: 2,
+                hours: 1,
+                days: 1,
+                rerolled: 0,
+                deleted: 12,
+            }
+        );
+
+        let minutes = minute.items().unwrap();
+        assert_eq!(minutes[0].timestamp, start);
+        assert_eq!(minutes[0].count, 6);
+        assert_eq!(
+            minutes[0].temperature,
+            RollupStats {
+                min: 18.0,
+                max: 23.0,
+                mean: 20.5,
+            }
+        );
+        assert_eq!(minutes[1].timestamp, start + TimeDelta::minutes(1));
+        assert_eq!(minutes[1].online_fraction, 0.0);
+
+        let days = day.items().unwrap();
+        assert_eq!(days.len(), 1);
+        assert_eq!(days[0].count, 12);
+        assert_eq!(days[0].temperature.mean, 23.5);
+        assert_eq!(days[0].humidity.max, 40.0);
+        assert!((days[0].online_fraction - 1.0 / 3.0).abs() < 1e-6);
+        assert_eq!(days[0].device_id.as_deref(), Some("device-Lounge"));
+        assert_eq!(hour.items().unwrap()[0].count, 12);
+
+        let kept: Vec<String> = data_store
+            .items()
+            .unwrap()
+            .into_iter()
+            .map(|reading| reading.device_name)
+            .collect();
+        assert_eq!(kept, vec!["Kitchen".to_string()]);
+
+        // A later run only rolls up the new periods
+        let summary = retention
+            .run_once(now + TimeDelta::minutes(3), &shutdown)
+            .unwrap();
+        assert_eq!(
+            summary,
+            RetentionSummary {
+                minutes: 1,
+                ..RetentionSummary::default()
+            }
+        );
+    }
+
+    #[test]
+    fn readings_stored_after_their_minute_was_rolled_up_are_rolled_up_before_being_deleted() {
+        let start: DateTime<Utc> = "2026-01-01T00:00:00Z".parse().unwrap();
+        let at = |seconds| reading("Lounge", start + TimeDelta::seconds(seconds), 0.0);
+        let data_store = Arc::new(InMemoryStorage::new(&["device_id", "timestamp"]));
+        data_store
+            .save_items(&[
+                TemperatureData {
+                    temperature: 18.0,
+                    ..at(0)
+                },
+                TemperatureData {
+                    temperature: 20.0,
+                    ..at(10)
+                },
+                TemperatureData {
+                    temperature: 22.0,
+                    ..at(60)
+                },
+                TemperatureData {
+                    temperature: 24.0,
+                    ..at(600)
+                },
+                TemperatureData {
+                    temperature: 26.0,
+                    ..at(620)
+                },
+            ])
+            .unwrap();
+
+        let (minute, hour, day) = (store(), store(), store());
+        let retention = Retention::new(
+            &RetentionConfig {
+                enabled: true,
+                raw_days: 1,
+                interval_secs: 300,
+            },
+            data_store.clone(),
+            RollupStores {
+                minute: minute.clone(),
+                hour: hour.clone(),
+                day: day.clone(),
+            },
+        );
+        let shutdown = Shutdown::new();
+
+        // The readings of the first two minutes are rolled up and deleted
+        let now = start + TimeDelta::days(1) + TimeDelta::minutes(3);
+        let summary = retention.run_once(now, &shutdown).unwrap();
+        assert_eq!(
+            summary,
+            RetentionSummary {
+                minutes: 3,
+                hours: 1,
+                days: 1,
+                rerolled: 0,
+                deleted: 3,
+            }
+        );
+
+        // Late readings of a deleted minute and of one that has only been rolled up
+        data_store
+            .save_items(&[
+                TemperatureData {
+                    temperature: 30.0,
+                    ..at(30)
+                },
+                TemperatureData {
+                    temperature: 28.0,
+                    ..at(640)
+                },
+            ])
+            .unwrap();
+
+        let summary = retention
+            .run_once(now + TimeDelta::minutes(12), &shutdown)
+            .unwrap();
+        assert_eq!(
+            summary,
+            RetentionSummary {
+                rerolled: 2,
+                deleted: 4,
+                ..RetentionSummary::default()
+            }
+        );
+
+        let minutes: Vec<(u32, RollupStats)> = minute
+            .items()
+            .unwrap()
+            .into_iter()
+            .map(|rollup| (rollup.count, rollup.temperature))
+            .collect();
+        assert_eq!(
+            minutes,
+            vec![
+                (
+                    1,
+                    RollupStats {
+                        min: 22.0,
+                        max: 22.0,
+                        mean: 22.0,
+                    }
+                ),
+                (
+                    3,
+                    RollupStats {
+                        min: 18.0,
+                        max: 30.0,
+                        mean: 68.0 / 3.0,
+                    }
+                ),
+                (
+                    3,
+                    RollupStats {
+                        min: 24.0,
+                        max: 28.0,
+                        mean: 26.0,
+                    }
+                ),
+            ]
+        );
+        for rollups in [hour.items().unwrap(), day.items().unwrap()] {
+            assert_eq!(rollups.len(), 1);
+            assert_eq!(rollups[0].count, 7);
+            assert_eq!(rollups[0].temperature.mean, 24.0);
+        }
+        assert!(data_store.items().unwrap().is_empty());
+    }
+
+    #[test]
+    fn renamed_devices_keep_their_rollups_apart_from_devices_that_share_a_name() {
+        let start: DateTime<Utc> = "2026-01-01T00:00:00Z".parse().unwrap();
+        let at = |name, device_id: Option<&str>, seconds, temperature| TemperatureData {
+            device_id: device_id.map(str::to_string),
+            ..reading(name, start + TimeDelta::seconds(seconds), temperature)
+        };
+        let data_store = Arc::new(InMemoryStorage::new(&["device_id", "timestamp"]));
+        data_store
+            .save_items(&[
+                at("Lounge", Some("device-a"), 10, 20.0),
+                at("Living room", Some("device-a"), 70, 22.0),
+                at("Lounge", Some("device-b"), 20, 10.0),
+                // Stored before readings had device IDs
+                at("Lounge", None, 30, 30.0),
+            ])
+            .unwrap();
+
+        let (minute, hour, day) = (store(), store(), store());
+        let retention = Retention::new(
+            &RetentionConfig {
+                enabled: true,
+                raw_days: 1,
+                interval_secs: 300,
+            },
+            data_store.clone(),
+            RollupStores {
+                minute: minute.clone(),
+                hour: hour.clone(),
+                day: day.clone(),
+            },
+        );
+
+        let now = start + TimeDelta::days(1) + TimeDelta::minutes(3);
+        let summary = retention.run_once(now, &Shutdown::new()).unwrap();
+        assert_eq!(
+            summary,
+            RetentionSummary {
+                minutes: 4,
+                hours: 3,
+                days: 3,
+                rerolled: 0,
+                deleted: 4,
+            }
+        );
+
+        let series = |rollups: Vec<Rollup>| {
+            let mut series: Vec<(Option<String>, String, u32, f32)> = rollups
+                .into_iter()
+                .map(|rollup| {
+                    (
+                        rollup.device_id,
+                        rollup.device_name,
+                        rollup.count,
+                        rollup.temperature.mean,
+                    )
+                })
+                .collect();
+            series.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            series
+        };
+        let id = |id: &str| Some(id.to_string());
+        assert_eq!(
+            series(minute.items().unwrap()),
+            vec![
+                (None, "Lounge".to_string(), 1, 30.0),
+                (id("device-a"), "Living room".to_string(), 1, 22.0),
+                (id("device-a"), "Lounge".to_string(), 1, 20.0),
+                (id("device-b"), "Lounge".to_string(), 1, 10.0),
+            ]
+        );
+        assert_eq!(
+            series(day.items().unwrap()),
+            vec![
+                (None, "Lounge".to_string(), 1, 30.0),
+                (id("device-a"), "Living room".to_string(), 2, 21.0),
+                (id("device-b"), "Lounge".to_string(), 1, 10.0),
+            ]
+        );
+        assert!(data_store.items().unwrap().is_empty());
+    }
+}
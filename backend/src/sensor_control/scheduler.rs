@@ -0,0 +1,222 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use super::errors::SensorError;
+use super::models::TemperatureData;
+use super::source::SensorSource;
+use super::store;
+
+use crate::database::errors::DatabaseError;
+use crate::datastore::storage::Storage;
+use crate::metrics;
+use crate::shutdown::Shutdown;
+
+/// Poll counters for a single source
+#[derive(Debug, Default)]
+pub struct SourceStats {
+    pub polls: AtomicU64,
+    pub errors: AtomicU64,
+    pub consecutive_errors: AtomicU64,
+}
+
+/// Drives any number of `SensorSource`s, each on its own thread and at its own interval, and
+/// stores their readings through `store::store_temperatures`.
+pub struct Scheduler<T> {
+    data_store: Arc<T>,
+    jitter_fraction: f64,
+    sources: Vec<Box<dyn SensorSource>>,
+}
+
+/// The running scheduler threads and their counters
+pub struct SchedulerHandle {
+    threads: Vec<(String, thread::JoinHandle<()>)>,
+    stats: Vec<(String, Arc<SourceStats>)>,
+}
+
+impl<T> Scheduler<T>
+where
+    T: Storage<TemperatureData, Error = DatabaseError> + Send + Sync + 'static,
+{
+    /// Creates a scheduler that stores readings in `data_store`.
+    ///
+    /// Each sleep between polls is randomly lengthened or shortened by up to `jitter_fraction` of
+    /// the source's interval so sources do not stay in lockstep.
+    pub fn new(data_store: Arc<T>, jitter_fraction: f64) -> Self {
+        Scheduler {
+            data_store,
+            jitter_fraction,
+            sources: Vec::new(),
+        }
+    }
+
+    /// Adds a source to be polled once the scheduler is running
+    pub fn add_source(&mut self, source: Box<dyn SensorSource>) {
+        log::info!(
+            "Adding source {} with interval {:?}",
+            source.name(),
+            source.interval()
+        );
+        self.sources.push(source);
+    }
+
+    /// Starts a polling thread per source, which runs until shutdown is triggered.
+    ///
+    /// A poll that is in progress when shutdown is triggered is completed, including storing its
+    /// readings, before the thread exits.
+    pub fn run(self, shutdown: &Shutdown) -> Result<SchedulerHandle, SensorError> {
+        let mut handle = SchedulerHandle {
+            threads: Vec::new(),
+            stats: Vec::new(),
+        };
+
+        for source in self.sources {
+            let name = source.name().to_string();
+            let stats = Arc::new(SourceStats::default());
+            let data_store = Arc::clone(&self.data_store);
+            let jitter_fraction = self.jitter_fraction;
+            let shutdown = shutdown.clone();
+
+            handle.stats.push((name.clone(), Arc::clone(&stats)));
+
+            let thread = thread::Builder::new().name(name.clone()).spawn(move || {
+                while !shutdown.is_triggered() {
+                    poll_source(source.as_ref(), data_store.as_ref(), &stats);
+
+                    if shutdown.wait_timeout(jittered(source.interval(), jitter_fraction)) {
+                        break;
+                    }
+                }
+                log::debug!("{} source stopped", source.name());
+            })?;
+
+            handle.threads.push((name, thread));
+        }
+
+        Ok(handle)
+    }
+}
+
+impl SchedulerHandle {
+    /// Waits for every source thread to finish and logs the poll counts for each source.
+    ///
+    /// Returns false if any of the threads panicked.
+    pub fn join(self) -> bool {
+        let mut clean = true;
+
+        for (name, thread) in self.threads {
+            if thread.join().is_err() {
+                log::error!("Source thread {name} panicked");
+                clean = false;
+            }
+        }
+
+        for (name, stats) in &self.stats {
+            log::info!(
+                "{name}: {} poll(s), {} error(s)",
+                stats.polls.load(Ordering::Relaxed),
+                stats.errors.load(Ordering::Relaxed)
+            );
+        }
+
+        clean
+    }
+}
+
+/// Polls a source once, stores the readings and updates its counters
+fn poll_source<T>(source: &dyn SensorSource, data_store: &T, stats: &SourceStats)
+where
+    T: Storage<TemperatureData, Error = DatabaseError>,
+{
+    let name = source.name();
+    stats.polls.fetch_add(1, Ordering::Relaxed);
+
+    let result = source
+        .fetch_readings()
+        .map_err(|error| format!("Error getting readings: {error}"))
+        .and_then(|readings| {
+            log::trace!("{name} readings: {readings:?}");
+            metrics::record_readings(name, &readings);
+            store::store_temperatures(data_store, readings)
+                .map_err(|error| format!("Error saving readings: {error}"))
+        });
+
+    metrics::record_poll(name, result.is_ok());
+
+    match result {
+        Ok(()) => {
+            let previous_errors = stats.consecutive_errors.swap(0, Ordering::Relaxed);
+            if previous_errors > 0 {
+                log::info!("{name} recovered after {previous_errors} consecutive error(s)");
+            }
+            log::debug!("{name} poll complete");
+        }
+        Err(error) => {
+            let total = stats.errors.fetch_add(1, Ordering::Relaxed) + 1;
+            let consecutive = stats.consecutive_errors.fetch_add(1, Ordering::Relaxed) + 1;
+            log::error!("{name}: {error} ({consecutive} consecutive, {total} total)");
+        }
+    }
+}
+
+/// Randomly lengthens or shortens the interval by up to `jitter_fraction` of itself
+fn jittered(interval: Duration, jitter_fraction: f64) -> Duration {
+    let factor = 1.0 + jitter_fraction * (fastrand::f64() * 2.0 - 1.0);
+    interval.mul_f64(factor.max(0.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    use crate::datastore::memory::InMemoryStorage;
+
+    /// A source with a long interval, like Nest, that counts its polls
+    struct SlowSource {
+        polls: Arc<AtomicU64>,
+    }
+
+    impl SensorSource for SlowSource {
+        fn name(&self) -> &str {
+            "Slow"
+        }
+
+        fn interval(&self) -> Duration {
+            Duration::from_secs(300)
+        }
+
+        fn fetch_readings(&self) -> Result<Vec<TemperatureData>, SensorError> {
+            self.polls.fetch_add(1, Ordering::Relaxed);
+            Ok(Vec::new())
+        }
+    }
+
+    #[test]
+    fn shutdown_interrupts_poll_interval() {
+        let polls = Arc::new(AtomicU64::new(0));
+        let shutdown = Shutdown::new();
+
+        let mut scheduler = Scheduler::new(Arc::new(InMemoryStorage::new(&[])), 0.1);
+        scheduler.add_source(Box::new(SlowSource {
+            polls: Arc::clone(&polls),
+        }));
+        let handle = scheduler.run(&shutdown).unwrap();
+
+        // Wait for the first poll so the source is sleeping through its interval
+        let started = Instant::now();
+        while polls.load(Ordering::Relaxed) == 0 {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let triggered = Instant::now();
+        shutdown.trigger();
+
+        assert!(handle.join());
+        assert!(triggered.elapsed() < Duration::from_secs(1));
+        assert_eq!(polls.load(Ordering::Relaxed), 1);
+    }
+}
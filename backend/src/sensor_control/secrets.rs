@@ -0,0 +1,36 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+/// Writes a secret to `path`, replacing any existing file.
+///
+/// The new contents are written alongside the old file and renamed over it, so the secret is
+/// never left half written. An existing file keeps its permissions, and a new one is only
+/// readable by its owner.
+pub fn write_atomically(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let temp_path = path.with_file_name(format!(".{file_name}.tmp"));
+
+    let mut file = fs::File::create(&temp_path)?;
+    match fs::metadata(path) {
+        Ok(metadata) => file.set_permissions(metadata.permissions())?,
+        #[cfg(unix)]
+        Err(_) => {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        }
+        #[cfg(not(unix))]
+        Err(_) => {}
+    }
+    file.write_all(contents)?;
+    file.sync_all()?;
+
+    fs::rename(&temp_path, path)
+}
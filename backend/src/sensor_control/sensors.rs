@@ -1,109 +1,498 @@
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
 use std::thread;
+use std::time::{Duration, Instant};
 
-use ureq::tls::TlsConfig;
-
-use signal_hook::flag::register;
+use chrono::{DateTime, TimeDelta, Utc};
+use ureq::Agent;
 
 use super::errors::SensorError;
-use super::models::{DeviceList, HueBridge, HueTemperatureList, TemperatureData};
+use super::hue_tls;
+use super::models::{
+    BatteryData, DeviceList, DeviceRecord, DeviceRename, HueBridge, HueBridgeConfig, HueEvent,
+    HueEventData, HueResourceList, HueRoomList, HueTemperatureList, LightLevelData, MotionData,
+    TemperatureData, TemperatureReport,
+};
+use super::registry::DeviceRegistry;
+use super::source::SensorSource;
 use super::store;
 
+use crate::config::settings::HueConfig;
 use crate::database::errors::DatabaseError;
 
 use crate::datastore::storage::Storage;
+use crate::metrics;
+use crate::shutdown::Shutdown;
 
 #[derive(Debug)]
 pub struct Sensor {
     id: String,
     name: String,
+    /// The ID of the device, which is its ID in the device registry
+    device_id: String,
+    /// The model ID, e.g. SML001
+    model: Option<String>,
+    /// The room the device is in, if it has been put in one
+    room: Option<String>,
+    /// The IDs of the device's motion, light level and device power services
+    service_ids: Vec<String>,
+    /// The ID of the device's `zigbee_connectivity` service, if it has one
+    connectivity_id: Option<String>,
+}
+
+/// What is known of whether a sensor is online
+#[derive(Debug, Default)]
+struct SensorStatus {
+    /// Whether the bridge can reach the sensor, from its `zigbee_connectivity` resource
+    connected: Option<bool>,
+    /// The time and temperature of the latest report
+    report: Option<(DateTime<Utc>, f32)>,
+    /// The online status of the latest reading
+    online: Option<bool>,
 }
 
-pub struct Sensors<T> {
-    bridge_ip_address: String,
+type MotionStore = dyn Storage<MotionData, Error = DatabaseError> + Send + Sync;
+type LightLevelStore = dyn Storage<LightLevelData, Error = DatabaseError> + Send + Sync;
+type BatteryStore = dyn Storage<BatteryData, Error = DatabaseError> + Send + Sync;
+pub type RenameStore = dyn Storage<DeviceRename, Error = DatabaseError> + Send + Sync;
+
+/// Where the readings of the sensors' motion, light level and device power services, and the
+/// sensors' renames, are stored
+#[derive(Clone)]
+pub struct HueStores {
+    pub motion: Arc<MotionStore>,
+    pub light_level: Arc<LightLevelStore>,
+    pub battery: Arc<BatteryStore>,
+    pub renames: Arc<RenameStore>,
+}
+
+impl HueStores {
+    /// Writes back anything the stores held back while MongoDB was unavailable
+    pub fn replay(&self) -> Result<(), DatabaseError> {
+        self.motion.replay()?;
+        self.light_level.replay()?;
+        self.battery.replay()?;
+        self.renames.replay()
+    }
+
+    /// Writes out anything the stores are holding back, called once at shutdown
+    pub fn flush(&self) -> Result<(), DatabaseError> {
+        self.motion.flush()?;
+        self.light_level.flush()?;
+        self.battery.flush()?;
+        self.renames.flush()
+    }
+}
+
+pub struct Sensors {
+    bridge_id: String,
+    /// Verifies the bridge's certificate for `bridge_id`
+    agent: Agent,
+    bridge_url: String,
     hue_application_key: String,
-    sensors: Vec<Sensor>,
-    data_store: T,
+    poll_interval: Duration,
+    /// How often polls also read the connectivity and services of the sensors
+    services_poll_interval: Duration,
+    /// When the connectivity and services were last polled
+    last_services_poll: Mutex<Option<Instant>>,
+    /// The sensors from the device list, which is read again every `rediscovery_interval`
+    sensors: RwLock<Vec<Sensor>>,
+    /// Names also used on another bridge, which have the bridge ID added
+    shared_names: HashSet<String>,
+    rediscovery_interval: Duration,
+    last_discovery: Mutex<Instant>,
+    /// Temperature resource IDs that were still unknown after reading the device list for them
+    unknown_ids: Mutex<HashSet<String>>,
+    stores: Option<HueStores>,
+    registry: Option<Arc<DeviceRegistry>>,
+    /// The battery level and state last stored per device name
+    last_batteries: Mutex<HashMap<String, (u8, String)>>,
+    /// Reports older than this are stale, and their sensor offline
+    stale_after: TimeDelta,
+    /// The status of each sensor by temperature resource ID
+    statuses: Mutex<HashMap<String, SensorStatus>>,
 }
 
-const HUE_DOMAIN: &str = "hue-bridge.home.arpa";
-pub const HUE_DISCOVERY_URL: &str = "https://discovery.meethue.com/";
 pub const HUE_APPLICATION_KEY_HEADER: &str = "hue-application-key";
 pub const HUE_DEVICE_URL: &str = "/clip/v2/resource/device";
 pub const HUE_TEMPERATURE_URL: &str = "/clip/v2/resource/temperature";
+pub const HUE_EVENT_STREAM_URL: &str = "/eventstream/clip/v2";
+pub const HUE_MOTION_URL: &str = "/clip/v2/resource/motion";
+pub const HUE_LIGHT_LEVEL_URL: &str = "/clip/v2/resource/light_level";
+pub const HUE_DEVICE_POWER_URL: &str = "/clip/v2/resource/device_power";
+pub const HUE_ROOM_URL: &str = "/clip/v2/resource/room";
+pub const HUE_ZIGBEE_CONNECTIVITY_URL: &str = "/clip/v2/resource/zigbee_connectivity";
+/// The services of a temperature sensor that are recorded along with its temperature
+const HUE_SERVICE_TYPES: [&str; 3] = ["motion", "light_level", "device_power"];
+const EVENT_STREAM_MIN_BACKOFF_SECS: u64 = 1;
+const EVENT_STREAM_MAX_BACKOFF_SECS: u64 = 60;
+/// How often the event stream checks for shutdown while waiting for the next line
+const EVENT_STREAM_SHUTDOWN_CHECK: Duration = Duration::from_millis(100);
 
-impl<T> Sensors<T>
-where
-    T: Storage<TemperatureData, Error = DatabaseError> + Send + 'static,
-{
-    pub fn new(hue_application_key: &str, data_store: T) -> Result<Self, SensorError> {
+impl Sensors {
+    /// Connects to every Hue bridge, those in `config.bridges` or else those found by discovery,
+    /// each with its own application key.
+    ///
+    /// A discovered bridge that cannot be connected to, e.g. because it has not been paired, is
+    /// skipped, but every configured bridge must connect. Sensors with the same name on more than
+    /// one bridge have the bridge ID added to their name so their readings are kept apart.
+    pub fn new(config: &HueConfig) -> Result<Vec<Self>, SensorError> {
         log::trace!("Creating new Sensors");
 
-        // Get the IP address of the Hue bridge
-        let bridge_ip_address = Sensors::<T>::get_bridge()?;
-        log::debug!("Bridge IP: {bridge_ip_address}");
+        let addresses = if config.bridges.is_empty() {
+            Sensors::discover_bridges(config)?
+        } else {
+            config
+                .bridges
+                .iter()
+                .map(|bridge| bridge.address.clone())
+                .collect()
+        };
+        log::debug!("Bridge addresses: {addresses:?}");
+
+        let mut bridges = Vec::new();
+        for address in addresses {
+            match Sensors::connect(config, &address) {
+                Ok(sensors) => bridges.push(sensors),
+                Err(error) if config.bridges.is_empty() => {
+                    log::error!("Skipping Hue bridge at {address}: {error}");
+                }
+                Err(error) => return Err(error),
+            }
+        }
+
+        if bridges.is_empty() {
+            return Err(SensorError::DeviceNotFound(
+                "No Hue bridge could be connected to".to_string(),
+            ));
+        }
+
+        Sensors::qualify_duplicate_names(&mut bridges);
+
+        Ok(bridges)
+    }
+
+    /// Connects to the bridge at `address` with the application key for its ID
+    fn connect(config: &HueConfig, address: &str) -> Result<Self, SensorError> {
+        let bridge_url = format!("https://{address}");
+        let bridge_id = Sensors::get_bridge_id(config, &bridge_url)?;
+
+        let key_path = config.application_key_path(address, &bridge_id);
+        log::info!(
+            "Reading Hue Application Key for bridge {bridge_id} from {}",
+            key_path.display()
+        );
+        let hue_application_key = std::fs::read_to_string(&key_path)?;
+
+        Sensors::with_bridge(config, &bridge_id, &bridge_url, hue_application_key.trim())
+    }
+
+    /// Creates a Sensors instance for a bridge at a known base URL (e.g. `https://192.168.1.2`),
+    /// skipping discovery.
+    #[cfg(test)]
+    pub fn with_bridge_url(
+        config: &HueConfig,
+        bridge_url: &str,
+        hue_application_key: &str,
+    ) -> Result<Self, SensorError> {
+        let bridge_id = Sensors::get_bridge_id(config, bridge_url)?;
+
+        Sensors::with_bridge(config, &bridge_id, bridge_url, hue_application_key)
+    }
+
+    fn with_bridge(
+        config: &HueConfig,
+        bridge_id: &str,
+        bridge_url: &str,
+        hue_application_key: &str,
+    ) -> Result<Self, SensorError> {
+        log::info!("Connecting to Hue bridge {bridge_id} at {bridge_url}");
+
+        let agent = hue_tls::agent(config, Some(bridge_id))?;
 
         // Get the sensors from the Hue bridge
-        let sensor_list = Sensors::<T>::get_sensors(&bridge_ip_address, hue_application_key)?;
+        let sensor_list = Sensors::get_sensors(&agent, bridge_url, hue_application_key)?;
         log::trace!("Sensors: {sensor_list:?}");
 
         // Create a new Sensors struct
         let sensors = Sensors {
-            bridge_ip_address,
+            bridge_id: bridge_id.to_string(),
+            agent,
+            bridge_url: bridge_url.to_string(),
             hue_application_key: hue_application_key.to_string(),
-            sensors: sensor_list,
-            data_store,
+            poll_interval: Duration::from_secs(config.poll_interval_secs),
+            services_poll_interval: Duration::from_secs(config.services_poll_interval_secs),
+            last_services_poll: Mutex::new(None),
+            sensors: RwLock::new(sensor_list),
+            shared_names: HashSet::new(),
+            rediscovery_interval: Duration::from_secs(config.rediscovery_interval_secs),
+            last_discovery: Mutex::new(Instant::now()),
+            unknown_ids: Mutex::new(HashSet::new()),
+            stores: None,
+            registry: None,
+            last_batteries: Mutex::new(HashMap::new()),
+            stale_after: TimeDelta::seconds(config.stale_after_secs as i64),
+            statuses: Mutex::new(HashMap::new()),
         };
 
         // Return the Sensors struct
         Ok(sensors)
     }
 
-    pub fn run(self) -> Result<thread::JoinHandle<()>, SensorError> {
-        // Register signal handlers for SIGTERM and SIGINT
-        let term = Arc::new(AtomicBool::new(false));
-        register(signal_hook::consts::SIGTERM, Arc::clone(&term))?;
-        register(signal_hook::consts::SIGINT, Arc::clone(&term))?;
-
-        // Spawn a new thread to get the temperature
-        Ok(thread::spawn(move || {
-            while !term.load(Ordering::Relaxed) {
-                match self.get_temperatures() {
-                    Ok(temperatures) => {
-                        log::trace!("Temperatures: {temperatures:?}");
-                        // Store the temperatures in the data store
-                        if let Err(error) = store::store_temperatures(&self.data_store, temperatures)
-                        {
-                            log::error!("Error saving temperatures: {error}");
+    /// Records the motion, light level and battery of each sensor in `stores`, polled every
+    /// `services_poll_interval_secs`
+    pub fn with_stores(mut self, stores: HueStores) -> Self {
+        self.stores = Some(stores);
+        self
+    }
+
+    /// Registers the sensors in `registry` as their readings are taken
+    pub fn with_registry(mut self, registry: Arc<DeviceRegistry>) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    /// Subscribes to the bridge's event stream and stores temperature reports as they change.
+    ///
+    /// The stream is reconnected with exponential backoff whenever it drops, and a full poll of
+    /// the temperature resources is made after each (re)connect so no reports are missed.
+    ///
+    /// The stream is read on a separate reader thread, so that this thread notices shutdown while
+    /// waiting for an event and can be joined once its writes are done.
+    pub fn run_events<T>(
+        self,
+        data_store: Arc<T>,
+        shutdown: &Shutdown,
+    ) -> Result<thread::JoinHandle<()>, SensorError>
+    where
+        T: Storage<TemperatureData, Error = DatabaseError> + Send + Sync + 'static,
+    {
+        let shutdown = shutdown.clone();
+
+        Ok(thread::Builder::new()
+            .name("Hue events".to_string())
+            .spawn(move || {
+                // The last report timestamp seen per device name
+                let mut last_reports: HashMap<String, DateTime<Utc>> = HashMap::new();
+                let mut backoff_secs = EVENT_STREAM_MIN_BACKOFF_SECS;
+
+                while !shutdown.is_triggered() {
+                    match self.stream_events(data_store.as_ref(), &mut last_reports, &shutdown) {
+                        Ok(()) => {
+                            log::warn!("Hue event stream closed");
+                            backoff_secs = EVENT_STREAM_MIN_BACKOFF_SECS;
+                        }
+                        Err(error) => {
+                            log::error!("Hue event stream error: {error}");
                         }
-                        log::debug!("Storing temperatures");
                     }
-                    Err(error) => {
-                        log::error!("Error getting temperatures: {error}");
+
+                    if shutdown.is_triggered() {
+                        break;
                     }
+
+                    log::info!("Reconnecting to Hue event stream in {backoff_secs}s");
+                    if shutdown.wait_timeout(Duration::from_secs(backoff_secs)) {
+                        break;
+                    }
+                    backoff_secs = (backoff_secs * 2).min(EVENT_STREAM_MAX_BACKOFF_SECS);
                 }
+            })?)
+    }
+
+    /// Connects to the event stream, resyncs with a full poll and then stores changed temperature
+    /// reports until the stream ends or termination is requested.
+    fn stream_events<T>(
+        &self,
+        data_store: &T,
+        last_reports: &mut HashMap<String, DateTime<Utc>>,
+        shutdown: &Shutdown,
+    ) -> Result<(), SensorError>
+    where
+        T: Storage<TemperatureData, Error = DatabaseError>,
+    {
+        let hue_event_stream_url = format!("{}{}", self.bridge_url, HUE_EVENT_STREAM_URL);
+
+        log::info!("Connecting to Hue event stream: {hue_event_stream_url}");
 
-                thread::sleep(std::time::Duration::from_secs(1));
+        let response = metrics::record_http(
+            metrics::HUE_SERVICE,
+            self.agent
+                .get(&hue_event_stream_url)
+                .header(HUE_APPLICATION_KEY_HEADER, &self.hue_application_key)
+                .header("Accept", "text/event-stream")
+                .call(),
+        )?;
+
+        log::info!("Connected to Hue event stream");
+
+        // Anything that changed while we were disconnected is picked up by a full poll
+        let temperatures = self.poll(true)?;
+        self.store_changed(data_store, temperatures, last_reports);
+
+        // The reader thread may be blocked waiting for the bridge after shutdown, and is left to
+        // end when the stream does, as it holds nothing but the connection
+        let (sender, lines) = mpsc::channel();
+        let reader = BufReader::new(response.into_body().into_reader());
+        thread::Builder::new()
+            .name("Hue event reader".to_string())
+            .spawn(move || {
+                for line in reader.lines() {
+                    if sender.send(line).is_err() {
+                        break;
+                    }
+                }
+            })?;
+
+        // Server-sent events are separated by a blank line, with the payload on `data:` lines
+        let mut data = String::new();
+
+        while !shutdown.is_triggered() {
+            let line = match lines.recv_timeout(EVENT_STREAM_SHUTDOWN_CHECK) {
+                Ok(line) => line?,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+
+            if let Some(payload) = line.strip_prefix("data:") {
+                // A payload over several `data:` lines is joined with newlines, and only the one
+                // space after the colon is dropped
+                if !data.is_empty() {
+                    data.push('\n');
+                }
+                data.push_str(payload.strip_prefix(' ').unwrap_or(payload));
+            } else if line.is_empty() && !data.is_empty() {
+                match serde_json::from_str::<Vec<HueEvent>>(&data) {
+                    Ok(events) => {
+                        let now = Utc::now();
+                        let updates: Vec<&HueEventData> = events
+                            .iter()
+                            .filter(|event| event.event_type == "update")
+                            .flat_map(|event| event.data.iter())
+                            .collect();
+                        self.update_connectivity(&updates);
+
+                        // Staleness is only checked as events arrive, which any light changing
+                        // or sensor reporting causes
+                        let mut temperatures = self.temperatures_from_events(&updates, now);
+                        temperatures.extend(self.status_changes(now));
+                        self.register(&temperatures, now);
+                        self.store_changed(data_store, temperatures, last_reports);
+
+                        self.record_services(&updates, now);
+                    }
+                    Err(error) => log::warn!("Error parsing Hue event: {error}"),
+                }
+                data.clear();
             }
-        }))
+        }
+
+        Ok(())
+    }
+
+    /// Converts the temperature resources of `update` events into readings.
+    fn temperatures_from_events(
+        &self,
+        updates: &[&HueEventData],
+        now: DateTime<Utc>,
+    ) -> Vec<TemperatureData> {
+        let temperatures: Vec<&&HueEventData> = updates
+            .iter()
+            .filter(|data| data.resource_type == "temperature")
+            .collect();
+
+        let ids: Vec<&str> = temperatures.iter().map(|data| data.id.as_str()).collect();
+        self.rediscover_if_needed(&ids);
+
+        temperatures
+            .into_iter()
+            .filter_map(|data| {
+                let report = data.temperature.as_ref()?.temperature_report.as_ref()?;
+                Some(self.temperature_data(&data.id, report, now))
+            })
+            .collect()
     }
 
-    fn get_bridge() -> Result<String, SensorError> {
-        log::info!("Getting bridge");
+    /// Stores the readings whose report has changed since it was last seen.
+    fn store_changed<T>(
+        &self,
+        data_store: &T,
+        temperatures: Vec<TemperatureData>,
+        last_reports: &mut HashMap<String, DateTime<Utc>>,
+    ) where
+        T: Storage<TemperatureData, Error = DatabaseError>,
+    {
+        let temperatures: Vec<TemperatureData> = temperatures
+            .into_iter()
+            .filter(|temp| last_reports.get(&temp.device_name) != Some(&temp.timestamp))
+            .collect();
+
+        if temperatures.is_empty() {
+            log::trace!("No changed temperature reports");
+            return;
+        }
 
-        // Try getting the config from hue-bridge
-        let response = ureq::get(format!("http://{HUE_DOMAIN}/api/0/config")).call()?;
+        metrics::record_readings(self.name(), &temperatures);
+
+        log::trace!("Changed temperatures: {temperatures:?}");
+
+        let reports: Vec<(String, DateTime<Utc>)> = temperatures
+            .iter()
+            .map(|temp| (temp.device_name.clone(), temp.timestamp))
+            .collect();
 
-        if response.status() == 200 {
-            log::info!("Got response from hue-bridge");
-            return Ok(HUE_DOMAIN.to_string());
+        match store::store_temperatures(data_store, temperatures) {
+            Ok(()) => last_reports.extend(reports),
+            Err(error) => log::error!("Error saving temperatures: {error}"),
         }
+    }
+
+    /// The ID of the bridge at `bridge_url`, in lower case as listed by discovery.
+    ///
+    /// The certificate is verified for the ID it names, and the bridge must then present a
+    /// certificate for the returned ID on every later connection.
+    pub fn get_bridge_id(config: &HueConfig, bridge_url: &str) -> Result<String, SensorError> {
+        let mut response = metrics::record_http(
+            metrics::HUE_SERVICE,
+            hue_tls::agent(config, None)?
+                .get(format!("{bridge_url}/api/0/config"))
+                .call(),
+        )?;
+
+        let config = response.body_mut().read_json::<HueBridgeConfig>()?;
+
+        Ok(config.bridgeid.to_lowercase())
+    }
+
+    /// The addresses of the Hue bridges, `bridge_domain` if it answers and otherwise every bridge
+    /// found by discovery
+    pub fn discover_bridges(config: &HueConfig) -> Result<Vec<String>, SensorError> {
+        log::info!("Getting bridges");
 
-        log::info!("No response from hue-bridge, trying discovery");
+        // Try getting the config from hue-bridge, falling back to discovery if it cannot be reached
+        match metrics::record_http(
+            metrics::HUE_SERVICE,
+            ureq::get(format!("http://{}/api/0/config", config.bridge_domain)).call(),
+        ) {
+            Ok(response) if response.status() == 200 => {
+                log::info!("Got response from hue-bridge");
+                return Ok(vec![config.bridge_domain.clone()]);
+            }
+            Ok(response) => {
+                log::info!(
+                    "Unexpected response {} from hue-bridge, trying discovery",
+                    response.status()
+                );
+            }
+            Err(error) => {
+                log::info!("No response from hue-bridge ({error}), trying discovery");
+            }
+        }
 
         // Make a GET request to the Hue discovery URL
-        let mut response = ureq::get(HUE_DISCOVERY_URL).call()?;
+        let mut response = ureq::get(&config.discovery_url).call()?;
 
         log::trace!("Got response");
 
@@ -112,28 +501,185 @@ where
 
         log::trace!("Parsed body");
 
-        // Get the IP address of the first bridge in the list
-        let ip_address = &body[0].internalipaddress;
+        if body.is_empty() {
+            return Err(SensorError::DeviceNotFound(
+                "No Hue bridges were discovered".to_string(),
+            ));
+        }
+
+        // Return the IP addresses of every bridge in the list
+        Ok(body
+            .into_iter()
+            .map(|bridge| bridge.internalipaddress)
+            .collect())
+    }
+
+    /// Adds the bridge ID to the names of sensors whose name is used on more than one bridge.
+    ///
+    /// The names are only compared across bridges here, so a name that a rename later makes
+    /// shared is not qualified until the backend restarts.
+    fn qualify_duplicate_names(bridges: &mut [Sensors]) {
+        let mut bridge_counts: HashMap<String, usize> = HashMap::new();
+        for bridge in bridges.iter() {
+            let sensors = bridge.sensors();
+            let names: HashSet<&String> = sensors.iter().map(|sensor| &sensor.name).collect();
+            for name in names {
+                *bridge_counts.entry(name.clone()).or_default() += 1;
+            }
+        }
+
+        let shared_names: HashSet<String> = bridge_counts
+            .into_iter()
+            .filter(|(_, count)| *count > 1)
+            .map(|(name, _)| name)
+            .collect();
+
+        for bridge in bridges.iter_mut() {
+            let sensors = bridge
+                .sensors
+                .get_mut()
+                .expect("Hue sensor list lock poisoned");
+            Sensors::qualify_names(&bridge.bridge_id, &shared_names, sensors);
+            bridge.shared_names = shared_names.clone();
+        }
+    }
+
+    /// Adds the bridge ID to the names of the bridge's sensors that are in `shared_names`
+    fn qualify_names(bridge_id: &str, shared_names: &HashSet<String>, sensors: &mut [Sensor]) {
+        for sensor in sensors {
+            if shared_names.contains(&sensor.name) {
+                let name = format!("{} ({bridge_id})", sensor.name);
+                log::info!(
+                    "Naming {} on bridge {bridge_id} {name} as the name is used on another bridge",
+                    sensor.name
+                );
+                sensor.name = name;
+            }
+        }
+    }
 
-        // Return the IP address as a String
-        Ok(ip_address.to_string())
+    fn sensors(&self) -> RwLockReadGuard<'_, Vec<Sensor>> {
+        self.sensors.read().expect("Hue sensor list lock poisoned")
+    }
+
+    /// Reads the device list again if `rediscovery_interval` has passed, or if a temperature
+    /// resource belongs to no known sensor, so that new and renamed sensors are picked up.
+    ///
+    /// A resource that is still unknown afterwards is not looked up again until the next
+    /// scheduled read.
+    fn rediscover_if_needed(&self, temperature_ids: &[&str]) {
+        let due = self
+            .last_discovery
+            .lock()
+            .expect("Hue discovery mutex poisoned")
+            .elapsed()
+            >= self.rediscovery_interval;
+
+        let unknown: Vec<&str> = {
+            let sensors = self.sensors();
+            temperature_ids
+                .iter()
+                .copied()
+                .filter(|id| !sensors.iter().any(|sensor| sensor.id == *id))
+                .collect()
+        };
+
+        let mut unknown_ids = self
+            .unknown_ids
+            .lock()
+            .expect("Hue unknown ID mutex poisoned");
+        if due {
+            unknown_ids.clear();
+        }
+
+        let new_unknown: Vec<&str> = unknown
+            .iter()
+            .copied()
+            .filter(|id| !unknown_ids.contains(*id))
+            .collect();
+
+        if !new_unknown.is_empty() {
+            log::info!("Reading the Hue device list again for unknown sensors {new_unknown:?}");
+        } else if !due {
+            return;
+        }
+
+        self.rediscover();
+
+        let sensors = self.sensors();
+        unknown_ids.extend(
+            new_unknown
+                .into_iter()
+                .filter(|id| !sensors.iter().any(|sensor| sensor.id == *id))
+                .map(str::to_string),
+        );
+    }
+
+    /// Reads the device list again, recording the sensors that have been renamed
+    fn rediscover(&self) {
+        // A bridge that cannot list its devices is not asked again before the next scheduled read
+        *self
+            .last_discovery
+            .lock()
+            .expect("Hue discovery mutex poisoned") = Instant::now();
+
+        let mut sensors =
+            match Sensors::get_sensors(&self.agent, &self.bridge_url, &self.hue_application_key) {
+                Ok(sensors) => sensors,
+                Err(error) => {
+                    log::error!("Error reading the Hue device list: {error}");
+                    return;
+                }
+            };
+        Sensors::qualify_names(&self.bridge_id, &self.shared_names, &mut sensors);
+
+        let now = Utc::now();
+        let renames: Vec<DeviceRename> = self
+            .sensors()
+            .iter()
+            .filter_map(|previous| {
+                let sensor = sensors
+                    .iter()
+                    .find(|sensor| sensor.id == previous.id && sensor.name != previous.name)?;
+                log::info!("Hue sensor {} was renamed {}", previous.name, sensor.name);
+
+                Some(DeviceRename {
+                    device_name: sensor.name.clone(),
+                    previous_name: previous.name.clone(),
+                    bridge_id: self.bridge_id.clone(),
+                    device_id: sensor.device_id.clone(),
+                    timestamp: now,
+                })
+            })
+            .collect();
+
+        *self.sensors.write().expect("Hue sensor list lock poisoned") = sensors;
+
+        if let Some(stores) = &self.stores
+            && !renames.is_empty()
+            && let Err(error) = stores.renames.save_items(&renames)
+        {
+            log::error!("Error saving Hue sensor renames: {error}");
+        }
     }
 
     fn get_sensors(
-        bridge_ip_address: &str,
+        agent: &Agent,
+        bridge_url: &str,
         hue_application_key: &str,
     ) -> Result<Vec<Sensor>, SensorError> {
         log::debug!("Getting sensors");
-        let hue_device_url = format!("https://{bridge_ip_address}{HUE_DEVICE_URL}");
+        let hue_device_url = format!("{bridge_url}{HUE_DEVICE_URL}");
         log::debug!("Hue Device URL: {hue_device_url}");
 
         // Make a GET request to the Hue device URL
-        let mut response = ureq::get(&hue_device_url)
-            .header(HUE_APPLICATION_KEY_HEADER, hue_application_key)
-            .config()
-            .tls_config(TlsConfig::builder().disable_verification(true).build())
-            .build()
-            .call()?;
+        let mut response = metrics::record_http(
+            metrics::HUE_SERVICE,
+            agent
+                .get(&hue_device_url)
+                .header(HUE_APPLICATION_KEY_HEADER, hue_application_key)
+                .call(),
+        )?;
         log::trace!("Got response");
 
         // Parse the response body into a Device struct
@@ -141,6 +687,13 @@ where
 
         log::trace!("Parsed body");
 
+        // Rooms are only for the device registry, so sensors are still read without them
+        let rooms =
+            Sensors::get_rooms(agent, bridge_url, hue_application_key).unwrap_or_else(|error| {
+                log::warn!("Error getting the Hue rooms: {error}");
+                HashMap::new()
+            });
+
         // Create a vector of Sensor structs filtering out sensors that are not temperature sensors (services[n].rtype == "temperature")
         let sensors: Vec<Sensor> = body
             .data
@@ -165,6 +718,23 @@ where
                         String::new()
                     }),
                 name: device.metadata.name.clone(),
+                device_id: device.id.clone(),
+                model: device
+                    .product_data
+                    .as_ref()
+                    .map(|product_data| product_data.model_id.clone()),
+                room: rooms.get(&device.id).cloned(),
+                service_ids: device
+                    .services
+                    .iter()
+                    .filter(|service| HUE_SERVICE_TYPES.contains(&service.rtype.as_str()))
+                    .map(|service| service.rid.clone())
+                    .collect(),
+                connectivity_id: device
+                    .services
+                    .iter()
+                    .find(|service| service.rtype == "zigbee_connectivity")
+                    .map(|service| service.rid.clone()),
             })
             .collect();
 
@@ -202,21 +772,111 @@ where
         Ok(sensors)
     }
 
+    /// The name of the room each device is in, by device ID
+    fn get_rooms(
+        agent: &Agent,
+        bridge_url: &str,
+        hue_application_key: &str,
+    ) -> Result<HashMap<String, String>, SensorError> {
+        let mut response = metrics::record_http(
+            metrics::HUE_SERVICE,
+            agent
+                .get(format!("{bridge_url}{HUE_ROOM_URL}"))
+                .header(HUE_APPLICATION_KEY_HEADER, hue_application_key)
+                .call(),
+        )?;
+
+        let body = response.body_mut().read_json::<HueRoomList>()?;
+
+        Ok(body
+            .data
+            .into_iter()
+            .flat_map(|room| {
+                room.children
+                    .into_iter()
+                    .filter(|child| child.rtype == "device")
+                    .map(move |child| (child.rid, room.metadata.name.clone()))
+            })
+            .collect())
+    }
+
+    /// Registers the sensors the readings were taken from
+    fn register(&self, readings: &[TemperatureData], now: DateTime<Utc>) {
+        let Some(registry) = &self.registry else {
+            return;
+        };
+
+        let sensors = self.sensors();
+        let devices: Vec<DeviceRecord> = sensors
+            .iter()
+            .filter(|sensor| {
+                readings
+                    .iter()
+                    .any(|reading| reading.device_id.as_ref() == Some(&sensor.device_id))
+            })
+            .map(|sensor| DeviceRecord {
+                device_id: sensor.device_id.clone(),
+                vendor: "hue".to_string(),
+                model: sensor.model.clone(),
+                room: sensor.room.clone(),
+                display_name: sensor.name.clone(),
+                bridge_id: Some(self.bridge_id.clone()),
+                first_seen: now,
+                last_seen: now,
+            })
+            .collect();
+
+        registry.seen(devices, now);
+    }
+
+    /// Polls the temperature resources, and with `services` the connectivity of the sensors for
+    /// their online status and their motion, light level and device power resources
+    fn poll(&self, services: bool) -> Result<Vec<TemperatureData>, SensorError> {
+        if services {
+            self.poll_connectivity();
+        }
+
+        let temperatures = self.get_temperatures()?;
+
+        if services {
+            self.poll_services();
+        }
+
+        Ok(temperatures)
+    }
+
+    /// Whether a poll should also read the connectivity and services, which change less often
+    /// than the temperatures, noting the time if so
+    fn services_due(&self) -> bool {
+        let mut last_poll = self
+            .last_services_poll
+            .lock()
+            .expect("Hue services poll mutex poisoned");
+
+        if last_poll.is_some_and(|last_poll| last_poll.elapsed() < self.services_poll_interval) {
+            return false;
+        }
+
+        *last_poll = Some(Instant::now());
+        true
+    }
+
+    /// Polls the temperature resources
     fn get_temperatures(&self) -> Result<Vec<TemperatureData>, SensorError> {
         log::debug!("Getting temperatures");
 
-        let hue_temperature_url =
-            format!("https://{}{}", self.bridge_ip_address, HUE_TEMPERATURE_URL);
+        let hue_temperature_url = format!("{}{}", self.bridge_url, HUE_TEMPERATURE_URL);
 
         log::debug!("Hue Temperature URL: {hue_temperature_url}");
 
         // Request the temperature data for all sensors
-        let mut response = ureq::get(hue_temperature_url)
-            .header(HUE_APPLICATION_KEY_HEADER, &self.hue_application_key)
-            .config()
-            .tls_config(TlsConfig::builder().disable_verification(true).build())
-            .build()
-            .call()?;
+        let mut response = metrics::record_http(
+            metrics::HUE_SERVICE,
+            self.agent
+                .get(hue_temperature_url)
+                .header(HUE_APPLICATION_KEY_HEADER, &self.hue_application_key)
+                .call(),
+        )?;
         log::trace!("Got response");
 
         // Parse the response body into a Temperatures struct
@@ -224,28 +884,386 @@ where
 
         log::trace!("Parsed body");
 
+        let ids: Vec<&str> = body
+            .data
+            .iter()
+            .map(|temperature| temperature.id.as_str())
+            .collect();
+        self.rediscover_if_needed(&ids);
+
         // Create a vector of Temperature structs
+        let now = Utc::now();
         let temperatures: Vec<TemperatureData> = body
             .data
             .iter()
-            .map(|temperature| TemperatureData {
-                device_name: self
-                    .sensors
-                    .iter()
-                    .find(|sensor| sensor.id == temperature.id)
-                    .map(|sensor| sensor.name.clone())
-                    .unwrap_or_else(|| {
-                        log::warn!("Sensor not found for temperature data: {}", temperature.id);
-                        "Unknown".to_string()
-                    }),
-                online: true,
-                timestamp: temperature.temperature.temperature_report.changed,
-                temperature: temperature.temperature.temperature_report.temperature,
-                humidity: 0.0,
+            .map(|temperature| {
+                self.temperature_data(
+                    &temperature.id,
+                    &temperature.temperature.temperature_report,
+                    now,
+                )
             })
             .collect();
 
+        self.register(&temperatures, now);
+
         // Return the vector of Temperature structs
         Ok(temperatures)
     }
+
+    /// Polls the motion, light level and device power resources and records them, if there are
+    /// stores for them.
+    ///
+    /// Errors are logged rather than returned so they never hold up the temperatures.
+    fn poll_services(&self) {
+        if self.stores.is_none() {
+            return;
+        }
+
+        let mut resources = Vec::new();
+        for url in [HUE_MOTION_URL, HUE_LIGHT_LEVEL_URL, HUE_DEVICE_POWER_URL] {
+            match self.get_resources(url) {
+                Ok(list) => resources.extend(list),
+                Err(error) => log::error!("Error getting {url}: {error}"),
+            }
+        }
+
+        self.record_services(&resources.iter().collect::<Vec<_>>(), Utc::now());
+    }
+
+    /// Polls the sensors' `zigbee_connectivity` resources, keeping the connectivity last seen if
+    /// they cannot be read
+    fn poll_connectivity(&self) {
+        match self.get_resources(HUE_ZIGBEE_CONNECTIVITY_URL) {
+            Ok(resources) => self.update_connectivity(&resources.iter().collect::<Vec<_>>()),
+            Err(error) => log::error!("Error getting {HUE_ZIGBEE_CONNECTIVITY_URL}: {error}"),
+        }
+    }
+
+    /// The resources listed at `url`, one of the CLIP v2 resource URLs
+    fn get_resources(&self, url: &str) -> Result<Vec<HueEventData>, SensorError> {
+        log::debug!("Getting {url}");
+
+        let mut response = metrics::record_http(
+            metrics::HUE_SERVICE,
+            self.agent
+                .get(format!("{}{url}", self.bridge_url))
+                .header(HUE_APPLICATION_KEY_HEADER, &self.hue_application_key)
+                .call(),
+        )?;
+
+        Ok(response.body_mut().read_json::<HueResourceList>()?.data)
+    }
+
+    /// Records whether the bridge can reach each sensor from its `zigbee_connectivity` resource
+    fn update_connectivity(&self, resources: &[&HueEventData]) {
+        let mut statuses = self.statuses.lock().expect("Hue status mutex poisoned");
+        let sensors = self.sensors();
+
+        for resource in resources {
+            if resource.resource_type != "zigbee_connectivity" {
+                continue;
+            }
+            let Some(connectivity) = &resource.status else {
+                continue;
+            };
+            let Some(sensor) = sensors
+                .iter()
+                .find(|sensor| sensor.connectivity_id.as_ref() == Some(&resource.id))
+            else {
+                continue;
+            };
+
+            // A sensor the bridge cannot send to can still send its reports
+            let connected = matches!(
+                connectivity.as_str(),
+                "connected" | "unidirectional_incoming"
+            );
+            log::trace!("Hue sensor {} is {connectivity}", sensor.name);
+
+            statuses.entry(sensor.id.clone()).or_default().connected = Some(connected);
+        }
+    }
+
+    /// Stores the motion and light level reports and changed batteries of the sensors' services.
+    ///
+    /// Batteries have no report time, so they are recorded at `now`.
+    fn record_services(&self, resources: &[&HueEventData], now: DateTime<Utc>) {
+        let Some(stores) = &self.stores else {
+            return;
+        };
+
+        let mut motion = Vec::new();
+        let mut light_levels = Vec::new();
+        let mut batteries = Vec::new();
+        let sensors = self.sensors();
+
+        for resource in resources {
+            // Services of devices without a temperature sensor are ignored
+            let Some(sensor) = sensors
+                .iter()
+                .find(|sensor| sensor.service_ids.contains(&resource.id))
+            else {
+                continue;
+            };
+
+            if let Some(report) = resource
+                .motion
+                .as_ref()
+                .and_then(|motion| motion.motion_report.as_ref())
+            {
+                motion.push(MotionData {
+                    device_name: sensor.name.clone(),
+                    device_id: sensor.device_id.clone(),
+                    bridge_id: self.bridge_id.clone(),
+                    timestamp: report.changed,
+                    motion: report.motion,
+                });
+            }
+
+            if let Some(report) = resource
+                .light
+                .as_ref()
+                .and_then(|light| light.light_level_report.as_ref())
+            {
+                light_levels.push(LightLevelData {
+                    device_name: sensor.name.clone(),
+                    device_id: sensor.device_id.clone(),
+                    bridge_id: self.bridge_id.clone(),
+                    timestamp: report.changed,
+                    light_level: report.light_level,
+                    lux: lux(report.light_level),
+                });
+            }
+
+            if let Some(power_state) = &resource.power_state
+                && let Some(battery_level) = power_state.battery_level
+            {
+                batteries.push(BatteryData {
+                    device_name: sensor.name.clone(),
+                    device_id: sensor.device_id.clone(),
+                    bridge_id: self.bridge_id.clone(),
+                    timestamp: now,
+                    battery_level,
+                    battery_state: power_state.battery_state.clone().unwrap_or_default(),
+                });
+            }
+        }
+
+        log::trace!("Motion: {motion:?}, light levels: {light_levels:?}, batteries: {batteries:?}");
+
+        if let Err(error) = store::store_readings(stores.motion.as_ref(), motion) {
+            log::error!("Error saving Hue motion: {error}");
+        }
+
+        if let Err(error) = store::store_readings(stores.light_level.as_ref(), light_levels) {
+            log::error!("Error saving Hue light levels: {error}");
+        }
+
+        let mut last_batteries = self
+            .last_batteries
+            .lock()
+            .expect("Hue battery mutex poisoned");
+        let batteries: Vec<BatteryData> = batteries
+            .into_iter()
+            .filter(|battery| {
+                last_batteries.get(&battery.device_name)
+                    != Some(&(battery.battery_level, battery.battery_state.clone()))
+            })
+            .collect();
+        let changed: Vec<(String, (u8, String))> = batteries
+            .iter()
+            .map(|battery| {
+                (
+                    battery.device_name.clone(),
+                    (battery.battery_level, battery.battery_state.clone()),
+                )
+            })
+            .collect();
+
+        match store::store_readings(stores.battery.as_ref(), batteries) {
+            Ok(()) => last_batteries.extend(changed),
+            Err(error) => log::error!("Error saving Hue batteries: {error}"),
+        }
+    }
+
+    /// Maps a temperature report for a temperature resource ID onto a reading.
+    fn temperature_data(
+        &self,
+        id: &str,
+        report: &TemperatureReport,
+        now: DateTime<Utc>,
+    ) -> TemperatureData {
+        let mut statuses = self.statuses.lock().expect("Hue status mutex poisoned");
+        let status = statuses.entry(id.to_string()).or_default();
+        status.report = Some((report.changed, report.temperature));
+
+        self.reading(id, status, report.changed, report.temperature, now)
+    }
+
+    /// Readings for the sensors that have gone online or offline without a new report, because
+    /// the bridge lost or found them or their latest report went stale
+    fn status_changes(&self, now: DateTime<Utc>) -> Vec<TemperatureData> {
+        let mut statuses = self.statuses.lock().expect("Hue status mutex poisoned");
+
+        statuses
+            .iter_mut()
+            .filter_map(|(id, status)| {
+                let (changed, temperature) = status.report?;
+                let was_online = status.online;
+                let reading = self.reading(id, status, changed, temperature, now);
+                (was_online != Some(reading.online)).then_some(reading)
+            })
+            .collect()
+    }
+
+    /// The sensor's latest report as a reading, online if the bridge can reach the sensor and
+    /// the report is not stale.
+    ///
+    /// A reading that changes the sensor's online status is stamped `now` rather than with the
+    /// report time, so that the change is stored even though the report is not new.
+    fn reading(
+        &self,
+        id: &str,
+        status: &mut SensorStatus,
+        changed: DateTime<Utc>,
+        temperature: f32,
+        now: DateTime<Utc>,
+    ) -> TemperatureData {
+        let (device_name, device_id) = self
+            .sensors()
+            .iter()
+            .find(|sensor| sensor.id == id)
+            .map(|sensor| (sensor.name.clone(), Some(sensor.device_id.clone())))
+            .unwrap_or_else(|| {
+                log::warn!("Sensor not found for temperature data: {id}");
+                ("Unknown".to_string(), None)
+            });
+
+        let online = status.connected.unwrap_or(true) && now - changed <= self.stale_after;
+        let timestamp = match status.online {
+            Some(was_online) if was_online != online => {
+                log::info!(
+                    "Hue sensor {device_name} is now {}",
+                    if online { "online" } else { "offline" }
+                );
+                now.max(changed)
+            }
+            _ => changed,
+        };
+        status.online = Some(online);
+
+        TemperatureData {
+            device_name,
+            device_id,
+            bridge_id: Some(self.bridge_id.clone()),
+            source: Some("hue".to_string()),
+            online,
+            timestamp,
+            temperature,
+            humidity: 0.0,
+        }
+    }
+}
+
+impl SensorSource for Sensors {
+    fn name(&self) -> &str {
+        "Hue"
+    }
+
+    fn interval(&self) -> Duration {
+        self.poll_interval
+    }
+
+    fn fetch_readings(&self) -> Result<Vec<TemperatureData>, SensorError> {
+        self.poll(self.services_due())
+    }
+}
+
+/// Converts a Hue light level, 10000 * log10(lux) + 1, into lux
+fn lux(light_level: u32) -> f32 {
+    10f32.powf(light_level.saturating_sub(1) as f32 / 10000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::SubsecRound;
+
+    use super::*;
+
+    use crate::datastore::memory::InMemoryStorage;
+    use crate::tests::fake_hue::{APPLICATION_KEY, FakeHueBridge, hue_config};
+
+    const EVENT: &str = r#"[{"type": "update", "data": [{"id": "t1", "type": "temperature", "temperature": {"temperature": 21.0, "temperature_report": {"changed": "2026-01-01T00:05:00Z", "temperature": 21.0}}}]}]"#;
+    const CONNECTIVITY_EVENT: &str = r#"[{"type": "update", "data": [{"id": "zigbee_connectivity-t1", "type": "zigbee_connectivity", "status": "connectivity_issue"}]}]"#;
+    const LIGHT_EVENT: &str =
+        r#"[{"type": "update", "data": [{"id": "l1", "type": "light", "on": {"on": true}}]}]"#;
+
+    #[test]
+    fn event_stream_stores_only_changed_reports() {
+        let bridge = FakeHueBridge::start();
+        bridge.set_device("Lounge", "t1");
+        bridge.set_temperature("t1", "2026-01-01T00:00:00Z", 20.0);
+        bridge.set_events(&format!(
+            ": hi\n\nid: 1:0\ndata: {EVENT}\n\nid: 2:0\ndata: {EVENT}\n\nid: 3:0\ndata: {LIGHT_EVENT}\n\n"
+        ));
+
+        let storage = InMemoryStorage::new(&["device_name", "timestamp"]);
+        let sensors =
+            Sensors::with_bridge_url(&hue_config(), &bridge.server.url, APPLICATION_KEY).unwrap();
+
+        let mut last_reports = HashMap::new();
+        sensors
+            .stream_events(&storage, &mut last_reports, &Shutdown::new())
+            .unwrap();
+
+        let items = storage.items().unwrap();
+        let readings: Vec<(&str, f32)> = items
+            .iter()
+            .map(|item| (item.device_name.as_str(), item.temperature))
+            .collect();
+
+        // The resync poll followed by the first event, with the repeated event skipped
+        assert_eq!(readings, vec![("Lounge", 20.0), ("Lounge", 21.0)]);
+    }
+
+    #[test]
+    fn connectivity_events_store_the_sensor_going_offline() {
+        let reported = Utc::now().trunc_subsecs(0);
+        let bridge = FakeHueBridge::start();
+        bridge.set_device("Lounge", "t1");
+        bridge.set_temperature("t1", &reported.to_rfc3339(), 20.0);
+        bridge.set_events(&format!("id: 1:0\ndata: {CONNECTIVITY_EVENT}\n\n"));
+
+        let storage = InMemoryStorage::new(&["device_name", "timestamp"]);
+        let sensors =
+            Sensors::with_bridge_url(&hue_config(), &bridge.server.url, APPLICATION_KEY).unwrap();
+
+        sensors
+            .stream_events(&storage, &mut HashMap::new(), &Shutdown::new())
+            .unwrap();
+
+        let items = storage.items().unwrap();
+        let statuses: Vec<(bool, f32)> = items
+            .iter()
+            .map(|item| (item.online, item.temperature))
+            .collect();
+
+        assert_eq!(statuses, vec![(true, 20.0), (false, 20.0)]);
+        assert!(items[1].timestamp > reported);
+    }
+
+    #[test]
+    fn renames_stored_with_a_resource_id_are_read() {
+        let rename: DeviceRename = bson::deserialize_from_document(bson::doc! {
+            "device_name": "Guest",
+            "previous_name": "Bedroom",
+            "bridge_id": "bridge",
+            "resource_id": "t1",
+            "timestamp": bson::DateTime::from_millis(0),
+        })
+        .unwrap();
+
+        assert_eq!(rename.device_id, "t1");
+    }
 }
@@ -0,0 +1,19 @@
+use std::time::Duration;
+
+use super::errors::SensorError;
+use super::models::TemperatureData;
+
+/// A vendor of temperature readings that can be polled by the `Scheduler`.
+///
+/// Adding a new kind of sensor only requires implementing this trait and adding the source to
+/// the scheduler, which takes care of timing, error counting and storage.
+pub trait SensorSource: Send + Sync {
+    /// A short name for the source, used in logs
+    fn name(&self) -> &str;
+
+    /// How long to wait between polls
+    fn interval(&self) -> Duration;
+
+    /// Fetches the current readings from the source
+    fn fetch_readings(&self) -> Result<Vec<TemperatureData>, SensorError>;
+}
@@ -1,45 +1,233 @@
 use chrono::{DateTime, Utc};
-use std::collections::HashMap;
+use serde::{Serialize, de::DeserializeOwned};
 
 use crate::database::errors::DatabaseError;
 use crate::datastore::storage::Storage;
 
-use super::models::TemperatureData;
+use super::models::{BatteryData, LightLevelData, MotionData, Rollup, TemperatureData};
 
-/// Persist readings that are newer than the latest stored timestamp per `device_name`.
+/// A reading kept once per device and timestamp
+pub trait Reading: Serialize + DeserializeOwned + Unpin + Send + Sync + 'static {
+    fn device_name(&self) -> &str;
+    /// The device's stable ID, if the reading has one
+    fn device_id(&self) -> Option<&str>;
+    fn timestamp(&self) -> DateTime<Utc>;
+}
+
+impl Reading for TemperatureData {
+    fn device_name(&self) -> &str {
+        &self.device_name
+    }
+
+    fn device_id(&self) -> Option<&str> {
+        self.device_id.as_deref()
+    }
+
+    fn timestamp(&self) -> DateTime<Utc> {
+        self.timestamp
+    }
+}
+
+impl Reading for MotionData {
+    fn device_name(&self) -> &str {
+        &self.device_name
+    }
+
+    fn device_id(&self) -> Option<&str> {
+        Some(&self.device_id)
+    }
+
+    fn timestamp(&self) -> DateTime<Utc> {
+        self.timestamp
+    }
+}
+
+impl Reading for LightLevelData {
+    fn device_name(&self) -> &str {
+        &self.device_name
+    }
+
+    fn device_id(&self) -> Option<&str> {
+        Some(&self.device_id)
+    }
+
+    fn timestamp(&self) -> DateTime<Utc> {
+        self.timestamp
+    }
+}
+
+impl Reading for BatteryData {
+    fn device_name(&self) -> &str {
+        &self.device_name
+    }
+
+    fn device_id(&self) -> Option<&str> {
+        Some(&self.device_id)
+    }
+
+    fn timestamp(&self) -> DateTime<Utc> {
+        self.timestamp
+    }
+}
+
+impl Reading for Rollup {
+    fn device_name(&self) -> &str {
+        &self.device_name
+    }
+
+    fn device_id(&self) -> Option<&str> {
+        self.device_id.as_deref()
+    }
+
+    fn timestamp(&self) -> DateTime<Utc> {
+        self.timestamp
+    }
+}
+
+/// Persist readings that are newer than the latest stored timestamp of their device.
 pub fn store_temperatures<T>(
     data_store: &T,
     temperatures: Vec<TemperatureData>,
 ) -> Result<(), DatabaseError>
 where
-    T: Storage<TemperatureData, Error = DatabaseError>,
+    T: Storage<TemperatureData, Error = DatabaseError> + ?Sized,
 {
     log::debug!("Storing temperatures");
 
-    let latest_items = data_store.get_latest_items("device_name", "timestamp")?;
-
-    log::trace!("Latest items: {latest_items:?}");
+    store_readings(data_store, temperatures)
+}
 
-    let temp_map = latest_items
-        .into_iter()
-        .map(|item| (item.device_name.clone(), item.timestamp))
-        .collect::<HashMap<String, DateTime<Utc>>>();
+/// Persist readings of any kind that are newer than the latest stored timestamp of their device.
+///
+/// Devices are told apart by `device_id`, so that a device renamed to the name another device
+/// used to have is not mistaken for it. A device with no readings stored under its ID yet falls
+/// back to the latest timestamp under its `device_name` of the readings stored before readings
+/// had IDs.
+pub fn store_readings<T, R>(data_store: &T, readings: Vec<R>) -> Result<(), DatabaseError>
+where
+    T: Storage<R, Error = DatabaseError> + ?Sized,
+    R: Reading,
+{
+    let latest = data_store.get_latest_timestamps("device_id", "device_name", "timestamp")?;
 
-    log::trace!("Temp map: {temp_map:?}");
+    log::trace!("Latest timestamps: {latest:?}");
 
-    let temperatures: Vec<TemperatureData> = temperatures
+    let readings: Vec<R> = readings
         .into_iter()
-        .filter(|temp| {
-            !temp_map.contains_key(&temp.device_name) || temp_map[&temp.device_name] < temp.timestamp
+        .filter(|reading| {
+            reading
+                .device_id()
+                .and_then(|device_id| latest.by_id.get(device_id))
+                .or_else(|| latest.by_name.get(reading.device_name()))
+                .is_none_or(|timestamp| *timestamp < reading.timestamp())
         })
         .collect();
 
-    if !temperatures.is_empty() {
-        data_store.save_items(&temperatures)?;
-        log::debug!("Stored {} new temperature record(s)", temperatures.len());
-    } else {
-        log::debug!("No new temperatures to store");
+    if readings.is_empty() {
+        log::debug!("No new records to store");
+        return Ok(());
+    }
+
+    // Readings the unique index rejects were already stored, and the rest have been
+    match data_store.save_items(&readings) {
+        Ok(()) => log::debug!("Stored {} new record(s)", readings.len()),
+        Err(DatabaseError::DuplicateKey(duplicates)) => log::debug!(
+            "Stored {} new record(s), {duplicates} were already stored",
+            readings.len().saturating_sub(duplicates)
+        ),
+        Err(error) => return Err(error),
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::Timelike;
+
+    use super::*;
+
+    use crate::datastore::memory::InMemoryStorage;
+    use crate::tests::harness::{at_minute, reading};
+
+    #[test]
+    fn only_newer_readings_are_stored() {
+        let storage = InMemoryStorage::new(&["device_name", "timestamp"]);
+        store_temperatures(
+            &storage,
+            vec![
+                reading("Lounge", at_minute(5), 20.0),
+                reading("Kitchen", at_minute(5), 20.0),
+            ],
+        )
+        .unwrap();
+
+        // An unchanged, an older and a newer reading, plus one from a new device
+        store_temperatures(
+            &storage,
+            vec![
+                reading("Lounge", at_minute(5), 20.0),
+                reading("Kitchen", at_minute(4), 20.0),
+                reading("Kitchen", at_minute(6), 20.0),
+                reading("Hall", at_minute(1), 20.0),
+            ],
+        )
+        .unwrap();
+
+        let stored: Vec<(String, u32)> = storage
+            .items()
+            .unwrap()
+            .into_iter()
+            .map(|item| (item.device_name, item.timestamp.minute()))
+            .collect();
+
+        assert_eq!(
+            stored,
+            vec![
+                ("Lounge".to_string(), 5),
+                ("Kitchen".to_string(), 5),
+                ("Kitchen".to_string(), 6),
+                ("Hall".to_string(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn readings_are_kept_once_per_device_id_falling_back_to_their_name() {
+        let storage = InMemoryStorage::new(&["device_id", "timestamp"]);
+        let with_id = |device_name, device_id: &str, minute, temperature| TemperatureData {
+            device_id: Some(device_id.to_string()),
+            ..reading(device_name, at_minute(minute), temperature)
+        };
+        storage
+            .save_items(&[
+                with_id("Bedroom", "a", 5, 20.0),
+                reading("Lounge", at_minute(5), 20.0),
+            ])
+            .unwrap();
+
+        // The Bedroom's last reading again after it was renamed, another device renamed to
+        // Bedroom with readings from before the Bedroom's last, and the Lounge's first readings
+        // with an ID
+        store_temperatures(
+            &storage,
+            vec![
+                with_id("Guest", "a", 5, 20.0),
+                with_id("Bedroom", "b", 4, 17.0),
+                with_id("Bedroom", "b", 6, 18.0),
+                with_id("Lounge", "c", 5, 21.0),
+                with_id("Lounge", "c", 6, 22.0),
+            ],
+        )
+        .unwrap();
+
+        let stored: Vec<f32> = storage
+            .items()
+            .unwrap()
+            .into_iter()
+            .map(|item| item.temperature)
+            .collect();
+
+        assert_eq!(stored, vec![20.0, 20.0, 17.0, 18.0, 22.0]);
+    }
+}
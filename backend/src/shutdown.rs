@@ -0,0 +1,82 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+/// A shutdown flag shared between threads.
+///
+/// Threads wait on it instead of sleeping so they wake as soon as shutdown is requested, rather
+/// than at the end of their current interval.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    state: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Shutdown::default()
+    }
+
+    /// Triggers shutdown when SIGTERM or SIGINT is received
+    pub fn register_signals(&self) -> Result<(), std::io::Error> {
+        let mut signals = Signals::new([SIGTERM, SIGINT])?;
+        let shutdown = self.clone();
+
+        thread::Builder::new()
+            .name("signals".to_string())
+            .spawn(move || {
+                if let Some(signal) = signals.forever().next() {
+                    log::info!("Received signal {signal}, shutting down");
+                    shutdown.trigger();
+                }
+            })?;
+
+        Ok(())
+    }
+
+    /// Requests shutdown and wakes every waiting thread
+    pub fn trigger(&self) {
+        let (lock, condvar) = &*self.state;
+        *lock.lock().expect("Shutdown mutex poisoned") = true;
+        condvar.notify_all();
+    }
+
+    /// Whether shutdown has been requested
+    pub fn is_triggered(&self) -> bool {
+        let (lock, _) = &*self.state;
+        *lock.lock().expect("Shutdown mutex poisoned")
+    }
+
+    /// Waits until shutdown is requested
+    pub fn wait(&self) {
+        let (lock, condvar) = &*self.state;
+        let _guard = condvar
+            .wait_while(lock.lock().expect("Shutdown mutex poisoned"), |triggered| {
+                !*triggered
+            })
+            .expect("Shutdown mutex poisoned");
+    }
+
+    /// Waits for up to `timeout`, returning true if shutdown was requested
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let (lock, condvar) = &*self.state;
+        let deadline = Instant::now() + timeout;
+        let mut triggered = lock.lock().expect("Shutdown mutex poisoned");
+
+        // Loop to guard against spurious wake-ups
+        while !*triggered {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            triggered = condvar
+                .wait_timeout(triggered, remaining)
+                .expect("Shutdown mutex poisoned")
+                .0;
+        }
+
+        *triggered
+    }
+}
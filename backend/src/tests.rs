@@ -0,0 +1,9 @@
+//! Integration tests that run the sensor sources against local fake servers.
+
+pub mod fake_google;
+pub mod fake_hue;
+pub mod harness;
+
+mod hue;
+mod nest;
+mod nest_auth;
@@ -0,0 +1,296 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+
+use super::harness::{FakeResponse, FakeServer, temp_file};
+
+use crate::config::settings::NestConfig;
+use crate::sensor_control::models::NestCredentials;
+
+pub const PROJECT_ID: &str = "test-project";
+pub const REFRESH_TOKEN: &str = "test-refresh-token";
+pub const SUBSCRIPTION: &str = "projects/test-gcp-project/subscriptions/nest-events";
+/// The authorization code the token endpoint accepts, as if the user had just consented
+pub const AUTHORIZATION_CODE: &str = "test-authorization-code";
+pub const NEW_REFRESH_TOKEN: &str = "new-refresh-token";
+/// The refresh token issued by `rotate_refresh_token`
+pub const ROTATED_REFRESH_TOKEN: &str = "rotated-refresh-token";
+
+struct GoogleState {
+    refresh_token_revoked: bool,
+    refresh_token: String,
+    rotate_refresh_token: bool,
+    refresh_token_expires_in: Option<u64>,
+    tokens_issued: u32,
+    valid_access_tokens: Vec<String>,
+    devices: String,
+    pubsub_available: bool,
+    /// Events waiting to be pulled, with their ack IDs
+    events: Vec<(String, String)>,
+    events_published: u32,
+}
+
+impl Default for GoogleState {
+    fn default() -> Self {
+        GoogleState {
+            refresh_token_revoked: false,
+            refresh_token: REFRESH_TOKEN.to_string(),
+            rotate_refresh_token: false,
+            refresh_token_expires_in: None,
+            tokens_issued: 0,
+            valid_access_tokens: Vec::new(),
+            devices: DEVICES.to_string(),
+            pubsub_available: true,
+            events: Vec::new(),
+            events_published: 0,
+        }
+    }
+}
+
+/// A fake Google OAuth token endpoint (`/token`), SDM API (`/v1/enterprises/...`) and Pub/Sub
+/// subscription over HTTP
+pub struct FakeGoogle {
+    pub server: FakeServer,
+    state: Arc<Mutex<GoogleState>>,
+}
+
+impl FakeGoogle {
+    pub fn start() -> Self {
+        let state = Arc::new(Mutex::new(GoogleState::default()));
+        let handler_state = Arc::clone(&state);
+
+        let server = FakeServer::http(move |request| {
+            let mut state = handler_state.lock().unwrap();
+
+            if request.method == "POST" && request.path == "/token" {
+                let form: Vec<(String, String)> = form_urlencoded::parse(request.body.as_bytes())
+                    .into_owned()
+                    .collect();
+                let field = |name: &str| {
+                    form.iter()
+                        .find(|(key, _)| key == name)
+                        .map(|(_, value)| value.as_str())
+                };
+
+                if field("grant_type") == Some("authorization_code") {
+                    if field("code") != Some(AUTHORIZATION_CODE) {
+                        return FakeResponse::json(
+                            400,
+                            r#"{"error": "invalid_grant", "error_description": "Malformed auth code."}"#,
+                        );
+                    }
+
+                    return FakeResponse::json(
+                        200,
+                        serde_json::json!({
+                            "access_token": "access-token-from-code",
+                            "expires_in": 3599,
+                            "refresh_token": NEW_REFRESH_TOKEN,
+                            "refresh_token_expires_in": 604_799,
+                            "token_type": "Bearer",
+                        })
+                        .to_string(),
+                    );
+                }
+
+                let refresh_token = field("refresh_token");
+
+                if state.refresh_token_revoked || refresh_token != Some(&state.refresh_token) {
+                    return FakeResponse::json(
+                        400,
+                        r#"{"error": "invalid_grant", "error_description": "Token has been expired or revoked."}"#,
+                    );
+                }
+
+                state.tokens_issued += 1;
+                let access_token = format!("access-token-{}", state.tokens_issued);
+                state.valid_access_tokens.push(access_token.clone());
+
+                let mut response = serde_json::json!({
+                    "access_token": access_token,
+                    "expires_in": 3599,
+                    "token_type": "Bearer",
+                });
+                if std::mem::take(&mut state.rotate_refresh_token) {
+                    state.refresh_token = ROTATED_REFRESH_TOKEN.to_string();
+                    response["refresh_token"] = ROTATED_REFRESH_TOKEN.into();
+                }
+                if let Some(expires_in) = state.refresh_token_expires_in {
+                    response["refresh_token_expires_in"] = expires_in.into();
+                }
+
+                return FakeResponse::json(200, response.to_string());
+            }
+
+            let authorized = request
+                .header("Authorization")
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .is_some_and(|token| state.valid_access_tokens.iter().any(|valid| valid == token));
+
+            if request.path.starts_with(&format!("/v1/{SUBSCRIPTION}:")) {
+                if !state.pubsub_available {
+                    return FakeResponse::json(
+                        404,
+                        r#"{"error": {"code": 404, "status": "NOT_FOUND"}}"#,
+                    );
+                }
+
+                if !authorized {
+                    return FakeResponse::json(
+                        401,
+                        r#"{"error": {"code": 401, "status": "UNAUTHENTICATED"}}"#,
+                    );
+                }
+
+                if request.path.ends_with(":pull") {
+                    let messages: Vec<serde_json::Value> = state
+                        .events
+                        .drain(..)
+                        .map(|(ack_id, event)| {
+                            serde_json::json!({
+                                "ackId": ack_id,
+                                "message": { "data": BASE64_STANDARD.encode(event) },
+                            })
+                        })
+                        .collect();
+
+                    return FakeResponse::json(
+                        200,
+                        serde_json::json!({ "receivedMessages": messages }).to_string(),
+                    );
+                }
+
+                return FakeResponse::json(200, "{}");
+            }
+
+            if request
+                .path
+                .starts_with(&format!("/v1/enterprises/{PROJECT_ID}/devices"))
+            {
+                if !authorized {
+                    return FakeResponse::json(
+                        401,
+                        r#"{"error": {"code": 401, "status": "UNAUTHENTICATED"}}"#,
+                    );
+                }
+
+                if request.method == "POST" && request.path.ends_with(":executeCommand") {
+                    return FakeResponse::json(200, "{}");
+                }
+
+                return FakeResponse::json(200, state.devices.clone());
+            }
+
+            FakeResponse::not_found()
+        });
+
+        FakeGoogle { server, state }
+    }
+
+    /// Config pointing the Nest source at this server
+    pub fn config(&self) -> NestConfig {
+        NestConfig {
+            token_url: format!("{}/token", self.server.url),
+            sdm_devices_url: format!("{}/v1/enterprises", self.server.url),
+            pubsub_url: format!("{}/v1", self.server.url),
+            pubsub_subscription: SUBSCRIPTION.to_string(),
+            ..NestConfig::default()
+        }
+    }
+
+    pub fn credentials(&self) -> NestCredentials {
+        NestCredentials {
+            client_id: "test-client-id".to_string(),
+            client_secret: "test-client-secret".to_string(),
+            refresh_token: REFRESH_TOKEN.to_string(),
+            project_id: PROJECT_ID.to_string(),
+            refresh_token_expires_at: None,
+        }
+    }
+
+    /// `credentials()` written to a file in a new temporary directory
+    pub fn credentials_file(&self) -> PathBuf {
+        let credentials = serde_json::json!({
+            "client_id": "test-client-id",
+            "client_secret": "test-client-secret",
+            "refresh_token": REFRESH_TOKEN,
+            "project_id": PROJECT_ID,
+        });
+        temp_file("nest_credentials.json", &credentials.to_string())
+    }
+
+    /// Invalidates every access token issued so far, as if they had expired early
+    pub fn expire_access_tokens(&self) {
+        self.state.lock().unwrap().valid_access_tokens.clear();
+    }
+
+    /// Replaces the device list, which defaults to `DEVICES`
+    pub fn set_devices(&self, devices: &str) {
+        self.state.lock().unwrap().devices = devices.to_string();
+    }
+
+    /// Queues a Device Access event to be pulled from the subscription
+    pub fn publish_event(&self, event: serde_json::Value) {
+        let mut state = self.state.lock().unwrap();
+        state.events_published += 1;
+        let ack_id = format!("ack-{}", state.events_published);
+        state.events.push((ack_id, event.to_string()));
+    }
+
+    /// Makes the subscription return 404s, as if it had been deleted
+    pub fn set_pubsub_available(&self, available: bool) {
+        self.state.lock().unwrap().pubsub_available = available;
+    }
+
+    /// Issues `ROTATED_REFRESH_TOKEN` with the next access token, after which the old refresh
+    /// token is rejected
+    pub fn rotate_refresh_token(&self) {
+        self.state.lock().unwrap().rotate_refresh_token = true;
+    }
+
+    /// Reports that the refresh token expires in `secs` with each access token, as Google does
+    /// for OAuth apps in Testing
+    pub fn set_refresh_token_expires_in(&self, secs: u64) {
+        self.state.lock().unwrap().refresh_token_expires_in = Some(secs);
+    }
+
+    /// Revokes the refresh token, so refreshing fails with `invalid_grant`
+    pub fn revoke_refresh_token(&self) {
+        self.state.lock().unwrap().refresh_token_revoked = true;
+    }
+}
+
+/// A heating thermostat and a camera, which is not a thermostat and so is ignored
+pub const DEVICES: &str = r#"{
+  "devices": [
+    {
+      "name": "enterprises/test-project/devices/thermostat-1",
+      "type": "sdm.devices.types.THERMOSTAT",
+      "traits": {
+        "sdm.devices.traits.Info": { "customName": "" },
+        "sdm.devices.traits.Humidity": { "ambientHumidityPercent": 45 },
+        "sdm.devices.traits.Connectivity": { "status": "ONLINE" },
+        "sdm.devices.traits.Temperature": { "ambientTemperatureCelsius": 20.5 },
+        "sdm.devices.traits.ThermostatMode": { "mode": "HEAT", "availableModes": ["HEAT", "OFF"] },
+        "sdm.devices.traits.ThermostatEco": {
+          "mode": "OFF",
+          "availableModes": ["OFF", "MANUAL_ECO"],
+          "heatCelsius": 12.0,
+          "coolCelsius": 26.0
+        },
+        "sdm.devices.traits.ThermostatHvac": { "status": "HEATING" },
+        "sdm.devices.traits.ThermostatTemperatureSetpoint": { "heatCelsius": 21.0 }
+      },
+      "parentRelations": [
+        { "parent": "enterprises/test-project/structures/s/rooms/r", "displayName": "Hallway" }
+      ]
+    },
+    {
+      "name": "enterprises/test-project/devices/camera-1",
+      "type": "sdm.devices.types.CAMERA",
+      "traits": {}
+    }
+  ]
+}"#;
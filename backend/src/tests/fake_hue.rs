@@ -0,0 +1,438 @@
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock, Mutex};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use rcgen::{
+    BasicConstraints, CertificateParams, CertifiedIssuer, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair, KeyUsagePurpose,
+};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+
+use super::harness::{FakeResponse, FakeServer, temp_file};
+
+use crate::config::settings::HueConfig;
+use crate::sensor_control::sensors::{
+    HUE_APPLICATION_KEY_HEADER, HUE_DEVICE_POWER_URL, HUE_DEVICE_URL, HUE_EVENT_STREAM_URL,
+    HUE_LIGHT_LEVEL_URL, HUE_MOTION_URL, HUE_ROOM_URL, HUE_TEMPERATURE_URL,
+    HUE_ZIGBEE_CONNECTIVITY_URL,
+};
+
+/// The application key the fake bridge accepts
+pub const APPLICATION_KEY: &str = "test-application-key";
+/// The ID the fake bridge reports, which is listed in lower case by discovery
+pub const BRIDGE_ID: &str = "001788FFFE000000";
+/// The client key issued alongside the application key when pairing
+pub const CLIENT_KEY: &str = "0123456789ABCDEF0123456789ABCDEF";
+
+/// Stands in for the Hue root CA, issuing the fake bridges' certificates
+struct TestCa {
+    issuer: CertifiedIssuer<'static, KeyPair>,
+    /// The CA certificate as a PEM file, for `hue.root_ca_path`
+    pem_path: PathBuf,
+}
+
+static TEST_CA: LazyLock<TestCa> = LazyLock::new(|| {
+    let mut params = CertificateParams::new(Vec::new()).unwrap();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "test-root-bridge");
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+
+    let issuer = CertifiedIssuer::self_signed(params, KeyPair::generate().unwrap())
+        .expect("Failed to generate the test CA");
+
+    let base64 = STANDARD.encode(issuer.der());
+    let lines: Vec<&str> = base64
+        .as_bytes()
+        .chunks(64)
+        .map(|line| std::str::from_utf8(line).unwrap())
+        .collect();
+    let pem = format!(
+        "-----BEGIN CERTIFICATE-----\n{}\n-----END CERTIFICATE-----\n",
+        lines.join("\n")
+    );
+
+    TestCa {
+        pem_path: temp_file("hue_root_ca.pem", &pem),
+        issuer,
+    }
+});
+
+/// The default Hue config, but trusting the test CA that issues the fake bridges' certificates
+pub fn hue_config() -> HueConfig {
+    HueConfig {
+        root_ca_path: TEST_CA.pem_path.to_string_lossy().into_owned(),
+        ..HueConfig::default()
+    }
+}
+
+/// The certificate a fake bridge presents
+pub enum BridgeCertificate {
+    /// Issued by the test CA with this common name, like a real bridge's with its ID
+    Issued(String),
+    /// Self-signed, like those of bridges on older firmware
+    SelfSigned,
+}
+
+impl BridgeCertificate {
+    fn generate(&self) -> (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>) {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+
+        let certificate = match self {
+            BridgeCertificate::Issued(common_name) => {
+                params
+                    .distinguished_name
+                    .push(DnType::CommonName, common_name.as_str());
+                params.signed_by(&key, &TEST_CA.issuer)
+            }
+            BridgeCertificate::SelfSigned => params.self_signed(&key),
+        }
+        .expect("Failed to generate the bridge certificate");
+
+        (
+            vec![certificate.der().clone()],
+            PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der())),
+        )
+    }
+}
+
+#[derive(Default)]
+struct BridgeState {
+    /// (device name, temperature resource ID)
+    devices: Vec<(String, String)>,
+    /// (temperature resource ID, changed, temperature)
+    temperatures: Vec<(String, String, f32)>,
+    /// (motion resource ID, changed, motion)
+    motion: Vec<(String, String, bool)>,
+    /// (light level resource ID, changed, light level)
+    light_levels: Vec<(String, String, u32)>,
+    /// (device power resource ID, battery level, battery state)
+    batteries: Vec<(String, u8, String)>,
+    /// (zigbee connectivity resource ID, status)
+    connectivity: Vec<(String, String)>,
+    /// (room name, device ID)
+    rooms: Vec<(String, String)>,
+    /// The body served from the event stream before it closes
+    events: String,
+    /// Whether the event stream stays open after `events`
+    events_stay_open: bool,
+    /// Whether pairing requests succeed
+    link_button_pressed: bool,
+}
+
+/// A fake Hue bridge serving the CLIP v2 endpoints over HTTPS
+pub struct FakeHueBridge {
+    pub server: FakeServer,
+    state: Arc<Mutex<BridgeState>>,
+}
+
+impl FakeHueBridge {
+    pub fn start() -> Self {
+        FakeHueBridge::start_with(BRIDGE_ID, APPLICATION_KEY)
+    }
+
+    /// Starts a bridge with its own ID and application key, for tests with several bridges
+    pub fn start_with(bridge_id: &str, application_key: &str) -> Self {
+        FakeHueBridge::start_with_certificate(
+            bridge_id,
+            application_key,
+            BridgeCertificate::Issued(bridge_id.to_string()),
+        )
+    }
+
+    /// Starts a bridge presenting a freshly generated certificate of the given kind
+    pub fn start_with_certificate(
+        bridge_id: &str,
+        application_key: &str,
+        certificate: BridgeCertificate,
+    ) -> Self {
+        let (certificate_chain, private_key) = certificate.generate();
+        let state = Arc::new(Mutex::new(BridgeState::default()));
+        let handler_state = Arc::clone(&state);
+        let config = serde_json::json!({ "name": "Hue Bridge", "bridgeid": bridge_id }).to_string();
+        let application_key = application_key.to_string();
+
+        let server =
+            FakeServer::https_with_certificate(certificate_chain, private_key, move |request| {
+                if request.header(HUE_APPLICATION_KEY_HEADER) != Some(application_key.as_str())
+                    && request.path != "/api/0/config"
+                    && request.path != "/api"
+                {
+                    return FakeResponse::json(
+                        403,
+                        r#"{"errors": [{"description": "unauthorized user"}]}"#,
+                    );
+                }
+
+                let state = handler_state.lock().unwrap();
+                match request.path.as_str() {
+                    "/api/0/config" => FakeResponse::json(200, config.clone()),
+                    "/api" if request.method == "POST" => pairing_json(&state),
+                    HUE_DEVICE_URL => FakeResponse::json(200, devices_json(&state)),
+                    HUE_TEMPERATURE_URL => FakeResponse::json(200, temperatures_json(&state)),
+                    HUE_MOTION_URL => FakeResponse::json(200, motion_json(&state)),
+                    HUE_LIGHT_LEVEL_URL => FakeResponse::json(200, light_levels_json(&state)),
+                    HUE_DEVICE_POWER_URL => FakeResponse::json(200, batteries_json(&state)),
+                    HUE_ZIGBEE_CONNECTIVITY_URL => {
+                        FakeResponse::json(200, connectivity_json(&state))
+                    }
+                    HUE_ROOM_URL => FakeResponse::json(200, rooms_json(&state)),
+                    HUE_EVENT_STREAM_URL if state.events_stay_open => {
+                        FakeResponse::open_event_stream(state.events.clone())
+                    }
+                    HUE_EVENT_STREAM_URL => FakeResponse::event_stream(state.events.clone()),
+                    _ => FakeResponse::not_found(),
+                }
+            });
+
+        FakeHueBridge { server, state }
+    }
+
+    /// Adds a device with a temperature service, or renames it if the resource ID already exists
+    pub fn set_device(&self, name: &str, temperature_id: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.devices.retain(|(_, id)| id != temperature_id);
+        state
+            .devices
+            .push((name.to_string(), temperature_id.to_string()));
+    }
+
+    /// Sets the temperature report for a resource, `changed` being an RFC 3339 timestamp
+    pub fn set_temperature(&self, temperature_id: &str, changed: &str, temperature: f32) {
+        let mut state = self.state.lock().unwrap();
+        state.temperatures.retain(|(id, _, _)| id != temperature_id);
+        state
+            .temperatures
+            .push((temperature_id.to_string(), changed.to_string(), temperature));
+    }
+
+    /// Sets the motion report of the device with the temperature resource ID
+    pub fn set_motion(&self, temperature_id: &str, changed: &str, motion: bool) {
+        let id = format!("motion-{temperature_id}");
+        let mut state = self.state.lock().unwrap();
+        state.motion.retain(|(motion_id, _, _)| *motion_id != id);
+        state.motion.push((id, changed.to_string(), motion));
+    }
+
+    /// Sets the light level report of the device with the temperature resource ID
+    pub fn set_light_level(&self, temperature_id: &str, changed: &str, light_level: u32) {
+        let id = format!("light_level-{temperature_id}");
+        let mut state = self.state.lock().unwrap();
+        state
+            .light_levels
+            .retain(|(level_id, _, _)| *level_id != id);
+        state
+            .light_levels
+            .push((id, changed.to_string(), light_level));
+    }
+
+    /// Sets the battery of the device with the temperature resource ID
+    pub fn set_battery(&self, temperature_id: &str, battery_level: u8, battery_state: &str) {
+        let id = format!("device_power-{temperature_id}");
+        let mut state = self.state.lock().unwrap();
+        state.batteries.retain(|(power_id, _, _)| *power_id != id);
+        state
+            .batteries
+            .push((id, battery_level, battery_state.to_string()));
+    }
+
+    /// Sets the zigbee connectivity status of the device with the temperature resource ID, e.g.
+    /// `connected` or `disconnected`
+    pub fn set_connectivity(&self, temperature_id: &str, status: &str) {
+        let id = format!("zigbee_connectivity-{temperature_id}");
+        let mut state = self.state.lock().unwrap();
+        state
+            .connectivity
+            .retain(|(connectivity_id, _)| *connectivity_id != id);
+        state.connectivity.push((id, status.to_string()));
+    }
+
+    /// Puts the device with the temperature resource ID in a room, which need not exist yet
+    pub fn set_room(&self, temperature_id: &str, room: &str) {
+        let id = format!("device-{temperature_id}");
+        let mut state = self.state.lock().unwrap();
+        state.rooms.retain(|(_, device_id)| *device_id != id);
+        state.rooms.push((room.to_string(), id));
+    }
+
+    /// Lets pairing requests succeed, which fail with "link button not pressed" until then
+    pub fn press_link_button(&self) {
+        self.state.lock().unwrap().link_button_pressed = true;
+    }
+
+    /// Sets the server-sent events served by the event stream
+    pub fn set_events(&self, events: &str) {
+        self.state.lock().unwrap().events = events.to_string();
+    }
+
+    /// Keeps the event stream open after its events, as a bridge does between events
+    pub fn keep_event_stream_open(&self) {
+        self.state.lock().unwrap().events_stay_open = true;
+    }
+}
+
+/// The bridge answers pairing requests with a 200 whether or not they succeed
+fn pairing_json(state: &BridgeState) -> FakeResponse {
+    let result = if state.link_button_pressed {
+        serde_json::json!({ "success": { "username": APPLICATION_KEY, "clientkey": CLIENT_KEY } })
+    } else {
+        serde_json::json!({
+            "error": { "type": 101, "address": "", "description": "link button not pressed" },
+        })
+    };
+
+    FakeResponse::json(200, serde_json::json!([result]).to_string())
+}
+
+fn devices_json(state: &BridgeState) -> String {
+    let devices: Vec<serde_json::Value> = state
+        .devices
+        .iter()
+        .map(|(name, temperature_id)| {
+            serde_json::json!({
+                "id": format!("device-{temperature_id}"),
+                "metadata": { "name": name, "archetype": "unknown_archetype" },
+                "product_data": { "model_id": "SML001", "manufacturer_name": "Signify Netherlands B.V." },
+                "services": [
+                    { "rid": format!("motion-{temperature_id}"), "rtype": "motion" },
+                    { "rid": temperature_id, "rtype": "temperature" },
+                    { "rid": format!("light_level-{temperature_id}"), "rtype": "light_level" },
+                    { "rid": format!("device_power-{temperature_id}"), "rtype": "device_power" },
+                    {
+                        "rid": format!("zigbee_connectivity-{temperature_id}"),
+                        "rtype": "zigbee_connectivity",
+                    },
+                ],
+                "type": "device",
+            })
+        })
+        .collect();
+
+    serde_json::json!({ "errors": [], "data": devices }).to_string()
+}
+
+fn temperatures_json(state: &BridgeState) -> String {
+    let temperatures: Vec<serde_json::Value> = state
+        .temperatures
+        .iter()
+        .map(|(id, changed, temperature)| {
+            serde_json::json!({
+                "id": id,
+                "type": "temperature",
+                "enabled": true,
+                "temperature": {
+                    "temperature": temperature,
+                    "temperature_valid": true,
+                    "temperature_report": { "changed": changed, "temperature": temperature },
+                },
+            })
+        })
+        .collect();
+
+    serde_json::json!({ "errors": [], "data": temperatures }).to_string()
+}
+
+fn motion_json(state: &BridgeState) -> String {
+    let motion: Vec<serde_json::Value> = state
+        .motion
+        .iter()
+        .map(|(id, changed, motion)| {
+            serde_json::json!({
+                "id": id,
+                "type": "motion",
+                "enabled": true,
+                "motion": {
+                    "motion": motion,
+                    "motion_valid": true,
+                    "motion_report": { "changed": changed, "motion": motion },
+                },
+            })
+        })
+        .collect();
+
+    serde_json::json!({ "errors": [], "data": motion }).to_string()
+}
+
+fn light_levels_json(state: &BridgeState) -> String {
+    let light_levels: Vec<serde_json::Value> = state
+        .light_levels
+        .iter()
+        .map(|(id, changed, light_level)| {
+            serde_json::json!({
+                "id": id,
+                "type": "light_level",
+                "enabled": true,
+                "light": {
+                    "light_level": light_level,
+                    "light_level_valid": true,
+                    "light_level_report": { "changed": changed, "light_level": light_level },
+                },
+            })
+        })
+        .collect();
+
+    serde_json::json!({ "errors": [], "data": light_levels }).to_string()
+}
+
+fn batteries_json(state: &BridgeState) -> String {
+    let batteries: Vec<serde_json::Value> = state
+        .batteries
+        .iter()
+        .map(|(id, battery_level, battery_state)| {
+            serde_json::json!({
+                "id": id,
+                "type": "device_power",
+                "power_state": { "battery_state": battery_state, "battery_level": battery_level },
+            })
+        })
+        .collect();
+
+    serde_json::json!({ "errors": [], "data": batteries }).to_string()
+}
+
+fn connectivity_json(state: &BridgeState) -> String {
+    let connectivity: Vec<serde_json::Value> = state
+        .connectivity
+        .iter()
+        .map(|(id, status)| {
+            serde_json::json!({
+                "id": id,
+                "type": "zigbee_connectivity",
+                "status": status,
+                "mac_address": "00:17:88:01:00:00:00:00",
+            })
+        })
+        .collect();
+
+    serde_json::json!({ "errors": [], "data": connectivity }).to_string()
+}
+
+fn rooms_json(state: &BridgeState) -> String {
+    let mut rooms: Vec<(&str, Vec<serde_json::Value>)> = Vec::new();
+    for (room, device_id) in &state.rooms {
+        let child = serde_json::json!({ "rid": device_id, "rtype": "device" });
+        match rooms.iter_mut().find(|(name, _)| name == room) {
+            Some((_, children)) => children.push(child),
+            None => rooms.push((room, vec![child])),
+        }
+    }
+
+    let rooms: Vec<serde_json::Value> = rooms
+        .into_iter()
+        .enumerate()
+        .map(|(index, (name, children))| {
+            serde_json::json!({
+                "id": format!("room-{index}"),
+                "type": "room",
+                "metadata": { "name": name, "archetype": "living_room" },
+                "children": children,
+            })
+        })
+        .collect();
+
+    serde_json::json!({ "errors": [], "data": rooms }).to_string()
+}
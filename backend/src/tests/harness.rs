@@ -0,0 +1,288 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use chrono::{DateTime, TimeZone, Utc};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+
+use crate::sensor_control::models::TemperatureData;
+
+/// A request received by a fake server
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub method: String,
+    /// The path including any query string
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl RecordedRequest {
+    /// The value of a header, matched case-insensitively
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// A response from a fake server, sent with `Connection: close`
+pub struct FakeResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+    /// Whether the connection is held open after the body until the client closes it
+    pub keep_open: bool,
+}
+
+impl FakeResponse {
+    pub fn json(status: u16, body: impl Into<String>) -> Self {
+        FakeResponse {
+            status,
+            content_type: "application/json",
+            body: body.into(),
+            keep_open: false,
+        }
+    }
+
+    /// A server-sent event stream that ends after `body`
+    pub fn event_stream(body: impl Into<String>) -> Self {
+        FakeResponse {
+            status: 200,
+            content_type: "text/event-stream",
+            body: body.into(),
+            keep_open: false,
+        }
+    }
+
+    /// A server-sent event stream that sends `body` and then waits, as if for the next event
+    pub fn open_event_stream(body: impl Into<String>) -> Self {
+        FakeResponse {
+            keep_open: true,
+            ..FakeResponse::event_stream(body)
+        }
+    }
+
+    pub fn not_found() -> Self {
+        FakeResponse::json(404, r#"{"error": "not found"}"#)
+    }
+}
+
+type Handler = dyn Fn(&RecordedRequest) -> FakeResponse + Send + Sync;
+
+/// A local HTTP or HTTPS server that answers each request with a handler and records it
+pub struct FakeServer {
+    /// The base URL, e.g. `http://127.0.0.1:1234`
+    pub url: String,
+    /// The address and port, e.g. `127.0.0.1:1234`
+    pub address: String,
+    requests: Arc<Mutex<Vec<RecordedRequest>>>,
+}
+
+impl FakeServer {
+    /// Starts a plain HTTP server
+    pub fn http<F>(handler: F) -> Self
+    where
+        F: Fn(&RecordedRequest) -> FakeResponse + Send + Sync + 'static,
+    {
+        FakeServer::start("http", None, Arc::new(handler))
+    }
+
+    /// Starts an HTTPS server presenting the given certificate chain
+    pub fn https_with_certificate<F>(
+        certificate_chain: Vec<CertificateDer<'static>>,
+        private_key: PrivateKeyDer<'static>,
+        handler: F,
+    ) -> Self
+    where
+        F: Fn(&RecordedRequest) -> FakeResponse + Send + Sync + 'static,
+    {
+        let tls_config =
+            ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .expect("Failed to select TLS versions")
+                .with_no_client_auth()
+                .with_single_cert(certificate_chain, private_key)
+                .expect("Failed to configure TLS");
+
+        FakeServer::start("https", Some(Arc::new(tls_config)), Arc::new(handler))
+    }
+
+    fn start(scheme: &str, tls_config: Option<Arc<ServerConfig>>, handler: Arc<Handler>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind fake server");
+        let address = listener.local_addr().unwrap().to_string();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let recorded = Arc::clone(&requests);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let handler = Arc::clone(&handler);
+                let recorded = Arc::clone(&recorded);
+
+                match &tls_config {
+                    Some(tls_config) => {
+                        let connection = ServerConnection::new(Arc::clone(tls_config))
+                            .expect("Failed to create TLS connection");
+                        let stream = StreamOwned::new(connection, stream);
+                        thread::spawn(move || serve(stream, handler.as_ref(), &recorded));
+                    }
+                    None => {
+                        thread::spawn(move || serve(stream, handler.as_ref(), &recorded));
+                    }
+                }
+            }
+        });
+
+        FakeServer {
+            url: format!("{scheme}://{address}"),
+            address,
+            requests,
+        }
+    }
+
+    /// Every request received so far
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// The requests received so far for a path, ignoring any query string
+    pub fn requests_to(&self, path: &str) -> Vec<RecordedRequest> {
+        self.requests()
+            .into_iter()
+            .filter(|request| request.path.split('?').next() == Some(path))
+            .collect()
+    }
+}
+
+/// Waits up to five seconds for `condition` to hold, for tests of background threads
+pub fn wait_until(mut condition: impl FnMut() -> bool) {
+    let started = std::time::Instant::now();
+    while !condition() {
+        assert!(
+            started.elapsed() < std::time::Duration::from_secs(5),
+            "Timed out waiting for condition"
+        );
+        thread::sleep(std::time::Duration::from_millis(10));
+    }
+}
+
+/// Writes `contents` to a file in a new temporary directory, returning its path
+pub fn temp_file(file_name: &str, contents: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "rust-backend-test-{}-{}",
+        std::process::id(),
+        fastrand::u64(..)
+    ));
+    std::fs::create_dir_all(&dir).expect("Failed to create temporary directory");
+
+    let path = dir.join(file_name);
+    std::fs::write(&path, contents).expect("Failed to write temporary file");
+    path
+}
+
+/// `minute` minutes past midnight on 1 January 2026
+pub fn at_minute(minute: u32) -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(2026, 1, 1, 0, minute, 0).unwrap()
+}
+
+/// An online reading of `temperature` from `device_name` at `timestamp`, with no humidity or
+/// device ID. Tests needing other fields set them with struct update syntax.
+pub fn reading(device_name: &str, timestamp: DateTime<Utc>, temperature: f32) -> TemperatureData {
+    TemperatureData {
+        device_name: device_name.to_string(),
+        device_id: None,
+        bridge_id: None,
+        source: None,
+        timestamp,
+        online: true,
+        temperature,
+        humidity: 0.0,
+    }
+}
+
+/// An address that refuses connections, for simulating an unreachable server
+pub fn unreachable_address() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+/// Reads one request from the stream and writes the handler's response
+fn serve<S: Read + Write>(stream: S, handler: &Handler, recorded: &Mutex<Vec<RecordedRequest>>) {
+    let mut reader = BufReader::new(stream);
+
+    let Some(request) = read_request(&mut reader) else {
+        return;
+    };
+    recorded.lock().unwrap().push(request.clone());
+
+    let response = handler(&request);
+    let reason = match response.status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        _ => "Status",
+    };
+
+    // Event streams have no length and end when the connection closes
+    let length = if response.content_type == "text/event-stream" {
+        String::new()
+    } else {
+        format!("Content-Length: {}\r\n", response.body.len())
+    };
+
+    let stream = reader.get_mut();
+    let _ = write!(
+        stream,
+        "HTTP/1.1 {} {reason}\r\nContent-Type: {}\r\n{length}Connection: close\r\n\r\n{}",
+        response.status, response.content_type, response.body
+    );
+    let _ = stream.flush();
+
+    if response.keep_open {
+        let _ = io::copy(&mut reader, &mut io::sink());
+    }
+}
+
+fn read_request<S: Read>(reader: &mut BufReader<S>) -> Option<RecordedRequest> {
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).ok()?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let path = parts.next()?.to_string();
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((key, value)) = line.split_once(':') {
+            headers.push((key.trim().to_string(), value.trim().to_string()));
+        }
+    }
+
+    let length = headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.parse::<usize>().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).ok()?;
+
+    Some(RecordedRequest {
+        method,
+        path,
+        headers,
+        body: String::from_utf8_lossy(&body).to_string(),
+    })
+}
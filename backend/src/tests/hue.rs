@@ -0,0 +1,694 @@
+use chrono::{DateTime, SubsecRound, TimeDelta, Utc};
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::fake_hue::{
+    APPLICATION_KEY, BRIDGE_ID, BridgeCertificate, CLIENT_KEY, FakeHueBridge, hue_config,
+};
+use super::harness::{FakeResponse, FakeServer, temp_file, unreachable_address, wait_until};
+
+use crate::config::settings::{HueBridgeConfig, HueConfig, HueTlsVerification};
+use crate::datastore::memory::InMemoryStorage;
+use crate::sensor_control::errors::SensorError;
+use crate::sensor_control::models::TemperatureData;
+use crate::sensor_control::registry::DeviceRegistry;
+use crate::sensor_control::sensors::{
+    HUE_APPLICATION_KEY_HEADER, HUE_DEVICE_POWER_URL, HUE_DEVICE_URL, HUE_LIGHT_LEVEL_URL,
+    HUE_MOTION_URL, HUE_TEMPERATURE_URL, HUE_ZIGBEE_CONNECTIVITY_URL, HueStores, Sensors,
+};
+use crate::sensor_control::source::SensorSource;
+use crate::sensor_control::store;
+use crate::sensor_control::{hue_pair, hue_tls};
+use crate::shutdown::Shutdown;
+
+/// A discovery endpoint listing the given bridge addresses
+fn fake_discovery(addresses: &[&str]) -> FakeServer {
+    let bridges: Vec<serde_json::Value> = addresses
+        .iter()
+        .map(|address| serde_json::json!({ "id": "001788fffe000000", "internalipaddress": address, "port": 443 }))
+        .collect();
+    let body = serde_json::Value::Array(bridges).to_string();
+
+    FakeServer::http(move |_| FakeResponse::json(200, body.clone()))
+}
+
+/// Config for discovering bridges, which are paired with `APPLICATION_KEY`
+fn config(bridge_domain: &str, discovery: &FakeServer) -> HueConfig {
+    let key_path = temp_file("hue_application_key.txt", APPLICATION_KEY);
+
+    HueConfig {
+        bridge_domain: bridge_domain.to_string(),
+        discovery_url: discovery.url.clone(),
+        application_key_path: key_path.to_string_lossy().into_owned(),
+        ..hue_config()
+    }
+}
+
+fn readings(sensors: &Sensors) -> Vec<(String, f32)> {
+    sensors
+        .fetch_readings()
+        .unwrap()
+        .into_iter()
+        .map(|reading| (reading.device_name, reading.temperature))
+        .collect()
+}
+
+fn stored(storage: &InMemoryStorage<TemperatureData>) -> Vec<(String, DateTime<Utc>, f32)> {
+    storage
+        .items()
+        .unwrap()
+        .into_iter()
+        .map(|item| (item.device_name, item.timestamp, item.temperature))
+        .collect()
+}
+
+#[test]
+fn discovery_is_used_when_bridge_domain_is_unreachable() {
+    let bridge = FakeHueBridge::start();
+    bridge.set_device("Lounge", "t1");
+    bridge.set_temperature("t1", "2026-01-01T00:00:00Z", 20.5);
+    let discovery = fake_discovery(&[&bridge.server.address]);
+
+    let bridges = Sensors::new(&config(&unreachable_address(), &discovery)).unwrap();
+
+    assert_eq!(bridges.len(), 1);
+    assert_eq!(readings(&bridges[0]), vec![("Lounge".to_string(), 20.5)]);
+    assert_eq!(discovery.requests().len(), 1);
+
+    let device_requests = bridge.server.requests_to(HUE_DEVICE_URL);
+    assert_eq!(device_requests.len(), 1);
+    assert_eq!(
+        device_requests[0].header(HUE_APPLICATION_KEY_HEADER),
+        Some(APPLICATION_KEY)
+    );
+}
+
+#[test]
+fn discovery_is_used_when_bridge_domain_is_not_a_bridge() {
+    let bridge = FakeHueBridge::start();
+    bridge.set_device("Lounge", "t1");
+    bridge.set_temperature("t1", "2026-01-01T00:00:00Z", 20.5);
+    let discovery = fake_discovery(&[&bridge.server.address]);
+    let not_a_bridge = FakeServer::http(|_| FakeResponse::not_found());
+
+    let bridges = Sensors::new(&config(&not_a_bridge.address, &discovery)).unwrap();
+
+    assert_eq!(readings(&bridges[0]), vec![("Lounge".to_string(), 20.5)]);
+    assert_eq!(not_a_bridge.requests_to("/api/0/config").len(), 1);
+    assert_eq!(discovery.requests().len(), 1);
+}
+
+#[test]
+fn every_discovered_bridge_is_used_with_its_own_key() {
+    let upstairs = FakeHueBridge::start_with("001788FFFE00000A", "key-a");
+    upstairs.set_device("Landing", "t1");
+    upstairs.set_device("Bedroom", "t2");
+    upstairs.set_temperature("t1", "2026-01-01T00:00:00Z", 19.0);
+    upstairs.set_temperature("t2", "2026-01-01T00:00:00Z", 18.0);
+
+    let downstairs = FakeHueBridge::start_with("001788FFFE00000B", "key-b");
+    downstairs.set_device("Bedroom", "t1");
+    downstairs.set_temperature("t1", "2026-01-01T00:00:00Z", 21.0);
+
+    // A neighbour's bridge, which has not been paired and so is skipped
+    let unpaired = FakeHueBridge::start_with("001788FFFE00000C", "key-c");
+
+    let discovery = fake_discovery(&[
+        &upstairs.server.address,
+        &downstairs.server.address,
+        &unpaired.server.address,
+    ]);
+    let key_a = temp_file("hue_application_key_001788fffe00000a.txt", "key-a\n");
+    let key_dir = key_a.parent().unwrap();
+    std::fs::write(
+        key_dir.join("hue_application_key_001788fffe00000b.txt"),
+        "key-b",
+    )
+    .unwrap();
+    let config = HueConfig {
+        application_key_path: key_dir
+            .join("hue_application_key_{bridge_id}.txt")
+            .to_string_lossy()
+            .into_owned(),
+        ..config(&unreachable_address(), &discovery)
+    };
+
+    let bridges = Sensors::new(&config).unwrap();
+
+    // Bedroom is on both bridges, so the bridge ID is added to its name
+    let tagged: Vec<(String, Option<String>)> = bridges
+        .iter()
+        .flat_map(|sensors| sensors.fetch_readings().unwrap())
+        .map(|reading| (reading.device_name, reading.bridge_id))
+        .collect();
+    let a = Some("001788fffe00000a".to_string());
+    let b = Some("001788fffe00000b".to_string());
+    assert_eq!(
+        tagged,
+        vec![
+            ("Landing".to_string(), a.clone()),
+            ("Bedroom (001788fffe00000a)".to_string(), a),
+            ("Bedroom (001788fffe00000b)".to_string(), b),
+        ]
+    );
+    assert_eq!(unpaired.server.requests_to(HUE_DEVICE_URL).len(), 0);
+}
+
+#[test]
+fn configured_bridges_are_used_instead_of_discovery() {
+    let bridge = FakeHueBridge::start();
+    bridge.set_device("Lounge", "t1");
+    bridge.set_temperature("t1", "2026-01-01T00:00:00Z", 20.5);
+    let unpaired = FakeHueBridge::start_with("001788FFFE00000C", "key-c");
+    let discovery = fake_discovery(&[]);
+
+    let bridge_config = |address: &str| HueBridgeConfig {
+        address: address.to_string(),
+        application_key_path: None,
+    };
+    let mut config = HueConfig {
+        bridges: vec![bridge_config(&bridge.server.address)],
+        ..config(&unreachable_address(), &discovery)
+    };
+
+    let bridges = Sensors::new(&config).unwrap();
+    assert_eq!(readings(&bridges[0]), vec![("Lounge".to_string(), 20.5)]);
+    assert!(discovery.requests().is_empty());
+
+    // Unlike discovered bridges, a configured bridge that cannot be used is an error
+    config.bridges.push(bridge_config(&unpaired.server.address));
+    assert!(Sensors::new(&config).is_err());
+}
+
+#[test]
+fn no_discovered_bridges_is_an_error() {
+    let discovery = fake_discovery(&[]);
+
+    let result = Sensors::new(&config(&unreachable_address(), &discovery));
+
+    assert!(
+        matches!(result, Err(SensorError::DeviceNotFound(_))),
+        "{:?}",
+        result.err()
+    );
+}
+
+#[test]
+fn wrong_application_key_is_an_error() {
+    let bridge = FakeHueBridge::start();
+
+    assert!(Sensors::with_bridge_url(&hue_config(), &bridge.server.url, "wrong-key").is_err());
+}
+
+#[test]
+fn readings_use_device_names_and_renames_are_picked_up_on_reload() {
+    let bridge = FakeHueBridge::start();
+    bridge.set_device("Lounge", "t1");
+    bridge.set_temperature("t1", "2026-01-01T00:00:00Z", 20.0);
+    bridge.set_temperature("t2", "2026-01-01T00:00:00Z", 18.0);
+
+    let sensors =
+        Sensors::with_bridge_url(&hue_config(), &bridge.server.url, APPLICATION_KEY).unwrap();
+
+    // Temperature resources without a known device are stored as Unknown
+    assert_eq!(
+        readings(&sensors),
+        vec![("Lounge".to_string(), 20.0), ("Unknown".to_string(), 18.0)]
+    );
+
+    bridge.set_device("Living Room", "t1");
+    bridge.set_device("Kitchen", "t2");
+
+    // The device list is not read again for t2 until the rediscovery interval has passed, as it
+    // was still unknown when it was first looked up
+    assert_eq!(readings(&sensors)[0].0, "Lounge");
+
+    let sensors =
+        Sensors::with_bridge_url(&hue_config(), &bridge.server.url, APPLICATION_KEY).unwrap();
+
+    assert_eq!(
+        readings(&sensors),
+        vec![
+            ("Living Room".to_string(), 20.0),
+            ("Kitchen".to_string(), 18.0)
+        ]
+    );
+}
+
+#[test]
+fn new_and_renamed_sensors_are_picked_up_without_a_restart() {
+    let bridge = FakeHueBridge::start();
+    bridge.set_device("Lounge", "t1");
+    bridge.set_temperature("t1", "2026-01-01T00:00:00Z", 20.0);
+
+    let config = HueConfig {
+        rediscovery_interval_secs: 1,
+        ..hue_config()
+    };
+    let renames = Arc::new(InMemoryStorage::new(&["device_name", "timestamp"]));
+    let sensors = Sensors::with_bridge_url(&config, &bridge.server.url, APPLICATION_KEY)
+        .unwrap()
+        .with_stores(HueStores {
+            motion: Arc::new(InMemoryStorage::new(&["device_name", "timestamp"])),
+            light_level: Arc::new(InMemoryStorage::new(&["device_name", "timestamp"])),
+            battery: Arc::new(InMemoryStorage::new(&["device_name", "timestamp"])),
+            renames: renames.clone(),
+        });
+
+    // A report from an unknown sensor has the device list read again straight away
+    bridge.set_device("Kitchen", "t2");
+    bridge.set_temperature("t2", "2026-01-01T00:00:00Z", 18.0);
+    assert_eq!(
+        readings(&sensors),
+        vec![("Lounge".to_string(), 20.0), ("Kitchen".to_string(), 18.0)]
+    );
+
+    // Renames are picked up when the device list is next read
+    bridge.set_device("Living Room", "t1");
+    assert_eq!(readings(&sensors)[0].0, "Lounge");
+    std::thread::sleep(Duration::from_millis(1100));
+    assert_eq!(readings(&sensors)[0].0, "Living Room");
+
+    let renames: Vec<(String, String, String)> = renames
+        .items()
+        .unwrap()
+        .into_iter()
+        .map(|rename| (rename.previous_name, rename.device_name, rename.device_id))
+        .collect();
+    assert_eq!(
+        renames,
+        vec![(
+            "Lounge".to_string(),
+            "Living Room".to_string(),
+            "device-t1".to_string()
+        )]
+    );
+    assert_eq!(bridge.server.requests_to(HUE_DEVICE_URL).len(), 3);
+}
+
+#[test]
+fn sensors_are_registered_with_their_model_and_room() {
+    let bridge = FakeHueBridge::start();
+    bridge.set_device("Lounge", "t1");
+    bridge.set_room("t1", "Living Room");
+    bridge.set_temperature("t1", "2026-01-01T00:00:00Z", 20.0);
+
+    let devices = Arc::new(InMemoryStorage::new(&["device_id"]));
+    let registry = Arc::new(DeviceRegistry::new(devices.clone()));
+    let sensors = Sensors::with_bridge_url(&hue_config(), &bridge.server.url, APPLICATION_KEY)
+        .unwrap()
+        .with_registry(registry);
+
+    let reading = sensors.fetch_readings().unwrap().remove(0);
+    assert_eq!(reading.device_id.as_deref(), Some("device-t1"));
+
+    let device = devices.items().unwrap().remove(0);
+    assert_eq!(device.device_id, "device-t1");
+    assert_eq!(device.vendor, "hue");
+    assert_eq!(device.model.as_deref(), Some("SML001"));
+    assert_eq!(device.room.as_deref(), Some("Living Room"));
+    assert_eq!(device.display_name, "Lounge");
+    assert_eq!(device.bridge_id.as_deref(), Some("001788fffe000000"));
+}
+
+#[test]
+fn unchanged_reports_are_stored_once() {
+    let bridge = FakeHueBridge::start();
+    bridge.set_device("Lounge", "t1");
+    bridge.set_temperature("t1", "2026-01-01T00:00:00Z", 20.0);
+
+    let sensors =
+        Sensors::with_bridge_url(&hue_config(), &bridge.server.url, APPLICATION_KEY).unwrap();
+    let storage = InMemoryStorage::new(&["device_name", "timestamp"]);
+
+    for _ in 0..3 {
+        store::store_temperatures(&storage, sensors.fetch_readings().unwrap()).unwrap();
+    }
+
+    bridge.set_temperature("t1", "2026-01-01T00:05:00Z", 20.5);
+    store::store_temperatures(&storage, sensors.fetch_readings().unwrap()).unwrap();
+    store::store_temperatures(&storage, sensors.fetch_readings().unwrap()).unwrap();
+
+    assert_eq!(
+        stored(&storage),
+        vec![
+            (
+                "Lounge".to_string(),
+                "2026-01-01T00:00:00Z".parse().unwrap(),
+                20.0
+            ),
+            (
+                "Lounge".to_string(),
+                "2026-01-01T00:05:00Z".parse().unwrap(),
+                20.5
+            ),
+        ]
+    );
+}
+
+#[test]
+fn online_status_changes_are_stored_as_they_happen() {
+    // Bridges report whole seconds
+    let reported = (Utc::now() - TimeDelta::minutes(5)).trunc_subsecs(0);
+    let bridge = FakeHueBridge::start();
+    bridge.set_device("Hallway", "t1");
+    bridge.set_device("Attic", "t2");
+    bridge.set_temperature("t1", &reported.to_rfc3339(), 19.0);
+    bridge.set_connectivity("t1", "connected");
+    // Older than stale_after_secs, as a sensor with a flat battery stops reporting
+    let stale = (Utc::now() - TimeDelta::hours(2)).trunc_subsecs(0);
+    bridge.set_temperature("t2", &stale.to_rfc3339(), 12.0);
+
+    let config = HueConfig {
+        stale_after_secs: 3600,
+        services_poll_interval_secs: 0,
+        ..hue_config()
+    };
+    let sensors = Sensors::with_bridge_url(&config, &bridge.server.url, APPLICATION_KEY).unwrap();
+    let storage = InMemoryStorage::new(&["device_name", "timestamp"]);
+    let poll = || store::store_temperatures(&storage, sensors.fetch_readings().unwrap()).unwrap();
+
+    poll();
+    poll();
+    bridge.set_connectivity("t1", "disconnected");
+    poll();
+    poll();
+    bridge.set_connectivity("t1", "connected");
+    poll();
+
+    let items = storage.items().unwrap();
+    let statuses: Vec<(&str, bool)> = items
+        .iter()
+        .map(|item| (item.device_name.as_str(), item.online))
+        .collect();
+    assert_eq!(
+        statuses,
+        vec![
+            ("Hallway", true),
+            ("Attic", false),
+            ("Hallway", false),
+            ("Hallway", true),
+        ]
+    );
+
+    // The changes are stamped when they were seen, as the report itself has not changed
+    assert_eq!(items[0].timestamp, reported);
+    assert!(items[2].timestamp > reported);
+    assert!(items[3].timestamp > items[2].timestamp);
+    assert_eq!(items[3].temperature, 19.0);
+}
+
+#[test]
+fn events_over_several_data_lines_are_stored() {
+    let bridge = FakeHueBridge::start();
+    bridge.set_device("Lounge", "t1");
+    bridge.set_temperature("t1", "2026-01-01T00:00:00Z", 20.0);
+    bridge.set_events(concat!(
+        "id: 1:0\n",
+        "data: [{\"type\": \"update\", \"data\": [{\n",
+        "data:     \"id\": \"t1\", \"type\": \"temperature\",\n",
+        "data:     \"temperature\": {\"temperature_report\": {\n",
+        "data:         \"changed\": \"2026-01-01T00:05:00Z\", \"temperature\": 21.5\n",
+        "data: }}}]}]\n",
+        "\n",
+    ));
+
+    let sensors =
+        Sensors::with_bridge_url(&hue_config(), &bridge.server.url, APPLICATION_KEY).unwrap();
+    let storage = Arc::new(InMemoryStorage::new(&["device_name", "timestamp"]));
+    let shutdown = Shutdown::new();
+    let handle = sensors.run_events(Arc::clone(&storage), &shutdown).unwrap();
+
+    wait_until(|| storage.items().unwrap().len() == 2);
+    shutdown.trigger();
+    handle.join().unwrap();
+
+    assert_eq!(
+        stored(&storage)[1],
+        (
+            "Lounge".to_string(),
+            "2026-01-01T00:05:00Z".parse().unwrap(),
+            21.5
+        )
+    );
+}
+
+#[test]
+fn event_stream_stops_while_waiting_for_an_event() {
+    let bridge = FakeHueBridge::start();
+    bridge.set_device("Lounge", "t1");
+    bridge.set_temperature("t1", "2026-01-01T00:00:00Z", 20.0);
+    bridge.keep_event_stream_open();
+
+    let sensors =
+        Sensors::with_bridge_url(&hue_config(), &bridge.server.url, APPLICATION_KEY).unwrap();
+    let storage = Arc::new(InMemoryStorage::new(&["device_name", "timestamp"]));
+    let shutdown = Shutdown::new();
+    let handle = sensors.run_events(Arc::clone(&storage), &shutdown).unwrap();
+
+    // Connected, polled and waiting for the next event
+    wait_until(|| storage.items().unwrap().len() == 1);
+    shutdown.trigger();
+    let triggered = Instant::now();
+    handle.join().unwrap();
+
+    assert!(triggered.elapsed() < Duration::from_secs(1));
+}
+
+#[test]
+fn motion_light_level_and_battery_are_stored_when_they_change() {
+    let bridge = FakeHueBridge::start();
+    bridge.set_device("Hallway", "t1");
+    bridge.set_temperature("t1", "2026-01-01T00:00:00Z", 19.0);
+    bridge.set_motion("t1", "2026-01-01T00:00:00Z", true);
+    bridge.set_light_level("t1", "2026-01-01T00:00:00Z", 20001);
+    bridge.set_battery("t1", 80, "normal");
+
+    let motion = Arc::new(InMemoryStorage::new(&["device_name", "timestamp"]));
+    let light_level = Arc::new(InMemoryStorage::new(&["device_name", "timestamp"]));
+    let battery = Arc::new(InMemoryStorage::new(&["device_name", "timestamp"]));
+    let config = HueConfig {
+        services_poll_interval_secs: 0,
+        ..hue_config()
+    };
+    let sensors = Sensors::with_bridge_url(&config, &bridge.server.url, APPLICATION_KEY)
+        .unwrap()
+        .with_stores(HueStores {
+            motion: motion.clone(),
+            light_level: light_level.clone(),
+            battery: battery.clone(),
+            renames: Arc::new(InMemoryStorage::new(&["device_name", "timestamp"])),
+        });
+
+    sensors.fetch_readings().unwrap();
+    sensors.fetch_readings().unwrap();
+
+    bridge.set_motion("t1", "2026-01-01T00:01:00Z", false);
+    bridge.set_battery("t1", 75, "normal");
+    sensors.fetch_readings().unwrap();
+
+    let motion: Vec<(DateTime<Utc>, bool)> = motion
+        .items()
+        .unwrap()
+        .into_iter()
+        .map(|item| (item.timestamp, item.motion))
+        .collect();
+    assert_eq!(
+        motion,
+        vec![
+            ("2026-01-01T00:00:00Z".parse().unwrap(), true),
+            ("2026-01-01T00:01:00Z".parse().unwrap(), false),
+        ]
+    );
+
+    let light_levels = light_level.items().unwrap();
+    assert_eq!(light_levels.len(), 1);
+    assert_eq!(light_levels[0].device_name, "Hallway");
+    assert_eq!(light_levels[0].bridge_id, "001788fffe000000");
+    assert_eq!(light_levels[0].light_level, 20001);
+    assert!((light_levels[0].lux - 100.0).abs() < 0.01);
+
+    // Batteries have no report time, so they are stored only when the level or state changes
+    let batteries: Vec<(u8, String)> = battery
+        .items()
+        .unwrap()
+        .into_iter()
+        .map(|item| (item.battery_level, item.battery_state))
+        .collect();
+    assert_eq!(
+        batteries,
+        vec![(80, "normal".to_string()), (75, "normal".to_string())]
+    );
+}
+
+#[test]
+fn services_are_polled_less_often_than_the_temperatures() {
+    let bridge = FakeHueBridge::start();
+    bridge.set_device("Hallway", "t1");
+    bridge.set_temperature("t1", "2026-01-01T00:00:00Z", 19.0);
+
+    let config = HueConfig {
+        services_poll_interval_secs: 3600,
+        ..hue_config()
+    };
+    let sensors = Sensors::with_bridge_url(&config, &bridge.server.url, APPLICATION_KEY)
+        .unwrap()
+        .with_stores(HueStores {
+            motion: Arc::new(InMemoryStorage::new(&["device_name", "timestamp"])),
+            light_level: Arc::new(InMemoryStorage::new(&["device_name", "timestamp"])),
+            battery: Arc::new(InMemoryStorage::new(&["device_name", "timestamp"])),
+            renames: Arc::new(InMemoryStorage::new(&["device_name", "timestamp"])),
+        });
+
+    for _ in 0..3 {
+        sensors.fetch_readings().unwrap();
+    }
+
+    let requests = |path| bridge.server.requests_to(path).len();
+    assert_eq!(requests(HUE_TEMPERATURE_URL), 3);
+    for path in [
+        HUE_ZIGBEE_CONNECTIVITY_URL,
+        HUE_MOTION_URL,
+        HUE_LIGHT_LEVEL_URL,
+        HUE_DEVICE_POWER_URL,
+    ] {
+        assert_eq!(requests(path), 1, "{path}");
+    }
+}
+
+#[test]
+fn pairing_waits_for_the_link_button() {
+    let bridge = FakeHueBridge::start();
+
+    let pairing = {
+        let bridge_url = bridge.server.url.clone();
+        let agent = hue_tls::agent(&hue_config(), Some(BRIDGE_ID)).unwrap();
+        std::thread::spawn(move || {
+            hue_pair::pair(
+                &agent,
+                &bridge_url,
+                "rust-backend#test",
+                Duration::from_millis(10),
+                Duration::from_secs(5),
+            )
+        })
+    };
+
+    wait_until(|| bridge.server.requests_to("/api").len() >= 2);
+    bridge.press_link_button();
+
+    let keys = pairing.join().unwrap().unwrap();
+    assert_eq!(keys.username, APPLICATION_KEY);
+    assert_eq!(keys.clientkey.as_deref(), Some(CLIENT_KEY));
+
+    let request: serde_json::Value =
+        serde_json::from_str(&bridge.server.requests_to("/api")[0].body).unwrap();
+    assert_eq!(
+        request,
+        serde_json::json!({ "devicetype": "rust-backend#test", "generateclientkey": true })
+    );
+}
+
+#[test]
+fn pairing_gives_up_if_the_link_button_is_not_pressed() {
+    let bridge = FakeHueBridge::start();
+
+    let agent = hue_tls::agent(&hue_config(), Some(BRIDGE_ID)).unwrap();
+
+    let result = hue_pair::pair(
+        &agent,
+        &bridge.server.url,
+        "rust-backend#test",
+        Duration::from_millis(10),
+        Duration::from_millis(50),
+    );
+
+    assert!(matches!(result, Err(SensorError::Pairing(_))), "{result:?}");
+}
+
+#[test]
+fn certificates_not_issued_by_the_root_ca_are_rejected() {
+    let bridge = FakeHueBridge::start_with_certificate(
+        BRIDGE_ID,
+        APPLICATION_KEY,
+        BridgeCertificate::SelfSigned,
+    );
+    bridge.set_device("Lounge", "t1");
+
+    assert!(Sensors::with_bridge_url(&hue_config(), &bridge.server.url, APPLICATION_KEY).is_err());
+    assert!(bridge.server.requests().is_empty());
+
+    // The bundled Hue root CA did not issue the test CA's certificates either
+    let bridge = FakeHueBridge::start();
+    assert!(
+        Sensors::with_bridge_url(&HueConfig::default(), &bridge.server.url, APPLICATION_KEY)
+            .is_err()
+    );
+}
+
+#[test]
+fn certificates_for_another_bridge_are_rejected() {
+    let bridge = FakeHueBridge::start_with_certificate(
+        BRIDGE_ID,
+        APPLICATION_KEY,
+        BridgeCertificate::Issued("001788fffe00000a".to_string()),
+    );
+    bridge.set_device("Lounge", "t1");
+
+    let error = Sensors::with_bridge_url(&hue_config(), &bridge.server.url, APPLICATION_KEY)
+        .err()
+        .unwrap();
+
+    assert!(
+        error.to_string().contains("not 001788fffe000000"),
+        "{error}"
+    );
+    // The application key is never sent to the impostor
+    assert!(bridge.server.requests_to(HUE_DEVICE_URL).is_empty());
+}
+
+#[test]
+fn pinned_certificates_are_trusted_until_they_change() {
+    let pin_dir = temp_file("placeholder", "").parent().unwrap().to_path_buf();
+    let config = HueConfig {
+        tls_verification: HueTlsVerification::Pin,
+        certificate_pin_path: pin_dir
+            .join("hue_certificate_{bridge_id}.sha256")
+            .to_string_lossy()
+            .into_owned(),
+        ..hue_config()
+    };
+    let pin_path = pin_dir.join("hue_certificate_001788fffe000000.sha256");
+
+    let bridge = FakeHueBridge::start_with_certificate(
+        BRIDGE_ID,
+        APPLICATION_KEY,
+        BridgeCertificate::SelfSigned,
+    );
+    bridge.set_device("Lounge", "t1");
+    bridge.set_temperature("t1", "2026-01-01T00:00:00Z", 20.5);
+
+    // The first certificate seen is pinned and then trusted
+    let sensors = Sensors::with_bridge_url(&config, &bridge.server.url, APPLICATION_KEY).unwrap();
+    assert_eq!(readings(&sensors), vec![("Lounge".to_string(), 20.5)]);
+    let pinned = std::fs::read_to_string(&pin_path).unwrap();
+    assert_eq!(pinned.trim().len(), 95, "{pinned}");
+
+    // The same bridge with a new certificate is rejected
+    let replaced = FakeHueBridge::start_with_certificate(
+        BRIDGE_ID,
+        APPLICATION_KEY,
+        BridgeCertificate::SelfSigned,
+    );
+    let error = Sensors::with_bridge_url(&config, &replaced.server.url, APPLICATION_KEY)
+        .err()
+        .unwrap();
+    assert!(error.to_string().contains("has changed"), "{error}");
+    assert_eq!(std::fs::read_to_string(&pin_path).unwrap(), pinned);
+    assert!(replaced.server.requests_to(HUE_DEVICE_URL).is_empty());
+
+    // Until the pin is deleted
+    std::fs::remove_file(&pin_path).unwrap();
+    assert!(Sensors::with_bridge_url(&config, &replaced.server.url, APPLICATION_KEY).is_ok());
+}
@@ -0,0 +1,462 @@
+use std::sync::Arc;
+
+use chrono::{Duration, SubsecRound, Utc};
+
+use super::fake_google::{DEVICES, FakeGoogle, ROTATED_REFRESH_TOKEN, SUBSCRIPTION};
+use super::harness::wait_until;
+
+use crate::config::settings::NestConfig;
+use crate::datastore::memory::InMemoryStorage;
+use crate::sensor_control::commands::{ThermostatCommand, ThermostatMode};
+use crate::sensor_control::errors::SensorError;
+use crate::sensor_control::models::{
+    NestCredentials, TemperatureData, ThermostatCommandRecord, ThermostatState,
+};
+use crate::sensor_control::nest::{NestThermostat, RefreshTokenStatus};
+use crate::sensor_control::source::SensorSource;
+use crate::shutdown::Shutdown;
+
+const DEVICES_PATH: &str = "/v1/enterprises/test-project/devices";
+const EXECUTE_COMMAND_PATH: &str =
+    "/v1/enterprises/test-project/devices/thermostat-1:executeCommand";
+
+fn thermostat(google: &FakeGoogle) -> NestThermostat {
+    NestThermostat::with_credentials(&google.config(), google.credentials())
+}
+
+/// The ack IDs acknowledged so far
+fn acknowledged(google: &FakeGoogle) -> Vec<serde_json::Value> {
+    google
+        .server
+        .requests_to(&format!("/v1/{SUBSCRIPTION}:acknowledge"))
+        .iter()
+        .flat_map(|request| {
+            let body: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+            body["ackIds"].as_array().unwrap().clone()
+        })
+        .collect()
+}
+
+#[test]
+fn thermostat_readings_are_mapped() {
+    let google = FakeGoogle::start();
+
+    let readings = thermostat(&google).fetch_readings().unwrap();
+
+    // The camera is skipped and the thermostat is named after its room
+    assert_eq!(readings.len(), 1);
+    assert_eq!(readings[0].device_name, "Nest (Hallway)");
+    assert_eq!(readings[0].temperature, 20.5);
+    assert_eq!(readings[0].humidity, 45.0);
+    assert!(readings[0].online);
+
+    let devices_requests = google.server.requests_to(DEVICES_PATH);
+    assert_eq!(
+        devices_requests[0].header("Authorization"),
+        Some("Bearer access-token-1")
+    );
+}
+
+#[test]
+fn access_token_is_reused_until_it_expires() {
+    let google = FakeGoogle::start();
+    let thermostat = thermostat(&google);
+
+    thermostat.fetch_readings().unwrap();
+    thermostat.fetch_readings().unwrap();
+
+    assert_eq!(google.server.requests_to("/token").len(), 1);
+    assert_eq!(google.server.requests_to(DEVICES_PATH).len(), 2);
+}
+
+#[test]
+fn unauthorized_response_refreshes_the_token_and_retries() {
+    let google = FakeGoogle::start();
+    let thermostat = thermostat(&google);
+
+    thermostat.fetch_readings().unwrap();
+    google.expire_access_tokens();
+    let readings = thermostat.fetch_readings().unwrap();
+
+    assert_eq!(readings.len(), 1);
+    assert_eq!(google.server.requests_to("/token").len(), 2);
+
+    let authorizations: Vec<String> = google
+        .server
+        .requests_to(DEVICES_PATH)
+        .iter()
+        .filter_map(|request| request.header("Authorization").map(str::to_string))
+        .collect();
+    assert_eq!(
+        authorizations,
+        vec![
+            "Bearer access-token-1",
+            "Bearer access-token-1",
+            "Bearer access-token-2"
+        ]
+    );
+}
+
+#[test]
+fn invalid_grant_is_an_error() {
+    let google = FakeGoogle::start();
+    google.revoke_refresh_token();
+
+    let result = thermostat(&google).fetch_readings();
+
+    assert!(matches!(
+        result,
+        Err(SensorError::Ureq(ureq::Error::StatusCode(400)))
+    ));
+    assert!(google.server.requests_to(DEVICES_PATH).is_empty());
+}
+
+#[test]
+fn rotated_refresh_tokens_are_saved_and_used() {
+    let google = FakeGoogle::start();
+    let credentials_path = google.credentials_file();
+    let config = NestConfig {
+        credentials_path: credentials_path.to_string_lossy().into_owned(),
+        ..google.config()
+    };
+    let nest = NestThermostat::new(&config).unwrap();
+
+    google.rotate_refresh_token();
+    google.set_refresh_token_expires_in(7 * 24 * 60 * 60);
+    nest.fetch_readings().unwrap();
+
+    let saved: NestCredentials =
+        serde_json::from_str(&std::fs::read_to_string(&credentials_path).unwrap()).unwrap();
+    assert_eq!(saved.refresh_token, ROTATED_REFRESH_TOKEN);
+    let expires_at = saved.refresh_token_expires_at.unwrap();
+    assert!(expires_at > Utc::now() + Duration::days(6));
+
+    // The old refresh token is no longer accepted, so the next refresh must use the new one
+    google.expire_access_tokens();
+    nest.fetch_readings().unwrap();
+
+    let status = nest.refresh_token_status().lock().unwrap().clone();
+    assert_eq!(status.state(Utc::now()), "ok");
+    assert!(status.expires_at.unwrap() >= expires_at);
+    assert!(status.last_refreshed.is_some());
+
+    std::fs::remove_dir_all(credentials_path.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn refresh_token_status_reports_expiry_and_rejection() {
+    let google = FakeGoogle::start();
+    let nest = thermostat(&google);
+    let status = nest.refresh_token_status();
+
+    google.set_refresh_token_expires_in(60 * 60);
+    nest.fetch_readings().unwrap();
+    assert_eq!(status.lock().unwrap().state(Utc::now()), "expiring");
+
+    google.revoke_refresh_token();
+    google.expire_access_tokens();
+    assert!(nest.fetch_readings().is_err());
+
+    let rejected = status.lock().unwrap().clone();
+    assert_eq!(rejected.state(Utc::now()), "error");
+    assert!(rejected.error.unwrap().contains("invalid_grant"));
+
+    // Once the token has expired it counts as expired even before Google rejects it
+    let expired = RefreshTokenStatus {
+        error: None,
+        ..rejected
+    };
+    assert_eq!(expired.state(Utc::now() + Duration::hours(2)), "expired");
+}
+
+#[test]
+fn thermostat_state_is_recorded_with_each_poll() {
+    let google = FakeGoogle::start();
+    let state_store = Arc::new(InMemoryStorage::<ThermostatState>::new(&[
+        "device_name",
+        "timestamp",
+    ]));
+    let thermostat = thermostat(&google).with_state_store(state_store.clone());
+
+    thermostat.fetch_readings().unwrap();
+
+    // In eco mode the regular setpoint is not reported and the eco setpoints apply
+    google.set_devices(
+        &DEVICES
+            .replace(r#""mode": "OFF""#, r#""mode": "MANUAL_ECO""#)
+            .replace(r#""status": "HEATING""#, r#""status": "OFF""#)
+            .replace(r#"{ "heatCelsius": 21.0 }"#, "{}"),
+    );
+    thermostat.fetch_readings().unwrap();
+
+    let states = state_store.items().unwrap();
+    assert_eq!(states.len(), 2);
+
+    assert_eq!(states[0].device_name, "Nest (Hallway)");
+    assert_eq!(states[0].mode.as_deref(), Some("HEAT"));
+    assert_eq!(states[0].eco_mode.as_deref(), Some("OFF"));
+    assert_eq!(states[0].hvac_status.as_deref(), Some("HEATING"));
+    assert_eq!(states[0].heat_setpoint_celsius, Some(21.0));
+    assert_eq!(states[0].cool_setpoint_celsius, None);
+
+    assert_eq!(states[1].eco_mode.as_deref(), Some("MANUAL_ECO"));
+    assert_eq!(states[1].hvac_status.as_deref(), Some("OFF"));
+    assert_eq!(states[1].heat_setpoint_celsius, Some(12.0));
+    assert_eq!(states[1].cool_setpoint_celsius, Some(26.0));
+}
+
+#[test]
+fn commands_are_sent_and_recorded() {
+    let google = FakeGoogle::start();
+    let command_store = Arc::new(InMemoryStorage::<ThermostatCommandRecord>::new(&[]));
+    let thermostat = thermostat(&google).with_command_store(command_store.clone());
+
+    thermostat
+        .execute_command(
+            "Nest (Hallway)",
+            &ThermostatCommand::SetHeat { heat_celsius: 21.5 },
+        )
+        .unwrap();
+
+    let requests = google.server.requests_to(EXECUTE_COMMAND_PATH);
+    assert_eq!(requests.len(), 1);
+    assert_eq!(
+        requests[0].header("Authorization"),
+        Some("Bearer access-token-1")
+    );
+    let body: serde_json::Value = serde_json::from_str(&requests[0].body).unwrap();
+    assert_eq!(
+        body,
+        serde_json::json!({
+            "command": "sdm.devices.commands.ThermostatTemperatureSetpoint.SetHeat",
+            "params": { "heatCelsius": 21.5 },
+        })
+    );
+
+    let records = command_store.items().unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].device_name, "Nest (Hallway)");
+    assert_eq!(
+        records[0].params,
+        serde_json::json!({ "heatCelsius": 21.5 })
+    );
+    assert!(records[0].success);
+    assert_eq!(records[0].error, None);
+}
+
+#[test]
+fn invalid_commands_are_recorded_but_not_sent() {
+    let google = FakeGoogle::start();
+    let command_store = Arc::new(InMemoryStorage::<ThermostatCommandRecord>::new(&[]));
+    let thermostat = thermostat(&google).with_command_store(command_store.clone());
+
+    // The thermostat is heating, only supports HEAT and OFF, and has no fan
+    for command in [
+        ThermostatCommand::SetCool { cool_celsius: 24.0 },
+        ThermostatCommand::SetHeat { heat_celsius: 40.0 },
+        ThermostatCommand::SetMode(ThermostatMode::Cool),
+        ThermostatCommand::SetFanTimer(std::time::Duration::from_secs(900)),
+    ] {
+        let result = thermostat.execute_command("Nest (Hallway)", &command);
+        assert!(
+            matches!(result, Err(SensorError::InvalidCommand(_))),
+            "{command:?} gave {result:?}"
+        );
+    }
+
+    let result = thermostat.execute_command(
+        "Nest (Attic)",
+        &ThermostatCommand::SetMode(ThermostatMode::Off),
+    );
+    assert!(matches!(result, Err(SensorError::DeviceNotFound(_))));
+
+    assert!(google.server.requests_to(EXECUTE_COMMAND_PATH).is_empty());
+
+    let records = command_store.items().unwrap();
+    assert_eq!(records.len(), 5);
+    assert!(
+        records
+            .iter()
+            .all(|record| !record.success && record.error.is_some())
+    );
+}
+
+#[test]
+fn events_are_stored_with_their_own_timestamps() {
+    let google = FakeGoogle::start();
+    let storage = Arc::new(InMemoryStorage::<TemperatureData>::new(&[
+        "device_name",
+        "timestamp",
+    ]));
+    let shutdown = Shutdown::new();
+
+    // Events only carry the traits that changed
+    let first = Utc::now().trunc_subsecs(0) + Duration::minutes(1);
+    let second = first + Duration::minutes(1);
+    let name = "enterprises/test-project/devices/thermostat-1";
+    google.publish_event(serde_json::json!({
+        "eventId": "event-1",
+        "timestamp": first,
+        "resourceUpdate": {
+            "name": name,
+            "traits": { "sdm.devices.traits.Temperature": { "ambientTemperatureCelsius": 21.0 } },
+        },
+    }));
+    google.publish_event(serde_json::json!({
+        "eventId": "event-2",
+        "timestamp": second,
+        "resourceUpdate": {
+            "name": name,
+            "traits": { "sdm.devices.traits.Humidity": { "ambientHumidityPercent": 50 } },
+        },
+    }));
+
+    let handle = thermostat(&google)
+        .run_events(Arc::clone(&storage), &shutdown)
+        .unwrap();
+    wait_until(|| storage.items().unwrap().len() == 3);
+    shutdown.trigger();
+    handle.join().unwrap();
+
+    let readings: Vec<_> = storage
+        .items()
+        .unwrap()
+        .into_iter()
+        .map(|reading| (reading.temperature, reading.humidity))
+        .collect();
+    assert_eq!(readings, vec![(20.5, 45.0), (21.0, 45.0), (21.0, 50.0)]);
+
+    let items = storage.items().unwrap();
+    assert_eq!(items[1].timestamp, first);
+    assert_eq!(items[2].timestamp, second);
+
+    assert_eq!(acknowledged(&google), vec!["ack-1", "ack-2"]);
+}
+
+#[test]
+fn events_from_before_the_last_poll_are_stored_once() {
+    let google = FakeGoogle::start();
+    let storage = Arc::new(InMemoryStorage::<TemperatureData>::new(&[
+        "device_name",
+        "timestamp",
+    ]));
+    let shutdown = Shutdown::new();
+
+    let earlier = Utc::now().trunc_subsecs(0) - Duration::minutes(1);
+    let later = earlier + Duration::minutes(2);
+    let name = "enterprises/test-project/devices/thermostat-1";
+    let delayed = serde_json::json!({
+        "eventId": "event-1",
+        "timestamp": earlier,
+        "resourceUpdate": {
+            "name": name,
+            "traits": { "sdm.devices.traits.Temperature": { "ambientTemperatureCelsius": 21.0 } },
+        },
+    });
+    google.publish_event(delayed.clone());
+
+    let handle = thermostat(&google)
+        .run_events(Arc::clone(&storage), &shutdown)
+        .unwrap();
+    wait_until(|| acknowledged(&google).len() == 1);
+
+    // Redelivered, then followed by a newer event
+    google.publish_event(delayed);
+    google.publish_event(serde_json::json!({
+        "eventId": "event-2",
+        "timestamp": later,
+        "resourceUpdate": {
+            "name": name,
+            "traits": { "sdm.devices.traits.Humidity": { "ambientHumidityPercent": 50 } },
+        },
+    }));
+    wait_until(|| acknowledged(&google).len() == 3);
+    shutdown.trigger();
+    handle.join().unwrap();
+
+    let items = storage.items().unwrap();
+    let readings: Vec<_> = items
+        .iter()
+        .map(|reading| (reading.temperature, reading.humidity))
+        .collect();
+    // The delayed event is stored, but the newer polled temperature stays current
+    assert_eq!(readings, vec![(20.5, 45.0), (21.0, 45.0), (20.5, 50.0)]);
+    assert_eq!(items[1].timestamp, earlier);
+    assert_eq!(items[2].timestamp, later);
+}
+
+#[test]
+fn events_for_an_unknown_device_poll_for_it_before_being_acknowledged() {
+    let google = FakeGoogle::start();
+    google.set_devices(r#"{ "devices": [] }"#);
+    let storage = Arc::new(InMemoryStorage::<TemperatureData>::new(&[
+        "device_name",
+        "timestamp",
+    ]));
+    let shutdown = Shutdown::new();
+
+    let handle = thermostat(&google)
+        .run_events(Arc::clone(&storage), &shutdown)
+        .unwrap();
+    wait_until(|| google.server.requests_to(DEVICES_PATH).len() == 1);
+
+    // The thermostat is added after the first poll
+    google.set_devices(DEVICES);
+    google.publish_event(serde_json::json!({
+        "eventId": "event-1",
+        "timestamp": Utc::now().trunc_subsecs(0) + Duration::minutes(1),
+        "resourceUpdate": {
+            "name": "enterprises/test-project/devices/thermostat-1",
+            "traits": { "sdm.devices.traits.Temperature": { "ambientTemperatureCelsius": 21.0 } },
+        },
+    }));
+    // An event for a device that is not a thermostat is acknowledged after the poll
+    google.publish_event(serde_json::json!({
+        "eventId": "event-2",
+        "timestamp": Utc::now(),
+        "resourceUpdate": { "name": "enterprises/test-project/devices/camera-1", "traits": {} },
+    }));
+    wait_until(|| acknowledged(&google).len() == 2);
+    shutdown.trigger();
+    handle.join().unwrap();
+
+    let temperatures: Vec<f32> = storage
+        .items()
+        .unwrap()
+        .iter()
+        .map(|reading| reading.temperature)
+        .collect();
+    assert_eq!(temperatures, vec![20.5, 21.0]);
+    assert_eq!(google.server.requests_to(DEVICES_PATH).len(), 2);
+}
+
+#[test]
+fn thermostats_are_polled_while_the_subscription_is_unavailable() {
+    let google = FakeGoogle::start();
+    google.set_pubsub_available(false);
+    let storage = Arc::new(InMemoryStorage::<TemperatureData>::new(&[
+        "device_name",
+        "timestamp",
+    ]));
+    let shutdown = Shutdown::new();
+
+    let mut config = google.config();
+    config.poll_interval_secs = 1;
+    let handle = NestThermostat::with_credentials(&config, google.credentials())
+        .run_events(Arc::clone(&storage), &shutdown)
+        .unwrap();
+
+    wait_until(|| storage.items().unwrap().len() == 2);
+    shutdown.trigger();
+    handle.join().unwrap();
+
+    // The subscription is retried with each poll
+    assert!(google.server.requests_to(DEVICES_PATH).len() >= 2);
+    assert!(
+        google
+            .server
+            .requests_to(&format!("/v1/{SUBSCRIPTION}:pull"))
+            .len()
+            >= 2
+    );
+}
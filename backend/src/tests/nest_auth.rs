@@ -0,0 +1,139 @@
+use std::fs;
+use std::thread;
+
+use chrono::{Duration, SubsecRound, Utc};
+
+use super::fake_google::{AUTHORIZATION_CODE, FakeGoogle, NEW_REFRESH_TOKEN, PROJECT_ID};
+use super::harness::unreachable_address;
+
+use crate::sensor_control::errors::SensorError;
+use crate::sensor_control::models::NestCredentials;
+use crate::sensor_control::nest_auth;
+
+const REDIRECT_URI: &str = "http://localhost:8091";
+
+#[test]
+fn consent_url_asks_for_offline_access() {
+    let google = FakeGoogle::start();
+
+    let url = nest_auth::consent_url(&google.config(), &google.credentials(), REDIRECT_URI);
+
+    assert!(url.starts_with(&format!(
+        "https://nestservices.google.com/partnerconnections/{PROJECT_ID}/auth?"
+    )));
+    let query: Vec<(String, String)> =
+        form_urlencoded::parse(url.split_once('?').unwrap().1.as_bytes())
+            .into_owned()
+            .collect();
+    for (key, value) in [
+        ("redirect_uri", REDIRECT_URI),
+        ("access_type", "offline"),
+        ("prompt", "consent"),
+        ("client_id", "test-client-id"),
+        ("response_type", "code"),
+    ] {
+        assert!(
+            query.contains(&(key.to_string(), value.to_string())),
+            "missing {key}"
+        );
+    }
+}
+
+#[test]
+fn code_is_parsed_from_a_pasted_url() {
+    assert_eq!(
+        nest_auth::parse_code("https://www.google.com?code=4/abc-123&scope=x"),
+        Some("4/abc-123".to_string())
+    );
+    assert_eq!(
+        nest_auth::parse_code("  4/abc-123\n"),
+        Some("4/abc-123".to_string())
+    );
+    assert_eq!(nest_auth::parse_code("\n"), None);
+}
+
+#[test]
+fn code_is_exchanged_for_a_refresh_token() {
+    let google = FakeGoogle::start();
+
+    let tokens = nest_auth::exchange_code(
+        &google.config(),
+        &google.credentials(),
+        AUTHORIZATION_CODE,
+        REDIRECT_URI,
+    )
+    .unwrap();
+
+    assert_eq!(tokens.refresh_token.as_deref(), Some(NEW_REFRESH_TOKEN));
+    assert_eq!(tokens.refresh_token_expires_in, Some(604_799));
+
+    let request = &google.server.requests_to("/token")[0];
+    assert!(request.body.contains("grant_type=authorization_code"));
+    assert!(
+        request
+            .body
+            .contains("redirect_uri=http%3A%2F%2Flocalhost%3A8091")
+    );
+}
+
+#[test]
+fn invalid_code_is_an_error() {
+    let google = FakeGoogle::start();
+
+    let result = nest_auth::exchange_code(
+        &google.config(),
+        &google.credentials(),
+        "wrong-code",
+        REDIRECT_URI,
+    );
+
+    assert!(
+        matches!(&result, Err(SensorError::Authorization(message)) if message.contains("invalid_grant")),
+        "{result:?}"
+    );
+}
+
+#[test]
+fn refresh_token_is_replaced_in_place() {
+    let google = FakeGoogle::start();
+    let path = google.credentials_file();
+
+    let expires_at = Utc::now().trunc_subsecs(0) + Duration::days(7);
+
+    nest_auth::save_refresh_token(&path, NEW_REFRESH_TOKEN, Some(expires_at)).unwrap();
+
+    let saved: NestCredentials = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(saved.refresh_token, NEW_REFRESH_TOKEN);
+    assert_eq!(saved.refresh_token_expires_at, Some(expires_at));
+    assert_eq!(saved.client_id, "test-client-id");
+    assert_eq!(saved.project_id, PROJECT_ID);
+
+    // Only the credentials file is left behind
+    let dir = path.parent().unwrap();
+    assert_eq!(fs::read_dir(dir).unwrap().count(), 1);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn listener_returns_the_redirected_code() {
+    let address = unreachable_address();
+
+    let listener_address = address.clone();
+    let listener = thread::spawn(move || nest_auth::wait_for_code(&listener_address));
+
+    // Retry until the listener is up
+    let url = format!("http://{address}/?code={AUTHORIZATION_CODE}&scope=x");
+    let mut response = None;
+    for _ in 0..100 {
+        match ureq::get(&url).call() {
+            Ok(ok) => {
+                response = Some(ok);
+                break;
+            }
+            Err(_) => thread::sleep(std::time::Duration::from_millis(20)),
+        }
+    }
+
+    assert_eq!(response.expect("listener never started").status(), 200);
+    assert_eq!(listener.join().unwrap().unwrap(), AUTHORIZATION_CODE);
+}